@@ -17,16 +17,16 @@ use nrf52840_hal as hal;
 
 use rtic::cyccnt::U32Ext;
 use rubble::beacon::Beacon;
-use rubble::link::{ad_structure::AdStructure, MIN_PDU_BUF};
+use rubble::link::{ad_structure::AdStructure, MAX_PDU_BUF};
 use rubble_nrf5x::radio::{BleRadio, PacketBuffer};
 use rubble_nrf5x::utils::get_device_address;
 
 #[rtic::app(device = crate::hal::pac, peripherals = true, monotonic = rtic::cyccnt::CYCCNT)]
 const APP: () = {
     struct Resources {
-        #[init([0; MIN_PDU_BUF])]
+        #[init([0; MAX_PDU_BUF])]
         ble_tx_buf: PacketBuffer,
-        #[init([0; MIN_PDU_BUF])]
+        #[init([0; MAX_PDU_BUF])]
         ble_rx_buf: PacketBuffer,
         radio: BleRadio,
         beacon: Beacon,
@@ -50,29 +50,39 @@ use core::fmt::Write;
 use core::sync::atomic::{compiler_fence, Ordering};
 use hal::uarte::{Baudrate, Parity, Uarte};
 use hal::{gpio::Level, pac::UARTE0};
+use rtic::Mutex;
 use rubble::l2cap::{BleChannelMap, L2CAPState};
 use rubble::link::queue::{PacketQueue, SimpleQueue};
-use rubble::link::{ad_structure::AdStructure, LinkLayer, Responder, MIN_PDU_BUF};
+use rubble::link::advertising::{AdvertisingParameters, AdvertisingType};
+use rubble::link::{ad_structure::AdStructure, pcap::NoCapture, LinkLayer, Responder, MAX_PDU_BUF};
+use rubble::link::ConnectionEvent;
 use rubble::time::{Duration, Timer};
-use rubble::{config::Config, gatt::BatteryServiceAttrs, security::NoSecurity};
+use rubble::gatt::{characteristic::Appearance, BatteryServiceAttrs, GenericServices};
+use rubble::{config::Config, crypto::SoftAesProvider, security::NoSecurity};
 use rubble_nrf5x::radio::{BleRadio, PacketBuffer};
 use rubble_nrf5x::{timer::BleTimer, utils::get_device_address};
 
+/// Device name used in the advertising data and the *Generic Access* service.
+const DEVICE_NAME: &str = "CONCVRRENS CERTA CELERIS";
+
 pub enum AppConfig {}
 
 impl Config for AppConfig {
     type Timer = BleTimer<hal::pac::TIMER0>;
     type Transmitter = BleRadio;
-    type ChannelMapper = BleChannelMap<BatteryServiceAttrs, NoSecurity>;
+    type ChannelMapper = BleChannelMap<GenericServices<'static, BatteryServiceAttrs>, NoSecurity>;
     type PacketQueue = &'static mut SimpleQueue;
+    type AesProvider = SoftAesProvider;
+    type Rng = hal::rng::Rng;
+    type PcapSink = NoCapture;
 }
 
 #[rtic::app(device = crate::hal::pac, peripherals = true)]
 const APP: () = {
     struct Resources {
-        #[init([0; MIN_PDU_BUF])]
+        #[init([0; MAX_PDU_BUF])]
         ble_tx_buf: PacketBuffer,
-        #[init([0; MIN_PDU_BUF])]
+        #[init([0; MAX_PDU_BUF])]
         ble_rx_buf: PacketBuffer,
         #[init(SimpleQueue::new())]
         tx_queue: SimpleQueue,
@@ -117,19 +127,31 @@ const APP: () = {
         let (rx_prod, rx) = ctx.resources.rx_queue.split();
 
         // Create the actual BLE stack objects
-        let mut ble_ll = LinkLayer::<AppConfig>::new(device_address, ble_timer);
+        let rng = hal::rng::Rng::new(ctx.device.RNG);
+        let mut ble_ll =
+            LinkLayer::<AppConfig>::new(device_address, ble_timer, SoftAesProvider::new(), rng);
 
         let ble_r = Responder::new(
             tx,
             rx,
-            L2CAPState::new(BleChannelMap::with_attributes(BatteryServiceAttrs::new())),
+            L2CAPState::new(BleChannelMap::with_attributes(GenericServices::new(
+                BatteryServiceAttrs::new(),
+                DEVICE_NAME,
+                Appearance::Unknown,
+            ))),
         );
 
         // Send advertisement and set up regular interrupt
+        let params = AdvertisingParameters::new(
+            AdvertisingType::ConnectableUndirected,
+            Duration::from_millis(200),
+            Duration::from_millis(200),
+        )
+        .unwrap();
         let next_update = ble_ll
             .start_advertise(
-                Duration::from_millis(200),
-                &[AdStructure::CompleteLocalName("CONCVRRENS CERTA CELERIS")],
+                params,
+                &[AdStructure::CompleteLocalName(DEVICE_NAME)],
                 &mut radio,
                 tx_cons,
                 rx_prod,
@@ -209,8 +231,22 @@ const APP: () = {
         }
     }
 
-    #[task(resources = [ble_r], priority = 2)]
-    fn ble_worker(ctx: ble_worker::Context) {
+    #[task(resources = [ble_ll, ble_r], priority = 2)]
+    fn ble_worker(mut ctx: ble_worker::Context) {
+        // Reset the upper layers when a connection was established or lost
+        while let Some((event, local)) = ctx
+            .resources
+            .ble_ll
+            .lock(|ble_ll| ble_ll.take_connection_event().map(|ev| (ev, ble_ll.device_address())))
+        {
+            match event {
+                ConnectionEvent::Established { peer } => {
+                    ctx.resources.ble_r.connection_established(local, peer)
+                }
+                ConnectionEvent::Ended(_) => ctx.resources.ble_r.connection_ended(),
+            }
+        }
+
         // Fully drain the packet queue
         while ctx.resources.ble_r.has_work() {
             ctx.resources.ble_r.process_one().unwrap();
@@ -57,14 +57,18 @@ use core::cmp;
 use core::sync::atomic::{compiler_fence, Ordering};
 use pac::{radio::state::STATE_R, RADIO};
 use rubble::config::Config;
+use rubble::crypto::MIC_SIZE;
 use rubble::link::{
-    advertising, data, Cmd, LinkLayer, RadioCmd, Transmitter, CRC_POLY, MIN_PDU_BUF,
+    advertising, data, Cmd, LinkLayer, RadioCmd, Transmitter, CRC_POLY, MAX_DATA_PAYLOAD_BUF,
+    MAX_PDU_BUF,
 };
 use rubble::phy::{AdvertisingChannel, DataChannel};
 use rubble::time::{Duration, Instant};
 
 /// A packet buffer that can hold header and payload of any advertising or data channel packet.
-pub type PacketBuffer = [u8; MIN_PDU_BUF];
+///
+/// This is large enough for data channel packets using the Packet Length Extension.
+pub type PacketBuffer = [u8; MAX_PDU_BUF];
 
 /// An interface to the nRF radio in BLE mode.
 pub struct BleRadio {
@@ -473,6 +477,11 @@ impl Transmitter for BleRadio {
         &mut self.tx_buf[2..]
     }
 
+    fn max_data_payload(&self) -> u8 {
+        // Leave room for the header and the MIC of encrypted PDUs.
+        cmp::min(self.tx_buf.len() - 2 - MIC_SIZE, MAX_DATA_PAYLOAD_BUF) as u8
+    }
+
     fn transmit_advertising(&mut self, header: advertising::Header, channel: AdvertisingChannel) {
         let raw_header = header.to_u16();
         // S0 = 8 bits (LSB)
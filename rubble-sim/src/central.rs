@@ -0,0 +1,794 @@
+//! A scripted central for testing the peripheral side of a connection.
+//!
+//! Unlike a central running on a second Rubble stack, the `ScriptedCentral` sends exactly the data
+//! channel PDUs it is told to, and can inject faults that a well-behaved stack would never
+//! produce: Missed connection events, corrupted packets, and packets with unexpected sequence
+//! numbers. It talks to a single `Node` directly, without a `Medium`, and returns the peripheral's
+//! response to every packet, so tests can check how the Link-Layer reacts.
+
+use crate::device::Node;
+use crate::radio::Packet;
+use crate::timer::{since, SimTimer};
+use rubble::bytes::{ByteReader, ByteWriter, FromBytes, ToBytes};
+use rubble::l2cap::Channel;
+use rubble::link::advertising::{self, ConnectRequestData, PduBuf, PduType};
+use rubble::link::data::{self, Llid};
+use rubble::link::llcp::{ConnectionUpdateData, ControlPdu};
+use rubble::link::{ChannelMap, DeviceAddress, RadioCmd, SeqNum};
+use rubble::phy::DataChannel;
+use rubble::time::{Duration, Instant, Timer};
+
+/// Max. time to wait for the peripheral to advertise before giving up.
+const ADV_TIMEOUT: Duration = Duration::from_micros(15_000_000);
+
+/// A data channel PDU to be sent by the `ScriptedCentral`.
+#[derive(Debug, Clone)]
+pub struct DataPdu {
+    llid: Llid,
+    payload: Vec<u8>,
+}
+
+impl DataPdu {
+    /// Creates a PDU with an arbitrary LLID and payload.
+    pub fn new(llid: Llid, payload: &[u8]) -> Self {
+        Self {
+            llid,
+            payload: payload.to_vec(),
+        }
+    }
+
+    /// Creates an empty PDU, which is sent when there's no data to transmit.
+    pub fn empty() -> Self {
+        Self::new(Llid::DataCont, &[])
+    }
+
+    /// Creates a PDU carrying an LL Control PDU.
+    pub fn control(pdu: &ControlPdu<'_>) -> Self {
+        let mut buf = [0; 255];
+        let mut writer = ByteWriter::new(&mut buf);
+        pdu.to_bytes(&mut writer).unwrap();
+        let len = 255 - writer.space_left();
+        Self::new(Llid::Control, &buf[..len])
+    }
+
+    /// Creates a PDU carrying a complete (unfragmented) L2CAP message for `channel`.
+    pub fn l2cap(channel: Channel, message: &[u8]) -> Self {
+        let mut payload = Vec::with_capacity(4 + message.len());
+        payload.extend_from_slice(&(message.len() as u16).to_le_bytes());
+        payload.extend_from_slice(&channel.as_raw().to_le_bytes());
+        payload.extend_from_slice(message);
+        Self {
+            llid: Llid::DataStart,
+            payload,
+        }
+    }
+}
+
+/// What the `ScriptedCentral` does in a connection event.
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Sends a PDU, acknowledging the peripheral's last PDU.
+    Send(DataPdu),
+
+    /// Sends a PDU that arrives with a CRC error.
+    Corrupt(DataPdu),
+
+    /// Sends a PDU without acknowledging the peripheral's last PDU, as if it was lost.
+    ///
+    /// The peripheral should retransmit its last PDU in response.
+    SendWithoutAck(DataPdu),
+
+    /// Sends the last PDU again, with the same SN and NESN bits.
+    Replay,
+
+    /// Doesn't transmit anything in this connection event.
+    Skip,
+}
+
+/// The peripheral's response in a connection event.
+#[derive(Debug, Clone)]
+pub struct Reply {
+    header: data::Header,
+    payload: Vec<u8>,
+    new: bool,
+}
+
+impl Reply {
+    /// Returns the data channel PDU header.
+    pub fn header(&self) -> data::Header {
+        self.header
+    }
+
+    /// Returns the PDU payload.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Returns whether this is a new PDU, or a retransmission of the previous reply (according to
+    /// its SN bit).
+    pub fn is_new(&self) -> bool {
+        self.new
+    }
+
+    /// Returns whether this is an empty PDU.
+    pub fn is_empty(&self) -> bool {
+        self.header.llid() == Llid::DataCont && self.payload.is_empty()
+    }
+
+    /// Parses the payload as an LL Control PDU.
+    ///
+    /// Returns `None` if this isn't a control PDU or it can't be parsed.
+    pub fn control_pdu(&self) -> Option<ControlPdu<'_>> {
+        if self.header.llid() == Llid::Control {
+            ControlPdu::from_bytes(&mut ByteReader::new(&self.payload)).ok()
+        } else {
+            None
+        }
+    }
+}
+
+/// A connection parameter change taking effect at an instant.
+#[derive(Debug, Clone)]
+enum Update {
+    Connection(ConnectionUpdateData),
+    ChannelMap { map: ChannelMap, instant: u16 },
+}
+
+impl Update {
+    fn instant(&self) -> u16 {
+        match self {
+            Update::Connection(data) => data.instant(),
+            Update::ChannelMap { instant, .. } => *instant,
+        }
+    }
+}
+
+/// A central in a connection with a simulated peripheral, executing a script of `Step`s.
+///
+/// The central follows the connection's timing and channel hopping, and applies the connection
+/// updates and channel maps it sends at their instant. It does not retransmit unacknowledged PDUs
+/// by itself: `Step::Replay` has to be used for that.
+#[derive(Debug)]
+pub struct ScriptedCentral {
+    timer: SimTimer,
+
+    access_address: u32,
+    crc_init: u32,
+    interval: Duration,
+    hop: u8,
+    channel_map: ChannelMap,
+    unmapped_channel: u8,
+    channel: DataChannel,
+
+    /// Anchor point of the next connection event.
+    anchor: Instant,
+    event_counter: u16,
+
+    sn: SeqNum,
+    nesn: SeqNum,
+    last_sent: Option<(data::Header, Vec<u8>)>,
+    acked: bool,
+
+    /// SN of the last reply, for detecting retransmissions.
+    last_reply_sn: Option<SeqNum>,
+    /// Whether the last reply was acknowledged by toggling `nesn`.
+    last_reply_acked: bool,
+
+    pending_update: Option<Update>,
+}
+
+impl ScriptedCentral {
+    /// Connects to the advertising device `peripheral`.
+    ///
+    /// This waits for the peripheral to send a connectable advertisement and answers it with a
+    /// `CONNECT_REQ` containing `lldata`. `timer` must use the same clock as the peripheral's timer.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the peripheral doesn't send a connectable advertisement within a few
+    /// seconds, or doesn't accept the connection.
+    pub fn connect(
+        addr: DeviceAddress,
+        timer: SimTimer,
+        peripheral: &mut dyn Node,
+        lldata: &ConnectRequestData,
+    ) -> Self {
+        let start = timer.now();
+        let (channel, advertiser, adv_end) = loop {
+            let at = peripheral
+                .next_update()
+                .expect("peripheral is not advertising");
+            assert!(
+                since(at, start) < ADV_TIMEOUT,
+                "no connectable advertisement received"
+            );
+            timer.advance_to(at);
+            peripheral.timer_expired();
+
+            let adv = peripheral.radio().take_sent().into_iter().find_map(|p| {
+                let air_time = p.air_time();
+                let (channel, header, payload) = match p {
+                    Packet::Advertising {
+                        channel,
+                        header,
+                        payload,
+                    } => (channel, header, payload),
+                    Packet::Data { .. } => return None,
+                };
+                let pdu = advertising::Pdu::from_header_and_payload(
+                    header,
+                    &mut ByteReader::new(&payload),
+                )
+                .ok()?;
+                let connectable = match header.type_() {
+                    PduType::AdvInd => true,
+                    PduType::AdvDirectInd => pdu.receiver() == Some(&addr),
+                    _ => false,
+                };
+                if connectable {
+                    Some((channel, *pdu.sender(), at + air_time))
+                } else {
+                    None
+                }
+            });
+            if let Some(adv) = adv {
+                break adv;
+            }
+        };
+
+        let request = PduBuf::connect_request(addr, advertiser, lldata);
+        let packet = Packet::Advertising {
+            channel,
+            header: request.header(),
+            payload: request.payload().to_vec(),
+        };
+        let rx_end = adv_end + Duration::T_IFS + packet.air_time();
+        timer.advance_to(rx_end);
+        peripheral.receive_advertising(rx_end, request.header(), request.payload(), true);
+        match peripheral.radio().receiver() {
+            RadioCmd::ListenData { .. } => {}
+            cmd => panic!("peripheral didn't accept connection, radio: {:?}", cmd),
+        }
+
+        let mut this = Self {
+            timer,
+            access_address: lldata.access_address(),
+            crc_init: lldata.crc_init(),
+            interval: lldata.interval(),
+            hop: lldata.hop(),
+            channel_map: *lldata.channel_map(),
+            unmapped_channel: 0,
+            channel: DataChannel::new(0),
+            anchor: rx_end + lldata.start_of_tx_window(),
+            event_counter: 0,
+            sn: SeqNum::ZERO,
+            nesn: SeqNum::ZERO,
+            last_sent: None,
+            acked: true,
+            last_reply_sn: None,
+            last_reply_acked: false,
+            pending_update: None,
+        };
+        this.hop_channel();
+        this
+    }
+
+    /// Returns the counter of the next connection event.
+    pub fn event_counter(&self) -> u16 {
+        self.event_counter
+    }
+
+    /// Returns the data channel used by the next connection event.
+    pub fn channel(&self) -> DataChannel {
+        self.channel
+    }
+
+    /// Returns the current connection interval.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Returns whether the last PDU sent has been acknowledged by the peripheral.
+    pub fn is_acked(&self) -> bool {
+        self.acked
+    }
+
+    /// Performs `step` in the next connection event.
+    ///
+    /// The timer of `peripheral` is run up to the anchor point of the connection event. Returns
+    /// the peripheral's response, or `None` if it didn't respond.
+    pub fn step(&mut self, peripheral: &mut dyn Node, step: Step) -> Option<Reply> {
+        self.run_timer_until(peripheral, self.anchor);
+
+        let (header, payload, crc_ok) = match step {
+            Step::Send(pdu) => self.prepare(pdu, true),
+            Step::Corrupt(pdu) => self.prepare(pdu, false),
+            Step::SendWithoutAck(pdu) => {
+                if self.last_reply_acked {
+                    self.nesn += SeqNum::ONE;
+                    self.last_reply_acked = false;
+                }
+                self.prepare(pdu, true)
+            }
+            Step::Replay => {
+                let (header, payload) = self.last_sent.clone().expect("nothing sent yet");
+                (header, payload, true)
+            }
+            Step::Skip => {
+                self.next_event();
+                return None;
+            }
+        };
+
+        let packet = Packet::Data {
+            channel: self.channel,
+            access_address: self.access_address,
+            crc_init: self.crc_init,
+            header,
+            payload,
+        };
+        let rx_end = self.anchor + packet.air_time();
+        self.timer.advance_to(rx_end);
+        let listening = packet.received_by(peripheral.radio().receiver()).is_some();
+        let reply = if listening {
+            peripheral.receive_data(rx_end, header, packet.payload(), crc_ok);
+            self.take_reply(peripheral)
+        } else {
+            None
+        };
+
+        self.next_event();
+        reply
+    }
+
+    /// Performs each step of `script` in consecutive connection events, and returns the replies.
+    pub fn run(
+        &mut self,
+        peripheral: &mut dyn Node,
+        script: impl IntoIterator<Item = Step>,
+    ) -> Vec<Option<Reply>> {
+        script
+            .into_iter()
+            .map(|step| self.step(peripheral, step))
+            .collect()
+    }
+
+    /// Builds the header for a new PDU, and records it as the last PDU sent.
+    fn prepare(&mut self, pdu: DataPdu, crc_ok: bool) -> (data::Header, Vec<u8>, bool) {
+        let mut header = data::Header::new(pdu.llid);
+        header.set_payload_length(pdu.payload.len() as u8);
+        header.set_sn(self.sn);
+        header.set_nesn(self.nesn);
+
+        if pdu.llid == Llid::Control {
+            self.record_update(&pdu.payload);
+        }
+
+        self.last_sent = Some((header, pdu.payload.clone()));
+        self.acked = false;
+        (header, pdu.payload, crc_ok)
+    }
+
+    /// Remembers connection updates and channel maps sent to the peripheral.
+    fn record_update(&mut self, payload: &[u8]) {
+        match ControlPdu::from_bytes(&mut ByteReader::new(payload)) {
+            Ok(ControlPdu::ConnectionUpdateReq(data)) => {
+                self.pending_update = Some(Update::Connection(data));
+            }
+            Ok(ControlPdu::ChannelMapReq { map, instant }) => {
+                self.pending_update = Some(Update::ChannelMap { map, instant });
+            }
+            _ => {}
+        }
+    }
+
+    /// Takes the peripheral's response from its radio, and updates the sequence numbers.
+    fn take_reply(&mut self, peripheral: &mut dyn Node) -> Option<Reply> {
+        let packet = peripheral.radio().take_sent().into_iter().next()?;
+        let (header, payload) = match packet {
+            Packet::Data {
+                header, payload, ..
+            } => (header, payload),
+            Packet::Advertising { .. } => panic!("peripheral sent advertising packet"),
+        };
+
+        // The peripheral acknowledges our last PDU by expecting the next SN
+        if !self.acked && header.nesn() == self.sn + SeqNum::ONE {
+            self.sn += SeqNum::ONE;
+            self.acked = true;
+        }
+
+        self.last_reply_acked = header.sn() == self.nesn;
+        if self.last_reply_acked {
+            self.nesn += SeqNum::ONE;
+        }
+        let new = self.last_reply_sn != Some(header.sn());
+        self.last_reply_sn = Some(header.sn());
+
+        Some(Reply {
+            header,
+            payload,
+            new,
+        })
+    }
+
+    /// Moves on to the next connection event, applying pending updates at their instant.
+    fn next_event(&mut self) {
+        self.event_counter = self.event_counter.wrapping_add(1);
+        let mut next_anchor = self.anchor + self.interval;
+
+        if let Some(update) = self.pending_update.take() {
+            if update.instant() == self.event_counter {
+                match update {
+                    Update::Connection(data) => {
+                        next_anchor += data.win_offset();
+                        self.interval = data.interval();
+                    }
+                    Update::ChannelMap { map, .. } => self.channel_map = map,
+                }
+            } else {
+                self.pending_update = Some(update);
+            }
+        }
+
+        self.anchor = next_anchor;
+        self.hop_channel();
+    }
+
+    /// Channel Selection Algorithm #1.
+    fn hop_channel(&mut self) {
+        self.unmapped_channel = (self.unmapped_channel + self.hop) % 37;
+        let unmapped = DataChannel::new(self.unmapped_channel);
+        self.channel = if self.channel_map.is_used(unmapped) {
+            unmapped
+        } else {
+            let used = self.channel_map.num_used_channels();
+            self.channel_map.by_index(self.unmapped_channel % used)
+        };
+    }
+
+    /// Expires the peripheral's timer until `end`, and sets the clock to `end`.
+    fn run_timer_until(&self, peripheral: &mut dyn Node, end: Instant) {
+        let now = self.timer.now();
+        let limit = since(end, now);
+        while let Some(at) = peripheral.next_update() {
+            if since(at, now) >= limit {
+                break;
+            }
+            self.timer.advance_to(at);
+            peripheral.timer_expired();
+            peripheral.radio().take_sent();
+        }
+        self.timer.advance_to(end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+    use crate::tests::{queue, PeripheralConfig, TestRng};
+    use rubble::crypto::SoftAesProvider;
+    use rubble::gatt::BatteryServiceAttrs;
+    use rubble::l2cap::{BleChannelMap, L2CAPState};
+    use rubble::link::advertising::{AdvertisingParameters, AdvertisingType};
+    use rubble::link::queue::PacketQueue;
+    use rubble::link::{AddressKind, DisconnectReason, LinkLayer, Responder};
+
+    /// ATT Read Request for the battery level.
+    const READ_REQ: [u8; 3] = [0x0A, 0x03, 0x00];
+
+    /// ATT Read Response containing the battery level.
+    const READ_RSP: [u8; 2] = [0x0B, 48];
+
+    fn ms(ms: u16) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn connect() -> (Device<PeripheralConfig>, ScriptedCentral) {
+        let timer = SimTimer::new();
+        let addr = DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Random);
+        let central_addr = DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Random);
+
+        let (tx, tx_cons) = queue().split();
+        let (rx_prod, rx) = queue().split();
+        let ll = LinkLayer::<PeripheralConfig>::new(
+            addr,
+            timer.clone(),
+            SoftAesProvider::new(),
+            TestRng(7),
+        );
+        let l2cap = L2CAPState::new(BleChannelMap::with_attributes(BatteryServiceAttrs::new()));
+        let mut peripheral = Device::new(ll, Responder::new(tx, rx, l2cap));
+        let params =
+            AdvertisingParameters::new(AdvertisingType::ConnectableUndirected, ms(20), ms(20))
+                .unwrap();
+        peripheral
+            .start_advertise(params, &[], tx_cons, rx_prod)
+            .unwrap();
+
+        let lldata = ConnectRequestData::new(
+            ms(30),
+            0,
+            ms(1000),
+            ChannelMap::with_all_channels(),
+            &mut TestRng(3),
+        )
+        .unwrap();
+        let central = ScriptedCentral::connect(central_addr, timer, &mut peripheral, &lldata);
+        (peripheral, central)
+    }
+
+    fn read_responses(replies: &[Option<Reply>]) -> usize {
+        replies
+            .iter()
+            .flatten()
+            .filter(|reply| reply.payload().ends_with(&READ_RSP))
+            .count()
+    }
+
+    #[test]
+    fn acknowledgement() {
+        let (mut peripheral, mut central) = connect();
+
+        let reply = central.step(&mut peripheral, Step::Send(DataPdu::empty()));
+        let reply = reply.unwrap();
+        assert!(reply.is_new());
+        assert!(reply.is_empty());
+        assert!(central.is_acked());
+
+        // The request is answered in a later connection event
+        let replies = central.run(
+            &mut peripheral,
+            vec![
+                Step::Send(DataPdu::l2cap(Channel::ATT, &READ_REQ)),
+                Step::Send(DataPdu::empty()),
+                Step::Send(DataPdu::empty()),
+            ],
+        );
+        assert!(replies.iter().all(|reply| reply.as_ref().unwrap().is_new()));
+        assert_eq!(read_responses(&replies), 1);
+        assert!(peripheral.link_layer().connection().is_some());
+    }
+
+    #[test]
+    fn retransmission() {
+        let (mut peripheral, mut central) = connect();
+
+        let replies = central.run(
+            &mut peripheral,
+            vec![
+                Step::Send(DataPdu::l2cap(Channel::ATT, &READ_REQ)),
+                Step::Send(DataPdu::empty()),
+            ],
+        );
+        assert_eq!(read_responses(&replies), 1);
+
+        // Pretend we didn't receive the response, so the peripheral has to send it again
+        let reply = central.step(&mut peripheral, Step::SendWithoutAck(DataPdu::empty()));
+        let reply = reply.unwrap();
+        assert!(!reply.is_new());
+        assert!(reply.payload().ends_with(&READ_RSP));
+
+        // Once acknowledged, the peripheral moves on to an empty PDU
+        let reply = central.step(&mut peripheral, Step::Send(DataPdu::empty()));
+        let reply = reply.unwrap();
+        assert!(reply.is_new());
+        assert!(reply.is_empty());
+    }
+
+    #[test]
+    fn duplicate_is_ignored() {
+        let (mut peripheral, mut central) = connect();
+
+        let replies = central.run(
+            &mut peripheral,
+            vec![
+                Step::Send(DataPdu::l2cap(Channel::ATT, &READ_REQ)),
+                Step::Replay,
+                Step::Replay,
+                Step::Send(DataPdu::empty()),
+                Step::Send(DataPdu::empty()),
+                Step::Send(DataPdu::empty()),
+            ],
+        );
+        let new_responses = replies
+            .iter()
+            .flatten()
+            .filter(|reply| reply.is_new() && reply.payload().ends_with(&READ_RSP))
+            .count();
+        assert_eq!(new_responses, 1);
+    }
+
+    #[test]
+    fn corrupted_packet() {
+        let (mut peripheral, mut central) = connect();
+
+        // A PDU with a CRC error must not be acknowledged or processed
+        let reply = central.step(
+            &mut peripheral,
+            Step::Corrupt(DataPdu::l2cap(Channel::ATT, &READ_REQ)),
+        );
+        let reply = reply.unwrap();
+        assert!(reply.is_empty());
+        assert!(!central.is_acked());
+
+        let replies = central.run(
+            &mut peripheral,
+            vec![Step::Replay, Step::Send(DataPdu::empty())],
+        );
+        assert!(central.is_acked());
+        assert_eq!(read_responses(&replies), 1);
+    }
+
+    #[test]
+    fn missed_events() {
+        let (mut peripheral, mut central) = connect();
+
+        central.step(&mut peripheral, Step::Send(DataPdu::empty()));
+        let replies = central.run(
+            &mut peripheral,
+            vec![
+                Step::Skip,
+                Step::Skip,
+                Step::Skip,
+                Step::Send(DataPdu::empty()),
+            ],
+        );
+        assert!(replies[..3].iter().all(Option::is_none));
+        assert!(replies[3].is_some());
+        assert_eq!(central.event_counter(), 5);
+    }
+
+    #[test]
+    fn supervision_timeout() {
+        let (mut peripheral, mut central) = connect();
+
+        // Without packets, the 1 s supervision timeout expires after ~33 connection events
+        central.step(&mut peripheral, Step::Send(DataPdu::empty()));
+        central.run(&mut peripheral, (0..33).map(|_| Step::Skip));
+        assert!(peripheral.link_layer().is_connected());
+        central.run(&mut peripheral, (0..2).map(|_| Step::Skip));
+        assert!(!peripheral.link_layer().is_connected());
+        assert_eq!(
+            peripheral.link_layer().disconnect_reason(),
+            Some(DisconnectReason::Timeout)
+        );
+
+        // The peripheral advertises again and accepts a new connection
+        assert!(peripheral.link_layer().is_advertising());
+        let central_addr = DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Random);
+        let lldata = ConnectRequestData::new(
+            ms(30),
+            0,
+            ms(1000),
+            ChannelMap::with_all_channels(),
+            &mut TestRng(8),
+        )
+        .unwrap();
+        let timer = central.timer.clone();
+        let mut central = ScriptedCentral::connect(central_addr, timer, &mut peripheral, &lldata);
+        let reply = central.step(&mut peripheral, Step::Send(DataPdu::empty()));
+        assert!(reply.is_some());
+    }
+
+    #[test]
+    fn reconnect_after_timeout() {
+        let (mut peripheral, mut central) = connect();
+
+        // Leave a queued write, a partially received message and an unacknowledged response
+        let replies = central.run(
+            &mut peripheral,
+            vec![
+                Step::Send(DataPdu::empty()),
+                Step::Send(DataPdu::l2cap(
+                    Channel::ATT,
+                    &[0x16, 0x03, 0x00, 0x00, 0x00, 0x42],
+                )),
+                Step::Send(DataPdu::l2cap(Channel::ATT, &READ_REQ)),
+                Step::Send(DataPdu::new(Llid::DataStart, &[3, 0, 4, 0, 0x0A])),
+            ],
+        );
+        assert_eq!(read_responses(&replies), 1);
+        central.run(&mut peripheral, (0..35).map(|_| Step::Skip));
+        assert!(peripheral.link_layer().is_advertising());
+
+        let central_addr = DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Random);
+        let lldata = ConnectRequestData::new(
+            ms(30),
+            0,
+            ms(1000),
+            ChannelMap::with_all_channels(),
+            &mut TestRng(8),
+        )
+        .unwrap();
+        let timer = central.timer.clone();
+        let mut central = ScriptedCentral::connect(central_addr, timer, &mut peripheral, &lldata);
+
+        // The old response isn't sent, and the rest of the old message isn't reassembled
+        let replies = central.run(
+            &mut peripheral,
+            vec![
+                Step::Send(DataPdu::empty()),
+                Step::Send(DataPdu::new(Llid::DataCont, &[0x03, 0x00])),
+                Step::Send(DataPdu::empty()),
+                Step::Send(DataPdu::empty()),
+            ],
+        );
+        assert!(replies
+            .iter()
+            .all(|reply| reply.as_ref().unwrap().is_empty()));
+
+        // The prepare queue is empty, so executing it succeeds without writing anything
+        let replies = central.run(
+            &mut peripheral,
+            vec![
+                Step::Send(DataPdu::l2cap(Channel::ATT, &[0x18, 0x01])),
+                Step::Send(DataPdu::empty()),
+            ],
+        );
+        assert_eq!(replies[1].as_ref().unwrap().payload(), [1, 0, 4, 0, 0x19]);
+    }
+
+    #[test]
+    fn connection_not_established() {
+        let (mut peripheral, mut central) = connect();
+
+        // The peripheral keeps listening in the following connection events
+        let replies = central.run(
+            &mut peripheral,
+            vec![Step::Skip, Step::Skip, Step::Send(DataPdu::empty())],
+        );
+        assert!(replies[2].is_some());
+
+        // Without any packet, the connection is lost after 6 connection intervals
+        let (mut peripheral, mut central) = connect();
+        central.run(&mut peripheral, (0..6).map(|_| Step::Skip));
+        assert!(peripheral.link_layer().is_connected());
+        central.run(&mut peripheral, (0..2).map(|_| Step::Skip));
+        assert!(peripheral.link_layer().is_advertising());
+        assert_eq!(
+            peripheral.link_layer().disconnect_reason(),
+            Some(DisconnectReason::FailedToEstablish)
+        );
+    }
+
+    #[test]
+    fn connection_update() {
+        let (mut peripheral, mut central) = connect();
+
+        let instant = central.event_counter() + 6;
+        let update = ConnectionUpdateData::new(ms(50), 0, ms(2000), instant).unwrap();
+        let mut script = vec![Step::Send(DataPdu::control(
+            &ControlPdu::ConnectionUpdateReq(update),
+        ))];
+        script.extend((0..10).map(|_| Step::Send(DataPdu::empty())));
+        let replies = central.run(&mut peripheral, script);
+
+        assert!(replies.iter().all(Option::is_some));
+        assert_eq!(central.interval(), ms(50));
+        let conn = peripheral.link_layer().connection().unwrap();
+        assert_eq!(conn.connection_interval(), ms(50));
+    }
+
+    #[test]
+    fn channel_map_update() {
+        let (mut peripheral, mut central) = connect();
+
+        let mut raw = [0; 5];
+        raw[0] = 0b0000_0110;
+        let map = ChannelMap::from_raw(raw);
+        let instant = central.event_counter() + 6;
+        let mut script = vec![Step::Send(DataPdu::control(&ControlPdu::ChannelMapReq {
+            map,
+            instant,
+        }))];
+        script.extend((0..10).map(|_| Step::Send(DataPdu::empty())));
+        let replies = central.run(&mut peripheral, script);
+
+        assert!(replies.iter().all(Option::is_some));
+        assert!(
+            central.channel() == DataChannel::new(1) || central.channel() == DataChannel::new(2)
+        );
+    }
+}
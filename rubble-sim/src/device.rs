@@ -0,0 +1,195 @@
+//! Simulated devices running a Rubble stack.
+
+use crate::{radio::SimRadio, timer::SimTimer};
+use rubble::config::Config;
+use rubble::link::advertising::{AdvertisingParameters, ConnectRequestData};
+use rubble::link::queue::PacketQueue;
+use rubble::link::{ad_structure::AdStructure, Cmd, DeviceAddress, NextUpdate, RadioCmd};
+use rubble::link::{ConnectionEvent, LinkLayer, Responder};
+use rubble::time::Instant;
+use rubble::{link::advertising, link::data, Error};
+
+type Producer<C> = <<C as Config>::PacketQueue as PacketQueue>::Producer;
+type Consumer<C> = <<C as Config>::PacketQueue as PacketQueue>::Consumer;
+
+/// Interface between a `Medium` and the devices it connects.
+///
+/// This is object-safe, so that devices using different `Config`s can share a `Medium`.
+pub trait Node {
+    /// Returns the radio of this node.
+    fn radio(&mut self) -> &mut SimRadio;
+
+    /// Returns when the Link-Layer's timer expires next, if it is enabled.
+    fn next_update(&self) -> Option<Instant>;
+
+    /// Called by the `Medium` when the timer has expired.
+    fn timer_expired(&mut self);
+
+    /// Called by the `Medium` when a packet was received on the advertising channel the radio is
+    /// listening on.
+    fn receive_advertising(
+        &mut self,
+        rx_end: Instant,
+        header: advertising::Header,
+        payload: &[u8],
+        crc_ok: bool,
+    );
+
+    /// Called by the `Medium` when a packet was received on the data channel the radio is
+    /// listening on.
+    fn receive_data(&mut self, rx_end: Instant, header: data::Header, payload: &[u8], crc_ok: bool);
+}
+
+/// A simulated device, made up of a `LinkLayer`, its `SimRadio` and a `Responder`.
+///
+/// All `Cmd`s returned by the Link-Layer are applied to the radio and timer automatically, and
+/// the `Responder` is run whenever there's work in the packet queues. The `Responder` is also reset
+/// whenever a connection is established or lost. This plays the role of the interrupt handlers and
+/// idle loop of an application running on real hardware.
+pub struct Device<C: Config<Timer = SimTimer, Transmitter = SimRadio>> {
+    ll: LinkLayer<C>,
+    radio: SimRadio,
+    responder: Responder<C>,
+    next_update: Option<Instant>,
+}
+
+impl<C: Config<Timer = SimTimer, Transmitter = SimRadio>> Device<C> {
+    /// Creates a device from a Link-Layer in standby state and the `Responder` processing its
+    /// packet queues.
+    pub fn new(ll: LinkLayer<C>, responder: Responder<C>) -> Self {
+        Self {
+            ll,
+            radio: SimRadio::new(),
+            responder,
+            next_update: None,
+        }
+    }
+
+    /// Returns a reference to the Link-Layer.
+    ///
+    /// If a method returning a `Cmd` is called on it, the `Cmd` has to be passed to `apply`.
+    pub fn link_layer(&mut self) -> &mut LinkLayer<C> {
+        &mut self.ll
+    }
+
+    /// Returns a reference to the `Responder`.
+    ///
+    /// This gives access to the L2CAP and ATT state of the device, eg. to send requests as the ATT
+    /// client. Queued work is processed the next time the device is driven by the `Medium`.
+    pub fn responder(&mut self) -> &mut Responder<C> {
+        &mut self.responder
+    }
+
+    /// Starts advertising (see `LinkLayer::start_advertise`).
+    ///
+    /// `tx` and `rx` are the queue halves not used by the `Responder`.
+    pub fn start_advertise(
+        &mut self,
+        params: AdvertisingParameters,
+        data: &[AdStructure<'_>],
+        tx: Consumer<C>,
+        rx: Producer<C>,
+    ) -> Result<(), Error> {
+        let next_update = self
+            .ll
+            .start_advertise(params, data, &mut self.radio, tx, rx)?;
+        // The first advertising PDU was just sent on the first channel, listen for requests there
+        self.apply(Cmd {
+            radio: RadioCmd::ListenAdvertising {
+                channel: params.channels().first(),
+            },
+            next_update,
+            queued_work: false,
+        });
+        Ok(())
+    }
+
+    /// Starts connecting to `peer` (see `LinkLayer::start_connecting`).
+    ///
+    /// `tx` and `rx` are the queue halves not used by the `Responder`.
+    pub fn start_connecting(
+        &mut self,
+        peer: DeviceAddress,
+        lldata: ConnectRequestData,
+        tx: Consumer<C>,
+        rx: Producer<C>,
+    ) {
+        let cmd = self.ll.start_connecting(peer, lldata, tx, rx);
+        self.apply(cmd);
+    }
+
+    /// Applies a `Cmd` returned by the Link-Layer to the radio and timer, and runs the
+    /// `Responder` if work was queued.
+    pub fn apply(&mut self, cmd: Cmd) {
+        self.radio.configure_receiver(cmd.radio);
+        self.apply_next_update(cmd.next_update);
+        self.process_work();
+    }
+
+    fn apply_next_update(&mut self, next_update: NextUpdate) {
+        match next_update {
+            NextUpdate::Keep => {}
+            NextUpdate::Disable => self.next_update = None,
+            NextUpdate::At(instant) => self.next_update = Some(instant),
+        }
+    }
+
+    /// Handles connection events, then fully drains the packet queues.
+    fn process_work(&mut self) {
+        while let Some(event) = self.ll.take_connection_event() {
+            match event {
+                ConnectionEvent::Established { peer } => {
+                    let local = self.ll.device_address();
+                    self.responder.connection_established(local, peer);
+                }
+                ConnectionEvent::Ended(_) => self.responder.connection_ended(),
+            }
+        }
+        while self.responder.has_work() {
+            self.responder.process_one().unwrap();
+        }
+    }
+}
+
+impl<C: Config<Timer = SimTimer, Transmitter = SimRadio>> Node for Device<C> {
+    fn radio(&mut self) -> &mut SimRadio {
+        &mut self.radio
+    }
+
+    fn next_update(&self) -> Option<Instant> {
+        self.next_update
+    }
+
+    fn timer_expired(&mut self) {
+        // Like a hardware timer interrupt, this fires once. `NextUpdate::Keep` doesn't rearm it.
+        self.next_update = None;
+        let cmd = self.ll.update_timer(&mut self.radio);
+        self.apply(cmd);
+    }
+
+    fn receive_advertising(
+        &mut self,
+        rx_end: Instant,
+        header: advertising::Header,
+        payload: &[u8],
+        crc_ok: bool,
+    ) {
+        let cmd = self
+            .ll
+            .process_adv_packet(rx_end, &mut self.radio, header, payload, crc_ok);
+        self.apply(cmd);
+    }
+
+    fn receive_data(
+        &mut self,
+        rx_end: Instant,
+        header: data::Header,
+        payload: &[u8],
+        crc_ok: bool,
+    ) {
+        let cmd = self
+            .ll
+            .process_data_packet(rx_end, &mut self.radio, header, payload, crc_ok);
+        self.apply(cmd);
+    }
+}
@@ -0,0 +1,525 @@
+//! Simulated HCI controllers, driven by H4 byte streams.
+
+use crate::{device::Node, radio::SimRadio, timer::SimTimer};
+use rubble::config::Config;
+use rubble::hci::{Controller, H4Decoder, H4Sink};
+use rubble::link::{advertising, data, Cmd, NextUpdate};
+use rubble::time::Instant;
+use rubble::Error;
+
+/// Collects the packets the controller sends to the host.
+struct Output(Vec<u8>);
+
+impl H4Sink for Output {
+    fn write_packet(&mut self, packet: &[u8]) {
+        self.0.extend_from_slice(packet);
+    }
+}
+
+/// A simulated device running an HCI `Controller`.
+///
+/// The host side is represented by byte streams: Bytes the host sends over the UART are passed
+/// to `write`, and everything the controller sends back can be retrieved via `take_output`. This
+/// allows testing the controller against recorded HCI traffic.
+pub struct HciDevice<C: Config<Timer = SimTimer, Transmitter = SimRadio>> {
+    controller: Controller<C>,
+    radio: SimRadio,
+    decoder: H4Decoder,
+    output: Output,
+    next_update: Option<Instant>,
+}
+
+impl<C: Config<Timer = SimTimer, Transmitter = SimRadio>> HciDevice<C> {
+    /// Creates a device running `controller`.
+    pub fn new(controller: Controller<C>) -> Self {
+        Self {
+            controller,
+            radio: SimRadio::new(),
+            decoder: H4Decoder::new(),
+            output: Output(Vec::new()),
+            next_update: None,
+        }
+    }
+
+    /// Returns a reference to the `Controller`.
+    pub fn controller(&mut self) -> &mut Controller<C> {
+        &mut self.controller
+    }
+
+    /// Processes `bytes` sent by the host.
+    ///
+    /// Returns the first error returned by the `H4Decoder`. Decoding continues after errors, so
+    /// all valid packets in `bytes` are processed.
+    pub fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let mut result = Ok(());
+        for &byte in bytes {
+            match self.decoder.push(byte) {
+                Ok(Some(packet)) => {
+                    let cmd =
+                        self.controller
+                            .process_packet(packet, &mut self.radio, &mut self.output);
+                    if let Some(cmd) = cmd {
+                        self.radio.configure_receiver(cmd.radio);
+                        self.apply_next_update(cmd.next_update);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns all bytes sent to the host since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output.0)
+    }
+
+    /// Applies a `Cmd` returned by the controller, and polls it like an idle loop would.
+    fn apply(&mut self, cmd: Cmd) {
+        self.radio.configure_receiver(cmd.radio);
+        self.apply_next_update(cmd.next_update);
+        self.controller.poll(&mut self.output);
+    }
+
+    fn apply_next_update(&mut self, next_update: NextUpdate) {
+        match next_update {
+            NextUpdate::Keep => {}
+            NextUpdate::Disable => self.next_update = None,
+            NextUpdate::At(instant) => self.next_update = Some(instant),
+        }
+    }
+}
+
+impl<C: Config<Timer = SimTimer, Transmitter = SimRadio>> Node for HciDevice<C> {
+    fn radio(&mut self) -> &mut SimRadio {
+        &mut self.radio
+    }
+
+    fn next_update(&self) -> Option<Instant> {
+        self.next_update
+    }
+
+    fn timer_expired(&mut self) {
+        self.next_update = None;
+        let cmd = self.controller.update_timer(&mut self.radio);
+        self.apply(cmd);
+    }
+
+    fn receive_advertising(
+        &mut self,
+        rx_end: Instant,
+        header: advertising::Header,
+        payload: &[u8],
+        crc_ok: bool,
+    ) {
+        let cmd =
+            self.controller
+                .process_adv_packet(rx_end, &mut self.radio, header, payload, crc_ok);
+        self.apply(cmd);
+    }
+
+    fn receive_data(
+        &mut self,
+        rx_end: Instant,
+        header: data::Header,
+        payload: &[u8],
+        crc_ok: bool,
+    ) {
+        let cmd =
+            self.controller
+                .process_data_packet(rx_end, &mut self.radio, header, payload, crc_ok);
+        self.apply(cmd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+    use crate::medium::Medium;
+    use crate::tests::{queue, CentralConfig, PeripheralConfig, Reads, TestRng};
+    use rubble::att::{Handle, NoAttributes};
+    use rubble::crypto::SoftAesProvider;
+    use rubble::gatt::BatteryServiceAttrs;
+    use rubble::l2cap::{BleChannelMap, L2CAPState};
+    use rubble::link::ad_structure::AdStructure;
+    use rubble::link::advertising::{AdvertisingParameters, AdvertisingType, ConnectRequestData};
+    use rubble::link::pcap::NoCapture;
+    use rubble::link::queue::{PacketQueue, SimpleQueue};
+    use rubble::link::{AddressKind, ChannelMap, DeviceAddress, LinkLayer, Responder};
+    use rubble::security::{NoSecurity, SecurityManager};
+    use rubble::time::Duration;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    enum ControllerConfig {}
+
+    impl Config for ControllerConfig {
+        type Timer = SimTimer;
+        type Transmitter = SimRadio;
+        type ChannelMapper = BleChannelMap<NoAttributes, NoSecurity>;
+        type PacketQueue = &'static mut SimpleQueue;
+        type AesProvider = SoftAesProvider;
+        type Rng = TestRng;
+        type PcapSink = NoCapture;
+    }
+
+    const CONTROLLER_ADDR: [u8; 6] = [1, 2, 3, 4, 5, 6];
+
+    fn controller(medium: &Medium) -> HciDevice<ControllerConfig> {
+        let addr = DeviceAddress::new(CONTROLLER_ADDR, AddressKind::Public);
+        let ll = LinkLayer::new(addr, medium.timer(), SoftAesProvider::new(), TestRng(6));
+        HciDevice::new(Controller::new(ll, queue(), queue(), &mut TestRng(7)))
+    }
+
+    /// Sends a command from the host and checks the response.
+    fn command(device: &mut HciDevice<ControllerConfig>, command: &[u8], response: &[u8]) {
+        device.write(command).unwrap();
+        assert_eq!(device.take_output(), response);
+    }
+
+    /// Makes the controller scan actively, with a 10 ms interval and window.
+    fn start_active_scan(controller: &mut HciDevice<ControllerConfig>) {
+        // LE Set Scan Parameters: Active, 10 ms interval and window
+        command(
+            controller,
+            &[
+                0x01, 0x0B, 0x20, 0x07, 0x01, 0x10, 0x00, 0x10, 0x00, 0x00, 0x00,
+            ],
+            &[0x04, 0x0E, 0x04, 0x01, 0x0B, 0x20, 0x00],
+        );
+        // LE Set Scan Enable
+        command(
+            controller,
+            &[0x01, 0x0C, 0x20, 0x02, 0x01, 0x00],
+            &[0x04, 0x0E, 0x04, 0x01, 0x0C, 0x20, 0x00],
+        );
+    }
+
+    /// Creates a device sending scannable advertisements every 20 ms, with `name` in its scan
+    /// response.
+    fn advertiser(medium: &Medium, name: &str) -> Device<PeripheralConfig> {
+        let ms = Duration::from_millis;
+        let advertiser_addr = DeviceAddress::new([9, 8, 7, 6, 5, 4], AddressKind::Random);
+        let (tx, tx_cons) = queue().split();
+        let (rx_prod, rx) = queue().split();
+        let ll = LinkLayer::<PeripheralConfig>::new(
+            advertiser_addr,
+            medium.timer(),
+            SoftAesProvider::new(),
+            TestRng(5),
+        );
+        let l2cap = L2CAPState::new(BleChannelMap::with_attributes(BatteryServiceAttrs::new()));
+        let mut advertiser = Device::new(ll, Responder::new(tx, rx, l2cap));
+        advertiser
+            .link_layer()
+            .set_scan_response_data(&[AdStructure::CompleteLocalName(name)])
+            .unwrap();
+        let params =
+            AdvertisingParameters::new(AdvertisingType::ScannableUndirected, ms(20), ms(20))
+                .unwrap();
+        advertiser
+            .start_advertise(params, &[], tx_cons, rx_prod)
+            .unwrap();
+        advertiser
+    }
+
+    /// Returns the LE Advertising Report the controller sends for a scan response of `advertiser`
+    /// containing `name`.
+    fn scan_report(name: &str) -> Vec<u8> {
+        let len = name.len() as u8;
+        let mut report = vec![0x04, 0x3E, 14 + len, 0x02, 0x01, 0x04, 0x01];
+        report.extend_from_slice(&[9, 8, 7, 6, 5, 4, 2 + len, 1 + len, 0x09]);
+        report.extend_from_slice(name.as_bytes());
+        report.push(0x7F);
+        report
+    }
+
+    #[test]
+    fn peripheral() {
+        let mut medium = Medium::new();
+        let mut controller = controller(&medium);
+        let ms = Duration::from_millis;
+
+        // Reset
+        command(
+            &mut controller,
+            &[0x01, 0x03, 0x0C, 0x00],
+            &[0x04, 0x0E, 0x04, 0x01, 0x03, 0x0C, 0x00],
+        );
+        // Read BD_ADDR
+        command(
+            &mut controller,
+            &[0x01, 0x09, 0x10, 0x00],
+            &[0x04, 0x0E, 0x0A, 0x01, 0x09, 0x10, 0x00, 1, 2, 3, 4, 5, 6],
+        );
+        // LE Read Buffer Size
+        command(
+            &mut controller,
+            &[0x01, 0x02, 0x20, 0x00],
+            &[0x04, 0x0E, 0x07, 0x01, 0x02, 0x20, 0x00, 27, 0, 1],
+        );
+        // LE Set Advertising Parameters: 20 ms, ADV_IND, all channels
+        let mut params = vec![0x01, 0x06, 0x20, 0x0F, 0x20, 0x00, 0x20, 0x00];
+        params.extend_from_slice(&[0; 9]);
+        params.extend_from_slice(&[0x07, 0x00]);
+        command(
+            &mut controller,
+            &params,
+            &[0x04, 0x0E, 0x04, 0x01, 0x06, 0x20, 0x00],
+        );
+        // LE Set Advertising Data: Complete Local Name "rubble"
+        let mut data = vec![0x01, 0x08, 0x20, 0x20, 0x08, 0x07, 0x09];
+        data.extend_from_slice(b"rubble");
+        data.extend_from_slice(&[0; 23]);
+        command(
+            &mut controller,
+            &data,
+            &[0x04, 0x0E, 0x04, 0x01, 0x08, 0x20, 0x00],
+        );
+        // LE Set Advertising Enable
+        command(
+            &mut controller,
+            &[0x01, 0x0A, 0x20, 0x01, 0x01],
+            &[0x04, 0x0E, 0x04, 0x01, 0x0A, 0x20, 0x00],
+        );
+        // Disconnect: Not connected
+        command(
+            &mut controller,
+            &[0x01, 0x06, 0x04, 0x03, 0x01, 0x00, 0x13],
+            &[0x04, 0x0F, 0x04, 0x02, 0x01, 0x06, 0x04],
+        );
+
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let central_addr = DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Random);
+        let (tx, tx_cons) = queue().split();
+        let (rx_prod, rx) = queue().split();
+        let ll = LinkLayer::<CentralConfig>::new(
+            central_addr,
+            medium.timer(),
+            SoftAesProvider::new(),
+            TestRng(2),
+        );
+        let mapper = BleChannelMap::with_client(
+            NoAttributes,
+            SecurityManager::no_security(),
+            Reads(reads.clone()),
+        );
+        let mut central = Device::new(ll, Responder::new(tx, rx, L2CAPState::new(mapper)));
+        let lldata = ConnectRequestData::new(
+            ms(30),
+            0,
+            ms(1000),
+            ChannelMap::with_all_channels(),
+            &mut TestRng(3),
+        )
+        .unwrap();
+        let peer = DeviceAddress::new(CONTROLLER_ADDR, AddressKind::Public);
+        central.start_connecting(peer, lldata, tx_cons, rx_prod);
+
+        medium.run_for(&mut [&mut controller, &mut central], ms(200));
+        assert!(central.link_layer().connection().is_some());
+        // LE Connection Complete: Slave, 30 ms interval, 1 s supervision timeout
+        assert_eq!(
+            controller.take_output(),
+            [
+                0x04, 0x3E, 0x13, 0x01, 0x00, 0x01, 0x00, 0x01, 0x01, 6, 5, 4, 3, 2, 1, 0x18, 0x00,
+                0x00, 0x00, 0x64, 0x00, 0x00
+            ]
+        );
+
+        // The central reads handle 3, and the host answers
+        central
+            .responder()
+            .l2cap()
+            .att()
+            .unwrap()
+            .client()
+            .0
+            .read(Handle::from_raw(3))
+            .unwrap();
+        medium.run_for(&mut [&mut controller, &mut central], ms(200));
+        assert_eq!(
+            controller.take_output(),
+            [0x02, 0x01, 0x20, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x0A, 0x03, 0x00]
+        );
+        controller
+            .write(&[
+                0x02, 0x01, 0x00, 0x06, 0x00, 0x02, 0x00, 0x04, 0x00, 0x0B, 48,
+            ])
+            .unwrap();
+        medium.run_for(&mut [&mut controller, &mut central], ms(200));
+        // Number Of Completed Packets
+        assert_eq!(
+            controller.take_output(),
+            [0x04, 0x13, 0x05, 0x01, 0x01, 0x00, 0x01, 0x00]
+        );
+        assert_eq!(*reads.borrow(), [vec![48]]);
+
+        // Disconnect: Remote user terminated connection
+        command(
+            &mut controller,
+            &[0x01, 0x06, 0x04, 0x03, 0x01, 0x00, 0x13],
+            &[0x04, 0x0F, 0x04, 0x00, 0x01, 0x06, 0x04],
+        );
+        medium.run_for(&mut [&mut controller, &mut central], ms(200));
+        assert_eq!(
+            controller.take_output(),
+            [0x04, 0x05, 0x04, 0x00, 0x01, 0x00, 0x16]
+        );
+        assert!(central.link_layer().connection().is_none());
+        assert!(!controller.controller().link_layer().is_connected());
+
+        // Advertising can be started again
+        command(
+            &mut controller,
+            &[0x01, 0x0A, 0x20, 0x01, 0x01],
+            &[0x04, 0x0E, 0x04, 0x01, 0x0A, 0x20, 0x00],
+        );
+        assert!(controller.controller().link_layer().is_advertising());
+    }
+
+    #[test]
+    fn connection_timeout() {
+        let mut medium = Medium::new();
+        let mut controller = controller(&medium);
+        let ms = Duration::from_millis;
+
+        // LE Set Advertising Parameters: 20 ms, ADV_IND, all channels
+        let mut params = vec![0x01, 0x06, 0x20, 0x0F, 0x20, 0x00, 0x20, 0x00];
+        params.extend_from_slice(&[0; 9]);
+        params.extend_from_slice(&[0x07, 0x00]);
+        command(
+            &mut controller,
+            &params,
+            &[0x04, 0x0E, 0x04, 0x01, 0x06, 0x20, 0x00],
+        );
+        // LE Set Advertising Enable
+        command(
+            &mut controller,
+            &[0x01, 0x0A, 0x20, 0x01, 0x01],
+            &[0x04, 0x0E, 0x04, 0x01, 0x0A, 0x20, 0x00],
+        );
+
+        let central_addr = DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Random);
+        let (tx, tx_cons) = queue().split();
+        let (rx_prod, rx) = queue().split();
+        let ll = LinkLayer::<CentralConfig>::new(
+            central_addr,
+            medium.timer(),
+            SoftAesProvider::new(),
+            TestRng(2),
+        );
+        let mapper = BleChannelMap::with_client(
+            NoAttributes,
+            SecurityManager::no_security(),
+            Reads(Rc::new(RefCell::new(Vec::new()))),
+        );
+        let mut central = Device::new(ll, Responder::new(tx, rx, L2CAPState::new(mapper)));
+        let lldata = ConnectRequestData::new(
+            ms(30),
+            0,
+            ms(1000),
+            ChannelMap::with_all_channels(),
+            &mut TestRng(3),
+        )
+        .unwrap();
+        let peer = DeviceAddress::new(CONTROLLER_ADDR, AddressKind::Public);
+        central.start_connecting(peer, lldata, tx_cons, rx_prod);
+
+        medium.run_for(&mut [&mut controller, &mut central], ms(200));
+        assert!(controller.controller().link_layer().is_connected());
+        controller.take_output();
+
+        // The central disappears
+        medium.run_for(&mut [&mut controller], ms(1200));
+        assert_eq!(
+            controller.take_output(),
+            [0x04, 0x05, 0x04, 0x00, 0x01, 0x00, 0x08]
+        );
+        // Advertising stays disabled until the host enables it again
+        assert!(!controller.controller().link_layer().is_advertising());
+        command(
+            &mut controller,
+            &[0x01, 0x0A, 0x20, 0x01, 0x01],
+            &[0x04, 0x0E, 0x04, 0x01, 0x0A, 0x20, 0x00],
+        );
+        assert!(controller.controller().link_layer().is_advertising());
+    }
+
+    #[test]
+    fn scanner() {
+        let mut medium = Medium::new();
+        let mut controller = controller(&medium);
+        let ms = Duration::from_millis;
+
+        let mut advertiser = advertiser(&medium, "rubble");
+
+        start_active_scan(&mut controller);
+        // Advertising is not allowed while scanning
+        command(
+            &mut controller,
+            &[0x01, 0x0A, 0x20, 0x01, 0x01],
+            &[0x04, 0x0E, 0x04, 0x01, 0x0A, 0x20, 0x0C],
+        );
+
+        medium.run_for(&mut [&mut controller, &mut advertiser], ms(500));
+        let output = controller.take_output();
+        // LE Advertising Report for the ADV_SCAN_IND, followed by the scan response
+        let adv_report = [
+            0x04, 0x3E, 0x0C, 0x02, 0x01, 0x02, 0x01, 9, 8, 7, 6, 5, 4, 0x00, 0x7F,
+        ];
+        let expected = [&adv_report[..], &scan_report("rubble")].concat();
+        assert!(output.len() >= expected.len());
+        assert_eq!(output[..expected.len()], expected[..]);
+
+        // LE Set Scan Enable: Disable
+        command(
+            &mut controller,
+            &[0x01, 0x0C, 0x20, 0x02, 0x00, 0x00],
+            &[0x04, 0x0E, 0x04, 0x01, 0x0C, 0x20, 0x00],
+        );
+    }
+
+    #[test]
+    fn scan_response_update() {
+        let mut medium = Medium::new();
+        let mut controller = controller(&medium);
+        let ms = Duration::from_millis;
+        let contains = |output: &[u8], report: &[u8]| {
+            output.windows(report.len()).any(|window| window == report)
+        };
+
+        let mut advertiser = advertiser(&medium, "rubble");
+        start_active_scan(&mut controller);
+        medium.run_for(&mut [&mut controller, &mut advertiser], ms(200));
+        assert!(contains(&controller.take_output(), &scan_report("rubble")));
+
+        // The scan response can be changed while advertising
+        advertiser
+            .link_layer()
+            .set_scan_response_data(&[AdStructure::CompleteLocalName("elbbur")])
+            .unwrap();
+        assert!(advertiser.link_layer().is_advertising());
+
+        // Data that doesn't fit in the SCAN_RSP PDU is rejected, and the current data is kept
+        let long_name = "0123456789abcdefghijklmnopqrst";
+        assert_eq!(
+            advertiser
+                .link_layer()
+                .set_scan_response_data(&[AdStructure::CompleteLocalName(long_name)]),
+            Err(Error::Eof)
+        );
+
+        medium.run_for(&mut [&mut controller, &mut advertiser], ms(200));
+        let output = controller.take_output();
+        assert!(contains(&output, &scan_report("elbbur")));
+        assert!(!contains(&output, &scan_report("rubble")));
+        assert!(!contains(&output, long_name.as_bytes()));
+    }
+}
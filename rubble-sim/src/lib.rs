@@ -0,0 +1,263 @@
+//! A simulated radio environment for running Rubble on a host machine.
+//!
+//! Real Rubble applications need a `Transmitter` and `Timer` implementation for their hardware
+//! (eg. the ones in `rubble-nrf5x`). This crate provides virtual ones instead, which allows running
+//! several complete BLE stacks in a single process, eg. in tests:
+//!
+//! * [`SimTimer`] is a `Timer` reading a virtual clock, which only advances when told to.
+//! * [`SimRadio`] is a `Transmitter` that records the packets sent by the Link-Layer.
+//! * [`Device`] bundles a `LinkLayer`, its `SimRadio` and the `Responder` processing its packet
+//!   queues, and applies every `Cmd` returned by the Link-Layer.
+//! * [`Medium`] owns the virtual clock and delivers packets between [`Node`]s (such as `Device`s)
+//!   according to the channel their radios listen on.
+//! * [`ScriptedCentral`] connects to a single peripheral `Node` and sends it scripted data channel
+//!   PDUs, optionally injecting faults, for testing the peripheral's Link-Layer.
+//! * [`IoSink`] lets the Link-Layer write packet captures to files (see `pcap::create_file`),
+//!   which can be opened in Wireshark.
+//! * [`HciDevice`] runs an HCI `Controller`, which is driven by the H4 byte stream a host would
+//!   send over a UART.
+//!
+//! A `Config` used with this crate has to use `SimTimer` as its `Timer` and `SimRadio` as its
+//! `Transmitter`. Timers have to be obtained from `Medium::timer`, so that all devices share the
+//! same clock.
+//!
+//! [`SimTimer`]: timer/struct.SimTimer.html
+//! [`SimRadio`]: radio/struct.SimRadio.html
+//! [`Device`]: device/struct.Device.html
+//! [`Node`]: device/trait.Node.html
+//! [`Medium`]: medium/struct.Medium.html
+//! [`ScriptedCentral`]: central/struct.ScriptedCentral.html
+//! [`IoSink`]: pcap/struct.IoSink.html
+//! [`HciDevice`]: hci/struct.HciDevice.html
+
+#![warn(rust_2018_idioms)]
+
+pub mod central;
+pub mod device;
+pub mod hci;
+pub mod medium;
+pub mod pcap;
+pub mod radio;
+pub mod timer;
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::device::Device;
+    use crate::medium::Medium;
+    use crate::pcap::IoSink;
+    use crate::radio::SimRadio;
+    use crate::timer::SimTimer;
+    use rand_core::{impls, CryptoRng, RngCore};
+    use rubble::att::{AttributeClientTx, ClientHandler, Handle, NoAttributes, Response};
+    use rubble::config::Config;
+    use rubble::crypto::SoftAesProvider;
+    use rubble::gatt::BatteryServiceAttrs;
+    use rubble::l2cap::{BleChannelMap, L2CAPState};
+    use rubble::link::advertising::{AdvertisingParameters, AdvertisingType, ConnectRequestData};
+    use rubble::link::pcap::{NoCapture, PcapWriter};
+    use rubble::link::queue::{PacketQueue, SimpleQueue};
+    use rubble::link::{AddressKind, ChannelMap, DeviceAddress, LinkLayer, Responder};
+    use rubble::security::{NoSecurity, SecurityManager};
+    use rubble::time::Duration;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    pub(crate) struct TestRng(pub(crate) u32);
+
+    impl RngCore for TestRng {
+        fn next_u32(&mut self) -> u32 {
+            self.0 = self.0.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            self.0
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            impls::next_u64_via_u32(self)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            impls::fill_bytes_via_next(self, dest)
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    // Predictable, which is what the tests want.
+    impl CryptoRng for TestRng {}
+
+    /// Records the values read by the ATT client.
+    pub(crate) struct Reads(pub(crate) Rc<RefCell<Vec<Vec<u8>>>>);
+
+    impl ClientHandler for Reads {
+        fn response(&mut self, response: Response<'_>, _client: AttributeClientTx<'_>) {
+            if let Response::Read { value } = response {
+                self.0.borrow_mut().push(value.to_vec());
+            }
+        }
+
+        fn notification(&mut self, _handle: Handle, _value: &[u8]) {}
+
+        fn indication(&mut self, _handle: Handle, _value: &[u8]) {}
+    }
+
+    pub(crate) enum PeripheralConfig {}
+
+    impl Config for PeripheralConfig {
+        type Timer = SimTimer;
+        type Transmitter = SimRadio;
+        type ChannelMapper = BleChannelMap<BatteryServiceAttrs, NoSecurity>;
+        type PacketQueue = &'static mut SimpleQueue;
+        type AesProvider = SoftAesProvider;
+        type Rng = TestRng;
+        type PcapSink = IoSink<Vec<u8>>;
+    }
+
+    pub(crate) enum CentralConfig {}
+
+    impl Config for CentralConfig {
+        type Timer = SimTimer;
+        type Transmitter = SimRadio;
+        type ChannelMapper = BleChannelMap<NoAttributes, NoSecurity, Reads>;
+        type PacketQueue = &'static mut SimpleQueue;
+        type AesProvider = SoftAesProvider;
+        type Rng = TestRng;
+        type PcapSink = NoCapture;
+    }
+
+    pub(crate) fn queue() -> &'static mut SimpleQueue {
+        Box::leak(Box::new(SimpleQueue::new()))
+    }
+
+    #[test]
+    fn connect_and_read() {
+        let mut medium = Medium::new();
+        let mut rng = TestRng(1);
+        let ms = Duration::from_millis;
+
+        let peripheral_addr = DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Random);
+        let central_addr = DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Random);
+
+        let (tx, tx_cons) = queue().split();
+        let (rx_prod, rx) = queue().split();
+        let ll = LinkLayer::<PeripheralConfig>::new(
+            peripheral_addr,
+            medium.timer(),
+            SoftAesProvider::new(),
+            TestRng(1),
+        );
+        let l2cap = L2CAPState::new(BleChannelMap::with_attributes(BatteryServiceAttrs::new()));
+        let mut peripheral = Device::new(ll, Responder::new(tx, rx, l2cap));
+        let writer = PcapWriter::new(IoSink::new(Vec::new())).unwrap();
+        peripheral.link_layer().start_capture(writer);
+        let params =
+            AdvertisingParameters::new(AdvertisingType::ConnectableUndirected, ms(20), ms(20))
+                .unwrap();
+        peripheral
+            .start_advertise(params, &[], tx_cons, rx_prod)
+            .unwrap();
+
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let (tx, tx_cons) = queue().split();
+        let (rx_prod, rx) = queue().split();
+        let ll = LinkLayer::<CentralConfig>::new(
+            central_addr,
+            medium.timer(),
+            SoftAesProvider::new(),
+            TestRng(2),
+        );
+        let mapper = BleChannelMap::with_client(
+            NoAttributes,
+            SecurityManager::no_security(),
+            Reads(reads.clone()),
+        );
+        let mut central = Device::new(ll, Responder::new(tx, rx, L2CAPState::new(mapper)));
+        let lldata = ConnectRequestData::new(
+            ms(30),
+            0,
+            ms(1000),
+            ChannelMap::with_all_channels(),
+            &mut rng,
+        )
+        .unwrap();
+        central.start_connecting(peripheral_addr, lldata, tx_cons, rx_prod);
+
+        medium.run_for(&mut [&mut peripheral, &mut central], ms(200));
+        assert!(!peripheral.link_layer().is_advertising());
+        assert!(peripheral.link_layer().connection().is_some());
+        assert!(central.link_layer().connection().is_some());
+
+        // LLCP: The central moves the connection to a different interval
+        central
+            .link_layer()
+            .update_connection_params(ms(45), 0, ms(1000))
+            .unwrap();
+        medium.run_for(&mut [&mut peripheral, &mut central], ms(500));
+        let conn = peripheral.link_layer().connection().unwrap();
+        assert_eq!(conn.connection_interval(), ms(45));
+
+        // GATT: Read the battery level
+        central
+            .responder()
+            .l2cap()
+            .att()
+            .unwrap()
+            .client()
+            .0
+            .read(Handle::from_raw(3))
+            .unwrap();
+        medium.run_for(&mut [&mut peripheral, &mut central], ms(500));
+        assert_eq!(*reads.borrow(), [vec![48]]);
+        assert!(central.link_layer().connection().is_some());
+
+        let writer = peripheral.link_layer().stop_capture().unwrap();
+        check_capture(&writer.into_inner().into_inner());
+    }
+
+    /// Checks that the capture of `connect_and_read` contains the expected packets.
+    fn check_capture(pcap: &[u8]) {
+        assert_eq!(pcap[..4], [0xD4, 0xC3, 0xB2, 0xA1]);
+        assert_eq!(pcap[20..24], [0, 1, 0, 0]);
+
+        let mut records = Vec::new();
+        let mut rest = &pcap[24..];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes([rest[8], rest[9], rest[10], rest[11]]) as usize;
+            let micros = u64::from(u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]))
+                * 1_000_000
+                + u64::from(u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]));
+            records.push((micros, &rest[16..16 + len]));
+            rest = &rest[16 + len..];
+        }
+
+        // Timestamps never go backwards
+        assert!(records.windows(2).all(|w| w[0].0 <= w[1].0));
+
+        // The peripheral advertises, and receives the `CONNECT_REQ` on an advertising channel
+        let pdu_type = |flags: u16| (flags >> 7) & 0b111;
+        let flags = |record: &[u8]| u16::from_le_bytes([record[8], record[9]]);
+        let conn_req = records
+            .iter()
+            .position(|(_, record)| pdu_type(flags(record)) == 1 && record[14] & 0x0F == 0x05)
+            .unwrap();
+        assert!(records[..conn_req]
+            .iter()
+            .all(|(_, record)| pdu_type(flags(record)) == 1 && record[14] & 0x0F == 0x00));
+
+        // Afterwards, packets are exchanged in both directions, with a valid CRC
+        let data = &records[conn_req + 1..];
+        assert!(data.iter().any(|(_, record)| pdu_type(flags(record)) == 2));
+        assert!(data.iter().any(|(_, record)| pdu_type(flags(record)) == 3));
+        assert!(data
+            .iter()
+            .all(|(_, record)| flags(record) & 0x0C00 == 0x0C00));
+
+        // The battery level is sent in an ATT Read Response
+        let payload = |record: &[u8]| record[16..record.len() - 3].to_vec();
+        assert!(data
+            .iter()
+            .any(|(_, record)| pdu_type(flags(record)) == 3
+                && payload(record).ends_with(&[0x0B, 48])));
+    }
+}
@@ -0,0 +1,194 @@
+//! The virtual air connecting simulated devices.
+
+use crate::device::Node;
+use crate::radio::Packet;
+use crate::timer::{since, SimTimer};
+use rubble::time::{Duration, Instant, Timer};
+
+/// A packet on its way to the receivers.
+#[derive(Debug)]
+struct InFlight {
+    /// Index of the sending node.
+    sender: usize,
+    packet: Packet,
+    rx_end: Instant,
+}
+
+/// Delivers packets between simulated devices and drives their timers.
+///
+/// The `Medium` owns the virtual clock shared by all devices (see `timer`). Running it advances the
+/// clock from event to event: When a node's timer expires, its `timer_expired` method is called,
+/// and when a packet has been fully transmitted, it is passed to every other node whose radio is
+/// listening on the packet's channel (and Access Address, for data channel packets) at that time.
+///
+/// Packets are delivered after their air time on the LE 1M PHY. Packets sent in response to a
+/// received packet are sent `T_IFS` after the reception, like real hardware would do. Collisions
+/// and interference are not simulated.
+///
+/// The nodes are passed to every method driving the simulation. They must be passed in the same
+/// order every time, since the `Medium` identifies the sender of a packet by its index.
+#[derive(Debug)]
+pub struct Medium {
+    timer: SimTimer,
+    in_flight: Vec<InFlight>,
+}
+
+impl Medium {
+    /// Creates an empty medium with a new virtual clock.
+    pub fn new() -> Self {
+        Self {
+            timer: SimTimer::new(),
+            in_flight: Vec::new(),
+        }
+    }
+
+    /// Returns a `SimTimer` using the clock of this medium.
+    ///
+    /// Every device taking part in the simulation must use such a timer.
+    pub fn timer(&self) -> SimTimer {
+        self.timer.clone()
+    }
+
+    /// Returns the current simulation time.
+    pub fn now(&self) -> Instant {
+        self.timer.now()
+    }
+
+    /// Runs the simulation for `duration`.
+    pub fn run_for(&mut self, nodes: &mut [&mut dyn Node], duration: Duration) {
+        let end = self.now() + duration;
+        self.run_until(nodes, end);
+    }
+
+    /// Runs the simulation until all events up to `end` have been processed.
+    ///
+    /// Afterwards, the clock is set to `end`.
+    pub fn run_until(&mut self, nodes: &mut [&mut dyn Node], end: Instant) {
+        // Pick up packets sent since the simulation was last run (eg. by starting to advertise)
+        let now = self.now();
+        self.collect_sent(nodes, now);
+
+        let limit = end.duration_since(now);
+        while let Some((time, event)) = self.next_event(nodes) {
+            if since(time, now) > limit {
+                break;
+            }
+            self.timer.advance_to(time);
+
+            match event {
+                Event::Timer(index) => {
+                    nodes[index].timer_expired();
+                    self.collect_sent_by(nodes, index, time);
+                }
+                Event::Delivery(pos) => {
+                    let InFlight {
+                        sender,
+                        packet,
+                        rx_end,
+                    } = self.in_flight.remove(pos);
+                    self.deliver(nodes, sender, &packet, rx_end);
+                }
+            }
+        }
+
+        self.timer.advance_to(end);
+    }
+
+    /// Returns the earliest pending event.
+    ///
+    /// Packet deliveries come before timer events at the same time. Both are ordered by node index
+    /// and transmission order, which keeps the simulation deterministic.
+    fn next_event(&self, nodes: &mut [&mut dyn Node]) -> Option<(Instant, Event)> {
+        let now = self.now();
+        let delivery = self
+            .in_flight
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, f)| since(f.rx_end, now))
+            .map(|(pos, f)| (f.rx_end, Event::Delivery(pos)));
+        let timer = nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(index, node)| node.next_update().map(|at| (at, Event::Timer(index))))
+            .min_by_key(|(at, _)| since(*at, now));
+
+        match (delivery, timer) {
+            (Some(delivery), Some(timer)) => {
+                if since(timer.0, now) < since(delivery.0, now) {
+                    Some(timer)
+                } else {
+                    Some(delivery)
+                }
+            }
+            (delivery, timer) => delivery.or(timer),
+        }
+    }
+
+    /// Passes `packet` to all nodes listening for it.
+    fn deliver(
+        &mut self,
+        nodes: &mut [&mut dyn Node],
+        sender: usize,
+        packet: &Packet,
+        rx_end: Instant,
+    ) {
+        for index in 0..nodes.len() {
+            if index == sender {
+                continue;
+            }
+
+            let node = &mut *nodes[index];
+            let crc_ok = match packet.received_by(node.radio().receiver()) {
+                Some(crc_ok) => crc_ok,
+                None => continue,
+            };
+
+            match packet {
+                Packet::Advertising {
+                    header, payload, ..
+                } => node.receive_advertising(rx_end, *header, payload, crc_ok),
+                Packet::Data {
+                    header, payload, ..
+                } => node.receive_data(rx_end, *header, payload, crc_ok),
+            }
+
+            // Responses are sent after the inter frame spacing
+            self.collect_sent_by(nodes, index, rx_end + Duration::T_IFS);
+        }
+    }
+
+    fn collect_sent(&mut self, nodes: &mut [&mut dyn Node], tx_start: Instant) {
+        for index in 0..nodes.len() {
+            self.collect_sent_by(nodes, index, tx_start);
+        }
+    }
+
+    /// Puts the packets sent by a node on the air, starting at `tx_start`.
+    fn collect_sent_by(&mut self, nodes: &mut [&mut dyn Node], index: usize, tx_start: Instant) {
+        let mut tx_start = tx_start;
+        for packet in nodes[index].radio().take_sent() {
+            let rx_end = tx_start + packet.air_time();
+            self.in_flight.push(InFlight {
+                sender: index,
+                packet,
+                rx_end,
+            });
+            // Multiple packets are sent back to back
+            tx_start = rx_end + Duration::T_IFS;
+        }
+    }
+}
+
+impl Default for Medium {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+enum Event {
+    /// The timer of the node at the given index expires.
+    Timer(usize),
+
+    /// The in-flight packet at the given position has been fully transmitted.
+    Delivery(usize),
+}
@@ -0,0 +1,44 @@
+//! Writing packet captures to files.
+
+use rubble::link::pcap::{PcapSink, PcapWriter};
+use rubble::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// A `PcapSink` writing to an `io::Write` implementation.
+#[derive(Debug)]
+pub struct IoSink<W: Write>(W);
+
+impl<W: Write> IoSink<W> {
+    /// Creates a sink writing to `inner`.
+    pub fn new(inner: W) -> Self {
+        IoSink(inner)
+    }
+
+    /// Returns a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.0
+    }
+
+    /// Consumes the sink and returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.0
+    }
+}
+
+impl<W: Write> PcapSink for IoSink<W> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.0.write_all(bytes).map_err(|_| Error::Eof)
+    }
+}
+
+/// Creates a pcap file at `path` and returns a `PcapWriter` writing to it.
+///
+/// The returned writer can be passed to `LinkLayer::start_capture` of a device whose `Config` uses
+/// `IoSink<BufWriter<File>>` as its `PcapSink`. The file is completely written when the `BufWriter`
+/// is flushed or dropped.
+pub fn create_file<P: AsRef<Path>>(path: P) -> io::Result<PcapWriter<IoSink<BufWriter<File>>>> {
+    let file = BufWriter::new(File::create(path)?);
+    PcapWriter::new(IoSink::new(file)).map_err(|e| io::Error::other(e.to_string()))
+}
@@ -0,0 +1,159 @@
+//! A virtual radio recording the packets sent by the Link-Layer.
+
+use rubble::link::{advertising, data, RadioCmd, Transmitter, MAX_PAYLOAD_BUF};
+use rubble::phy::{AdvertisingChannel, DataChannel};
+use rubble::time::Duration;
+use std::fmt;
+
+/// A Link-Layer packet sent over the simulated air.
+#[derive(Debug, Clone)]
+pub enum Packet {
+    /// An advertising channel packet.
+    Advertising {
+        channel: AdvertisingChannel,
+        header: advertising::Header,
+        payload: Vec<u8>,
+    },
+
+    /// A data channel packet.
+    Data {
+        channel: DataChannel,
+        access_address: u32,
+        crc_init: u32,
+        header: data::Header,
+        payload: Vec<u8>,
+    },
+}
+
+impl Packet {
+    /// Returns the PDU payload following the header.
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            Packet::Advertising { payload, .. } | Packet::Data { payload, .. } => payload,
+        }
+    }
+
+    /// Returns the time it takes to transmit this packet on the LE 1M PHY.
+    ///
+    /// This includes preamble, Access Address, header and CRC.
+    pub fn air_time(&self) -> Duration {
+        // 1 Byte preamble, 4 Bytes Access Address, 2 Bytes header, 3 Bytes CRC
+        let bytes = 1 + 4 + 2 + self.payload().len() + 3;
+        Duration::from_micros(bytes as u32 * 8)
+    }
+
+    /// Returns whether a radio configured according to `cmd` picks up this packet.
+    ///
+    /// If it does, returns whether the packet's CRC is correct from the point of view of the
+    /// receiver (which is not the case when it uses a different CRC initialization value).
+    pub fn received_by(&self, cmd: &RadioCmd) -> Option<bool> {
+        match (self, cmd) {
+            (
+                Packet::Advertising { channel, .. },
+                RadioCmd::ListenAdvertising {
+                    channel: rx_channel,
+                },
+            ) if channel == rx_channel => Some(true),
+            (
+                Packet::Data {
+                    channel,
+                    access_address,
+                    crc_init,
+                    ..
+                },
+                RadioCmd::ListenData {
+                    channel: rx_channel,
+                    access_address: rx_access_address,
+                    crc_init: rx_crc_init,
+                    ..
+                },
+            ) if channel == rx_channel && access_address == rx_access_address => {
+                Some((crc_init ^ rx_crc_init) & 0x00FF_FFFF == 0)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Implements Rubble's `Transmitter` trait without any hardware.
+///
+/// Transmitted packets are recorded and can be retrieved with `take_sent`. A `Medium` uses this to
+/// deliver them to other simulated devices, according to the `RadioCmd` they configured.
+pub struct SimRadio {
+    tx_buf: [u8; MAX_PAYLOAD_BUF],
+    receiver: RadioCmd,
+    sent: Vec<Packet>,
+}
+
+impl SimRadio {
+    /// Creates a radio that is turned off.
+    pub fn new() -> Self {
+        Self {
+            tx_buf: [0; MAX_PAYLOAD_BUF],
+            receiver: RadioCmd::Off,
+            sent: Vec::new(),
+        }
+    }
+
+    /// Configures the receiver according to a `RadioCmd` returned by the Link-Layer.
+    pub fn configure_receiver(&mut self, cmd: RadioCmd) {
+        self.receiver = cmd;
+    }
+
+    /// Returns how the receiver is currently configured.
+    pub fn receiver(&self) -> &RadioCmd {
+        &self.receiver
+    }
+
+    /// Removes and returns all packets transmitted since the last call.
+    pub fn take_sent(&mut self) -> Vec<Packet> {
+        std::mem::take(&mut self.sent)
+    }
+}
+
+impl Default for SimRadio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for SimRadio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimRadio")
+            .field("receiver", &self.receiver)
+            .field("sent", &self.sent)
+            .finish()
+    }
+}
+
+impl Transmitter for SimRadio {
+    fn tx_payload_buf(&mut self) -> &mut [u8] {
+        &mut self.tx_buf
+    }
+
+    fn transmit_advertising(&mut self, header: advertising::Header, channel: AdvertisingChannel) {
+        let payload = self.tx_buf[..usize::from(header.payload_length())].to_vec();
+        self.sent.push(Packet::Advertising {
+            channel,
+            header,
+            payload,
+        });
+    }
+
+    fn transmit_data(
+        &mut self,
+        access_address: u32,
+        crc_iv: u32,
+        header: data::Header,
+        channel: DataChannel,
+    ) {
+        let payload = self.tx_buf[..usize::from(header.payload_length())].to_vec();
+        self.sent.push(Packet::Data {
+            channel,
+            access_address,
+            crc_init: crc_iv,
+            header,
+            payload,
+        });
+    }
+}
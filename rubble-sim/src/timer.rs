@@ -0,0 +1,64 @@
+//! A manually advanced `Timer`.
+
+use rubble::time::{Duration, Instant, Timer};
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Implements Rubble's `Timer` trait on top of a virtual clock.
+///
+/// Time only moves forward when `advance` or `advance_to` is called. Clones of a `SimTimer` share
+/// the same clock, which lets a `Medium` advance the time of all simulated devices at once.
+#[derive(Clone, Debug)]
+pub struct SimTimer {
+    now: Rc<Cell<Instant>>,
+}
+
+impl SimTimer {
+    /// Creates a new virtual clock, starting at a raw value of 0.
+    pub fn new() -> Self {
+        Self {
+            now: Rc::new(Cell::new(Instant::from_raw_micros(0))),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+
+    /// Moves the clock forward to `instant`.
+    ///
+    /// Instants in the past are ignored, since the clock must never move backwards.
+    pub fn advance_to(&self, instant: Instant) {
+        let now = self.now.get();
+        if instant.raw_micros().wrapping_sub(now.raw_micros()) <= i32::MAX as u32 {
+            self.now.set(instant);
+        }
+    }
+}
+
+impl Default for SimTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer for SimTimer {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
+
+/// Returns the time from `now` until `at`, or zero if `at` has already passed.
+///
+/// Timers should never be set in the past, but the Link-Layer may be slightly late when applying
+/// a `Cmd` computed from a packet's reception time. `Instant`s can't be compared directly since
+/// they wrap around, so simulated events are ordered by their distance from the current time.
+pub(crate) fn since(at: Instant, now: Instant) -> Duration {
+    let micros = at.raw_micros().wrapping_sub(now.raw_micros());
+    if micros > i32::MAX as u32 {
+        Duration::from_micros(0)
+    } else {
+        Duration::from_micros(micros)
+    }
+}
@@ -0,0 +1,330 @@
+//! ATT client implementation.
+//!
+//! The ATT bearer on L2CAP channel `0x0004` is shared by the local server and client. Requests sent
+//! by the connected device are processed by the `AttributeServer`, while responses, notifications
+//! and indications are forwarded to a `ClientHandler`.
+
+use super::{
+    pdus::{AttPdu, Opcode},
+    transaction::Transaction,
+    AttError, AttUuid, Handle, HandleRange, RawHandleRange,
+};
+use crate::l2cap::Sender;
+use crate::uuid::Uuid16;
+use crate::{utils::HexSlice, Error};
+
+/// A response to a request sent by the ATT client.
+///
+/// List responses are passed on in their raw form, as documented in the Bluetooth specification
+/// (*Vol 3, Part F, 3.4*).
+#[derive(Debug, Copy, Clone)]
+pub enum Response<'a> {
+    /// The server rejected the request.
+    Error(AttError),
+
+    /// The server's maximum `ATT_MTU`.
+    ///
+    /// The `ATT_MTU` used from now on has already been updated when this is received.
+    ExchangeMtu { mtu: u16 },
+
+    /// List of handles and attribute types.
+    ///
+    /// `format` is 0x01 if the list contains 16-bit UUIDs, and 0x02 if it contains 128-bit UUIDs.
+    FindInformation { format: u8, data: &'a [u8] },
+
+    /// List of found attribute handles and their group end handles (2 Bytes each).
+    FindByTypeValue { handles: &'a [u8] },
+
+    /// List of handles and values, each entry being `length` Bytes long.
+    ReadByType { length: u8, data: &'a [u8] },
+
+    /// The (possibly truncated) value of the attribute.
+    Read { value: &'a [u8] },
+
+    /// The part of the attribute value starting at the requested offset.
+    ReadBlob { value: &'a [u8] },
+
+    /// The concatenated values of the requested attributes.
+    ReadMultiple { values: &'a [u8] },
+
+    /// List of handles, group end handles, and values, each entry being `length` Bytes long.
+    ReadByGroup { length: u8, data: &'a [u8] },
+
+    /// The attribute was written.
+    Write,
+
+    /// The value part was queued (echoed back by the server).
+    PrepareWrite {
+        handle: Handle,
+        offset: u16,
+        value: &'a [u8],
+    },
+
+    /// The queued writes were executed or cancelled.
+    ExecuteWrite,
+}
+
+impl<'a> Response<'a> {
+    /// Converts a response PDU to a `Response`.
+    ///
+    /// Returns `None` if `pdu` isn't a response.
+    pub(super) fn from_pdu(pdu: &AttPdu<'a>) -> Option<Self> {
+        Some(match *pdu {
+            AttPdu::ErrorRsp {
+                handle, error_code, ..
+            } => Response::Error(AttError::new(error_code, handle)),
+            AttPdu::ExchangeMtuRsp { mtu } => Response::ExchangeMtu { mtu },
+            AttPdu::FindInformationRsp { format, data } => Response::FindInformation {
+                format,
+                data: data.0,
+            },
+            AttPdu::FindByTypeValueRsp {
+                handles_information_list,
+            } => Response::FindByTypeValue {
+                handles: handles_information_list.0,
+            },
+            AttPdu::ReadByTypeRsp { length, data_list } => Response::ReadByType {
+                length,
+                data: data_list.0,
+            },
+            AttPdu::ReadRsp { value } => Response::Read { value: value.0 },
+            AttPdu::ReadBlobRsp { value } => Response::ReadBlob { value: value.0 },
+            AttPdu::ReadMultipleRsp { values } => Response::ReadMultiple { values: values.0 },
+            AttPdu::ReadByGroupRsp { length, data_list } => Response::ReadByGroup {
+                length,
+                data: data_list.0,
+            },
+            AttPdu::WriteRsp => Response::Write,
+            AttPdu::PrepareWriteRsp {
+                handle,
+                offset,
+                value,
+            } => Response::PrepareWrite {
+                handle,
+                offset,
+                value: value.0,
+            },
+            AttPdu::ExecuteWriteRsp => Response::ExecuteWrite,
+            _ => return None,
+        })
+    }
+}
+
+/// Trait for handlers of PDUs sent to the local ATT client.
+pub trait ClientHandler {
+    /// Called when the server responds to the request sent via `AttributeClientTx`.
+    ///
+    /// `client` can be used to send the next request right away.
+    fn response(&mut self, response: Response<'_>, client: AttributeClientTx<'_>);
+
+    /// Called when the server sends a *Handle Value Notification*.
+    fn notification(&mut self, handle: Handle, value: &[u8]);
+
+    /// Called when the server sends a *Handle Value Indication*.
+    ///
+    /// The indication is confirmed automatically after this returns.
+    fn indication(&mut self, handle: Handle, value: &[u8]);
+
+    /// Called when a new connection is established.
+    ///
+    /// Any procedure in progress on the previous connection has to be abandoned.
+    fn connection_established(&mut self) {}
+}
+
+/// A `ClientHandler` ignoring all PDUs.
+///
+/// This is used when the local device doesn't act as an ATT client.
+#[derive(Debug)]
+pub struct NoClient;
+
+impl ClientHandler for NoClient {
+    fn response(&mut self, response: Response<'_>, _client: AttributeClientTx<'_>) {
+        warn!("unexpected ATT response {:?}", response);
+    }
+
+    fn notification(&mut self, _handle: Handle, _value: &[u8]) {}
+
+    fn indication(&mut self, _handle: Handle, _value: &[u8]) {}
+}
+
+/// An ATT client handle that can send a request to the connected server.
+///
+/// Only one request can be outstanding at a time. The response is passed to the `ClientHandler`
+/// of the `AttributeServer`. Since every request is a transaction subject to the ATT timeout,
+/// `AttributeServer::check_timeout` has to be called regularly while a request is outstanding.
+///
+/// All methods sending a request return `Error::InvalidValue` if another request is still
+/// outstanding or the ATT bearer has timed out.
+pub struct AttributeClientTx<'a> {
+    pub(super) transaction: &'a mut Transaction<Opcode>,
+    pub(super) sender: Sender<'a>,
+    pub(super) att_mtu: u8,
+    pub(super) max_mtu: u8,
+    pub(super) timed_out: bool,
+}
+
+impl<'a> AttributeClientTx<'a> {
+    /// Returns whether a request is outstanding, in which case no new request can be sent.
+    pub fn is_busy(&self) -> bool {
+        self.timed_out || self.transaction.pending().is_some()
+    }
+
+    /// Returns the `ATT_MTU` currently in use.
+    pub fn att_mtu(&self) -> u8 {
+        self.att_mtu
+    }
+
+    /// Requests the largest `ATT_MTU` supported by the local server (see
+    /// `AttributeServer::set_max_mtu`).
+    pub fn exchange_mtu(self) -> Result<(), Error> {
+        let mtu = u16::from(self.max_mtu);
+        self.request(AttPdu::ExchangeMtuReq { mtu })
+    }
+
+    /// Requests the handles and types of all attributes in `range`.
+    pub fn find_information(self, range: HandleRange) -> Result<(), Error> {
+        self.request(AttPdu::FindInformationReq {
+            handle_range: RawHandleRange::from(range),
+        })
+    }
+
+    /// Requests the handles of all attributes in `range` with type `att_type` and value `value`.
+    pub fn find_by_type_value(
+        self,
+        range: HandleRange,
+        att_type: Uuid16,
+        value: &[u8],
+    ) -> Result<(), Error> {
+        self.check_len(2 + 2 + 2 + value.len())?;
+        self.request(AttPdu::FindByTypeValueReq {
+            handle_range: RawHandleRange::from(range),
+            attribute_type: att_type.0,
+            attribute_value: HexSlice(value),
+        })
+    }
+
+    /// Requests the handles and values of all attributes in `range` with type `att_type`.
+    pub fn read_by_type(self, range: HandleRange, att_type: AttUuid) -> Result<(), Error> {
+        self.request(AttPdu::ReadByTypeReq {
+            handle_range: RawHandleRange::from(range),
+            attribute_type: att_type,
+        })
+    }
+
+    /// Requests the value of the attribute at `handle`.
+    pub fn read(self, handle: Handle) -> Result<(), Error> {
+        self.request(AttPdu::ReadReq { handle })
+    }
+
+    /// Requests the part of the value of the attribute at `handle` that starts at `offset`.
+    pub fn read_blob(self, handle: Handle, offset: u16) -> Result<(), Error> {
+        self.request(AttPdu::ReadBlobReq { handle, offset })
+    }
+
+    /// Requests the values of the attributes at `handles` (at least 2).
+    ///
+    /// Returns `Error::InvalidLength` if fewer than 2 or too many handles are passed.
+    pub fn read_multiple(self, handles: &[Handle]) -> Result<(), Error> {
+        if handles.len() < 2 {
+            return Err(Error::InvalidLength);
+        }
+        self.check_len(1 + 2 * handles.len())?;
+
+        if self.is_busy() {
+            return Err(Error::InvalidValue);
+        }
+        let AttributeClientTx {
+            mut sender,
+            transaction,
+            ..
+        } = self;
+        sender.send_with(|writer| -> Result<(), Error> {
+            writer.write_u8(Opcode::ReadMultipleReq.into())?;
+            for handle in handles {
+                writer.write_u16_le(handle.as_u16())?;
+            }
+            Ok(())
+        })?;
+        transaction.start(Opcode::ReadMultipleReq);
+        Ok(())
+    }
+
+    /// Requests the handles, group end handles and values of all attributes in `range` with type
+    /// `group_type`.
+    pub fn read_by_group_type(self, range: HandleRange, group_type: AttUuid) -> Result<(), Error> {
+        self.request(AttPdu::ReadByGroupReq {
+            handle_range: RawHandleRange::from(range),
+            group_type,
+        })
+    }
+
+    /// Writes `value` to the attribute at `handle`, expecting a response.
+    ///
+    /// Returns `Error::InvalidLength` if `value` doesn't fit in the `ATT_MTU`.
+    pub fn write(self, handle: Handle, value: &[u8]) -> Result<(), Error> {
+        self.check_len(1 + 2 + value.len())?;
+        self.request(AttPdu::WriteReq {
+            handle,
+            value: HexSlice(value),
+        })
+    }
+
+    /// Writes `value` to the attribute at `handle`, without a response.
+    ///
+    /// Unlike requests, this can be sent while a request is outstanding.
+    ///
+    /// Returns `Error::InvalidLength` if `value` doesn't fit in the `ATT_MTU`.
+    pub fn write_command(mut self, handle: Handle, value: &[u8]) -> Result<(), Error> {
+        self.check_len(1 + 2 + value.len())?;
+        if self.timed_out {
+            return Err(Error::InvalidValue);
+        }
+
+        self.sender.send(AttPdu::WriteCommand {
+            handle,
+            value: HexSlice(value),
+        })
+    }
+
+    /// Queues the part of a value starting at `offset` on the server, to be written by
+    /// `execute_write`.
+    ///
+    /// Returns `Error::InvalidLength` if `value` doesn't fit in the `ATT_MTU`.
+    pub fn prepare_write(self, handle: Handle, offset: u16, value: &[u8]) -> Result<(), Error> {
+        self.check_len(1 + 2 + 2 + value.len())?;
+        self.request(AttPdu::PrepareWriteReq {
+            handle,
+            offset,
+            value: HexSlice(value),
+        })
+    }
+
+    /// Writes (if `write` is `true`) or discards all values queued by `prepare_write`.
+    pub fn execute_write(self, write: bool) -> Result<(), Error> {
+        self.request(AttPdu::ExecuteWriteReq {
+            flags: if write { 0x01 } else { 0x00 },
+        })
+    }
+
+    /// Returns `Error::InvalidLength` if a PDU of `len` Bytes doesn't fit in the `ATT_MTU`.
+    fn check_len(&self, len: usize) -> Result<(), Error> {
+        if len > usize::from(self.att_mtu) {
+            Err(Error::InvalidLength)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sends the request `pdu` and starts a transaction.
+    fn request(mut self, pdu: AttPdu<'_>) -> Result<(), Error> {
+        if self.is_busy() {
+            return Err(Error::InvalidValue);
+        }
+
+        let opcode = pdu.opcode();
+        debug!("ATT client-> {:?}", pdu);
+        self.sender.send(pdu)?;
+        self.transaction.start(opcode);
+        Ok(())
+    }
+}
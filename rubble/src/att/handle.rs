@@ -65,6 +65,15 @@ impl RawHandleRange {
     }
 }
 
+impl From<HandleRange> for RawHandleRange {
+    fn from(range: HandleRange) -> Self {
+        Self {
+            start: range.start(),
+            end: range.end(),
+        }
+    }
+}
+
 impl FromBytes<'_> for RawHandleRange {
     fn from_bytes(bytes: &mut ByteReader<'_>) -> Result<Self, Error> {
         Ok(Self {
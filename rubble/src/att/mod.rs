@@ -29,18 +29,25 @@
 //!
 //! [`Handle`]: struct.Handle.html
 
+mod client;
 mod handle;
 mod pdus;
 mod server;
+mod transaction;
 mod uuid;
 
-use self::{handle::*, pdus::*};
+use self::handle::*;
 use crate::{utils::HexSlice, Error};
 
+pub use self::client::{AttributeClientTx, ClientHandler, NoClient, Response};
 pub use self::handle::{Handle, HandleRange};
+pub use self::pdus::{AttError, ErrorCode};
 pub use self::server::{AttributeServer, AttributeServerTx};
 pub use self::uuid::AttUuid;
 
+/// Maximum length of an attribute value.
+pub(crate) const MAX_VALUE_LEN: usize = 512;
+
 /// An ATT server attribute
 pub struct Attribute<'a> {
     /// The type of the attribute as a UUID16, EG "Primary Service" or "Anaerobic Heart Rate Lower Limit"
@@ -80,7 +87,7 @@ pub trait AttributeProvider {
     /// currently possible to express the iterator type generically (it would need lifetime-generic
     /// associated types), and all workarounds seem to be severely limiting.
     fn for_attrs_in_range(
-        &mut self,
+        &self,
         range: HandleRange,
         f: impl FnMut(&Self, Attribute<'_>) -> Result<(), Error>,
     ) -> Result<(), Error>;
@@ -89,8 +96,8 @@ pub trait AttributeProvider {
     /// Group Type* requests.
     fn is_grouping_attr(&self, uuid: AttUuid) -> bool;
 
-    /// Queries the last attribute that is part of the attribute group denoted by the grouping
-    /// attribute at `handle`.
+    /// Queries the handle of the last attribute that is part of the attribute group denoted by the
+    /// grouping attribute at `handle`.
     ///
     /// If `handle` does not refer to a grouping attribute, returns `None`.
     ///
@@ -104,7 +111,39 @@ pub trait AttributeProvider {
     /// last attribute contained within that service.
     ///
     /// TODO: document what the BLE spec has to say about grouping for characteristics.
-    fn group_end(&self, handle: Handle) -> Option<&Attribute<'_>>;
+    fn group_end(&self, handle: Handle) -> Option<Handle>;
+
+    /// Writes `value` to the attribute at `handle`, as requested by the client.
+    ///
+    /// This is called for *Write Requests* and *Write Commands* addressed to an existing attribute,
+    /// and for every attribute written by an *Execute Write Request*. Values queued with *Prepare
+    /// Write Requests* are combined with the current value of the attribute, so `value` is always
+    /// the complete new value. Writes to *Client Characteristic Configuration Descriptors* are handled by the
+    /// `AttributeServer` and are not forwarded to the provider.
+    ///
+    /// If the write is not allowed or `value` is invalid, an `AttError` has to be returned (eg.
+    /// with `ErrorCode::WriteNotPermitted` or `ErrorCode::InvalidAttributeValueLength`). It will be
+    /// sent to the client in response to a *Write Request*.
+    ///
+    /// The default implementation rejects all writes with `ErrorCode::WriteNotPermitted`.
+    fn write_attr(&mut self, handle: Handle, value: &[u8]) -> Result<(), AttError> {
+        let _ = value;
+        Err(AttError::new(ErrorCode::WriteNotPermitted, handle))
+    }
+
+    /// Checks whether `value` may be written to the attribute at `handle`, without writing it.
+    ///
+    /// An *Execute Write Request* calls this for every value in the prepare queue before any of
+    /// them is passed to `write_attr`, so that the queued writes are applied either completely or
+    /// not at all. Providers that override `write_attr` should override this method with the same
+    /// permission and validity checks.
+    ///
+    /// The default implementation rejects all writes with `ErrorCode::WriteNotPermitted`, like
+    /// `write_attr`.
+    fn check_write(&self, handle: Handle, value: &[u8]) -> Result<(), AttError> {
+        let _ = value;
+        Err(AttError::new(ErrorCode::WriteNotPermitted, handle))
+    }
 }
 
 /// An empty attribute set.
@@ -114,7 +153,7 @@ pub struct NoAttributes;
 
 impl AttributeProvider for NoAttributes {
     fn for_attrs_in_range(
-        &mut self,
+        &self,
         _range: HandleRange,
         _f: impl FnMut(&Self, Attribute<'_>) -> Result<(), Error>,
     ) -> Result<(), Error> {
@@ -125,7 +164,7 @@ impl AttributeProvider for NoAttributes {
         false
     }
 
-    fn group_end(&self, _handle: Handle) -> Option<&Attribute<'_>> {
+    fn group_end(&self, _handle: Handle) -> Option<Handle> {
         None
     }
 }
@@ -8,7 +8,7 @@ enum_with_unknown! {
     /// Error codes that can be sent from the ATT server to the client in response to a request.
     ///
     /// Used as the payload of `ErrorRsp` PDUs.
-    #[derive(Copy, Clone, Debug)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
     pub enum ErrorCode(u8) {
         /// Attempted to use an `Handle` that isn't valid on this server.
         InvalidHandle = 0x01,
@@ -48,7 +48,7 @@ enum_with_unknown! {
 }
 
 /// An error on the ATT protocol layer. Can be sent as a response.
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct AttError {
     code: ErrorCode,
     handle: Handle,
@@ -88,8 +88,11 @@ pub struct ByTypeAttData<'a> {
 impl<'a> ByTypeAttData<'a> {
     /// Creates a *Read By Type Response* attribute data structure from the attribute's handle and
     /// value.
+    ///
+    /// The value is truncated to fit in a response with the given `ATT_MTU`.
     pub fn new(att_mtu: u8, handle: Handle, mut value: &'a [u8]) -> Self {
-        let max_val_len = usize::from(att_mtu - 2);
+        // 1 Byte opcode, 1 Byte length, 2 Bytes for `handle`
+        let max_val_len = usize::from(att_mtu - 1 - 1 - 2);
         if value.len() > max_val_len {
             value = &value[..max_val_len];
         }
@@ -137,9 +140,12 @@ pub struct ByGroupAttData<'a> {
 }
 
 impl<'a> ByGroupAttData<'a> {
+    /// Creates a *Read By Group Type Response* attribute data structure.
+    ///
+    /// The value is truncated to fit in a response with the given `ATT_MTU`.
     pub fn new(att_mtu: u8, handle: Handle, group_end_handle: Handle, mut value: &'a [u8]) -> Self {
-        // 2 Bytes for `handle`, 2 Bytes for `group_end_handle`
-        let max_val_len = usize::from(att_mtu - 2 - 2);
+        // 1 Byte opcode, 1 Byte length, 2 Bytes for `handle`, 2 Bytes for `group_end_handle`
+        let max_val_len = usize::from(att_mtu - 1 - 1 - 2 - 2);
         if value.len() > max_val_len {
             value = &value[..max_val_len];
         }
@@ -659,7 +665,7 @@ impl AttPdu<'_> {
             AttPdu::ReadMultipleReq { .. } => Opcode::ReadMultipleReq,
             AttPdu::ReadMultipleRsp { .. } => Opcode::ReadMultipleRsp,
             AttPdu::ReadByGroupReq { .. } => Opcode::ReadByGroupReq,
-            AttPdu::ReadByGroupRsp { .. } => Opcode::ReadBlobRsp,
+            AttPdu::ReadByGroupRsp { .. } => Opcode::ReadByGroupRsp,
             AttPdu::WriteReq { .. } => Opcode::WriteReq,
             AttPdu::WriteRsp { .. } => Opcode::WriteRsp,
             AttPdu::WriteCommand { .. } => Opcode::WriteCommand,
@@ -1,41 +1,508 @@
 //! ATT server implementation.
 
 use super::{
+    client::{AttributeClientTx, ClientHandler, NoClient, Response},
     pdus::{AttPdu, ByGroupAttData, ByTypeAttData, ErrorCode, Opcode},
-    AttError, AttributeProvider, Handle, HandleRange,
+    transaction::Transaction,
+    AttError, AttUuid, Attribute, AttributeProvider, Handle, HandleRange, MAX_VALUE_LEN,
 };
 use crate::bytes::{ByteReader, FromBytes, ToBytes};
 use crate::l2cap::{Protocol, ProtocolObj, Sender};
+use crate::time::Instant;
+use crate::uuid::Uuid16;
 use crate::{utils::HexSlice, Error};
+use core::{cmp, mem};
+use heapless::{
+    consts::{U16, U512, U8},
+    Vec,
+};
+
+/// Attribute type of *Client Characteristic Configuration Descriptors* (CCCDs).
+const CCCD: AttUuid = AttUuid::Uuid16(Uuid16(0x2902));
+
+/// CCCD bit enabling notifications.
+const CCCD_NOTIFY: u16 = 0x0001;
+
+/// CCCD bit enabling indications.
+const CCCD_INDICATE: u16 = 0x0002;
+
+/// A value part queued by a *Prepare Write Request*.
+///
+/// The value itself is stored in `AttributeServer::prepare_buf`, after the values of all previously
+/// queued parts.
+#[derive(Debug, Copy, Clone)]
+struct PreparedWrite {
+    handle: Handle,
+    offset: u16,
+    len: u16,
+}
 
 /// An Attribute Protocol server providing read and write access to stored attributes.
-pub struct AttributeServer<A: AttributeProvider> {
+///
+/// The server keeps track of the *Client Characteristic Configuration Descriptors* (CCCDs) written
+/// by the client, which determine whether notifications and indications may be sent (see
+/// `AttributeServerTx::notify` and `AttributeServerTx::indicate`). Up to 8 CCCDs can be enabled at
+/// the same time.
+///
+/// The ATT bearer is shared with the local ATT client: Responses, notifications and indications
+/// sent by the connected device are passed to the `ClientHandler` `H`, and requests can be sent via
+/// `AttributeServerTx::client`.
+///
+/// While an indication is not confirmed by the client, or a request sent by the local client is not
+/// answered, `check_timeout` has to be called regularly to enforce the ATT transaction timeout.
+pub struct AttributeServer<A: AttributeProvider, H: ClientHandler = NoClient> {
     attrs: A,
+
+    /// Handler for PDUs addressed to the local client.
+    handler: H,
+
+    /// The largest `ATT_MTU` supported by the server.
+    max_mtu: u8,
+
+    /// The `ATT_MTU` negotiated with the connected client.
+    att_mtu: u8,
+
+    /// Non-zero CCCD values written by the client, by CCCD handle.
+    cccds: Vec<(Handle, u16), U8>,
+
+    /// Indication the client hasn't confirmed yet.
+    indication: Transaction<()>,
+
+    /// Request sent by the local client that hasn't been answered yet, by request opcode.
+    request: Transaction<Opcode>,
+
+    /// Whether an ATT transaction has timed out.
+    ///
+    /// No further ATT PDUs may be exchanged on the connection after that.
+    timed_out: bool,
+
+    /// The prepare queue, holding up to 16 value parts of queued writes.
+    prepared: Vec<PreparedWrite, U16>,
+
+    /// The values of all parts in the prepare queue.
+    prepare_buf: Vec<u8, U512>,
 }
 
 impl<A: AttributeProvider> AttributeServer<A> {
     /// Creates an `AttributeServer` hosting attributes from an `AttributeProvider`.
+    ///
+    /// The server only supports the default `ATT_MTU` of 23 Bytes, unless `set_max_mtu` is called.
     pub fn new(attrs: A) -> Self {
-        Self { attrs }
+        Self::with_client(attrs, NoClient)
+    }
+}
+
+impl<A: AttributeProvider, H: ClientHandler> AttributeServer<A, H> {
+    /// Creates an `AttributeServer` hosting attributes from an `AttributeProvider`, and passing
+    /// PDUs addressed to the local client to `handler`.
+    pub fn with_client(attrs: A, handler: H) -> Self {
+        Self {
+            attrs,
+            handler,
+            max_mtu: Self::RSP_PDU_SIZE,
+            att_mtu: Self::RSP_PDU_SIZE,
+            cccds: Vec::new(),
+            indication: Transaction::new(),
+            request: Transaction::new(),
+            timed_out: false,
+            prepared: Vec::new(),
+            prepare_buf: Vec::new(),
+        }
+    }
+
+    /// Sets the largest `ATT_MTU` the server supports.
+    ///
+    /// When the client requests a larger `ATT_MTU` via an *Exchange MTU Request*, the smaller of
+    /// both values is used for the rest of the connection.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `mtu` is smaller than the default `ATT_MTU` of 23 Bytes.
+    pub fn set_max_mtu(&mut self, mtu: u8) {
+        assert!(
+            mtu >= Self::RSP_PDU_SIZE,
+            "ATT_MTU must be at least 23 Bytes"
+        );
+        self.max_mtu = mtu;
+    }
+
+    /// Resets all per-connection state.
+    ///
+    /// This must be called whenever a new connection is established. It resets the `ATT_MTU` to
+    /// the default of 23 Bytes, disables all notifications and indications, and discards any
+    /// pending indication and queued writes.
+    pub fn connection_established(&mut self) {
+        self.att_mtu = Self::RSP_PDU_SIZE;
+        self.cccds = Vec::new();
+        self.clear_prepare_queue();
+        self.indication = Transaction::new();
+        self.request = Transaction::new();
+        self.timed_out = false;
+        self.handler.connection_established();
+    }
+
+    /// Checks whether an ATT transaction has timed out.
+    ///
+    /// If an indication isn't confirmed by the client, or a request sent by the local client isn't
+    /// answered within 30 seconds, the ATT transaction times out and no further ATT PDUs can be
+    /// exchanged on the connection. The connection should then be closed. This method should be
+    /// called regularly (eg. from the app's idle loop) with the current time.
+    pub fn check_timeout(&mut self, now: Instant) {
+        if self.indication.check_timeout(now) {
+            warn!("ATT timeout, indication not confirmed");
+            self.timed_out = true;
+        }
+
+        if self.request.check_timeout(now) {
+            warn!("ATT timeout, request not answered");
+            self.timed_out = true;
+        }
+    }
+
+    /// Returns whether the ATT transaction timeout has expired on this connection.
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// Returns whether an indication was sent that the client hasn't confirmed yet.
+    ///
+    /// Only one indication can be outstanding at a time.
+    pub fn indication_pending(&self) -> bool {
+        self.indication.pending().is_some()
+    }
+
+    /// Returns whether the local client sent a request that the server hasn't answered yet.
+    ///
+    /// Only one request can be outstanding at a time.
+    pub fn request_pending(&self) -> bool {
+        self.request.pending().is_some()
+    }
+
+    /// Returns whether the client has enabled notifications for the characteristic value at
+    /// `handle`.
+    pub fn notifications_enabled(&self, handle: Handle) -> bool {
+        self.client_config(handle) & CCCD_NOTIFY != 0
+    }
+
+    /// Returns whether the client has enabled indications for the characteristic value at
+    /// `handle`.
+    pub fn indications_enabled(&self, handle: Handle) -> bool {
+        self.client_config(handle) & CCCD_INDICATE != 0
+    }
+
+    /// Returns the CCCD value the client has written for the characteristic value at `handle`.
+    fn client_config(&self, handle: Handle) -> u16 {
+        match self.cccd_of(handle) {
+            Some(cccd) => cccd_value(&self.cccds, cccd),
+            None => 0,
+        }
+    }
+
+    /// Finds the CCCD belonging to the characteristic value at `handle`.
+    ///
+    /// The CCCD is one of the descriptors following the value, before the next characteristic or
+    /// service declaration.
+    fn cccd_of(&self, handle: Handle) -> Option<Handle> {
+        let start = handle.as_u16().checked_add(1)?;
+        let range = HandleRange::new(Handle::from_raw(start), Handle::from_raw(0xFFFF));
+
+        let mut cccd = None;
+        self.attrs
+            .for_attrs_in_range(range, |_provider, attr| {
+                if attr.att_type == CCCD {
+                    cccd = Some(attr.handle);
+                    Err(Error::Eof)
+                } else if attr.att_type == Uuid16(0x2800)
+                    || attr.att_type == Uuid16(0x2801)
+                    || attr.att_type == Uuid16(0x2803)
+                {
+                    // "Primary Service", "Secondary Service", or "Characteristic"
+                    Err(Error::Eof)
+                } else {
+                    Ok(())
+                }
+            })
+            .ok();
+        cccd
+    }
+
+    /// Returns the type of the attribute at `handle`, or `None` if it doesn't exist.
+    fn attr_type(&self, handle: Handle) -> Option<AttUuid> {
+        if handle == Handle::NULL {
+            return None;
+        }
+
+        let mut att_type = None;
+        self.attrs
+            .for_attrs_in_range(HandleRange::new(handle, handle), |_provider, attr| {
+                att_type = Some(attr.att_type);
+                Ok(())
+            })
+            .ok();
+        att_type
+    }
+
+    /// Calls `f` with the value of the attribute at `handle` and returns its result.
+    ///
+    /// The values of CCCDs are provided by the server. If there's no attribute at `handle`, returns
+    /// an `InvalidHandle` error.
+    fn read_value<R>(&self, handle: Handle, f: impl FnOnce(&[u8]) -> R) -> Result<R, AttError> {
+        if handle == Handle::NULL {
+            return Err(AttError::new(ErrorCode::InvalidHandle, handle));
+        }
+
+        let (attrs, cccds) = (&self.attrs, &self.cccds);
+        let mut f = Some(f);
+        let mut result = None;
+        attrs
+            .for_attrs_in_range(HandleRange::new(handle, handle), |_provider, attr| {
+                if let Some(f) = f.take() {
+                    result = Some(f(attr_value(cccds, &attr, &mut [0; 2])));
+                }
+                Ok(())
+            })
+            .ok();
+        result.ok_or_else(|| AttError::new(ErrorCode::InvalidHandle, handle))
+    }
+
+    /// Writes `value` to the attribute at `handle`.
+    ///
+    /// CCCDs are handled by the server, all other writes are forwarded to the `AttributeProvider`.
+    fn write(&mut self, handle: Handle, value: &[u8]) -> Result<(), AttError> {
+        match self.attr_type(handle) {
+            None => Err(AttError::new(ErrorCode::InvalidHandle, handle)),
+            Some(att_type) if att_type == CCCD => self.write_cccd(handle, value),
+            Some(_) => self.attrs.write_attr(handle, value),
+        }
+    }
+
+    /// Adds a value part to the prepare queue.
+    ///
+    /// Offsets are only validated when the queued writes are executed.
+    fn prepare_write(&mut self, handle: Handle, offset: u16, value: &[u8]) -> Result<(), AttError> {
+        if self.attr_type(handle).is_none() {
+            return Err(AttError::new(ErrorCode::InvalidHandle, handle));
+        }
+
+        if self.prepared.len() == self.prepared.capacity()
+            || self.prepare_buf.len() + value.len() > self.prepare_buf.capacity()
+        {
+            return Err(AttError::new(ErrorCode::PrepareQueueFull, handle));
+        }
+
+        self.prepared
+            .push(PreparedWrite {
+                handle,
+                offset,
+                len: value.len() as u16,
+            })
+            .unwrap();
+        self.prepare_buf.extend_from_slice(value).unwrap();
+        Ok(())
+    }
+
+    /// Discards all values in the prepare queue.
+    fn clear_prepare_queue(&mut self) {
+        self.prepared = Vec::new();
+        self.prepare_buf = Vec::new();
+    }
+
+    /// Writes all values in the prepare queue and clears it.
+    ///
+    /// All queued values are validated (via `AttributeProvider::check_write`) before anything is
+    /// written, so either all or none of them are written. Each attribute's new value is assembled
+    /// by `assemble_prepared`, so the `AttributeProvider` always gets the complete value.
+    fn execute_writes(&mut self) -> Result<(), AttError> {
+        let prepared = mem::replace(&mut self.prepared, Vec::new());
+        let buf = mem::replace(&mut self.prepare_buf, Vec::new());
+
+        // Every attribute is written once, in the order of its first part.
+        let handles = prepared
+            .iter()
+            .enumerate()
+            .filter(|(i, write)| prepared[..*i].iter().all(|w| w.handle != write.handle))
+            .map(|(_, write)| write.handle);
+
+        let mut value = [0; MAX_VALUE_LEN];
+        for handle in handles.clone() {
+            let len = self.assemble_prepared(&prepared, &buf, handle, &mut value)?;
+            self.check_write(handle, &value[..len])?;
+        }
+        for handle in handles {
+            let len = self.assemble_prepared(&prepared, &buf, handle, &mut value)?;
+            self.write(handle, &value[..len])?;
+        }
+        Ok(())
+    }
+
+    /// Assembles the new value of the attribute at `handle` from its parts in the prepare queue.
+    ///
+    /// The parts have to be contiguous. If the first one starts at a non-zero offset, the current
+    /// value of the attribute is kept up to that offset. Returns an `InvalidOffset` error if the
+    /// parts aren't contiguous or the first offset lies beyond the end of the current value.
+    ///
+    /// On success, the value is written to the start of `value` and its length is returned.
+    fn assemble_prepared(
+        &self,
+        prepared: &[PreparedWrite],
+        buf: &[u8],
+        handle: Handle,
+        value: &mut [u8; MAX_VALUE_LEN],
+    ) -> Result<usize, AttError> {
+        let invalid_offset = AttError::new(ErrorCode::InvalidOffset, handle);
+        let mut len = None;
+        let mut start = 0;
+        for part in prepared {
+            let data = &buf[start..start + usize::from(part.len)];
+            start += data.len();
+            if part.handle != handle {
+                continue;
+            }
+
+            let offset = usize::from(part.offset);
+            match len {
+                None => self.read_value(handle, |current| {
+                    if offset > cmp::min(current.len(), MAX_VALUE_LEN) {
+                        return Err(invalid_offset);
+                    }
+                    value[..offset].copy_from_slice(&current[..offset]);
+                    Ok(())
+                })??,
+                Some(len) if len != offset => return Err(invalid_offset),
+                Some(_) => {}
+            }
+
+            let end = offset + data.len();
+            if end > MAX_VALUE_LEN {
+                return Err(AttError::new(
+                    ErrorCode::InvalidAttributeValueLength,
+                    handle,
+                ));
+            }
+            value[offset..end].copy_from_slice(data);
+            len = Some(end);
+        }
+        Ok(len.unwrap_or(0))
+    }
+
+    /// Checks whether `value` may be written to the attribute at `handle`, without writing it.
+    fn check_write(&self, handle: Handle, value: &[u8]) -> Result<(), AttError> {
+        match self.attr_type(handle) {
+            None => Err(AttError::new(ErrorCode::InvalidHandle, handle)),
+            Some(att_type) if att_type == CCCD => self.check_cccd(handle, value),
+            Some(_) => self.attrs.check_write(handle, value),
+        }
+    }
+
+    /// Checks whether `value` can be stored as the value of the CCCD at `handle`.
+    fn check_cccd(&self, handle: Handle, value: &[u8]) -> Result<(), AttError> {
+        if value.len() != 2 {
+            return Err(AttError::new(
+                ErrorCode::InvalidAttributeValueLength,
+                handle,
+            ));
+        }
+
+        let known = self.cccds.iter().any(|(cccd, _)| *cccd == handle);
+        if !known && value != [0, 0] && self.cccds.len() == self.cccds.capacity() {
+            return Err(AttError::new(ErrorCode::InsufficientResources, handle));
+        }
+        Ok(())
+    }
+
+    /// Stores a CCCD value written by the client.
+    fn write_cccd(&mut self, handle: Handle, value: &[u8]) -> Result<(), AttError> {
+        self.check_cccd(handle, value)?;
+        let value = u16::from_le_bytes([value[0], value[1]]);
+
+        let index = self.cccds.iter().position(|(cccd, _)| *cccd == handle);
+        match (index, value) {
+            (Some(index), 0) => {
+                self.cccds.swap_remove(index);
+            }
+            (Some(index), _) => self.cccds[index].1 = value,
+            (None, 0) => {}
+            (None, _) => self.cccds.push((handle, value)).unwrap(),
+        }
+
+        debug!("CCCD {:?} = {:#06X}", handle, value);
+        Ok(())
+    }
+
+    /// Returns a reference to the hosted attributes.
+    ///
+    /// This can be used to access values written by the client.
+    pub fn provider(&mut self) -> &mut A {
+        &mut self.attrs
+    }
+
+    /// Returns a reference to the handler of PDUs addressed to the local client.
+    pub fn client_handler(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// Returns the `ATT_MTU` value, the maximum size of an ATT PDU that can be processed and sent
+    /// out by the server.
+    ///
+    /// This is 23 Bytes until the client requests a larger `ATT_MTU`.
+    pub fn att_mtu(&self) -> u8 {
+        self.att_mtu
     }
 
     /// Prepares for performing a server-initiated action (eg. sending a notification/indication).
     ///
-    /// The caller must ensure that `sender` has at least `RSP_PDU_SIZE` bytes of free space
+    /// The caller must ensure that `sender` has at least `att_mtu()` bytes of free space
     /// available.
     ///
     /// It is usually not necessary to use this function. Instead, call `L2CAPStateTx::att`.
-    pub fn with_sender<'a>(&'a mut self, sender: Sender<'a>) -> AttributeServerTx<'a, A> {
+    pub fn with_sender<'a>(&'a mut self, sender: Sender<'a>) -> AttributeServerTx<'a, A, H> {
         AttributeServerTx {
             server: self,
             sender,
         }
     }
 
-    /// Returns the `ATT_MTU` value, the maximum size of an ATT PDU that can be processed and sent
-    /// out by the server.
-    fn att_mtu(&self) -> u8 {
-        Self::RSP_PDU_SIZE
+    /// Passes a response to the request sent by the local client to the `ClientHandler`.
+    ///
+    /// Responses that don't belong to the outstanding request are ignored.
+    fn process_response(
+        &mut self,
+        pdu: &AttPdu<'_>,
+        response: Response<'_>,
+        mut sender: Sender<'_>,
+    ) {
+        let answers = |request: Opcode| match pdu {
+            AttPdu::ErrorRsp { opcode, .. } => opcode.raw() == request.raw(),
+            // All other responses use the request's opcode + 1
+            _ => pdu.opcode().raw() == request.raw() + 1,
+        };
+        match self.request.pending() {
+            Some(request) if answers(request) => {
+                debug!("ATT client<- {:?} for {:?}", response, request);
+                self.request.finish();
+            }
+            _ => {
+                warn!("unexpected ATT response {:?}", response);
+                return;
+            }
+        }
+
+        // `sender` can only fit PDUs of the `ATT_MTU` that was in use when the response came in
+        let sender_mtu = self.att_mtu;
+        if let Response::ExchangeMtu { mtu } = response {
+            let server_mtu = cmp::max(mtu, u16::from(Self::RSP_PDU_SIZE));
+            self.att_mtu = cmp::min(server_mtu, u16::from(self.max_mtu)) as u8;
+            debug!("ATT_MTU is now {}", self.att_mtu);
+        }
+
+        let client = AttributeClientTx {
+            transaction: &mut self.request,
+            sender: sender.reborrow(),
+            att_mtu: sender_mtu,
+            max_mtu: self.max_mtu,
+            timed_out: self.timed_out,
+        };
+        self.handler.response(response, client);
     }
 
     /// Process an incoming request (or command) PDU and return a response.
@@ -66,15 +533,117 @@ impl<A: AttributeProvider> AttributeServer<A> {
         }
 
         match msg {
-            AttPdu::ExchangeMtuReq { mtu: _mtu } => {
+            AttPdu::ExchangeMtuReq { mtu } => {
+                // The response is still limited by the old `ATT_MTU`, the new one applies to all
+                // PDUs sent afterwards.
                 responder
                     .send(AttPdu::ExchangeMtuRsp {
-                        mtu: u16::from(Self::RSP_PDU_SIZE),
+                        mtu: u16::from(self.max_mtu),
                     })
                     .unwrap();
+
+                // Clients must not request an `ATT_MTU` below the default, but don't shrink it if
+                // they do.
+                let client_mtu = cmp::max(*mtu, u16::from(Self::RSP_PDU_SIZE));
+                self.att_mtu = cmp::min(client_mtu, u16::from(self.max_mtu)) as u8;
+                debug!("ATT_MTU is now {}", self.att_mtu);
                 Ok(())
             }
 
+            AttPdu::FindInformationReq { handle_range } => {
+                let range = handle_range.check()?;
+
+                let result = responder.send_with(|writer| {
+                    writer.write_u8(Opcode::FindInformationRsp.into())?;
+                    let format = writer.split_next_mut().ok_or(Error::Eof)?;
+
+                    let start = range.start();
+
+                    // The format (16- or 128-bit UUIDs) is determined by the first attribute. The
+                    // list ends before the first attribute using the other format.
+                    let mut uuid_len = None;
+                    self.attrs
+                        .for_attrs_in_range(range, |_provider, attr| {
+                            let len = match attr.att_type {
+                                AttUuid::Uuid16(_) => 2,
+                                AttUuid::Uuid128(_) => 16,
+                            };
+                            if uuid_len.unwrap_or(len) != len || writer.space_left() < 2 + len {
+                                return Err(Error::Eof);
+                            }
+
+                            writer.write_u16_le(attr.handle.as_u16())?;
+                            attr.att_type.to_bytes(writer)?;
+                            uuid_len = Some(len);
+                            Ok(())
+                        })
+                        .ok();
+
+                    match uuid_len {
+                        Some(2) => *format = 0x01,
+                        Some(_) => *format = 0x02,
+                        None => {
+                            return Err(AttError::new(ErrorCode::AttributeNotFound, start).into())
+                        }
+                    }
+                    Ok(())
+                });
+
+                match result {
+                    Ok(()) => Ok(()),
+                    Err(RspError(e)) => Err(e),
+                }
+            }
+
+            AttPdu::FindByTypeValueReq {
+                handle_range,
+                attribute_type,
+                attribute_value,
+            } => {
+                let range = handle_range.check()?;
+                let att_type = AttUuid::Uuid16(Uuid16(*attribute_type));
+
+                let result = responder.send_with(|writer| {
+                    writer.write_u8(Opcode::FindByTypeValueRsp.into())?;
+
+                    let start = range.start();
+                    let mut found = false;
+                    let value: &[u8] = attribute_value.as_ref();
+                    let (attrs, cccds) = (&self.attrs, &self.cccds);
+                    attrs
+                        .for_attrs_in_range(range, |provider, attr| {
+                            if attr.att_type == att_type
+                                && attr_value(cccds, &attr, &mut [0; 2]) == value
+                            {
+                                if writer.space_left() < 4 {
+                                    return Err(Error::Eof);
+                                }
+
+                                // Attributes that aren't grouping attributes form their own group
+                                let group_end =
+                                    provider.group_end(attr.handle).unwrap_or(attr.handle);
+                                writer.write_u16_le(attr.handle.as_u16())?;
+                                writer.write_u16_le(group_end.as_u16())?;
+                                found = true;
+                            }
+
+                            Ok(())
+                        })
+                        .ok();
+
+                    if found {
+                        Ok(())
+                    } else {
+                        Err(AttError::new(ErrorCode::AttributeNotFound, start).into())
+                    }
+                });
+
+                match result {
+                    Ok(()) => Ok(()),
+                    Err(RspError(e)) => Err(e),
+                }
+            }
+
             AttPdu::ReadByTypeReq {
                 handle_range,
                 attribute_type,
@@ -90,11 +659,13 @@ impl<A: AttributeProvider> AttributeServer<A> {
 
                     let mut size = None;
                     let att_mtu = self.att_mtu();
-                    self.attrs
+                    let (attrs, cccds) = (&self.attrs, &self.cccds);
+                    attrs
                         .for_attrs_in_range(range, |_provider, attr| {
                             if attr.att_type == *attribute_type {
-                                let data =
-                                    ByTypeAttData::new(att_mtu, attr.handle, attr.value.as_ref());
+                                let mut buf = [0; 2];
+                                let value = attr_value(cccds, &attr, &mut buf);
+                                let data = ByTypeAttData::new(att_mtu, attr.handle, value);
                                 if size == Some(data.encoded_size()) || size.is_none() {
                                     // Can try to encode `data`. If we run out of space, end the list.
                                     data.to_bytes(writer)?;
@@ -150,7 +721,7 @@ impl<A: AttributeProvider> AttributeServer<A> {
                                 let data = ByGroupAttData::new(
                                     att_mtu,
                                     attr.handle,
-                                    provider.group_end(attr.handle).unwrap().handle,
+                                    provider.group_end(attr.handle).unwrap(),
                                     attr.value.as_ref(),
                                 );
                                 if size == Some(data.encoded_size()) || size.is_none() {
@@ -185,44 +756,134 @@ impl<A: AttributeProvider> AttributeServer<A> {
             }
 
             AttPdu::ReadReq { handle } => {
-                responder
-                    .send_with(|writer| -> Result<(), Error> {
-                        writer.write_u8(Opcode::ReadRsp.into())?;
-
-                        self.attrs.for_attrs_in_range(
-                            HandleRange::new(*handle, *handle),
-                            |_provider, attr| {
-                                let value = if writer.space_left() < attr.value.as_ref().len() {
-                                    &attr.value.as_ref()[..writer.space_left()]
-                                } else {
-                                    attr.value.as_ref()
-                                };
-                                writer.write_slice(value)
-                            },
-                        )?;
+                // The value is truncated to `ATT_MTU - 1` Bytes, the rest can be read with *Read
+                // Blob Requests*.
+                let max_len = usize::from(self.att_mtu - 1);
+                let result = responder.send_with(|writer| -> Result<(), RspError> {
+                    writer.write_u8(Opcode::ReadRsp.into())?;
+
+                    self.read_value(*handle, |value| {
+                        let len = cmp::min(value.len(), cmp::min(max_len, writer.space_left()));
+                        writer.write_slice(&value[..len])
+                    })??;
+
+                    Ok(())
+                });
+
+                match result {
+                    Ok(()) => Ok(()),
+                    Err(RspError(e)) => Err(e),
+                }
+            }
+
+            AttPdu::ReadBlobReq { handle, offset } => {
+                let att_mtu = usize::from(self.att_mtu);
+                let offset = usize::from(*offset);
+                let result = responder.send_with(|writer| -> Result<(), RspError> {
+                    writer.write_u8(Opcode::ReadBlobRsp.into())?;
 
+                    self.read_value(*handle, |value| -> Result<(), RspError> {
+                        if offset > value.len() {
+                            return Err(AttError::new(ErrorCode::InvalidOffset, *handle).into());
+                        }
+
+                        // Values that always fit in a *Read Response* can't be read in parts
+                        if value.len() <= att_mtu - 3 {
+                            return Err(AttError::new(ErrorCode::AttributeNotLong, *handle).into());
+                        }
+
+                        writer.write_slice_truncate(&value[offset..]);
                         Ok(())
-                    })
-                    .unwrap();
+                    })??;
+
+                    Ok(())
+                });
+
+                match result {
+                    Ok(()) => Ok(()),
+                    Err(RspError(e)) => Err(e),
+                }
+            }
+
+            AttPdu::ReadMultipleReq { handles } => {
+                let handles = handles.as_ref();
+                if handles.len() < 4 || handles.len() % 2 != 0 {
+                    return Err(AttError::new(ErrorCode::InvalidPdu, Handle::NULL));
+                }
+
+                let result = responder.send_with(|writer| -> Result<(), RspError> {
+                    writer.write_u8(Opcode::ReadMultipleRsp.into())?;
+
+                    // The concatenated values are truncated to fit in the response
+                    let mut bytes = ByteReader::new(handles);
+                    while !bytes.is_empty() {
+                        let handle = Handle::from_bytes(&mut bytes)?;
+                        self.read_value(handle, |value| writer.write_slice_truncate(value))?;
+                    }
+
+                    Ok(())
+                });
+
+                match result {
+                    Ok(()) => Ok(()),
+                    Err(RspError(e)) => Err(e),
+                }
+            }
+
+            AttPdu::WriteReq { handle, value } => {
+                self.write(*handle, value.as_ref())?;
+
+                responder.send(AttPdu::WriteRsp).unwrap();
+                Ok(())
+            }
 
+            AttPdu::WriteCommand { handle, value } => {
+                // Commands are never answered, not even with an error.
+                if let Err(e) = self.write(*handle, value.as_ref()) {
+                    debug!("ATT Write Command failed: {:?}", e);
+                }
                 Ok(())
             }
 
-            AttPdu::WriteReq { .. } => {
-                // FIXME: ATT Writes are not yet supported, but we pretend they work so that some
-                // applications that only need CCCD writes work (eg. BLE MIDI).
-                warn!("NYI: ATT Write Req");
+            AttPdu::PrepareWriteReq {
+                handle,
+                offset,
+                value,
+            } => {
+                self.prepare_write(*handle, *offset, value.as_ref())?;
 
+                // The value is echoed back so the client can verify it
                 responder
-                    .send_with(|writer| -> Result<(), Error> {
-                        writer.write_u8(Opcode::WriteRsp.into())?;
-                        Ok(())
+                    .send(AttPdu::PrepareWriteRsp {
+                        handle: *handle,
+                        offset: *offset,
+                        value: *value,
                     })
                     .unwrap();
                 Ok(())
             }
 
-            // Responses are always invalid here
+            AttPdu::ExecuteWriteReq { flags } => {
+                match flags {
+                    0x00 => self.clear_prepare_queue(),
+                    0x01 => self.execute_writes()?,
+                    _ => return Err(AttError::new(ErrorCode::InvalidPdu, Handle::NULL)),
+                }
+
+                responder.send(AttPdu::ExecuteWriteRsp).unwrap();
+                Ok(())
+            }
+
+            AttPdu::HandleValueConfirmation => {
+                if self.indication.finish().is_none() {
+                    warn!("unexpected ATT Handle Value Confirmation");
+                }
+
+                // Confirmations are not answered
+                Ok(())
+            }
+
+            // PDUs addressed to the client are handled by `process_message`
             AttPdu::ErrorRsp { .. }
             | AttPdu::ExchangeMtuRsp { .. }
             | AttPdu::FindInformationRsp { .. }
@@ -236,21 +897,10 @@ impl<A: AttributeProvider> AttributeServer<A> {
             | AttPdu::PrepareWriteRsp { .. }
             | AttPdu::ExecuteWriteRsp { .. }
             | AttPdu::HandleValueNotification { .. }
-            | AttPdu::HandleValueIndication { .. } => {
-                Err(AttError::new(ErrorCode::InvalidPdu, Handle::NULL))
-            }
+            | AttPdu::HandleValueIndication { .. } => unreachable!(),
 
             // Unknown (undecoded) or unimplemented requests and commands
-            AttPdu::Unknown { .. }
-            | AttPdu::FindInformationReq { .. }
-            | AttPdu::FindByTypeValueReq { .. }
-            | AttPdu::ReadBlobReq { .. }
-            | AttPdu::ReadMultipleReq { .. }
-            | AttPdu::WriteCommand { .. }
-            | AttPdu::SignedWriteCommand { .. }
-            | AttPdu::PrepareWriteReq { .. }
-            | AttPdu::ExecuteWriteReq { .. }
-            | AttPdu::HandleValueConfirmation { .. } => {
+            AttPdu::Unknown { .. } | AttPdu::SignedWriteCommand { .. } => {
                 if msg.opcode().is_command() {
                     // According to the spec, unknown Command PDUs should be ignored
                     Ok(())
@@ -263,12 +913,35 @@ impl<A: AttributeProvider> AttributeServer<A> {
     }
 }
 
-impl<A: AttributeProvider> ProtocolObj for AttributeServer<A> {
+impl<A: AttributeProvider, H: ClientHandler> ProtocolObj for AttributeServer<A, H> {
     fn process_message(&mut self, message: &[u8], mut responder: Sender<'_>) -> Result<(), Error> {
         let pdu = &AttPdu::from_bytes(&mut ByteReader::new(message))?;
         let opcode = pdu.opcode();
         debug!("ATT<- {:?}", pdu);
 
+        if self.timed_out {
+            // No more ATT PDUs may be sent after a timeout.
+            warn!("ATT timed out, ignoring {:?}", pdu);
+            return Ok(());
+        }
+
+        match pdu {
+            AttPdu::HandleValueNotification { handle, value } => {
+                self.handler.notification(*handle, value.as_ref());
+                return Ok(());
+            }
+            AttPdu::HandleValueIndication { handle, value } => {
+                self.handler.indication(*handle, value.as_ref());
+                return responder.send(AttPdu::HandleValueConfirmation);
+            }
+            _ => {}
+        }
+
+        if let Some(response) = Response::from_pdu(pdu) {
+            self.process_response(pdu, response, responder);
+            return Ok(());
+        }
+
         match self.process_request(pdu, &mut responder) {
             Ok(()) => Ok(()),
             Err(att_error) => {
@@ -284,32 +957,79 @@ impl<A: AttributeProvider> ProtocolObj for AttributeServer<A> {
     }
 }
 
-impl<A: AttributeProvider> Protocol for AttributeServer<A> {
-    // FIXME: Would it be useful to have this as a runtime parameter instead?
+impl<A: AttributeProvider, H: ClientHandler> Protocol for AttributeServer<A, H> {
+    /// The default `ATT_MTU` for LE.
     const RSP_PDU_SIZE: u8 = 23;
+
+    fn rsp_pdu_size(&self) -> u8 {
+        self.att_mtu
+    }
 }
 
 /// An ATT server handle that can send packets and initiate actions.
 ///
 /// This type is needed for any server-initiated procedure, where the server sends out a packet on
 /// its own instead of reacting to a client packet.
-pub struct AttributeServerTx<'a, A: AttributeProvider> {
-    #[allow(unused)]
-    server: &'a mut AttributeServer<A>,
+pub struct AttributeServerTx<'a, A: AttributeProvider, H: ClientHandler = NoClient> {
+    server: &'a mut AttributeServer<A, H>,
 
     sender: Sender<'a>,
 }
 
-impl<'a, A: AttributeProvider> AttributeServerTx<'a, A> {
+impl<'a, A: AttributeProvider, H: ClientHandler> AttributeServerTx<'a, A, H> {
+    /// Notifies the connected client of the value of the characteristic at `handle`.
+    ///
+    /// Notifications are not acknowledged by the client. `value` is truncated to fit in the
+    /// `ATT_MTU`, as described in `notify_raw`.
+    ///
+    /// Returns `Error::InvalidValue` if the client hasn't enabled notifications for `handle` by
+    /// writing the characteristic's CCCD.
+    pub fn notify(self, handle: Handle, value: &[u8]) -> Result<(), Error> {
+        if self.server.timed_out || !self.server.notifications_enabled(handle) {
+            return Err(Error::InvalidValue);
+        }
+
+        self.notify_raw(handle, value);
+        Ok(())
+    }
+
+    /// Indicates the value of the characteristic at `handle` to the connected client.
+    ///
+    /// Unlike notifications, indications have to be confirmed by the client. Until that happens,
+    /// no further indication can be sent (see `AttributeServer::indication_pending`). If the client
+    /// doesn't confirm the indication within 30 seconds, the ATT transaction times out (see
+    /// `AttributeServer::check_timeout`).
+    ///
+    /// `value` is truncated to fit in the `ATT_MTU`, as described in `notify_raw`.
+    ///
+    /// Returns `Error::InvalidValue` if the client hasn't enabled indications for `handle`, or if
+    /// the previous indication hasn't been confirmed yet.
+    pub fn indicate(mut self, handle: Handle, value: &[u8]) -> Result<(), Error> {
+        if self.server.timed_out
+            || self.server.indication_pending()
+            || !self.server.indications_enabled(handle)
+        {
+            return Err(Error::InvalidValue);
+        }
+
+        self.sender.send(AttPdu::HandleValueIndication {
+            handle,
+            value: HexSlice(value),
+        })?;
+        self.server.indication.start(());
+        Ok(())
+    }
+
     /// Sends an attribute value notification to the connected client.
     ///
     /// Notifications are not acknowledged by the client.
     ///
     /// If `value` is too large to be transmitted in a single `ATT_MTU`, it will be truncated to
     /// fit. A client may fetch the rest of the truncated value by using a *Read Blob Request*.
-    /// If this is unwanted, only notify with a `value` of 19 Bytes or less.
+    /// If this is unwanted, only notify with a `value` of `ATT_MTU - 3` Bytes or less (19 Bytes
+    /// unless a larger `ATT_MTU` was negotiated).
     pub fn notify_raw(mut self, handle: Handle, value: &[u8]) {
-        // This cannot fail. The `self` guarantees that there's `RSP_PDU_SIZE` bytes free in
+        // This cannot fail. The `self` guarantees that there's `ATT_MTU` bytes free in
         // `sender`, and is consumed by this method. `AttPdu`s encoder will truncate `value` to fit
         // and doesn't error.
         self.sender
@@ -319,4 +1039,550 @@ impl<'a, A: AttributeProvider> AttributeServerTx<'a, A> {
             })
             .unwrap()
     }
+
+    /// Turns this into a handle for sending a request as the local ATT client.
+    ///
+    /// Also returns the `ClientHandler`, which will receive the response.
+    pub fn client(self) -> (AttributeClientTx<'a>, &'a mut H) {
+        let server = self.server;
+        let client = AttributeClientTx {
+            transaction: &mut server.request,
+            sender: self.sender,
+            att_mtu: server.att_mtu,
+            max_mtu: server.max_mtu,
+            timed_out: server.timed_out,
+        };
+        (client, &mut server.handler)
+    }
+}
+
+/// Returns the value of the CCCD at `handle` (0 if the client hasn't written it).
+fn cccd_value(cccds: &[(Handle, u16)], handle: Handle) -> u16 {
+    cccds
+        .iter()
+        .find(|(cccd, _)| *cccd == handle)
+        .map_or(0, |(_, value)| *value)
+}
+
+/// Returns the value of `attr` as seen by the client.
+///
+/// CCCD values are tracked by the server instead of the `AttributeProvider`, so they're written to
+/// `buf` and returned from there.
+fn attr_value<'a>(
+    cccds: &[(Handle, u16)],
+    attr: &'a Attribute<'_>,
+    buf: &'a mut [u8; 2],
+) -> &'a [u8] {
+    if attr.att_type == CCCD {
+        *buf = cccd_value(cccds, attr.handle).to_le_bytes();
+        buf
+    } else {
+        attr.value.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::att::Attribute;
+    use crate::gatt::MidiServiceAttrs;
+    use crate::l2cap::{BleChannelMap, L2CAPState};
+    use crate::link::queue::{Consume, Consumer, PacketQueue, SimpleQueue};
+    use crate::security::NoSecurity;
+
+    type L2CAP<A = MidiServiceAttrs> = L2CAPState<BleChannelMap<A, NoSecurity>>;
+
+    /// Sends an ATT PDU to the server and returns the response, if any.
+    fn request<A: AttributeProvider>(
+        l2cap: &mut L2CAP<A>,
+        pdu: &[u8],
+    ) -> Option<std::vec::Vec<u8>> {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = queue.split();
+        let mut message = vec![pdu.len() as u8, 0, 0x04, 0];
+        message.extend_from_slice(pdu);
+        assert!(l2cap.tx(&mut tx).process_start(&message).should_consume());
+
+        let mut rsp = None;
+        rx.consume_raw_with(|_, payload| {
+            rsp = Some(payload[4..].to_vec());
+            Consume::always(Ok(()))
+        })
+        .ok();
+        rsp
+    }
+
+    /// Sends a server-initiated PDU via `f` and returns it.
+    fn send(
+        l2cap: &mut L2CAP,
+        f: impl FnOnce(AttributeServerTx<'_, MidiServiceAttrs>) -> Result<(), Error>,
+    ) -> Result<std::vec::Vec<u8>, Error> {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = queue.split();
+        f(l2cap.tx(&mut tx).att().unwrap())?;
+
+        let mut pdu = None;
+        rx.consume_raw_with(|_, payload| {
+            pdu = Some(payload[4..].to_vec());
+            Consume::always(Ok(()))
+        })
+        .unwrap();
+        Ok(pdu.unwrap())
+    }
+
+    #[test]
+    fn notify_and_indicate() {
+        let mut l2cap = L2CAPState::new(BleChannelMap::with_attributes(MidiServiceAttrs::new()));
+        let value = Handle::from_raw(0x0003);
+
+        // Nothing can be sent before the client writes the CCCD.
+        assert_eq!(
+            send(&mut l2cap, |att| att.notify(value, &[1, 2])),
+            Err(Error::InvalidValue)
+        );
+
+        // Enable notifications.
+        assert_eq!(
+            request(&mut l2cap, &[0x12, 0x04, 0x00, 0x01, 0x00]),
+            Some(vec![0x13])
+        );
+        assert_eq!(
+            request(&mut l2cap, &[0x0A, 0x04, 0x00]),
+            Some(vec![0x0B, 0x01, 0x00])
+        );
+        assert_eq!(
+            send(&mut l2cap, |att| att.notify(value, &[1, 2])),
+            Ok(vec![0x1B, 0x03, 0x00, 1, 2])
+        );
+        assert_eq!(
+            send(&mut l2cap, |att| att.indicate(value, &[1, 2])),
+            Err(Error::InvalidValue)
+        );
+
+        // Enable indications. Only one indication can be outstanding.
+        assert_eq!(
+            request(&mut l2cap, &[0x12, 0x04, 0x00, 0x02, 0x00]),
+            Some(vec![0x13])
+        );
+        assert_eq!(
+            send(&mut l2cap, |att| att.indicate(value, &[3])),
+            Ok(vec![0x1D, 0x03, 0x00, 3])
+        );
+        assert!(l2cap.attribute_server().indication_pending());
+        assert_eq!(
+            send(&mut l2cap, |att| att.indicate(value, &[4])),
+            Err(Error::InvalidValue)
+        );
+        assert_eq!(request(&mut l2cap, &[0x1E]), None);
+        assert!(!l2cap.attribute_server().indication_pending());
+
+        // The client doesn't confirm this one in time.
+        send(&mut l2cap, |att| att.indicate(value, &[5])).unwrap();
+        let att = l2cap.attribute_server();
+        att.check_timeout(Instant::from_raw_micros(0));
+        att.check_timeout(Instant::from_raw_micros(29_000_000));
+        assert!(!att.timed_out());
+        att.check_timeout(Instant::from_raw_micros(30_000_000));
+        assert!(att.timed_out());
+        assert_eq!(request(&mut l2cap, &[0x0A, 0x04, 0x00]), None);
+    }
+
+    #[test]
+    fn write() {
+        let mut l2cap = L2CAPState::new(BleChannelMap::with_attributes(MidiServiceAttrs::new()));
+
+        // MIDI Data I/O is writable, its declaration isn't.
+        assert_eq!(
+            request(&mut l2cap, &[0x12, 0x03, 0x00, 0x80]),
+            Some(vec![0x13])
+        );
+        assert_eq!(
+            request(&mut l2cap, &[0x12, 0x02, 0x00, 0x80]),
+            Some(vec![0x01, 0x12, 0x02, 0x00, 0x03])
+        );
+        assert_eq!(
+            request(&mut l2cap, &[0x12, 0x10, 0x00, 0x80]),
+            Some(vec![0x01, 0x12, 0x10, 0x00, 0x01])
+        );
+        assert_eq!(
+            request(&mut l2cap, &[0x12, 0x04, 0x00, 0x01]),
+            Some(vec![0x01, 0x12, 0x04, 0x00, 0x0D])
+        );
+
+        // Write Commands are never answered.
+        assert_eq!(request(&mut l2cap, &[0x52, 0x03, 0x00, 0x80]), None);
+        assert_eq!(request(&mut l2cap, &[0x52, 0x02, 0x00, 0x80]), None);
+    }
+
+    #[test]
+    fn queued_writes() {
+        let mut l2cap = L2CAPState::new(BleChannelMap::with_attributes(MidiServiceAttrs::new()));
+
+        // Write the CCCD in two parts.
+        assert_eq!(
+            request(&mut l2cap, &[0x16, 0x04, 0x00, 0x00, 0x00, 0x01]),
+            Some(vec![0x17, 0x04, 0x00, 0x00, 0x00, 0x01])
+        );
+        assert_eq!(
+            request(&mut l2cap, &[0x16, 0x04, 0x00, 0x01, 0x00, 0x00]),
+            Some(vec![0x17, 0x04, 0x00, 0x01, 0x00, 0x00])
+        );
+        assert!(!l2cap
+            .attribute_server()
+            .notifications_enabled(Handle::from_raw(0x0003)));
+        assert_eq!(request(&mut l2cap, &[0x18, 0x01]), Some(vec![0x19]));
+        assert!(l2cap
+            .attribute_server()
+            .notifications_enabled(Handle::from_raw(0x0003)));
+
+        // Parts with a gap are rejected, and nothing is written.
+        request(&mut l2cap, &[0x16, 0x04, 0x00, 0x00, 0x00, 0x00]);
+        request(&mut l2cap, &[0x16, 0x03, 0x00, 0x00, 0x00, 0x80]);
+        request(&mut l2cap, &[0x16, 0x04, 0x00, 0x02, 0x00, 0x00]);
+        assert_eq!(
+            request(&mut l2cap, &[0x18, 0x01]),
+            Some(vec![0x01, 0x18, 0x04, 0x00, 0x07])
+        );
+        assert!(l2cap
+            .attribute_server()
+            .notifications_enabled(Handle::from_raw(0x0003)));
+
+        // The queue was cleared by the failed execution, so this is a no-op.
+        assert_eq!(request(&mut l2cap, &[0x18, 0x01]), Some(vec![0x19]));
+
+        // Cancelling discards the queue.
+        request(&mut l2cap, &[0x16, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(request(&mut l2cap, &[0x18, 0x00]), Some(vec![0x19]));
+        assert_eq!(request(&mut l2cap, &[0x18, 0x01]), Some(vec![0x19]));
+        assert!(l2cap
+            .attribute_server()
+            .notifications_enabled(Handle::from_raw(0x0003)));
+
+        // Invalid flags and handles are rejected.
+        assert_eq!(
+            request(&mut l2cap, &[0x18, 0x02]),
+            Some(vec![0x01, 0x18, 0x00, 0x00, 0x04])
+        );
+        assert_eq!(
+            request(&mut l2cap, &[0x16, 0x10, 0x00, 0x00, 0x00, 0x00]),
+            Some(vec![0x01, 0x16, 0x10, 0x00, 0x01])
+        );
+
+        // The queue holds a limited number of parts.
+        for i in 0..16 {
+            assert_eq!(
+                request(&mut l2cap, &[0x16, 0x03, 0x00, i, 0x00, 0x80])
+                    .unwrap()
+                    .first(),
+                Some(&0x17)
+            );
+        }
+        assert_eq!(
+            request(&mut l2cap, &[0x16, 0x03, 0x00, 16, 0x00, 0x80]),
+            Some(vec![0x01, 0x16, 0x03, 0x00, 0x09])
+        );
+        assert_eq!(request(&mut l2cap, &[0x18, 0x01]), Some(vec![0x19]));
+    }
+
+    /// A writable 2-Byte attribute, followed by a read-only one.
+    struct WritableValue([u8; 2]);
+
+    impl AttributeProvider for WritableValue {
+        fn for_attrs_in_range(
+            &self,
+            range: HandleRange,
+            mut f: impl FnMut(&Self, Attribute<'_>) -> Result<(), Error>,
+        ) -> Result<(), Error> {
+            let attrs = [
+                Attribute::new(Uuid16(0x2A19).into(), Handle::from_raw(0x0001), &self.0),
+                Attribute::new(Uuid16(0x2A00).into(), Handle::from_raw(0x0002), b"ro"),
+            ];
+            for attr in attrs.iter().filter(|attr| range.contains(attr.handle)) {
+                f(
+                    self,
+                    Attribute::new(attr.att_type, attr.handle, attr.value()),
+                )?;
+            }
+            Ok(())
+        }
+
+        fn is_grouping_attr(&self, _uuid: AttUuid) -> bool {
+            false
+        }
+
+        fn group_end(&self, _handle: Handle) -> Option<Handle> {
+            None
+        }
+
+        fn write_attr(&mut self, handle: Handle, value: &[u8]) -> Result<(), AttError> {
+            self.check_write(handle, value)?;
+            self.0.copy_from_slice(value);
+            Ok(())
+        }
+
+        fn check_write(&self, handle: Handle, value: &[u8]) -> Result<(), AttError> {
+            if handle != Handle::from_raw(0x0001) {
+                Err(AttError::new(ErrorCode::WriteNotPermitted, handle))
+            } else if value.len() != 2 {
+                Err(AttError::new(
+                    ErrorCode::InvalidAttributeValueLength,
+                    handle,
+                ))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn queued_writes_are_atomic() {
+        let mut l2cap = L2CAPState::new(BleChannelMap::with_attributes(WritableValue([0; 2])));
+
+        // The second attribute is read-only, so the first one isn't written either.
+        request(&mut l2cap, &[0x16, 0x01, 0x00, 0x00, 0x00, 0x01, 0x02]);
+        request(&mut l2cap, &[0x16, 0x02, 0x00, 0x00, 0x00, 0x80]);
+        assert_eq!(
+            request(&mut l2cap, &[0x18, 0x01]),
+            Some(vec![0x01, 0x18, 0x02, 0x00, 0x03])
+        );
+        assert_eq!(l2cap.attribute_server().provider().0, [0, 0]);
+
+        request(&mut l2cap, &[0x16, 0x01, 0x00, 0x00, 0x00, 0x01, 0x02]);
+        assert_eq!(request(&mut l2cap, &[0x18, 0x01]), Some(vec![0x19]));
+        assert_eq!(l2cap.attribute_server().provider().0, [1, 2]);
+    }
+
+    #[test]
+    fn queued_write_at_offset() {
+        let mut l2cap = L2CAPState::new(BleChannelMap::with_attributes(WritableValue([1, 2])));
+
+        // The current value is kept up to the offset of the first part.
+        assert_eq!(
+            request(&mut l2cap, &[0x16, 0x01, 0x00, 0x01, 0x00, 0x05]),
+            Some(vec![0x17, 0x01, 0x00, 0x01, 0x00, 0x05])
+        );
+        assert_eq!(request(&mut l2cap, &[0x18, 0x01]), Some(vec![0x19]));
+        assert_eq!(l2cap.attribute_server().provider().0, [1, 5]);
+
+        // Offsets past the end of the current value are rejected.
+        request(&mut l2cap, &[0x16, 0x01, 0x00, 0x03, 0x00, 0x05]);
+        assert_eq!(
+            request(&mut l2cap, &[0x18, 0x01]),
+            Some(vec![0x01, 0x18, 0x01, 0x00, 0x07])
+        );
+        assert_eq!(l2cap.attribute_server().provider().0, [1, 5]);
+    }
+
+    #[test]
+    fn find_information() {
+        let mut l2cap = L2CAPState::new(BleChannelMap::with_attributes(MidiServiceAttrs::new()));
+
+        // The list ends before the first attribute with a 128-bit UUID.
+        assert_eq!(
+            request(&mut l2cap, &[0x04, 0x01, 0x00, 0xFF, 0xFF]),
+            Some(vec![
+                0x05, 0x01, 0x01, 0x00, 0x00, 0x28, 0x02, 0x00, 0x03, 0x28
+            ])
+        );
+
+        let rsp = request(&mut l2cap, &[0x04, 0x03, 0x00, 0xFF, 0xFF]).unwrap();
+        assert_eq!(rsp[..4], [0x05, 0x02, 0x03, 0x00]);
+        assert_eq!(rsp.len(), 4 + 16);
+
+        assert_eq!(
+            request(&mut l2cap, &[0x04, 0x04, 0x00, 0xFF, 0xFF]),
+            Some(vec![0x05, 0x01, 0x04, 0x00, 0x02, 0x29])
+        );
+        assert_eq!(
+            request(&mut l2cap, &[0x04, 0x10, 0x00, 0xFF, 0xFF]),
+            Some(vec![0x01, 0x04, 0x10, 0x00, 0x0A])
+        );
+        assert_eq!(
+            request(&mut l2cap, &[0x04, 0x00, 0x00, 0xFF, 0xFF]),
+            Some(vec![0x01, 0x04, 0x00, 0x00, 0x01])
+        );
+    }
+
+    #[test]
+    fn find_by_type_value() {
+        let mut l2cap = L2CAPState::new(BleChannelMap::with_attributes(MidiServiceAttrs::new()));
+
+        let mut req = vec![0x06, 0x01, 0x00, 0xFF, 0xFF, 0x00, 0x28];
+        req.extend_from_slice(&[
+            0x00, 0xC7, 0xC4, 0x4E, 0xE3, 0x6C, 0x51, 0xA7, 0x33, 0x4B, 0xE8, 0xED, 0x5A, 0x0E,
+            0xB8, 0x03,
+        ]);
+        assert_eq!(
+            request(&mut l2cap, &req),
+            Some(vec![0x07, 0x01, 0x00, 0x04, 0x00])
+        );
+
+        // Non-grouping attributes are their own group.
+        assert_eq!(
+            request(
+                &mut l2cap,
+                &[0x06, 0x01, 0x00, 0xFF, 0xFF, 0x02, 0x29, 0x00, 0x00]
+            ),
+            Some(vec![0x07, 0x04, 0x00, 0x04, 0x00])
+        );
+
+        // Mismatching value or handle range.
+        assert_eq!(
+            request(
+                &mut l2cap,
+                &[0x06, 0x01, 0x00, 0xFF, 0xFF, 0x02, 0x29, 0x01, 0x00]
+            ),
+            Some(vec![0x01, 0x06, 0x01, 0x00, 0x0A])
+        );
+        assert_eq!(
+            request(
+                &mut l2cap,
+                &[0x06, 0x01, 0x00, 0x03, 0x00, 0x02, 0x29, 0x00, 0x00]
+            ),
+            Some(vec![0x01, 0x06, 0x01, 0x00, 0x0A])
+        );
+
+        // CCCD values are the ones written by the client.
+        assert_eq!(
+            request(&mut l2cap, &[0x12, 0x04, 0x00, 0x01, 0x00]),
+            Some(vec![0x13])
+        );
+        assert_eq!(
+            request(
+                &mut l2cap,
+                &[0x06, 0x01, 0x00, 0xFF, 0xFF, 0x02, 0x29, 0x01, 0x00]
+            ),
+            Some(vec![0x07, 0x04, 0x00, 0x04, 0x00])
+        );
+    }
+
+    #[test]
+    fn read_cccd_by_type() {
+        let mut l2cap = L2CAPState::new(BleChannelMap::with_attributes(MidiServiceAttrs::new()));
+        let req = [0x08, 0x01, 0x00, 0xFF, 0xFF, 0x02, 0x29];
+        assert_eq!(
+            request(&mut l2cap, &req),
+            Some(vec![0x09, 0x04, 0x04, 0x00, 0x00, 0x00])
+        );
+
+        assert_eq!(
+            request(&mut l2cap, &[0x12, 0x04, 0x00, 0x02, 0x00]),
+            Some(vec![0x13])
+        );
+        assert_eq!(
+            request(&mut l2cap, &req),
+            Some(vec![0x09, 0x04, 0x04, 0x00, 0x02, 0x00])
+        );
+    }
+
+    /// A single attribute with a value that doesn't fit in a *Read Response*.
+    struct LongValue;
+
+    impl AttributeProvider for LongValue {
+        fn for_attrs_in_range(
+            &self,
+            range: HandleRange,
+            mut f: impl FnMut(&Self, Attribute<'_>) -> Result<(), Error>,
+        ) -> Result<(), Error> {
+            let attr = Attribute::new(
+                Uuid16(0x2A00).into(),
+                Handle::from_raw(0x0001),
+                b"0123456789abcdefghijklmnop",
+            );
+            if range.contains(attr.handle) {
+                f(self, attr)?;
+            }
+            Ok(())
+        }
+
+        fn is_grouping_attr(&self, _uuid: AttUuid) -> bool {
+            false
+        }
+
+        fn group_end(&self, _handle: Handle) -> Option<Handle> {
+            None
+        }
+    }
+
+    #[test]
+    fn read_long() {
+        let mut l2cap = L2CAPState::new(BleChannelMap::with_attributes(LongValue));
+
+        let rsp = request(&mut l2cap, &[0x0A, 0x01, 0x00]).unwrap();
+        assert_eq!(rsp[0], 0x0B);
+        assert_eq!(rsp[1..], b"0123456789abcdefghijkl"[..]);
+
+        assert_eq!(
+            request(&mut l2cap, &[0x0C, 0x01, 0x00, 22, 0x00]),
+            Some(vec![0x0D, b'm', b'n', b'o', b'p'])
+        );
+        assert_eq!(
+            request(&mut l2cap, &[0x0C, 0x01, 0x00, 26, 0x00]),
+            Some(vec![0x0D])
+        );
+        assert_eq!(
+            request(&mut l2cap, &[0x0C, 0x01, 0x00, 27, 0x00]),
+            Some(vec![0x01, 0x0C, 0x01, 0x00, 0x07])
+        );
+        assert_eq!(
+            request(&mut l2cap, &[0x0C, 0x02, 0x00, 0x00, 0x00]),
+            Some(vec![0x01, 0x0C, 0x02, 0x00, 0x01])
+        );
+        assert_eq!(
+            request(&mut l2cap, &[0x0A, 0x02, 0x00]),
+            Some(vec![0x01, 0x0A, 0x02, 0x00, 0x01])
+        );
+
+        // Short values can't be read with *Read Blob Requests*.
+        let mut l2cap = L2CAPState::new(BleChannelMap::with_attributes(MidiServiceAttrs::new()));
+        assert_eq!(
+            request(&mut l2cap, &[0x0C, 0x04, 0x00, 0x00, 0x00]),
+            Some(vec![0x01, 0x0C, 0x04, 0x00, 0x0B])
+        );
+    }
+
+    #[test]
+    fn null_handle() {
+        let mut l2cap = L2CAPState::new(BleChannelMap::with_attributes(MidiServiceAttrs::new()));
+
+        // Read, Read Blob, Read Multiple, Write and Prepare Write Requests for handle 0x0000
+        let requests: [&[u8]; 5] = [
+            &[0x0A, 0x00, 0x00],
+            &[0x0C, 0x00, 0x00, 0x00, 0x00],
+            &[0x0E, 0x00, 0x00, 0x03, 0x00],
+            &[0x12, 0x00, 0x00, 0x01, 0x00],
+            &[0x16, 0x00, 0x00, 0x00, 0x00, 0x01],
+        ];
+        for pdu in &requests {
+            assert_eq!(
+                request(&mut l2cap, pdu),
+                Some(vec![0x01, pdu[0], 0x00, 0x00, 0x01])
+            );
+        }
+    }
+
+    #[test]
+    fn read_multiple() {
+        let mut l2cap = L2CAPState::new(BleChannelMap::with_attributes(MidiServiceAttrs::new()));
+
+        // Empty MIDI data, followed by the CCCD value.
+        assert_eq!(
+            request(&mut l2cap, &[0x0E, 0x03, 0x00, 0x04, 0x00]),
+            Some(vec![0x0F, 0x00, 0x00])
+        );
+
+        // The response is truncated to `ATT_MTU - 1` Bytes.
+        let rsp = request(&mut l2cap, &[0x0E, 0x01, 0x00, 0x02, 0x00]).unwrap();
+        assert_eq!(rsp.len(), 23);
+        assert_eq!(rsp[..3], [0x0F, 0x00, 0xC7]);
+
+        assert_eq!(
+            request(&mut l2cap, &[0x0E, 0x03, 0x00, 0x10, 0x00]),
+            Some(vec![0x01, 0x0E, 0x10, 0x00, 0x01])
+        );
+        assert_eq!(
+            request(&mut l2cap, &[0x0E, 0x03, 0x00]),
+            Some(vec![0x01, 0x0E, 0x00, 0x00, 0x04])
+        );
+    }
 }
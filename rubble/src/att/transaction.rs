@@ -0,0 +1,70 @@
+use crate::time::{Duration, Instant};
+
+/// Tracks an outstanding ATT transaction and its 30 second timeout.
+///
+/// A transaction is started when a request or indication is sent, and finished when the peer
+/// responds to it. Only one transaction can be outstanding at a time.
+///
+/// Since sending a PDU doesn't have access to the current time, the timer is only started by the
+/// first call to `check_timeout` after the transaction was started.
+#[derive(Debug)]
+pub(super) struct Transaction<T> {
+    /// Data about the outstanding transaction, if any.
+    pending: Option<T>,
+
+    /// Time at which the pending transaction was started.
+    timer_start: Option<Instant>,
+
+    /// Whether a transaction was started since the last call to `check_timeout`.
+    timer_restart: bool,
+}
+
+impl<T: Copy> Transaction<T> {
+    pub fn new() -> Self {
+        Self {
+            pending: None,
+            timer_start: None,
+            timer_restart: false,
+        }
+    }
+
+    /// Returns the outstanding transaction's data, or `None` if there's none.
+    pub fn pending(&self) -> Option<T> {
+        self.pending
+    }
+
+    /// Starts a new transaction.
+    pub fn start(&mut self, data: T) {
+        self.pending = Some(data);
+        self.timer_restart = true;
+    }
+
+    /// Finishes the outstanding transaction and returns its data.
+    pub fn finish(&mut self) -> Option<T> {
+        self.pending.take()
+    }
+
+    /// Checks whether the outstanding transaction has timed out.
+    ///
+    /// Returns `true` once when the timeout expires, which also finishes the transaction.
+    pub fn check_timeout(&mut self, now: Instant) -> bool {
+        if self.pending.is_none() {
+            self.timer_start = None;
+            self.timer_restart = false;
+            return false;
+        }
+
+        if self.timer_restart {
+            self.timer_restart = false;
+            self.timer_start = Some(now);
+        }
+
+        match self.timer_start {
+            Some(start) if now.duration_since(start) >= Duration::from_secs(30) => {
+                self.pending = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
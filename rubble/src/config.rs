@@ -1,7 +1,8 @@
 //! Stack configuration trait.
 
-use crate::link::{queue::PacketQueue, Transmitter};
-use crate::{l2cap::ChannelMapper, time::Timer};
+use crate::link::{pcap::PcapSink, queue::PacketQueue, Transmitter};
+use crate::{crypto::AesProvider, l2cap::ChannelMapper, time::Timer};
+use rand_core::{CryptoRng, RngCore};
 
 // TODO: Use associated type defaults in the trait once stable
 // https://github.com/rust-lang/rust/issues/29661
@@ -27,6 +28,22 @@ pub trait Config {
     /// The packet queue to use for exchanging data between the real-time Link-Layer and
     /// non-realtime parts of the stack.
     type PacketQueue: PacketQueue;
+
+    /// The AES-128 implementation used to encrypt connections.
+    ///
+    /// `crypto::SoftAesProvider` can be used if the hardware has no AES accelerator.
+    type AesProvider: AesProvider;
+
+    /// A cryptographically secure random number generator.
+    ///
+    /// Used by the Link-Layer to generate its contribution to the session key of encrypted
+    /// connections, and to seed the (non-cryptographic) PRNGs used for advertising.
+    type Rng: RngCore + CryptoRng;
+
+    /// The sink packets are written to when `LinkLayer::start_capture` is used.
+    ///
+    /// `link::pcap::NoCapture` can be used if packets are never captured.
+    type PcapSink: PcapSink;
 }
 
 // Helper aliases to make accessing producer/consumer more convenient.
@@ -0,0 +1,370 @@
+//! AES-128 and AES-CCM primitives used by BLE.
+//!
+//! BLE uses AES-128 in several places: The Link-Layer encrypts data channel PDUs with AES-CCM once
+//! a connection is encrypted, and the Security Manager uses AES-128 to derive keys during pairing.
+//!
+//! Many BLE chips come with hardware accelerators for these operations, and using them might be
+//! the only way to encrypt packets quickly enough to meet the Link-Layer's timing requirements.
+//! This module provides the [`AesProvider`] trait for plugging in such implementations. Rubble
+//! comes with [`SoftAesProvider`], a portable software implementation using the [`aes`] crate.
+//!
+//! [`AesProvider`]: trait.AesProvider.html
+//! [`SoftAesProvider`]: struct.SoftAesProvider.html
+//! [`aes`]: https://docs.rs/aes
+
+use crate::Error;
+use aes::{Aes128, BlockCipher, NewBlockCipher};
+use core::fmt;
+
+/// Size of the Message Integrity Check (MIC) appended to encrypted data channel PDUs.
+pub const MIC_SIZE: usize = 4;
+
+/// A 128-bit key.
+///
+/// Like all other multi-byte values in BLE, keys are stored with the least significant octet
+/// first. This is the order in which keys are transmitted over the air (eg. by the Security
+/// Manager).
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Key(pub [u8; 16]);
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't leak key material into logs.
+        f.write_str("Key(..)")
+    }
+}
+
+/// The CCM nonce used to encrypt and authenticate a single data channel PDU.
+#[derive(Debug, Copy, Clone)]
+pub struct Nonce {
+    packet_counter: u64,
+    master_to_slave: bool,
+    iv: [u8; 8],
+}
+
+impl Nonce {
+    /// Creates a nonce from its components.
+    ///
+    /// # Parameters
+    ///
+    /// * **`packet_counter`**: The 39-bit packet counter of the sending device.
+    /// * **`master_to_slave`**: `true` if the PDU is sent by the master, `false` if it is sent by
+    ///   the slave.
+    /// * **`iv`**: The initialization vector (`IV`) of the connection, formed by concatenating
+    ///   `IVm` and `IVs`.
+    pub fn new(packet_counter: u64, master_to_slave: bool, iv: [u8; 8]) -> Self {
+        assert!(packet_counter < 1 << 39, "packet counter overflow");
+        Self {
+            packet_counter,
+            master_to_slave,
+            iv,
+        }
+    }
+
+    /// Returns the 39-bit packet counter.
+    pub fn packet_counter(&self) -> u64 {
+        self.packet_counter
+    }
+
+    /// Returns whether the PDU is sent from master to slave.
+    pub fn master_to_slave(&self) -> bool {
+        self.master_to_slave
+    }
+
+    /// Returns the connection's initialization vector.
+    pub fn iv(&self) -> [u8; 8] {
+        self.iv
+    }
+
+    /// Returns the 13-octet encoding of the nonce used by CCM.
+    pub fn to_raw(&self) -> [u8; 13] {
+        let mut raw = [0; 13];
+        raw[..5].copy_from_slice(&self.packet_counter.to_le_bytes()[..5]);
+        if self.master_to_slave {
+            raw[4] |= 0x80;
+        }
+        raw[5..].copy_from_slice(&self.iv);
+        raw
+    }
+}
+
+/// Trait for AES-128 implementations.
+///
+/// Only `aes128_encrypt` has to be implemented. The CCM methods have default implementations in
+/// terms of `aes128_encrypt`, but may be overridden to make use of a hardware CCM engine.
+pub trait AesProvider {
+    /// Encrypts a single 128-bit block using AES-128.
+    ///
+    /// Both `key` and `block` are in the byte order used by FIPS-197 (most significant octet
+    /// first), which is the reverse of the order used by BLE.
+    fn aes128_encrypt(&mut self, key: &[u8; 16], block: &mut [u8; 16]);
+
+    /// Encrypts a data channel PDU payload in place and returns its MIC.
+    ///
+    /// This uses AES-CCM as specified for the Link-Layer, with a 4-octet MIC and the first octet of
+    /// the data channel PDU header as additional authenticated data.
+    ///
+    /// # Parameters
+    ///
+    /// * **`key`**: The session key (SK).
+    /// * **`nonce`**: The CCM nonce for this PDU.
+    /// * **`header`**: The first octet of the data channel PDU header. The `NESN`, `SN` and `MD`
+    ///   bits are ignored.
+    /// * **`payload`**: The plaintext payload, which will be replaced by the ciphertext.
+    fn ccm_encrypt(
+        &mut self,
+        key: &Key,
+        nonce: &Nonce,
+        header: u8,
+        payload: &mut [u8],
+    ) -> [u8; MIC_SIZE] {
+        let key = reversed(&key.0);
+        let nonce = nonce.to_raw();
+        let tag = cbc_mac(self, &key, &nonce, header, payload);
+        ctr(self, &key, &nonce, payload);
+        mic(self, &key, &nonce, &tag)
+    }
+
+    /// Decrypts a data channel PDU payload in place and checks its MIC.
+    ///
+    /// The parameters are the same as for `ccm_encrypt`, except that `payload` contains the
+    /// ciphertext (not including the MIC), which will be replaced by the plaintext.
+    ///
+    /// Returns `Error::InvalidValue` if `mic` doesn't match the decrypted payload. In that case,
+    /// the contents of `payload` are unspecified.
+    fn ccm_decrypt(
+        &mut self,
+        key: &Key,
+        nonce: &Nonce,
+        header: u8,
+        payload: &mut [u8],
+        mic: &[u8; MIC_SIZE],
+    ) -> Result<(), Error> {
+        let key = reversed(&key.0);
+        let nonce = nonce.to_raw();
+        ctr(self, &key, &nonce, payload);
+        let tag = cbc_mac(self, &key, &nonce, header, payload);
+        let expected = self::mic(self, &key, &nonce, &tag);
+
+        // Compare without short-circuiting.
+        let diff = expected
+            .iter()
+            .zip(mic)
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+        if diff == 0 {
+            Ok(())
+        } else {
+            Err(Error::InvalidValue)
+        }
+    }
+}
+
+/// The security function `e` defined by the Bluetooth specification.
+///
+/// Encrypts `plaintext` with AES-128 using `key`. Unlike `AesProvider::aes128_encrypt`, this uses
+/// the byte order used by BLE (least significant octet first) for all values.
+pub fn e<P: AesProvider + ?Sized>(aes: &mut P, key: &Key, plaintext: [u8; 16]) -> [u8; 16] {
+    let mut block = reversed(&plaintext);
+    aes.aes128_encrypt(&reversed(&key.0), &mut block);
+    reversed(&block)
+}
+
+/// Computes the CBC-MAC over the CCM blocks `B0`, `B1` and the payload.
+fn cbc_mac<P: AesProvider + ?Sized>(
+    aes: &mut P,
+    key: &[u8; 16],
+    nonce: &[u8; 13],
+    header: u8,
+    payload: &[u8],
+) -> [u8; 16] {
+    // B0: Flags (Adata, M = 4, L = 2), nonce, payload length.
+    let mut x = [0; 16];
+    x[0] = 0x49;
+    x[1..14].copy_from_slice(nonce);
+    x[14..].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+    aes.aes128_encrypt(key, &mut x);
+
+    // B1: Length of additional data (1), and the masked header octet.
+    x[1] ^= 0x01;
+    x[2] ^= header & 0b1110_0011;
+    aes.aes128_encrypt(key, &mut x);
+
+    for chunk in payload.chunks(16) {
+        for (x, b) in x.iter_mut().zip(chunk) {
+            *x ^= b;
+        }
+        aes.aes128_encrypt(key, &mut x);
+    }
+
+    x
+}
+
+/// Applies the CCM keystream (blocks `A1`, `A2`, ...) to `payload`.
+fn ctr<P: AesProvider + ?Sized>(aes: &mut P, key: &[u8; 16], nonce: &[u8; 13], payload: &mut [u8]) {
+    for (i, chunk) in payload.chunks_mut(16).enumerate() {
+        let s = keystream_block(aes, key, nonce, i as u16 + 1);
+        for (b, s) in chunk.iter_mut().zip(&s) {
+            *b ^= s;
+        }
+    }
+}
+
+/// Encrypts the CBC-MAC `tag` with keystream block `A0`, yielding the MIC.
+fn mic<P: AesProvider + ?Sized>(
+    aes: &mut P,
+    key: &[u8; 16],
+    nonce: &[u8; 13],
+    tag: &[u8; 16],
+) -> [u8; MIC_SIZE] {
+    let s0 = keystream_block(aes, key, nonce, 0);
+    let mut mic = [0; MIC_SIZE];
+    for (i, m) in mic.iter_mut().enumerate() {
+        *m = tag[i] ^ s0[i];
+    }
+    mic
+}
+
+fn keystream_block<P: AesProvider + ?Sized>(
+    aes: &mut P,
+    key: &[u8; 16],
+    nonce: &[u8; 13],
+    counter: u16,
+) -> [u8; 16] {
+    let mut a = [0; 16];
+    a[0] = 0x01;
+    a[1..14].copy_from_slice(nonce);
+    a[14..].copy_from_slice(&counter.to_be_bytes());
+    aes.aes128_encrypt(key, &mut a);
+    a
+}
+
+fn reversed(bytes: &[u8; 16]) -> [u8; 16] {
+    let mut out = *bytes;
+    out.reverse();
+    out
+}
+
+/// A portable software implementation of `AesProvider`.
+///
+/// This is backed by the [`aes`] crate. Note that software encryption might be too slow to meet
+/// the Link-Layer's timing requirements on slower microcontrollers.
+///
+/// [`aes`]: https://docs.rs/aes
+#[derive(Debug)]
+pub struct SoftAesProvider {
+    _p: (),
+}
+
+impl SoftAesProvider {
+    /// Creates a new software AES provider.
+    pub fn new() -> Self {
+        Self { _p: () }
+    }
+}
+
+impl Default for SoftAesProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AesProvider for SoftAesProvider {
+    fn aes128_encrypt(&mut self, key: &[u8; 16], block: &mut [u8; 16]) {
+        let cipher = Aes128::new(&(*key).into());
+        let mut buf = (*block).into();
+        cipher.encrypt_block(&mut buf);
+        block.copy_from_slice(&buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks the block cipher against the FIPS-197 example vector.
+    #[test]
+    fn aes128() {
+        let key = [
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f,
+        ];
+        let mut block = [
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+            0xee, 0xff,
+        ];
+        SoftAesProvider::new().aes128_encrypt(&key, &mut block);
+        assert_eq!(
+            block,
+            [
+                0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4,
+                0xc5, 0x5a
+            ]
+        );
+    }
+
+    #[test]
+    fn ccm_roundtrip() {
+        let mut aes = SoftAesProvider::new();
+        let key = Key([0x42; 16]);
+        let nonce = Nonce::new(1, true, [1, 2, 3, 4, 5, 6, 7, 8]);
+        let plaintext = *b"hello world, this is a test payload!";
+
+        let mut payload = plaintext;
+        let mic = aes.ccm_encrypt(&key, &nonce, 0x0E, &mut payload);
+        assert_ne!(payload, plaintext);
+
+        // NESN, SN and MD are not authenticated.
+        aes.ccm_decrypt(&key, &nonce, 0x12, &mut payload, &mic)
+            .unwrap();
+        assert_eq!(payload, plaintext);
+
+        // Any other change to the header, the nonce, or the payload is detected.
+        let mut payload2 = payload;
+        let mic = aes.ccm_encrypt(&key, &nonce, 0x02, &mut payload2);
+        let mut tampered = payload2;
+        assert!(aes
+            .ccm_decrypt(&key, &nonce, 0x03, &mut tampered, &mic)
+            .is_err());
+        let mut tampered = payload2;
+        let other = Nonce::new(1, false, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(aes
+            .ccm_decrypt(&key, &other, 0x02, &mut tampered, &mic)
+            .is_err());
+        let mut tampered = payload2;
+        tampered[20] ^= 1;
+        assert!(aes
+            .ccm_decrypt(&key, &nonce, 0x02, &mut tampered, &mic)
+            .is_err());
+    }
+
+    /// Checks key derivation and encryption against the sample data in the specification (Vol 6,
+    /// Part C, Section 1).
+    #[test]
+    fn spec_sample_data() {
+        let mut aes = SoftAesProvider::new();
+        let ltk = Key([
+            0xbf, 0x01, 0xfb, 0x9d, 0x4e, 0xf3, 0xbc, 0x36, 0xd8, 0x74, 0xf5, 0x39, 0x41, 0x38,
+            0x68, 0x4c,
+        ]);
+        let skd = [
+            0x13, 0x02, 0xf1, 0xe0, 0xdf, 0xce, 0xbd, 0xac, 0x79, 0x68, 0x57, 0x46, 0x35, 0x24,
+            0x13, 0x02,
+        ];
+        let sk = Key(e(&mut aes, &ltk, skd));
+        assert_eq!(
+            sk.0,
+            [
+                0x66, 0xc6, 0xc2, 0x27, 0x8e, 0x3b, 0x8e, 0x05, 0x3e, 0x7e, 0xa3, 0x26, 0x52, 0x1b,
+                0xad, 0x99
+            ]
+        );
+
+        // LL_START_ENC_RSP sent by the master.
+        let iv = [0x24, 0xab, 0xdc, 0xba, 0xbe, 0xba, 0xaf, 0xde];
+        let nonce = Nonce::new(0, true, iv);
+        let mut payload = [0x06];
+        let mic = aes.ccm_encrypt(&sk, &nonce, 0x0F, &mut payload);
+        assert_eq!(payload, [0x9f]);
+        assert_eq!(mic, [0xcd, 0xa7, 0xf4, 0x48]);
+    }
+}
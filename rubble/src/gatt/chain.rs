@@ -0,0 +1,234 @@
+//! Combining several `AttributeProvider`s.
+
+use super::{last_handle, CHARACTERISTIC, INCLUDE};
+use crate::att::{AttError, AttUuid, Attribute, AttributeProvider, Handle, HandleRange};
+use crate::{utils::HexSlice, Error};
+
+/// An `AttributeProvider` hosting the attributes of 2 other providers.
+///
+/// The attributes of `second` are placed after the last attribute of `first`: The handles of
+/// `first` are unchanged, while all handles of `second` are offset by the last handle of `first`.
+/// This includes the handles contained in the characteristic and include declarations of
+/// `second`. `second_handle` can be used to find the new handle of an attribute of `second` (eg.
+/// to send notifications).
+///
+/// More than 2 providers can be combined by nesting `Chain`s.
+pub struct Chain<A: AttributeProvider, B: AttributeProvider> {
+    first: A,
+    second: B,
+
+    /// Offset added to the handles of `second`.
+    offset: u16,
+}
+
+impl<A: AttributeProvider, B: AttributeProvider> Chain<A, B> {
+    /// Combines the attributes of `first` and `second`.
+    ///
+    /// The attribute handles used by both providers must not change afterwards.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the attributes of `second` don't fit after the ones of `first`.
+    pub fn new(first: A, second: B) -> Self {
+        let offset = last_handle(&first);
+        assert!(
+            u32::from(offset) + u32::from(last_handle(&second)) <= 0xFFFF,
+            "handle space exhausted"
+        );
+
+        Self {
+            first,
+            second,
+            offset,
+        }
+    }
+
+    /// Returns a reference to the first `AttributeProvider`.
+    pub fn first(&self) -> &A {
+        &self.first
+    }
+
+    /// Returns a mutable reference to the first `AttributeProvider`.
+    pub fn first_mut(&mut self) -> &mut A {
+        &mut self.first
+    }
+
+    /// Returns a reference to the second `AttributeProvider`.
+    pub fn second(&self) -> &B {
+        &self.second
+    }
+
+    /// Returns a mutable reference to the second `AttributeProvider`.
+    pub fn second_mut(&mut self) -> &mut B {
+        &mut self.second
+    }
+
+    /// Translates the handle of an attribute of the second provider to the handle used by the
+    /// `Chain`.
+    pub fn second_handle(&self, handle: Handle) -> Handle {
+        Handle::from_raw(handle.as_u16() + self.offset)
+    }
+}
+
+impl<A: AttributeProvider, B: AttributeProvider> AttributeProvider for Chain<A, B> {
+    fn for_attrs_in_range(
+        &self,
+        range: HandleRange,
+        mut f: impl FnMut(&Self, Attribute<'_>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let (start, end) = (range.start().as_u16(), range.end().as_u16());
+        self.first
+            .for_attrs_in_range(range, |_, attr| f(self, attr))?;
+
+        if end <= self.offset {
+            return Ok(());
+        }
+        let start = if start > self.offset {
+            start - self.offset
+        } else {
+            1
+        };
+        let range = HandleRange::new(Handle::from_raw(start), Handle::from_raw(end - self.offset));
+        self.second.for_attrs_in_range(range, |_, attr| {
+            let mut buf = [0; 19];
+            let value = offset_handles(attr.att_type, attr.value(), self.offset, &mut buf);
+            f(
+                self,
+                Attribute {
+                    att_type: attr.att_type,
+                    handle: self.second_handle(attr.handle),
+                    value: HexSlice(value),
+                },
+            )
+        })
+    }
+
+    fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+        self.first.is_grouping_attr(uuid) || self.second.is_grouping_attr(uuid)
+    }
+
+    fn group_end(&self, handle: Handle) -> Option<Handle> {
+        match handle.as_u16().checked_sub(self.offset) {
+            Some(raw) if raw > 0 => self
+                .second
+                .group_end(Handle::from_raw(raw))
+                .map(|end| self.second_handle(end)),
+            _ => self.first.group_end(handle),
+        }
+    }
+
+    fn write_attr(&mut self, handle: Handle, value: &[u8]) -> Result<(), AttError> {
+        match handle.as_u16().checked_sub(self.offset) {
+            Some(raw) if raw > 0 => self
+                .second
+                .write_attr(Handle::from_raw(raw), value)
+                .map_err(|e| AttError::new(e.error_code(), handle)),
+            _ => self.first.write_attr(handle, value),
+        }
+    }
+
+    fn check_write(&self, handle: Handle, value: &[u8]) -> Result<(), AttError> {
+        match handle.as_u16().checked_sub(self.offset) {
+            Some(raw) if raw > 0 => self
+                .second
+                .check_write(Handle::from_raw(raw), value)
+                .map_err(|e| AttError::new(e.error_code(), handle)),
+            _ => self.first.check_write(handle, value),
+        }
+    }
+}
+
+/// Adds `offset` to the handles contained in the value of a declaration of type `att_type`.
+///
+/// Returns `value` itself if it doesn't contain handles, or the modified value stored in `buf`.
+fn offset_handles<'a>(
+    att_type: AttUuid,
+    value: &'a [u8],
+    offset: u16,
+    buf: &'a mut [u8; 19],
+) -> &'a [u8] {
+    // Byte positions of the handles
+    let positions: &[usize] = if att_type == CHARACTERISTIC {
+        &[1]
+    } else if att_type == INCLUDE {
+        &[0, 2]
+    } else {
+        &[]
+    };
+    if positions.is_empty() || value.len() > buf.len() {
+        return value;
+    }
+
+    let buf = &mut buf[..value.len()];
+    buf.copy_from_slice(value);
+    for &pos in positions {
+        if let Some(raw) = buf.get_mut(pos..pos + 2) {
+            let handle = u16::from_le_bytes([raw[0], raw[1]]).wrapping_add(offset);
+            raw.copy_from_slice(&handle.to_le_bytes());
+        }
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::att::ErrorCode;
+    use crate::gatt::{BatteryServiceAttrs, MidiServiceAttrs};
+    use std::vec::Vec;
+
+    #[test]
+    fn offsets() {
+        let mut attrs = Chain::new(BatteryServiceAttrs::new(), MidiServiceAttrs::new());
+        assert_eq!(
+            attrs.second_handle(Handle::from_raw(3)),
+            Handle::from_raw(6)
+        );
+
+        let mut found = Vec::new();
+        let range = HandleRange::new(Handle::from_raw(0x0003), Handle::from_raw(0x0006));
+        attrs
+            .for_attrs_in_range(range, |_, attr| {
+                let value = attr.value().iter().take(3).cloned().collect::<Vec<_>>();
+                found.push((attr.handle.as_u16(), value));
+                Ok(())
+            })
+            .ok();
+        assert_eq!(
+            found,
+            [
+                (3, vec![48]),
+                (4, vec![0x00, 0xC7, 0xC4]),
+                // Characteristic declaration pointing to the value at 6
+                (5, vec![0x1E, 0x06, 0x00]),
+                (6, vec![]),
+            ]
+        );
+
+        assert_eq!(
+            attrs.group_end(Handle::from_raw(1)),
+            Some(Handle::from_raw(3))
+        );
+        assert_eq!(
+            attrs.group_end(Handle::from_raw(4)),
+            Some(Handle::from_raw(7))
+        );
+        assert_eq!(attrs.group_end(Handle::from_raw(5)), None);
+
+        assert_eq!(attrs.write_attr(Handle::from_raw(6), &[0x80]), Ok(()));
+        assert_eq!(
+            attrs.write_attr(Handle::from_raw(5), &[0x80]),
+            Err(AttError::new(
+                ErrorCode::WriteNotPermitted,
+                Handle::from_raw(5)
+            ))
+        );
+        assert_eq!(
+            attrs.write_attr(Handle::from_raw(3), &[0x80]),
+            Err(AttError::new(
+                ErrorCode::WriteNotPermitted,
+                Handle::from_raw(3)
+            ))
+        );
+    }
+}
@@ -0,0 +1,800 @@
+//! GATT client procedures.
+//!
+//! The `GattClient` is plugged into the `AttributeServer` as its `ClientHandler`. Procedures are
+//! started with an `AttributeClientTx` (obtained via `AttributeServerTx::client`), and may consist
+//! of several ATT requests, which are sent automatically as the responses come in. Their results
+//! are passed to a `ClientCallback`.
+
+use super::{characteristic::Properties, CHARACTERISTIC, PRIMARY_SERVICE};
+use crate::att::{
+    AttError, AttUuid, AttributeClientTx, ClientHandler, ErrorCode, Handle, HandleRange, Response,
+    MAX_VALUE_LEN,
+};
+use crate::bytes::{ByteReader, ByteWriter, FromBytes, ToBytes};
+use crate::Error;
+use core::mem;
+
+/// A primary service discovered on the server.
+#[derive(Debug, Copy, Clone)]
+pub struct RemoteService {
+    /// Handle of the service declaration.
+    pub handle: Handle,
+
+    /// Handle of the last attribute belonging to the service.
+    pub end_handle: Handle,
+
+    /// The service UUID.
+    pub uuid: AttUuid,
+}
+
+impl RemoteService {
+    /// Returns the range of handles of all attributes belonging to the service.
+    pub fn range(&self) -> HandleRange {
+        HandleRange::new(self.handle, self.end_handle)
+    }
+}
+
+/// A characteristic discovered on the server.
+#[derive(Debug, Copy, Clone)]
+pub struct RemoteCharacteristic {
+    /// Handle of the characteristic declaration.
+    pub handle: Handle,
+
+    /// The operations supported by the characteristic.
+    pub properties: Properties,
+
+    /// Handle of the attribute holding the characteristic value.
+    pub value_handle: Handle,
+
+    /// The characteristic UUID.
+    pub uuid: AttUuid,
+}
+
+/// A characteristic descriptor (or other attribute) discovered on the server.
+#[derive(Debug, Copy, Clone)]
+pub struct RemoteDescriptor {
+    /// Handle of the descriptor.
+    pub handle: Handle,
+
+    /// The descriptor's attribute type.
+    pub uuid: AttUuid,
+}
+
+/// Callback for the results of `GattClient` procedures.
+///
+/// Except for `complete`, all methods do nothing by default.
+pub trait ClientCallback {
+    /// Called for every service found by `GattClient::discover_services` or
+    /// `GattClient::discover_services_by_uuid`.
+    fn service(&mut self, service: RemoteService) {
+        let _ = service;
+    }
+
+    /// Called for every characteristic found by `GattClient::discover_characteristics`.
+    fn characteristic(&mut self, characteristic: RemoteCharacteristic) {
+        let _ = characteristic;
+    }
+
+    /// Called for every descriptor found by `GattClient::discover_descriptors`.
+    fn descriptor(&mut self, descriptor: RemoteDescriptor) {
+        let _ = descriptor;
+    }
+
+    /// Called with the value read by `GattClient::read`.
+    ///
+    /// Values that don't fit in a single response are passed in several parts, with increasing
+    /// `offset`.
+    fn value(&mut self, handle: Handle, offset: u16, value: &[u8]) {
+        let _ = (handle, offset, value);
+    }
+
+    /// Called when the server notifies the value of the characteristic at `handle`.
+    fn notification(&mut self, handle: Handle, value: &[u8]) {
+        let _ = (handle, value);
+    }
+
+    /// Called when the server indicates the value of the characteristic at `handle`.
+    ///
+    /// The indication is confirmed automatically.
+    fn indication(&mut self, handle: Handle, value: &[u8]) {
+        let _ = (handle, value);
+    }
+
+    /// Called when the last started procedure has finished.
+    ///
+    /// If the server rejected one of the procedure's requests, `result` holds the error.
+    fn complete(&mut self, result: Result<(), AttError>);
+}
+
+/// The procedure in progress, and the state needed to continue it.
+#[derive(Debug, Copy, Clone)]
+enum Procedure {
+    Idle,
+    ExchangeMtu,
+    DiscoverServices,
+    DiscoverServicesByUuid { uuid: AttUuid },
+    DiscoverCharacteristics { end: Handle },
+    DiscoverDescriptors { end: Handle },
+    Read { handle: Handle, offset: u16 },
+    Write,
+}
+
+/// A GATT client running one procedure at a time.
+pub struct GattClient<C: ClientCallback> {
+    callback: C,
+    procedure: Procedure,
+}
+
+impl<C: ClientCallback> GattClient<C> {
+    /// Creates a GATT client that passes procedure results to `callback`.
+    pub fn new(callback: C) -> Self {
+        Self {
+            callback,
+            procedure: Procedure::Idle,
+        }
+    }
+
+    /// Returns a reference to the callback.
+    pub fn callback(&mut self) -> &mut C {
+        &mut self.callback
+    }
+
+    /// Returns whether a procedure is in progress.
+    ///
+    /// No other procedure can be started until `ClientCallback::complete` is called.
+    pub fn is_busy(&self) -> bool {
+        !matches!(self.procedure, Procedure::Idle)
+    }
+
+    /// Negotiates the `ATT_MTU` with the server.
+    pub fn exchange_mtu(&mut self, client: AttributeClientTx<'_>) -> Result<(), Error> {
+        self.start(Procedure::ExchangeMtu, || client.exchange_mtu())
+    }
+
+    /// Discovers all primary services of the server.
+    pub fn discover_services(&mut self, client: AttributeClientTx<'_>) -> Result<(), Error> {
+        self.start(Procedure::DiscoverServices, || {
+            client.read_by_group_type(all_handles(), PRIMARY_SERVICE.into())
+        })
+    }
+
+    /// Discovers all primary services of the server with a specific UUID.
+    pub fn discover_services_by_uuid(
+        &mut self,
+        client: AttributeClientTx<'_>,
+        uuid: AttUuid,
+    ) -> Result<(), Error> {
+        self.start(Procedure::DiscoverServicesByUuid { uuid }, || {
+            find_service(client, uuid, Handle::from_raw(0x0001))
+        })
+    }
+
+    /// Discovers all characteristics declared in `range`.
+    ///
+    /// This is usually the `RemoteService::range` of a discovered service.
+    pub fn discover_characteristics(
+        &mut self,
+        client: AttributeClientTx<'_>,
+        range: HandleRange,
+    ) -> Result<(), Error> {
+        let end = range.end();
+        self.start(Procedure::DiscoverCharacteristics { end }, || {
+            client.read_by_type(range, CHARACTERISTIC.into())
+        })
+    }
+
+    /// Discovers all descriptors in `range`.
+    ///
+    /// The descriptors of a characteristic follow its value, up to the next characteristic
+    /// declaration or the end of the service.
+    pub fn discover_descriptors(
+        &mut self,
+        client: AttributeClientTx<'_>,
+        range: HandleRange,
+    ) -> Result<(), Error> {
+        let end = range.end();
+        self.start(Procedure::DiscoverDescriptors { end }, || {
+            client.find_information(range)
+        })
+    }
+
+    /// Reads the value of the attribute at `handle`.
+    ///
+    /// Long values are read in several parts using *Read Blob Requests*.
+    pub fn read(&mut self, client: AttributeClientTx<'_>, handle: Handle) -> Result<(), Error> {
+        self.start(Procedure::Read { handle, offset: 0 }, || {
+            client.read(handle)
+        })
+    }
+
+    /// Writes `value` to the attribute at `handle`.
+    ///
+    /// Returns `Error::InvalidLength` if `value` is longer than `ATT_MTU - 3` Bytes.
+    pub fn write(
+        &mut self,
+        client: AttributeClientTx<'_>,
+        handle: Handle,
+        value: &[u8],
+    ) -> Result<(), Error> {
+        self.start(Procedure::Write, || client.write(handle, value))
+    }
+
+    /// Writes `value` to the attribute at `handle`, without a response from the server.
+    ///
+    /// This is not a procedure, so it can be used while another procedure is in progress.
+    ///
+    /// Returns `Error::InvalidLength` if `value` is longer than `ATT_MTU - 3` Bytes.
+    pub fn write_without_response(
+        &mut self,
+        client: AttributeClientTx<'_>,
+        handle: Handle,
+        value: &[u8],
+    ) -> Result<(), Error> {
+        client.write_command(handle, value)
+    }
+
+    /// Enables or disables notifications and indications by writing the characteristic's
+    /// *Client Characteristic Configuration Descriptor* at `cccd`.
+    ///
+    /// Received notifications and indications are passed to `ClientCallback::notification` and
+    /// `ClientCallback::indication`.
+    pub fn subscribe(
+        &mut self,
+        client: AttributeClientTx<'_>,
+        cccd: Handle,
+        notify: bool,
+        indicate: bool,
+    ) -> Result<(), Error> {
+        let value = u16::from(notify) | u16::from(indicate) << 1;
+        self.write(client, cccd, &value.to_le_bytes())
+    }
+
+    /// Starts `procedure` by sending its first request via `request`.
+    fn start(
+        &mut self,
+        procedure: Procedure,
+        request: impl FnOnce() -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        if self.is_busy() {
+            return Err(Error::InvalidValue);
+        }
+
+        request()?;
+        self.procedure = procedure;
+        Ok(())
+    }
+
+    /// Processes a response to the request sent by `procedure`.
+    ///
+    /// Returns the procedure state to continue with after sending the next request, or `None` if
+    /// the procedure is complete.
+    fn process_response(
+        &mut self,
+        procedure: Procedure,
+        response: Response<'_>,
+        client: AttributeClientTx<'_>,
+    ) -> Result<Option<Procedure>, AttError> {
+        let invalid = AttError::new(ErrorCode::InvalidPdu, Handle::NULL);
+        let att_mtu = client.att_mtu();
+
+        // Sending the next request can't fail: The transaction just finished, and `client` fits
+        // all requests sent here.
+
+        match (procedure, response) {
+            (Procedure::ExchangeMtu, Response::ExchangeMtu { .. })
+            | (Procedure::Write, Response::Write) => Ok(None),
+
+            (Procedure::DiscoverServices, Response::ReadByGroup { length, data }) => {
+                if data.is_empty() || (length != 2 + 2 + 2 && length != 2 + 2 + 16) {
+                    return Err(invalid);
+                }
+
+                let mut end = 0;
+                for entry in data.chunks(length.into()) {
+                    let mut bytes = ByteReader::new(entry);
+                    let service = RemoteService {
+                        handle: Handle::from_bytes(&mut bytes).map_err(|_| invalid)?,
+                        end_handle: Handle::from_bytes(&mut bytes).map_err(|_| invalid)?,
+                        uuid: AttUuid::from_bytes(&mut bytes).map_err(|_| invalid)?,
+                    };
+                    end = service.end_handle.as_u16();
+                    self.callback.service(service);
+                }
+
+                match next_handle(end, Handle::from_raw(0xFFFF)) {
+                    Some(start) => {
+                        client
+                            .read_by_group_type(
+                                HandleRange::new(start, Handle::from_raw(0xFFFF)),
+                                PRIMARY_SERVICE.into(),
+                            )
+                            .unwrap();
+                        Ok(Some(procedure))
+                    }
+                    None => Ok(None),
+                }
+            }
+
+            (Procedure::DiscoverServicesByUuid { uuid }, Response::FindByTypeValue { handles }) => {
+                if handles.is_empty() || handles.len() % 4 != 0 {
+                    return Err(invalid);
+                }
+
+                let mut end = 0;
+                for entry in handles.chunks(4) {
+                    let service = RemoteService {
+                        handle: Handle::from_raw(u16::from_le_bytes([entry[0], entry[1]])),
+                        end_handle: Handle::from_raw(u16::from_le_bytes([entry[2], entry[3]])),
+                        uuid,
+                    };
+                    end = service.end_handle.as_u16();
+                    self.callback.service(service);
+                }
+
+                match next_handle(end, Handle::from_raw(0xFFFF)) {
+                    Some(start) => {
+                        find_service(client, uuid, start).unwrap();
+                        Ok(Some(procedure))
+                    }
+                    None => Ok(None),
+                }
+            }
+
+            (Procedure::DiscoverCharacteristics { end }, Response::ReadByType { length, data }) => {
+                if data.is_empty() || (length != 2 + 1 + 2 + 2 && length != 2 + 1 + 2 + 16) {
+                    return Err(invalid);
+                }
+
+                let mut last = 0;
+                for entry in data.chunks(length.into()) {
+                    let mut bytes = ByteReader::new(entry);
+                    let characteristic = RemoteCharacteristic {
+                        handle: Handle::from_bytes(&mut bytes).map_err(|_| invalid)?,
+                        properties: Properties::from_bits_truncate(
+                            bytes.read_u8().map_err(|_| invalid)?,
+                        ),
+                        value_handle: Handle::from_bytes(&mut bytes).map_err(|_| invalid)?,
+                        uuid: AttUuid::from_bytes(&mut bytes).map_err(|_| invalid)?,
+                    };
+                    last = characteristic.handle.as_u16();
+                    self.callback.characteristic(characteristic);
+                }
+
+                match next_handle(last, end) {
+                    Some(start) => {
+                        client
+                            .read_by_type(HandleRange::new(start, end), CHARACTERISTIC.into())
+                            .unwrap();
+                        Ok(Some(procedure))
+                    }
+                    None => Ok(None),
+                }
+            }
+
+            (
+                Procedure::DiscoverDescriptors { end },
+                Response::FindInformation { format, data },
+            ) => {
+                let length = match format {
+                    0x01 => 2 + 2,
+                    0x02 => 2 + 16,
+                    _ => return Err(invalid),
+                };
+                if data.is_empty() || data.len() % length != 0 {
+                    return Err(invalid);
+                }
+
+                let mut last = 0;
+                for entry in data.chunks(length) {
+                    let mut bytes = ByteReader::new(entry);
+                    let descriptor = RemoteDescriptor {
+                        handle: Handle::from_bytes(&mut bytes).map_err(|_| invalid)?,
+                        uuid: AttUuid::from_bytes(&mut bytes).map_err(|_| invalid)?,
+                    };
+                    last = descriptor.handle.as_u16();
+                    self.callback.descriptor(descriptor);
+                }
+
+                match next_handle(last, end) {
+                    Some(start) => {
+                        client
+                            .find_information(HandleRange::new(start, end))
+                            .unwrap();
+                        Ok(Some(procedure))
+                    }
+                    None => Ok(None),
+                }
+            }
+
+            (Procedure::Read { handle, offset }, Response::Read { value })
+            | (Procedure::Read { handle, offset }, Response::ReadBlob { value }) => {
+                self.callback.value(handle, offset, value);
+
+                // A response that's as large as possible means there might be more
+                if value.len() < usize::from(att_mtu - 1) {
+                    return Ok(None);
+                }
+                // Values are at most 512 Bytes long, so a server sending more is misbehaving
+                let offset = match usize::from(offset).checked_add(value.len()) {
+                    Some(MAX_VALUE_LEN) => return Ok(None),
+                    Some(offset) if offset < MAX_VALUE_LEN => offset as u16,
+                    _ => return Err(AttError::new(ErrorCode::InvalidOffset, handle)),
+                };
+                client.read_blob(handle, offset).unwrap();
+                Ok(Some(Procedure::Read { handle, offset }))
+            }
+
+            // The end of a list was reached
+            (Procedure::DiscoverServices, Response::Error(e))
+            | (Procedure::DiscoverServicesByUuid { .. }, Response::Error(e))
+            | (Procedure::DiscoverCharacteristics { .. }, Response::Error(e))
+            | (Procedure::DiscoverDescriptors { .. }, Response::Error(e))
+                if e.error_code() == ErrorCode::AttributeNotFound =>
+            {
+                Ok(None)
+            }
+
+            // The value of a long read turned out to be short
+            (Procedure::Read { offset, .. }, Response::Error(e))
+                if offset != 0 && e.error_code() == ErrorCode::AttributeNotLong =>
+            {
+                Ok(None)
+            }
+
+            (_, Response::Error(e)) => Err(e),
+
+            _ => Err(invalid),
+        }
+    }
+}
+
+impl<C: ClientCallback> ClientHandler for GattClient<C> {
+    fn response(&mut self, response: Response<'_>, client: AttributeClientTx<'_>) {
+        let procedure = mem::replace(&mut self.procedure, Procedure::Idle);
+        if let Procedure::Idle = procedure {
+            warn!("GATT client received {:?} while idle", response);
+            return;
+        }
+
+        match self.process_response(procedure, response, client) {
+            Ok(Some(next)) => self.procedure = next,
+            Ok(None) => self.callback.complete(Ok(())),
+            Err(e) => {
+                debug!("GATT procedure {:?} failed: {:?}", procedure, e);
+                self.callback.complete(Err(e));
+            }
+        }
+    }
+
+    fn notification(&mut self, handle: Handle, value: &[u8]) {
+        self.callback.notification(handle, value);
+    }
+
+    fn indication(&mut self, handle: Handle, value: &[u8]) {
+        self.callback.indication(handle, value);
+    }
+
+    fn connection_established(&mut self) {
+        self.procedure = Procedure::Idle;
+    }
+}
+
+/// Returns the range of all valid handles.
+fn all_handles() -> HandleRange {
+    HandleRange::new(Handle::from_raw(0x0001), Handle::from_raw(0xFFFF))
+}
+
+/// Returns the handle after `last`, or `None` if that's past `end`.
+fn next_handle(last: u16, end: Handle) -> Option<Handle> {
+    match last.checked_add(1) {
+        Some(next) if next <= end.as_u16() => Some(Handle::from_raw(next)),
+        _ => None,
+    }
+}
+
+/// Sends a request for primary services with UUID `uuid`, starting at `start`.
+fn find_service(client: AttributeClientTx<'_>, uuid: AttUuid, start: Handle) -> Result<(), Error> {
+    let mut buf = [0; 16];
+    let mut writer = ByteWriter::new(&mut buf);
+    uuid.to_bytes(&mut writer)?;
+    let len = 16 - writer.space_left();
+
+    client.find_by_type_value(
+        HandleRange::new(start, Handle::from_raw(0xFFFF)),
+        PRIMARY_SERVICE,
+        &buf[..len],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::att::NoAttributes;
+    use crate::l2cap::{BleChannelMap, L2CAPState};
+    use crate::link::queue::{Consume, Consumer, PacketQueue, SimpleQueue};
+    use crate::security::SecurityManager;
+    use crate::uuid::Uuid16;
+    use std::vec::Vec;
+
+    /// Records all callback invocations.
+    #[derive(Default)]
+    struct Recorder {
+        services: Vec<(u16, u16)>,
+        characteristics: Vec<(u16, u16)>,
+        descriptors: Vec<u16>,
+        values: Vec<(u16, u16, Vec<u8>)>,
+        notifications: Vec<(u16, Vec<u8>)>,
+        results: Vec<Result<(), AttError>>,
+    }
+
+    impl ClientCallback for Recorder {
+        fn service(&mut self, service: RemoteService) {
+            self.services
+                .push((service.handle.as_u16(), service.end_handle.as_u16()));
+        }
+
+        fn characteristic(&mut self, characteristic: RemoteCharacteristic) {
+            self.characteristics.push((
+                characteristic.handle.as_u16(),
+                characteristic.value_handle.as_u16(),
+            ));
+        }
+
+        fn descriptor(&mut self, descriptor: RemoteDescriptor) {
+            self.descriptors.push(descriptor.handle.as_u16());
+        }
+
+        fn value(&mut self, handle: Handle, offset: u16, value: &[u8]) {
+            self.values.push((handle.as_u16(), offset, value.to_vec()));
+        }
+
+        fn notification(&mut self, handle: Handle, value: &[u8]) {
+            self.notifications.push((handle.as_u16(), value.to_vec()));
+        }
+
+        fn indication(&mut self, handle: Handle, value: &[u8]) {
+            self.notifications.push((handle.as_u16(), value.to_vec()));
+        }
+
+        fn complete(&mut self, result: Result<(), AttError>) {
+            self.results.push(result);
+        }
+    }
+
+    type L2CAP =
+        L2CAPState<BleChannelMap<NoAttributes, crate::security::NoSecurity, GattClient<Recorder>>>;
+
+    fn l2cap() -> L2CAP {
+        L2CAPState::new(BleChannelMap::with_client(
+            NoAttributes,
+            SecurityManager::no_security(),
+            GattClient::new(Recorder::default()),
+        ))
+    }
+
+    /// Returns the ATT PDU sent via `queue`, if any.
+    fn sent(queue: &mut SimpleQueue) -> Option<Vec<u8>> {
+        let (_, mut rx) = queue.split();
+        let mut pdu = None;
+        rx.consume_raw_with(|_, payload| {
+            pdu = Some(payload[4..].to_vec());
+            Consume::always(Ok(()))
+        })
+        .ok();
+        pdu
+    }
+
+    /// Starts a procedure via `f` and returns the request it sends.
+    fn start(
+        l2cap: &mut L2CAP,
+        f: impl FnOnce(&mut GattClient<Recorder>, AttributeClientTx<'_>) -> Result<(), Error>,
+    ) -> Result<Vec<u8>, Error> {
+        let mut queue = SimpleQueue::new();
+        {
+            let (mut tx, _) = queue.split();
+            let mut l2cap = l2cap.tx(&mut tx);
+            let (client, gatt) = l2cap.att().unwrap().client();
+            f(gatt, client)?;
+        }
+        Ok(sent(&mut queue).unwrap())
+    }
+
+    /// Passes an ATT PDU from the server to the client and returns the client's next PDU, if any.
+    fn receive(l2cap: &mut L2CAP, pdu: &[u8]) -> Option<Vec<u8>> {
+        let mut queue = SimpleQueue::new();
+        {
+            let (mut tx, _) = queue.split();
+            let mut message = vec![pdu.len() as u8, 0, 0x04, 0];
+            message.extend_from_slice(pdu);
+            assert!(l2cap.tx(&mut tx).process_start(&message).should_consume());
+        }
+        sent(&mut queue)
+    }
+
+    fn recorder(l2cap: &mut L2CAP) -> &mut Recorder {
+        l2cap.attribute_server().client_handler().callback()
+    }
+
+    #[test]
+    fn discovery() {
+        let mut l2cap = l2cap();
+
+        // Primary services, in 2 responses.
+        assert_eq!(
+            start(&mut l2cap, |gatt, client| gatt.discover_services(client)),
+            Ok(vec![0x10, 0x01, 0x00, 0xFF, 0xFF, 0x00, 0x28])
+        );
+        assert_eq!(
+            start(&mut l2cap, |gatt, client| gatt.discover_services(client)),
+            Err(Error::InvalidValue)
+        );
+        assert_eq!(
+            receive(
+                &mut l2cap,
+                &[0x11, 6, 0x01, 0x00, 0x05, 0x00, 0x0F, 0x18, 0x06, 0x00, 0x09, 0x00, 0x05, 0x18]
+            ),
+            Some(vec![0x10, 0x0A, 0x00, 0xFF, 0xFF, 0x00, 0x28])
+        );
+        assert_eq!(receive(&mut l2cap, &[0x01, 0x10, 0x0A, 0x00, 0x0A]), None);
+        assert_eq!(recorder(&mut l2cap).services, [(1, 5), (6, 9)]);
+        assert_eq!(recorder(&mut l2cap).results, [Ok(())]);
+
+        // Service by UUID.
+        assert_eq!(
+            start(&mut l2cap, |gatt, client| gatt
+                .discover_services_by_uuid(client, Uuid16(0x1805).into())),
+            Ok(vec![0x06, 0x01, 0x00, 0xFF, 0xFF, 0x00, 0x28, 0x05, 0x18])
+        );
+        assert_eq!(receive(&mut l2cap, &[0x07, 0x06, 0x00, 0xFF, 0xFF]), None);
+        assert_eq!(recorder(&mut l2cap).services[2], (6, 0xFFFF));
+
+        // Characteristics of the second service.
+        let range = HandleRange::new(Handle::from_raw(6), Handle::from_raw(9));
+        assert_eq!(
+            start(&mut l2cap, |gatt, client| gatt
+                .discover_characteristics(client, range)),
+            Ok(vec![0x08, 0x06, 0x00, 0x09, 0x00, 0x03, 0x28])
+        );
+        assert_eq!(
+            receive(
+                &mut l2cap,
+                &[0x09, 7, 0x07, 0x00, 0x12, 0x08, 0x00, 0x2B, 0x2A]
+            ),
+            Some(vec![0x08, 0x08, 0x00, 0x09, 0x00, 0x03, 0x28])
+        );
+        assert_eq!(receive(&mut l2cap, &[0x01, 0x08, 0x08, 0x00, 0x0A]), None);
+        assert_eq!(recorder(&mut l2cap).characteristics, [(7, 8)]);
+
+        // Descriptors. The last one ends the procedure.
+        let range = HandleRange::new(Handle::from_raw(9), Handle::from_raw(9));
+        assert_eq!(
+            start(&mut l2cap, |gatt, client| gatt
+                .discover_descriptors(client, range)),
+            Ok(vec![0x04, 0x09, 0x00, 0x09, 0x00])
+        );
+        assert_eq!(
+            receive(&mut l2cap, &[0x05, 0x01, 0x09, 0x00, 0x02, 0x29]),
+            None
+        );
+        assert_eq!(recorder(&mut l2cap).descriptors, [9]);
+        assert_eq!(recorder(&mut l2cap).results, [Ok(()); 4]);
+
+        // Responses without a request are ignored.
+        assert_eq!(receive(&mut l2cap, &[0x13]), None);
+        assert_eq!(recorder(&mut l2cap).results.len(), 4);
+    }
+
+    #[test]
+    fn read_write_subscribe() {
+        let mut l2cap = l2cap();
+
+        // A long read.
+        assert_eq!(
+            start(&mut l2cap, |gatt, client| gatt
+                .read(client, Handle::from_raw(8))),
+            Ok(vec![0x0A, 0x08, 0x00])
+        );
+        let mut rsp = vec![0x0B];
+        rsp.extend_from_slice(&[0xAA; 22]);
+        assert_eq!(
+            receive(&mut l2cap, &rsp),
+            Some(vec![0x0C, 0x08, 0x00, 22, 0x00])
+        );
+        assert_eq!(receive(&mut l2cap, &[0x0D, 1, 2, 3]), None);
+        assert_eq!(
+            recorder(&mut l2cap).values,
+            [(8, 0, vec![0xAA; 22]), (8, 22, vec![1, 2, 3])]
+        );
+
+        // Errors are passed to the callback.
+        assert_eq!(
+            start(&mut l2cap, |gatt, client| gatt.write(
+                client,
+                Handle::from_raw(8),
+                &[1]
+            )),
+            Ok(vec![0x12, 0x08, 0x00, 1])
+        );
+        assert_eq!(receive(&mut l2cap, &[0x01, 0x12, 0x08, 0x00, 0x03]), None);
+        assert_eq!(
+            recorder(&mut l2cap).results,
+            [
+                Ok(()),
+                Err(AttError::new(
+                    ErrorCode::WriteNotPermitted,
+                    Handle::from_raw(8)
+                ))
+            ]
+        );
+
+        // Subscribe to notifications and indications.
+        assert_eq!(
+            start(&mut l2cap, |gatt, client| gatt.subscribe(
+                client,
+                Handle::from_raw(9),
+                true,
+                true
+            )),
+            Ok(vec![0x12, 0x09, 0x00, 0x03, 0x00])
+        );
+        assert_eq!(receive(&mut l2cap, &[0x13]), None);
+        assert_eq!(recorder(&mut l2cap).results.len(), 3);
+
+        assert_eq!(receive(&mut l2cap, &[0x1B, 0x08, 0x00, 1, 2]), None);
+        assert_eq!(
+            receive(&mut l2cap, &[0x1D, 0x08, 0x00, 3]),
+            Some(vec![0x1E])
+        );
+        assert_eq!(
+            recorder(&mut l2cap).notifications,
+            [(8, vec![1, 2]), (8, vec![3])]
+        );
+    }
+
+    #[test]
+    fn long_read_limit() {
+        let mut l2cap = l2cap();
+        start(&mut l2cap, |gatt, client| {
+            gatt.read(client, Handle::from_raw(8))
+        })
+        .unwrap();
+
+        // A server that keeps sending full responses can't make the offset exceed 512.
+        let mut rsp = vec![0x0B];
+        rsp.extend_from_slice(&[0xAA; 22]);
+        assert_eq!(
+            receive(&mut l2cap, &rsp),
+            Some(vec![0x0C, 0x08, 0x00, 22, 0x00])
+        );
+        rsp[0] = 0x0D;
+        for offset in (44..512u16).step_by(22) {
+            let [lo, hi] = offset.to_le_bytes();
+            assert_eq!(
+                receive(&mut l2cap, &rsp),
+                Some(vec![0x0C, 0x08, 0x00, lo, hi])
+            );
+        }
+        assert_eq!(receive(&mut l2cap, &rsp), None);
+        assert_eq!(recorder(&mut l2cap).values.len(), 24);
+        assert_eq!(
+            recorder(&mut l2cap).results,
+            [Err(AttError::new(
+                ErrorCode::InvalidOffset,
+                Handle::from_raw(8)
+            ))]
+        );
+    }
+
+    #[test]
+    fn request_timeout() {
+        let mut l2cap = l2cap();
+        start(&mut l2cap, |gatt, client| gatt.exchange_mtu(client)).unwrap();
+
+        let att = l2cap.attribute_server();
+        assert!(att.request_pending());
+        att.check_timeout(crate::time::Instant::from_raw_micros(0));
+        att.check_timeout(crate::time::Instant::from_raw_micros(30_000_000));
+        assert!(att.timed_out());
+        assert!(!att.request_pending());
+    }
+}
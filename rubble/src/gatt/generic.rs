@@ -0,0 +1,303 @@
+//! The mandatory *Generic Access* and *Generic Attribute* services.
+
+use super::characteristic::{Appearance, Properties};
+use super::{AttributeTable, Chain};
+use crate::att::{AttError, AttUuid, Attribute, AttributeProvider, Handle, HandleRange};
+use crate::time::Duration;
+use crate::uuid::Uuid16;
+use crate::Error;
+use heapless::consts::U11;
+
+/// Adds the *Generic Access* (GAP) and *Generic Attribute* (GATT) services to an
+/// `AttributeProvider`.
+///
+/// Every GATT server has to contain these services. The *Generic Access* service exposes the
+/// *Device Name*, *Appearance* and *Peripheral Preferred Connection Parameters* characteristics.
+/// The *Generic Attribute* service contains the *Service Changed* characteristic, which can be
+/// indicated to clients (via `AttributeServerTx::indicate`) when the attributes of the wrapped
+/// provider change.
+///
+/// The services are placed first, at fixed handles: The *Generic Access* service starts at handle
+/// `0x0001`, and the *Service Changed* characteristic value is at `0x000A`. The attributes of the
+/// wrapped provider follow after them, so their handles are offset by 11. `attr_handle` translates
+/// them.
+pub struct GenericServices<'a, A: AttributeProvider> {
+    chain: Chain<GenericAttrs<'a>, A>,
+}
+
+impl<'a, A: AttributeProvider> GenericServices<'a, A> {
+    /// Creates a provider hosting the *Generic Access* and *Generic Attribute* services, followed
+    /// by the attributes of `attrs`.
+    ///
+    /// `device_name` is the UTF-8 encoded name of the device, and should match the name in the
+    /// advertising data (if any). The preferred connection parameters are initially unset.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `attrs` uses handles too high to fit after the services.
+    pub fn new(attrs: A, device_name: &'a str, appearance: Appearance) -> Self {
+        let generic = GenericAttrs::new(device_name.as_bytes(), appearance);
+        Self {
+            chain: Chain::new(generic, attrs),
+        }
+    }
+
+    /// Returns a reference to the wrapped `AttributeProvider`.
+    pub fn attrs(&self) -> &A {
+        self.chain.second()
+    }
+
+    /// Returns a mutable reference to the wrapped `AttributeProvider`.
+    pub fn attrs_mut(&mut self) -> &mut A {
+        self.chain.second_mut()
+    }
+
+    /// Translates the handle of an attribute of the wrapped provider to the handle used by the
+    /// `GenericServices` (eg. to send notifications).
+    pub fn attr_handle(&self, handle: Handle) -> Handle {
+        self.chain.second_handle(handle)
+    }
+
+    /// Sets the *Peripheral Preferred Connection Parameters* reported to the central.
+    ///
+    /// # Parameters
+    ///
+    /// * `min`, `max`: Preferred range of the connection interval (rounded down to units of
+    ///   1.25 ms).
+    /// * `slave_latency`: Preferred slave latency in number of connection events (0 to 499).
+    /// * `timeout`: Preferred supervision timeout (rounded down to units of 10 ms).
+    ///
+    /// Returns `Error::InvalidValue` if `min > max` or if `slave_latency` is out of range. The
+    /// previous parameters are kept in that case.
+    pub fn set_preferred_connection_params(
+        &mut self,
+        min: Duration,
+        max: Duration,
+        slave_latency: u16,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        if min > max || slave_latency > 499 {
+            return Err(Error::InvalidValue);
+        }
+
+        let min = (min.as_micros() / 1_250) as u16;
+        let max = (max.as_micros() / 1_250) as u16;
+        let timeout = (timeout.as_micros() / 10_000) as u16;
+        let ppcp = &mut self.chain.first_mut().ppcp;
+        ppcp[0..2].copy_from_slice(&min.to_le_bytes());
+        ppcp[2..4].copy_from_slice(&max.to_le_bytes());
+        ppcp[4..6].copy_from_slice(&slave_latency.to_le_bytes());
+        ppcp[6..8].copy_from_slice(&timeout.to_le_bytes());
+        Ok(())
+    }
+
+    /// Returns the handle of the *Service Changed* characteristic value.
+    ///
+    /// The value to indicate is the range of affected handles, as 2 little-endian 16-bit handles.
+    pub fn service_changed(&self) -> Handle {
+        self.chain.first().service_changed
+    }
+}
+
+impl<A: AttributeProvider> AttributeProvider for GenericServices<'_, A> {
+    fn for_attrs_in_range(
+        &self,
+        range: HandleRange,
+        mut f: impl FnMut(&Self, Attribute<'_>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        self.chain
+            .for_attrs_in_range(range, |_, attr| f(self, attr))
+    }
+
+    fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+        self.chain.is_grouping_attr(uuid)
+    }
+
+    fn group_end(&self, handle: Handle) -> Option<Handle> {
+        self.chain.group_end(handle)
+    }
+
+    fn write_attr(&mut self, handle: Handle, value: &[u8]) -> Result<(), AttError> {
+        self.chain.write_attr(handle, value)
+    }
+
+    fn check_write(&self, handle: Handle, value: &[u8]) -> Result<(), AttError> {
+        self.chain.check_write(handle, value)
+    }
+}
+
+/// The attributes of both services, starting at handle `0x0001`.
+///
+/// The table is built once. It can't borrow the *Appearance* and *Peripheral Preferred Connection
+/// Parameters* values stored here, so they're filled in by `for_attrs_in_range`. None of the
+/// attributes are writable.
+struct GenericAttrs<'a> {
+    table: AttributeTable<'a, U11>,
+
+    appearance: [u8; 2],
+
+    /// Value of the *Peripheral Preferred Connection Parameters* characteristic.
+    ppcp: [u8; 8],
+
+    /// Handle of the *Appearance* characteristic value.
+    appearance_handle: Handle,
+
+    /// Handle of the *Peripheral Preferred Connection Parameters* characteristic value.
+    ppcp_handle: Handle,
+
+    /// Handle of the *Service Changed* characteristic value.
+    service_changed: Handle,
+}
+
+impl<'a> GenericAttrs<'a> {
+    fn new(device_name: &'a [u8], appearance: Appearance) -> Self {
+        let mut table = AttributeTable::new();
+        table.service(Uuid16(0x1800)).unwrap(); // "Generic Access"
+        table
+            .characteristic_with(Properties::READ, Uuid16(0x2A00), device_name) // "Device Name"
+            .unwrap();
+        let appearance_handle = table
+            .characteristic_with(Properties::READ, Uuid16(0x2A01), &[]) // "Appearance"
+            .unwrap();
+        let ppcp_handle = table
+            .characteristic_with(Properties::READ, Uuid16(0x2A04), &[]) // "PPCP"
+            .unwrap();
+        table.service(Uuid16(0x1801)).unwrap(); // "Generic Attribute"
+
+        // Only sent in indications, so the value is left empty. Gets a CCCD.
+        let service_changed = table
+            .characteristic_with(Properties::INDICATE, Uuid16(0x2A05), &[]) // "Service Changed"
+            .unwrap();
+
+        Self {
+            table,
+            appearance: (appearance as u16).to_le_bytes(),
+            // 0xFFFF means "no specific value" for the intervals and the timeout. The slave
+            // latency has no such value, so it defaults to 0.
+            ppcp: [0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0xFF, 0xFF],
+            appearance_handle,
+            ppcp_handle,
+            service_changed,
+        }
+    }
+}
+
+impl AttributeProvider for GenericAttrs<'_> {
+    fn for_attrs_in_range(
+        &self,
+        range: HandleRange,
+        mut f: impl FnMut(&Self, Attribute<'_>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        for attr in self.table.attrs_in_range(range) {
+            let value = if attr.handle == self.appearance_handle {
+                &self.appearance[..]
+            } else if attr.handle == self.ppcp_handle {
+                &self.ppcp[..]
+            } else {
+                attr.value()
+            };
+            f(self, Attribute::new(attr.att_type, attr.handle, value))?;
+        }
+        Ok(())
+    }
+
+    fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+        self.table.is_grouping_attr(uuid)
+    }
+
+    fn group_end(&self, handle: Handle) -> Option<Handle> {
+        self.table.group_end(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::att::ErrorCode;
+    use crate::gatt::{BatteryServiceAttrs, PRIMARY_SERVICE};
+    use std::vec::Vec;
+
+    #[test]
+    fn layout() {
+        let mut attrs =
+            GenericServices::new(BatteryServiceAttrs::new(), "rubble", Appearance::GenericTag);
+
+        // Unset intervals and timeout are 0xFFFF, the slave latency is 0
+        let mut ppcp = Vec::new();
+        let range = HandleRange::new(Handle::from_raw(7), Handle::from_raw(7));
+        attrs
+            .for_attrs_in_range(range, |_, attr| {
+                ppcp.extend_from_slice(attr.value());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(ppcp, [0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0xFF, 0xFF]);
+
+        let ms = Duration::from_millis;
+        assert_eq!(
+            attrs.set_preferred_connection_params(ms(15), ms(30), 0, Duration::from_secs(2)),
+            Ok(())
+        );
+        assert_eq!(
+            attrs.set_preferred_connection_params(ms(30), ms(15), 0, ms(500)),
+            Err(Error::InvalidValue)
+        );
+        assert_eq!(
+            attrs.set_preferred_connection_params(ms(15), ms(30), 500, ms(500)),
+            Err(Error::InvalidValue)
+        );
+        assert_eq!(attrs.service_changed(), Handle::from_raw(10));
+        assert_eq!(attrs.attr_handle(Handle::from_raw(3)), Handle::from_raw(14));
+
+        // GAP and GATT come first, at fixed handles, followed by the wrapped provider
+        let mut values = Vec::new();
+        let range = HandleRange::new(Handle::from_raw(0x0001), Handle::from_raw(0xFFFF));
+        attrs
+            .for_attrs_in_range(range, |_, attr| {
+                values.push((attr.handle.as_u16(), attr.value().to_vec()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            values,
+            [
+                (1, vec![0x00, 0x18]),
+                (2, vec![0x02, 0x03, 0x00, 0x00, 0x2A]),
+                (3, b"rubble".to_vec()),
+                (4, vec![0x02, 0x05, 0x00, 0x01, 0x2A]),
+                (5, vec![0x00, 0x02]),
+                (6, vec![0x02, 0x07, 0x00, 0x04, 0x2A]),
+                (7, vec![12, 0, 24, 0, 0, 0, 200, 0]),
+                (8, vec![0x01, 0x18]),
+                (9, vec![0x20, 0x0A, 0x00, 0x05, 0x2A]),
+                (10, vec![]),
+                (11, vec![0x00, 0x00]),
+                (12, vec![0x0F, 0x18]),
+                (13, vec![0x02, 0x0E, 0x00, 0x19, 0x2A]),
+                (14, vec![48]),
+            ]
+        );
+
+        assert!(attrs.is_grouping_attr(PRIMARY_SERVICE.into()));
+        assert_eq!(
+            attrs.group_end(Handle::from_raw(1)),
+            Some(Handle::from_raw(7))
+        );
+        assert_eq!(
+            attrs.group_end(Handle::from_raw(8)),
+            Some(Handle::from_raw(11))
+        );
+        assert_eq!(
+            attrs.group_end(Handle::from_raw(12)),
+            Some(Handle::from_raw(14))
+        );
+        assert_eq!(attrs.group_end(Handle::from_raw(13)), None);
+        assert_eq!(
+            attrs.write_attr(Handle::from_raw(3), b"x"),
+            Err(AttError::new(
+                ErrorCode::WriteNotPermitted,
+                Handle::from_raw(3)
+            ))
+        );
+    }
+}
@@ -3,87 +3,85 @@
 //! GATT describes a service framework that uses the Attribute Protocol for discovery and
 //! interaction
 
+mod chain;
 pub mod characteristic;
-
-use crate::att::{AttUuid, Attribute, AttributeProvider, Handle, HandleRange};
+mod client;
+mod generic;
+mod table;
+
+pub use self::chain::Chain;
+pub use self::client::{
+    ClientCallback, GattClient, RemoteCharacteristic, RemoteDescriptor, RemoteService,
+};
+pub use self::generic::GenericServices;
+pub use self::table::{AttributeTable, TableEntry};
+
+use self::characteristic::Properties;
+use crate::att::{AttError, AttUuid, Attribute, AttributeProvider, ErrorCode, Handle, HandleRange};
 use crate::uuid::{Uuid128, Uuid16};
 use crate::{utils::HexSlice, Error};
-use core::{cmp, slice};
+use core::slice;
+use heapless::consts::{U3, U4};
+
+/// Attribute type of *Primary Service* declarations.
+const PRIMARY_SERVICE: Uuid16 = Uuid16(0x2800);
+
+/// Attribute type of *Include* declarations.
+const INCLUDE: Uuid16 = Uuid16(0x2802);
+
+/// Attribute type of *Characteristic* declarations.
+const CHARACTERISTIC: Uuid16 = Uuid16(0x2803);
+
+/// Attribute type of *Client Characteristic Configuration Descriptors*.
+const CCCD: Uuid16 = Uuid16(0x2902);
+
+/// Returns the highest handle used by `attrs`, or 0 if it has no attributes.
+fn last_handle(attrs: &impl AttributeProvider) -> u16 {
+    let all = HandleRange::new(Handle::from_raw(0x0001), Handle::from_raw(0xFFFF));
+    let mut last = 0;
+    attrs
+        .for_attrs_in_range(all, |_, attr| {
+            last = attr.handle.as_u16();
+            Ok(())
+        })
+        .ok();
+    last
+}
 
 /// A demo `AttributeProvider` that will enumerate as a *Battery Service*.
 pub struct BatteryServiceAttrs {
-    attributes: [Attribute<'static>; 3],
+    table: AttributeTable<'static, U3>,
 }
 
 impl BatteryServiceAttrs {
     pub fn new() -> Self {
-        Self {
-            attributes: [
-                Attribute {
-                    att_type: Uuid16(0x2800).into(), // "Primary Service"
-                    handle: Handle::from_raw(0x0001),
-                    value: HexSlice(&[0x0F, 0x18]), // "Battery Service" = 0x180F
-                },
-                Attribute {
-                    att_type: Uuid16(0x2803).into(), // "Characteristic"
-                    handle: Handle::from_raw(0x0002),
-                    value: HexSlice(&[
-                        0x02, // 1 byte properties: READ = 0x02
-                        0x03, 0x00, // 2 bytes handle = 0x0003
-                        0x19, 0x2A, // 2 bytes UUID = 0x2A19 (Battery Level)
-                    ]),
-                },
-                // Characteristic value (Battery Level)
-                Attribute {
-                    att_type: AttUuid::Uuid16(Uuid16(0x2A19)), // "Battery Level"
-                    handle: Handle::from_raw(0x0003),
-                    value: HexSlice(&[48u8]),
-                },
-            ],
-        }
+        let mut table = AttributeTable::new();
+        table.service(Uuid16(0x180F)).unwrap(); // "Battery Service"
+        table
+            .characteristic_with(Properties::READ, Uuid16(0x2A19), &[48]) // "Battery Level"
+            .unwrap();
+        Self { table }
     }
 }
 
 impl AttributeProvider for BatteryServiceAttrs {
     fn for_attrs_in_range(
-        &mut self,
+        &self,
         range: HandleRange,
         mut f: impl FnMut(&Self, Attribute<'_>) -> Result<(), Error>,
     ) -> Result<(), Error> {
-        let count = self.attributes.len();
-        let start = usize::from(range.start().as_u16() - 1); // handles start at 1, not 0
-        let end = usize::from(range.end().as_u16() - 1);
-
-        let attrs = if start >= count {
-            &[]
-        } else {
-            let end = cmp::min(count - 1, end);
-            &self.attributes[start..=end]
-        };
-
-        for attr in attrs {
-            f(
-                self,
-                Attribute {
-                    att_type: attr.att_type,
-                    handle: attr.handle,
-                    value: attr.value,
-                },
-            )?;
+        for attr in self.table.attrs_in_range(range) {
+            f(self, attr)?;
         }
         Ok(())
     }
 
     fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
-        uuid == Uuid16(0x2800) // FIXME not characteristics?
+        self.table.is_grouping_attr(uuid)
     }
 
-    fn group_end(&self, handle: Handle) -> Option<&Attribute<'_>> {
-        match handle.as_u16() {
-            0x0001 => Some(&self.attributes[2]),
-            0x0002 => Some(&self.attributes[2]),
-            _ => None,
-        }
+    fn group_end(&self, handle: Handle) -> Option<Handle> {
+        self.table.group_end(handle)
     }
 }
 
@@ -107,115 +105,81 @@ impl<'a> Iterator for Attributes<'a> {
 ///
 /// Also refer to https://www.midi.org/specifications-old/item/bluetooth-le-midi
 pub struct MidiServiceAttrs {
-    attributes: [Attribute<'static>; 4],
+    table: AttributeTable<'static, U4>,
+
+    /// Handle of the *MIDI Data I/O* characteristic value.
+    data_io: Handle,
 }
 
 // MIDI Service (UUID: 03B80E5A-EDE8-4B33-A751-6CE34EC4C700)
+const MIDI_SERVICE: Uuid128 = Uuid128::from_bytes([
+    0x00, 0xC7, 0xC4, 0x4E, 0xE3, 0x6C, /* - */
+    0x51, 0xA7, /* - */
+    0x33, 0x4B, /* - */
+    0xE8, 0xED, /* - */
+    0x5A, 0x0E, 0xB8, 0x03,
+]);
+
 // MIDI Data I/O Characteristic (UUID: 7772E5DB-3868-4112-A1A9-F2669D106BF3)
+const MIDI_DATA_IO: Uuid128 = Uuid128::from_bytes([
+    0xF3, 0x6B, 0x10, 0x9D, 0x66, 0xF2, /* - */
+    0xA9, 0xA1, /* - */
+    0x12, 0x41, /* - */
+    0x68, 0x38, /* - */
+    0xDB, 0xE5, 0x72, 0x77,
+]);
 
 impl MidiServiceAttrs {
     pub fn new() -> Self {
-        Self {
-            attributes: [
-                Attribute {
-                    att_type: Uuid16(0x2800).into(), // "Primary Service"
-                    handle: Handle::from_raw(0x0001),
-                    value: HexSlice(&[
-                        0x00, 0xC7, 0xC4, 0x4E, 0xE3, 0x6C, /* - */
-                        0x51, 0xA7, /* - */
-                        0x33, 0x4B, /* - */
-                        0xE8, 0xED, /* - */
-                        0x5A, 0x0E, 0xB8, 0x03,
-                    ]), // "Midi Service"
-                },
-                Attribute {
-                    att_type: Uuid16(0x2803).into(), // "Characteristic"
-                    handle: Handle::from_raw(0x0002),
-                    value: HexSlice(&[
-                        0x02 | 0x08 | 0x04 | 0x10, // 1 byte properties: READ = 0x02, WRITE_REQ = 0x08, WRITE_CMD = 0x04, NOTIFICATION = 0x10
-                        0x03,
-                        0x00, // 2 bytes handle = 0x0003
-                        // the actual UUID
-                        0xF3,
-                        0x6B,
-                        0x10,
-                        0x9D,
-                        0x66,
-                        0xF2, /*-*/
-                        0xA9,
-                        0xA1, /*-*/
-                        0x12,
-                        0x41, /*-*/
-                        0x68,
-                        0x38, /*-*/
-                        0xDB,
-                        0xE5,
-                        0x72,
-                        0x77,
-                    ]),
-                },
-                // Characteristic value (Empty Packet)
-                Attribute {
-                    att_type: AttUuid::Uuid128(Uuid128::from_bytes([
-                        0xF3, 0x6B, 0x10, 0x9D, 0x66, 0xF2, /*-*/
-                        0xA9, 0xA1, /*-*/
-                        0x12, 0x41, /*-*/
-                        0x68, 0x38, /*-*/
-                        0xDB, 0xE5, 0x72, 0x77,
-                    ])),
-                    handle: Handle::from_raw(0x0003),
-                    value: HexSlice(&[]),
-                },
-                // CCCD
-                Attribute {
-                    att_type: AttUuid::Uuid16(Uuid16(0x2902)),
-                    handle: Handle::from_raw(0x0004),
-                    value: HexSlice(&[0x00, 0x00]),
-                },
-            ],
-        }
+        let mut table = AttributeTable::new();
+        table.service(MIDI_SERVICE).unwrap();
+        // Gets a CCCD because of `NOTIFY`. The value is an empty packet.
+        let data_io = table
+            .characteristic_with(
+                Properties::READ
+                    | Properties::WRITE_NO_RSP
+                    | Properties::WRITE
+                    | Properties::NOTIFY,
+                MIDI_DATA_IO,
+                &[],
+            )
+            .unwrap();
+        Self { table, data_io }
     }
 }
 
 impl AttributeProvider for MidiServiceAttrs {
     fn for_attrs_in_range(
-        &mut self,
+        &self,
         range: HandleRange,
         mut f: impl FnMut(&Self, Attribute<'_>) -> Result<(), Error>,
     ) -> Result<(), Error> {
-        let count = self.attributes.len();
-        let start = usize::from(range.start().as_u16() - 1); // handles start at 1, not 0
-        let end = usize::from(range.end().as_u16() - 1);
-
-        let attrs = if start >= count {
-            &[]
-        } else {
-            let end = cmp::min(count - 1, end);
-            &self.attributes[start..=end]
-        };
-
-        for attr in attrs {
-            f(
-                self,
-                Attribute {
-                    att_type: attr.att_type,
-                    handle: attr.handle,
-                    value: attr.value,
-                },
-            )?;
+        for attr in self.table.attrs_in_range(range) {
+            f(self, attr)?;
         }
         Ok(())
     }
 
     fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
-        uuid == Uuid16(0x2800) // FIXME not characteristics?
+        self.table.is_grouping_attr(uuid)
     }
 
-    fn group_end(&self, handle: Handle) -> Option<&Attribute<'_>> {
-        match handle.as_u16() {
-            0x0001 => Some(&self.attributes[3]),
-            0x0002 => Some(&self.attributes[3]),
-            _ => None,
+    fn group_end(&self, handle: Handle) -> Option<Handle> {
+        self.table.group_end(handle)
+    }
+
+    fn write_attr(&mut self, handle: Handle, value: &[u8]) -> Result<(), AttError> {
+        self.check_write(handle, value)?;
+        // There's no MIDI output, so incoming data is discarded.
+        debug!("MIDI data: {:?}", HexSlice(value));
+        Ok(())
+    }
+
+    fn check_write(&self, handle: Handle, _value: &[u8]) -> Result<(), AttError> {
+        if handle == self.data_io {
+            Ok(())
+        } else {
+            Err(AttError::new(ErrorCode::WriteNotPermitted, handle))
         }
     }
 }
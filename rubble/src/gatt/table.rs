@@ -0,0 +1,366 @@
+//! Construction of static attribute tables.
+
+use super::characteristic::{Characteristic, Properties};
+use super::{CCCD, CHARACTERISTIC, PRIMARY_SERVICE};
+use crate::att::{AttUuid, Attribute, AttributeProvider, Handle, HandleRange};
+use crate::bytes::{ByteWriter, ToBytes};
+use crate::{utils::HexSlice, Error};
+use core::cmp;
+use heapless::{ArrayLength, Vec};
+
+/// An `AttributeProvider` hosting a fixed set of services.
+///
+/// The table is filled by calling `service`, `characteristic` and `descriptor` in the order the
+/// attributes should appear on the server. Handles are assigned automatically, starting at
+/// `0x0001`. Service and characteristic declarations are generated from the arguments, and a
+/// *Client Characteristic Configuration Descriptor* (CCCD) is added after the value of every
+/// characteristic that supports notifications or indications.
+///
+/// Attribute values are borrowed, and can be replaced with `set_value`. The table itself rejects
+/// all writes by the client. Characteristics with the `WRITE` or `WRITE_NO_RSP` property need an
+/// `AttributeProvider` wrapping the table that handles `write_attr` (like `MidiServiceAttrs`).
+///
+/// `N` is the maximum number of attributes in the table: Every service takes up 1 attribute,
+/// every characteristic 2 (or 3 if it gets a CCCD), and every descriptor 1.
+pub struct AttributeTable<'a, N: ArrayLength<TableEntry<'a>>> {
+    entries: Vec<TableEntry<'a>, N>,
+
+    /// Index of the service declaration new characteristics are added to.
+    service: Option<usize>,
+
+    /// Whether a characteristic was added to the current service (and can get descriptors).
+    characteristic: bool,
+}
+
+/// An attribute stored in an `AttributeTable`.
+pub struct TableEntry<'a> {
+    att_type: AttUuid,
+    value: Value<'a>,
+
+    /// For service declarations, the handle of the last attribute of the service.
+    group_end: Option<Handle>,
+}
+
+enum Value<'a> {
+    /// A value passed in by the user.
+    Borrowed(&'a [u8]),
+
+    /// A generated declaration (a characteristic declaration with a 128-bit UUID is the longest).
+    Declaration { buf: [u8; 19], len: u8 },
+}
+
+impl<'a> Value<'a> {
+    fn declaration(
+        f: impl FnOnce(&mut ByteWriter<'_>) -> Result<(), Error>,
+    ) -> Result<Self, Error> {
+        let mut buf = [0; 19];
+        let mut writer = ByteWriter::new(&mut buf);
+        f(&mut writer)?;
+        let len = (19 - writer.space_left()) as u8;
+        Ok(Value::Declaration { buf, len })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Value::Borrowed(value) => value,
+            Value::Declaration { buf, len } => &buf[..usize::from(*len)],
+        }
+    }
+}
+
+impl<'a, N: ArrayLength<TableEntry<'a>>> AttributeTable<'a, N> {
+    /// Creates an empty attribute table.
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            service: None,
+            characteristic: false,
+        }
+    }
+
+    /// Starts a new primary service of type `uuid`.
+    ///
+    /// Characteristics added afterwards are part of this service, until `service` is called
+    /// again.
+    ///
+    /// Returns the handle of the service declaration, or `Error::Eof` if the table is full.
+    pub fn service(&mut self, uuid: impl Into<AttUuid>) -> Result<Handle, Error> {
+        self.reserve(1)?;
+        let uuid = uuid.into();
+        let handle = self.push(
+            PRIMARY_SERVICE.into(),
+            Value::declaration(|writer| uuid.to_bytes(writer))?,
+        );
+        let index = self.entries.len() - 1;
+        self.entries[index].group_end = Some(handle);
+        self.service = Some(index);
+        self.characteristic = false;
+        Ok(handle)
+    }
+
+    /// Adds a characteristic of type `C` with value `value` to the current service.
+    ///
+    /// Returns the handle of the characteristic value. Refer to `characteristic_with` for possible
+    /// errors.
+    pub fn characteristic<C: Characteristic>(&mut self, value: &'a [u8]) -> Result<Handle, Error> {
+        self.characteristic_with(C::PROPS, C::UUID, value)
+    }
+
+    /// Adds a characteristic with properties `props`, type `uuid`, and value `value` to the
+    /// current service.
+    ///
+    /// If `props` contains `NOTIFY` or `INDICATE`, a CCCD is added after the value.
+    ///
+    /// Returns the handle of the characteristic value. Returns `Error::InvalidValue` if no service
+    /// was started, and `Error::Eof` if the table is full.
+    pub fn characteristic_with(
+        &mut self,
+        props: Properties,
+        uuid: impl Into<AttUuid>,
+        value: &'a [u8],
+    ) -> Result<Handle, Error> {
+        if self.service.is_none() {
+            return Err(Error::InvalidValue);
+        }
+
+        let has_cccd = props.intersects(Properties::NOTIFY | Properties::INDICATE);
+        self.reserve(if has_cccd { 3 } else { 2 })?;
+
+        // The value directly follows the declaration
+        let uuid = uuid.into();
+        let value_handle = Handle::from_raw(self.entries.len() as u16 + 2);
+        self.push(
+            CHARACTERISTIC.into(),
+            Value::declaration(|writer| {
+                writer.write_u8(props.bits())?;
+                value_handle.to_bytes(writer)?;
+                uuid.to_bytes(writer)
+            })?,
+        );
+        self.push_borrowed(uuid, value);
+        if has_cccd {
+            self.push_borrowed(CCCD.into(), &[0x00, 0x00]);
+        }
+
+        self.characteristic = true;
+        Ok(value_handle)
+    }
+
+    /// Adds a descriptor of type `uuid` with value `value` to the last added characteristic.
+    ///
+    /// Returns the handle of the descriptor. Returns `Error::InvalidValue` if the current service
+    /// has no characteristic yet, and `Error::Eof` if the table is full.
+    pub fn descriptor(
+        &mut self,
+        uuid: impl Into<AttUuid>,
+        value: &'a [u8],
+    ) -> Result<Handle, Error> {
+        if !self.characteristic {
+            return Err(Error::InvalidValue);
+        }
+
+        self.reserve(1)?;
+        Ok(self.push_borrowed(uuid.into(), value))
+    }
+
+    /// Replaces the value of the characteristic value or descriptor at `handle` with `value`.
+    ///
+    /// Returns `Error::InvalidValue` if `handle` doesn't refer to a value added via
+    /// `characteristic`, `characteristic_with` or `descriptor`. Declarations are generated by the
+    /// table and CCCD values are managed by the `AttributeServer`, so neither can be replaced.
+    pub fn set_value(&mut self, handle: Handle, value: &'a [u8]) -> Result<(), Error> {
+        let index = usize::from(handle.as_u16()).checked_sub(1);
+        match index.and_then(|index| self.entries.get_mut(index)) {
+            Some(entry) if entry.att_type != CCCD => match &mut entry.value {
+                Value::Borrowed(old) => {
+                    *old = value;
+                    Ok(())
+                }
+                Value::Declaration { .. } => Err(Error::InvalidValue),
+            },
+            _ => Err(Error::InvalidValue),
+        }
+    }
+
+    /// Returns an iterator over all attributes whose handle is inside `range`, ascending.
+    ///
+    /// This allows wrapping an `AttributeTable` in another `AttributeProvider`.
+    pub fn attrs_in_range(&self, range: HandleRange) -> impl Iterator<Item = Attribute<'_>> {
+        // Handles start at 1, not 0. The invalid handle 0 yields no attributes.
+        let start = range.start().as_u16().checked_sub(1).map(usize::from);
+        let end = cmp::min(usize::from(range.end().as_u16()), self.entries.len());
+        let entries = start
+            .and_then(|start| self.entries.get(start..end))
+            .unwrap_or(&[]);
+        let start = start.unwrap_or(0);
+
+        entries.iter().enumerate().map(move |(i, entry)| Attribute {
+            att_type: entry.att_type,
+            handle: Handle::from_raw((start + i + 1) as u16),
+            value: HexSlice(entry.value.as_slice()),
+        })
+    }
+
+    /// Returns `Error::Eof` if fewer than `count` attributes can be added.
+    fn reserve(&self, count: usize) -> Result<(), Error> {
+        let len = self.entries.len() + count;
+        if len > self.entries.capacity() || len > 0xFFFF {
+            Err(Error::Eof)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Appends an attribute and returns its handle.
+    ///
+    /// `reserve` must have been called to make room.
+    fn push(&mut self, att_type: AttUuid, value: Value<'a>) -> Handle {
+        let entry = TableEntry {
+            att_type,
+            value,
+            group_end: None,
+        };
+        if self.entries.push(entry).is_err() {
+            unreachable!("table space was reserved");
+        }
+        Handle::from_raw(self.entries.len() as u16)
+    }
+
+    /// Appends an attribute belonging to the current service and returns its handle.
+    fn push_borrowed(&mut self, att_type: AttUuid, value: &'a [u8]) -> Handle {
+        let handle = self.push(att_type, Value::Borrowed(value));
+        let service = self.service.expect("no service started");
+        self.entries[service].group_end = Some(handle);
+        handle
+    }
+}
+
+impl<'a, N: ArrayLength<TableEntry<'a>>> Default for AttributeTable<'a, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, N: ArrayLength<TableEntry<'a>>> AttributeProvider for AttributeTable<'a, N> {
+    fn for_attrs_in_range(
+        &self,
+        range: HandleRange,
+        mut f: impl FnMut(&Self, Attribute<'_>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        for attr in self.attrs_in_range(range) {
+            f(self, attr)?;
+        }
+        Ok(())
+    }
+
+    fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+        uuid == PRIMARY_SERVICE
+    }
+
+    fn group_end(&self, handle: Handle) -> Option<Handle> {
+        let index = usize::from(handle.as_u16()).checked_sub(1)?;
+        self.entries.get(index)?.group_end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gatt::characteristic::BatteryLevel;
+    use crate::uuid::{Uuid128, Uuid16};
+    use heapless::consts::U8;
+    use std::vec::Vec;
+
+    fn collect(table: &AttributeTable<'_, U8>) -> Vec<(u16, AttUuid, Vec<u8>)> {
+        let all = HandleRange::new(Handle::from_raw(0x0001), Handle::from_raw(0xFFFF));
+        table
+            .attrs_in_range(all)
+            .map(|attr| (attr.handle.as_u16(), attr.att_type, attr.value().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn layout() {
+        let raw = [
+            0xF3, 0x6B, 0x10, 0x9D, 0x66, 0xF2, 0xA9, 0xA1, 0x12, 0x41, 0x68, 0x38, 0xDB, 0xE5,
+            0x72, 0x77,
+        ];
+        let uuid = Uuid128::from_bytes(raw);
+
+        let mut table = AttributeTable::<U8>::new();
+        assert_eq!(
+            table.characteristic::<BatteryLevel>(&[50]),
+            Err(Error::InvalidValue)
+        );
+        assert_eq!(table.service(Uuid16(0x180F)), Ok(Handle::from_raw(1)));
+        assert_eq!(
+            table.descriptor(Uuid16(0x2901), b"level"),
+            Err(Error::InvalidValue)
+        );
+        assert_eq!(
+            table.characteristic::<BatteryLevel>(&[50]),
+            Ok(Handle::from_raw(3))
+        );
+        assert_eq!(
+            table.descriptor(Uuid16(0x2901), b"level"),
+            Ok(Handle::from_raw(4))
+        );
+        assert_eq!(table.service(uuid), Ok(Handle::from_raw(5)));
+        assert_eq!(
+            table.characteristic_with(Properties::NOTIFY, uuid, &[]),
+            Ok(Handle::from_raw(7))
+        );
+
+        let mut declaration = vec![0x10, 0x07, 0x00];
+        declaration.extend_from_slice(&raw);
+        assert_eq!(
+            collect(&table),
+            [
+                (1, Uuid16(0x2800).into(), vec![0x0F, 0x18]),
+                (2, Uuid16(0x2803).into(), vec![0x0A, 0x03, 0x00, 0x19, 0x2A]),
+                (3, Uuid16(0x2A19).into(), vec![50]),
+                (4, Uuid16(0x2901).into(), b"level".to_vec()),
+                (5, Uuid16(0x2800).into(), raw.to_vec()),
+                (6, Uuid16(0x2803).into(), declaration),
+                (7, uuid.into(), vec![]),
+                (8, Uuid16(0x2902).into(), vec![0x00, 0x00]),
+            ]
+        );
+
+        assert_eq!(
+            table.group_end(Handle::from_raw(1)),
+            Some(Handle::from_raw(4))
+        );
+        assert_eq!(
+            table.group_end(Handle::from_raw(5)),
+            Some(Handle::from_raw(8))
+        );
+        assert!(table.group_end(Handle::from_raw(2)).is_none());
+        assert!(table.group_end(Handle::from_raw(9)).is_none());
+
+        // Full tables are left unchanged
+        assert_eq!(table.service(Uuid16(0x1800)), Err(Error::Eof));
+        assert_eq!(collect(&table).len(), 8);
+    }
+
+    #[test]
+    fn set_value() {
+        let mut table = AttributeTable::<U8>::new();
+        table.service(Uuid16(0x180F)).unwrap();
+        let level = table
+            .characteristic_with(Properties::READ | Properties::NOTIFY, Uuid16(0x2A19), &[50])
+            .unwrap();
+
+        assert_eq!(table.set_value(level, &[40]), Ok(()));
+        assert_eq!(collect(&table)[2], (3, Uuid16(0x2A19).into(), vec![40]));
+
+        // Declarations, CCCDs and missing attributes can't be changed
+        for &handle in &[0, 1, 2, 4, 5] {
+            assert_eq!(
+                table.set_value(Handle::from_raw(handle), &[1]),
+                Err(Error::InvalidValue)
+            );
+        }
+        assert_eq!(collect(&table)[3], (4, Uuid16(0x2902).into(), vec![0, 0]));
+    }
+}
@@ -0,0 +1,373 @@
+//! HCI commands sent from the host to the controller.
+
+use crate::bytes::{ByteReader, FromBytes};
+use crate::link::advertising::{AdvertisingParameters, AdvertisingType};
+use crate::link::{AddressKind, DeviceAddress};
+use crate::phy::AdvertisingChannelMap;
+use crate::time::Duration;
+use crate::Error;
+
+enum_with_unknown! {
+    /// HCI command opcodes supported by the `Controller`.
+    ///
+    /// The opcode combines the Opcode Group Field (OGF, upper 6 bits) and the Opcode Command Field
+    /// (OCF, lower 10 bits).
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum Opcode(u16) {
+        Disconnect = 0x0406,
+        Reset = 0x0C03,
+        ReadBdAddr = 0x1009,
+        LeReadBufferSize = 0x2002,
+        LeSetAdvertisingParameters = 0x2006,
+        LeSetAdvertisingData = 0x2008,
+        LeSetScanResponseData = 0x2009,
+        LeSetAdvertisingEnable = 0x200A,
+        LeSetScanParameters = 0x200B,
+        LeSetScanEnable = 0x200C,
+        LeCreateConnection = 0x200D,
+    }
+}
+
+/// Max. length of the advertising and scan response data set via HCI.
+pub const MAX_ADV_DATA_LEN: usize = 31;
+
+/// A parsed HCI command packet.
+#[derive(Debug, Copy, Clone)]
+pub enum Command<'a> {
+    /// Terminates the connection identified by `handle`.
+    Disconnect {
+        /// Connection handle.
+        handle: u16,
+        /// Error code sent to the remote device.
+        reason: u8,
+    },
+
+    /// Resets the controller to its initial state.
+    Reset,
+
+    /// Reads the public device address of the controller.
+    ReadBdAddr,
+
+    /// Reads the size and number of the controller's ACL data buffers.
+    LeReadBufferSize,
+
+    /// Sets the advertising parameters used by the next `LeSetAdvertisingEnable` command.
+    LeSetAdvertisingParameters(AdvertisingParameters),
+
+    /// Sets the advertising data.
+    LeSetAdvertisingData {
+        /// Raw AD structures.
+        data: &'a [u8],
+    },
+
+    /// Sets the scan response data.
+    LeSetScanResponseData {
+        /// Raw AD structures.
+        data: &'a [u8],
+    },
+
+    /// Starts or stops advertising.
+    LeSetAdvertisingEnable {
+        /// Whether to advertise.
+        enable: bool,
+    },
+
+    /// Sets the scan parameters used by the next `LeSetScanEnable` command.
+    LeSetScanParameters {
+        /// Whether to send scan requests to scannable advertisers.
+        active: bool,
+        /// Time spent listening on each advertising channel.
+        interval: Duration,
+    },
+
+    /// Starts or stops scanning.
+    LeSetScanEnable {
+        /// Whether to scan.
+        enable: bool,
+    },
+
+    /// Starts connecting to an advertiser.
+    LeCreateConnection(CreateConnection),
+
+    /// A command not supported by the `Controller`.
+    ///
+    /// Its parameters are not parsed.
+    Unknown(Opcode),
+}
+
+impl Command<'_> {
+    /// Returns the opcode of this command.
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Command::Disconnect { .. } => Opcode::Disconnect,
+            Command::Reset => Opcode::Reset,
+            Command::ReadBdAddr => Opcode::ReadBdAddr,
+            Command::LeReadBufferSize => Opcode::LeReadBufferSize,
+            Command::LeSetAdvertisingParameters(_) => Opcode::LeSetAdvertisingParameters,
+            Command::LeSetAdvertisingData { .. } => Opcode::LeSetAdvertisingData,
+            Command::LeSetScanResponseData { .. } => Opcode::LeSetScanResponseData,
+            Command::LeSetAdvertisingEnable { .. } => Opcode::LeSetAdvertisingEnable,
+            Command::LeSetScanParameters { .. } => Opcode::LeSetScanParameters,
+            Command::LeSetScanEnable { .. } => Opcode::LeSetScanEnable,
+            Command::LeCreateConnection(_) => Opcode::LeCreateConnection,
+            Command::Unknown(opcode) => *opcode,
+        }
+    }
+}
+
+/// Parses a command packet (without the H4 packet indicator).
+///
+/// Returns `Error::InvalidLength` or `Error::IncompleteParse` if the parameter length doesn't
+/// match the command, and `Error::InvalidValue` if a parameter is out of range or requests a
+/// feature the `Controller` doesn't support (such as white lists).
+impl<'a> FromBytes<'a> for Command<'a> {
+    fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
+        let opcode = Opcode::from(bytes.read_u16_le()?);
+        let len = bytes.read_u8()?;
+        let params = &mut bytes.split_off(len.into())?;
+
+        let cmd = match opcode {
+            Opcode::Disconnect => Command::Disconnect {
+                handle: params.read_u16_le()?,
+                reason: params.read_u8()?,
+            },
+            Opcode::Reset => Command::Reset,
+            Opcode::ReadBdAddr => Command::ReadBdAddr,
+            Opcode::LeReadBufferSize => Command::LeReadBufferSize,
+            Opcode::LeSetAdvertisingParameters => {
+                Command::LeSetAdvertisingParameters(read_advertising_parameters(params)?)
+            }
+            Opcode::LeSetAdvertisingData => Command::LeSetAdvertisingData {
+                data: read_adv_data(params)?,
+            },
+            Opcode::LeSetScanResponseData => Command::LeSetScanResponseData {
+                data: read_adv_data(params)?,
+            },
+            Opcode::LeSetAdvertisingEnable => Command::LeSetAdvertisingEnable {
+                enable: read_bool(params)?,
+            },
+            Opcode::LeSetScanParameters => {
+                let active = read_bool(params)?;
+                let interval = read_duration(params, 625)?;
+                let _window = params.read_u16_le()?;
+                let _own_addr_kind = params.read_u8()?;
+                if params.read_u8()? != 0 {
+                    // White lists are not supported
+                    return Err(Error::InvalidValue);
+                }
+                Command::LeSetScanParameters { active, interval }
+            }
+            Opcode::LeSetScanEnable => {
+                let enable = read_bool(params)?;
+                let _filter_duplicates = read_bool(params)?;
+                Command::LeSetScanEnable { enable }
+            }
+            Opcode::LeCreateConnection => {
+                Command::LeCreateConnection(CreateConnection::from_bytes(params)?)
+            }
+            Opcode::Unknown(_) => {
+                params.read_rest();
+                Command::Unknown(opcode)
+            }
+        };
+
+        if params.is_empty() {
+            Ok(cmd)
+        } else {
+            Err(Error::IncompleteParse)
+        }
+    }
+}
+
+/// Parameters of the *LE Create Connection* command.
+#[derive(Debug, Copy, Clone)]
+pub struct CreateConnection {
+    peer: DeviceAddress,
+    interval: Duration,
+    slave_latency: u16,
+    supervision_timeout: Duration,
+}
+
+impl CreateConnection {
+    /// Returns the address of the advertiser to connect to.
+    pub fn peer(&self) -> DeviceAddress {
+        self.peer
+    }
+
+    /// Returns the connection interval to use.
+    ///
+    /// This is the minimum interval requested by the host.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Returns the slave latency to use.
+    pub fn slave_latency(&self) -> u16 {
+        self.slave_latency
+    }
+
+    /// Returns the supervision timeout to use.
+    pub fn supervision_timeout(&self) -> Duration {
+        self.supervision_timeout
+    }
+}
+
+impl<'a> FromBytes<'a> for CreateConnection {
+    fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
+        let _scan_interval = bytes.read_u16_le()?;
+        let _scan_window = bytes.read_u16_le()?;
+        if bytes.read_u8()? != 0 {
+            // White lists are not supported
+            return Err(Error::InvalidValue);
+        }
+        let peer = read_address(bytes)?;
+        let _own_addr_kind = bytes.read_u8()?;
+        let interval = read_duration(bytes, 1250)?;
+        let _interval_max = bytes.read_u16_le()?;
+        let slave_latency = bytes.read_u16_le()?;
+        let supervision_timeout = read_duration(bytes, 10_000)?;
+        let _min_ce_length = bytes.read_u16_le()?;
+        let _max_ce_length = bytes.read_u16_le()?;
+        Ok(Self {
+            peer,
+            interval,
+            slave_latency,
+            supervision_timeout,
+        })
+    }
+}
+
+fn read_advertising_parameters(bytes: &mut ByteReader<'_>) -> Result<AdvertisingParameters, Error> {
+    let mut interval_min = read_duration(bytes, 625)?;
+    let mut interval_max = read_duration(bytes, 625)?;
+    let raw_ty = bytes.read_u8()?;
+    let _own_addr_kind = bytes.read_u8()?;
+    let peer = read_address(bytes)?;
+    let channels = AdvertisingChannelMap::from_raw(bytes.read_u8()?)?;
+    if bytes.read_u8()? != 0 {
+        // White lists are not supported
+        return Err(Error::InvalidValue);
+    }
+
+    let ty = match raw_ty {
+        0x00 => AdvertisingType::ConnectableUndirected,
+        0x01 | 0x04 => AdvertisingType::ConnectableDirected { initiator: peer },
+        0x02 => AdvertisingType::ScannableUndirected,
+        0x03 => AdvertisingType::NonconnectableUndirected,
+        _ => return Err(Error::InvalidValue),
+    };
+    if raw_ty == 0x01 {
+        // High duty cycle directed advertising ignores the intervals. We approximate it with the
+        // shortest interval allowed for low duty cycle advertising.
+        interval_min = Duration::from_millis(20);
+        interval_max = interval_min;
+    }
+
+    let mut params = AdvertisingParameters::new(ty, interval_min, interval_max)?;
+    params.set_channels(channels);
+    Ok(params)
+}
+
+/// Reads the fixed-size advertising data parameters, returning the used part.
+fn read_adv_data<'a>(bytes: &mut ByteReader<'a>) -> Result<&'a [u8], Error> {
+    let len = usize::from(bytes.read_u8()?);
+    let data = bytes.read_slice(MAX_ADV_DATA_LEN)?;
+    if len > MAX_ADV_DATA_LEN {
+        return Err(Error::InvalidLength);
+    }
+    Ok(&data[..len])
+}
+
+fn read_address(bytes: &mut ByteReader<'_>) -> Result<DeviceAddress, Error> {
+    let kind = match bytes.read_u8()? {
+        0x00 => AddressKind::Public,
+        0x01 => AddressKind::Random,
+        _ => return Err(Error::InvalidValue),
+    };
+    Ok(DeviceAddress::new(bytes.read_array()?, kind))
+}
+
+fn read_bool(bytes: &mut ByteReader<'_>) -> Result<bool, Error> {
+    match bytes.read_u8()? {
+        0x00 => Ok(false),
+        0x01 => Ok(true),
+        _ => Err(Error::InvalidValue),
+    }
+}
+
+/// Reads a 16-bit time value in units of `unit` µs.
+fn read_duration(bytes: &mut ByteReader<'_>, unit: u32) -> Result<Duration, Error> {
+    Ok(Duration::from_micros(
+        u32::from(bytes.read_u16_le()?) * unit,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> Result<Command<'_>, Error> {
+        Command::from_bytes(&mut ByteReader::new(bytes))
+    }
+
+    #[test]
+    fn advertising_parameters() {
+        // 100 ms, ADV_IND, public own address, all channels
+        let bytes = [
+            0x06, 0x20, 0x0F, 0xA0, 0x00, 0xA0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x07, 0x00,
+        ];
+        match parse(&bytes).unwrap() {
+            Command::LeSetAdvertisingParameters(params) => {
+                assert_eq!(
+                    params.advertising_type(),
+                    AdvertisingType::ConnectableUndirected
+                );
+                assert_eq!(params.interval_min(), Duration::from_millis(100));
+                assert_eq!(params.channels().to_raw(), 0x07);
+            }
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+
+        // Interval below 20 ms
+        let mut short = bytes;
+        short[3] = 0x10;
+        assert_eq!(parse(&short).unwrap_err(), Error::InvalidValue);
+
+        // Missing parameter
+        let mut truncated = bytes;
+        truncated[2] = 0x0E;
+        assert!(parse(&truncated[..17]).is_err());
+    }
+
+    #[test]
+    fn create_connection() {
+        let bytes = [
+            0x0D, 0x20, 0x19, 0x60, 0x00, 0x30, 0x00, 0x00, 0x01, 0x06, 0x05, 0x04, 0x03, 0x02,
+            0x01, 0x00, 0x18, 0x00, 0x28, 0x00, 0x00, 0x00, 0x2A, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        match parse(&bytes).unwrap() {
+            Command::LeCreateConnection(params) => {
+                assert_eq!(
+                    params.peer(),
+                    DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Random)
+                );
+                assert_eq!(params.interval(), Duration::from_millis(30));
+                assert_eq!(params.slave_latency(), 0);
+                assert_eq!(params.supervision_timeout(), Duration::from_millis(420));
+            }
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+    }
+
+    #[test]
+    fn unknown() {
+        // HCI_Read_Local_Version_Information
+        let cmd = parse(&[0x01, 0x10, 0x00]).unwrap();
+        assert_eq!(cmd.opcode(), Opcode::Unknown(0x1001));
+        assert_eq!(
+            parse(&[0x03, 0x0C, 0x01, 0x00]).unwrap_err(),
+            Error::IncompleteParse
+        );
+    }
+}
@@ -0,0 +1,275 @@
+//! HCI events sent from the controller to the host.
+
+use super::command::Opcode;
+use crate::bytes::{ByteWriter, ToBytes};
+use crate::link::advertising::PduType;
+use crate::link::{AddressKind, DeviceAddress};
+use crate::time::Duration;
+use crate::Error;
+
+enum_with_unknown! {
+    /// HCI status and error codes.
+    ///
+    /// These are used as the status of completed commands and as the reason for disconnections.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum Status(u8) {
+        Success = 0x00,
+        UnknownCommand = 0x01,
+        UnknownConnectionId = 0x02,
+        ConnectionTimeout = 0x08,
+        CommandDisallowed = 0x0C,
+        InvalidParameters = 0x12,
+        RemoteUserTerminated = 0x13,
+        LocalHostTerminated = 0x16,
+        ConnectionTerminatedMicFailure = 0x3D,
+        ConnectionFailedToBeEstablished = 0x3E,
+    }
+}
+
+enum_with_unknown! {
+    /// HCI event codes.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum EventCode(u8) {
+        DisconnectionComplete = 0x05,
+        CommandComplete = 0x0E,
+        CommandStatus = 0x0F,
+        NumberOfCompletedPackets = 0x13,
+        LeMeta = 0x3E,
+    }
+}
+
+/// An HCI event.
+#[derive(Debug, Copy, Clone)]
+pub enum Event<'a> {
+    /// A connection was terminated.
+    DisconnectionComplete {
+        /// Handle of the terminated connection.
+        handle: u16,
+        /// Why the connection was terminated.
+        reason: Status,
+    },
+
+    /// A command has been executed.
+    CommandComplete {
+        /// Opcode of the command.
+        opcode: Opcode,
+        /// Result of the command.
+        status: Status,
+        /// Command-specific return parameters following the status.
+        params: &'a [u8],
+    },
+
+    /// A command has been started and will complete later.
+    CommandStatus {
+        /// Opcode of the command.
+        opcode: Opcode,
+        /// Whether the command was started successfully.
+        status: Status,
+    },
+
+    /// ACL data packets of a connection have been sent (or dropped), freeing controller buffers.
+    NumberOfCompletedPackets {
+        /// Connection handle.
+        handle: u16,
+        /// Number of packets completed since the last event.
+        count: u16,
+    },
+
+    /// A connection was established (LE Meta event).
+    LeConnectionComplete {
+        /// Whether establishing the connection succeeded.
+        status: Status,
+        /// Handle of the new connection.
+        handle: u16,
+        /// Whether we are the master of the connection.
+        master: bool,
+        /// Address of the connected device.
+        peer: DeviceAddress,
+        /// Connection event interval.
+        interval: Duration,
+        /// Slave latency in connection events.
+        slave_latency: u16,
+        /// Supervision timeout.
+        supervision_timeout: Duration,
+    },
+
+    /// An advertising or scan response PDU was received (LE Meta event).
+    LeAdvertisingReport {
+        /// Type of the received PDU.
+        pdu_type: PduType,
+        /// Address of the advertiser.
+        address: DeviceAddress,
+        /// Advertising or scan response data.
+        data: &'a [u8],
+        /// Signal strength in dBm, or 127 if not available.
+        rssi: i8,
+    },
+}
+
+impl Event<'_> {
+    /// Returns the event code of this event.
+    pub fn code(&self) -> EventCode {
+        match self {
+            Event::DisconnectionComplete { .. } => EventCode::DisconnectionComplete,
+            Event::CommandComplete { .. } => EventCode::CommandComplete,
+            Event::CommandStatus { .. } => EventCode::CommandStatus,
+            Event::NumberOfCompletedPackets { .. } => EventCode::NumberOfCompletedPackets,
+            Event::LeConnectionComplete { .. } | Event::LeAdvertisingReport { .. } => {
+                EventCode::LeMeta
+            }
+        }
+    }
+
+    fn write_params(&self, writer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        match *self {
+            Event::DisconnectionComplete { handle, reason } => {
+                writer.write_u8(Status::Success.into())?;
+                writer.write_u16_le(handle)?;
+                writer.write_u8(reason.into())?;
+            }
+            Event::CommandComplete {
+                opcode,
+                status,
+                params,
+            } => {
+                // The controller can always accept the next command
+                writer.write_u8(1)?;
+                writer.write_u16_le(opcode.into())?;
+                writer.write_u8(status.into())?;
+                writer.write_slice(params)?;
+            }
+            Event::CommandStatus { opcode, status } => {
+                writer.write_u8(status.into())?;
+                writer.write_u8(1)?;
+                writer.write_u16_le(opcode.into())?;
+            }
+            Event::NumberOfCompletedPackets { handle, count } => {
+                writer.write_u8(1)?;
+                writer.write_u16_le(handle)?;
+                writer.write_u16_le(count)?;
+            }
+            Event::LeConnectionComplete {
+                status,
+                handle,
+                master,
+                peer,
+                interval,
+                slave_latency,
+                supervision_timeout,
+            } => {
+                writer.write_u8(0x01)?;
+                writer.write_u8(status.into())?;
+                writer.write_u16_le(handle)?;
+                writer.write_u8(if master { 0x00 } else { 0x01 })?;
+                write_address(writer, peer)?;
+                writer.write_u16_le((interval.as_micros() / 1250) as u16)?;
+                writer.write_u16_le(slave_latency)?;
+                writer.write_u16_le((supervision_timeout.as_micros() / 10_000) as u16)?;
+                // Master clock accuracy: We don't know it, so report the worst
+                writer.write_u8(0x00)?;
+            }
+            Event::LeAdvertisingReport {
+                pdu_type,
+                address,
+                data,
+                rssi,
+            } => {
+                writer.write_u8(0x02)?;
+                writer.write_u8(1)?;
+                writer.write_u8(match pdu_type {
+                    PduType::AdvInd => 0x00,
+                    PduType::AdvDirectInd => 0x01,
+                    PduType::AdvScanInd => 0x02,
+                    PduType::AdvNonconnInd => 0x03,
+                    PduType::ScanRsp => 0x04,
+                    _ => return Err(Error::InvalidValue),
+                })?;
+                write_address(writer, address)?;
+                writer.write_u8(data.len() as u8)?;
+                writer.write_slice(data)?;
+                writer.write_u8(rssi as u8)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Encodes the event code, parameter length and parameters of the event.
+impl ToBytes for Event<'_> {
+    fn to_bytes(&self, writer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        writer.write_u8(self.code().into())?;
+        let len = writer.split_next_mut().ok_or(Error::Eof)?;
+        let space = writer.space_left();
+        self.write_params(writer)?;
+        *len = (space - writer.space_left()) as u8;
+        Ok(())
+    }
+}
+
+fn write_address(writer: &mut ByteWriter<'_>, address: DeviceAddress) -> Result<(), Error> {
+    writer.write_u8(match address.kind() {
+        AddressKind::Public => 0x00,
+        AddressKind::Random => 0x01,
+    })?;
+    writer.write_slice(address.raw())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(event: Event<'_>) -> Vec<u8> {
+        let mut buf = [0; 64];
+        let mut writer = ByteWriter::new(&mut buf);
+        event.to_bytes(&mut writer).unwrap();
+        let len = 64 - writer.space_left();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn command_complete() {
+        let event = Event::CommandComplete {
+            opcode: Opcode::LeReadBufferSize,
+            status: Status::Success,
+            params: &[27, 0, 1],
+        };
+        assert_eq!(
+            encode(event),
+            [0x0E, 0x07, 0x01, 0x02, 0x20, 0x00, 27, 0, 1]
+        );
+    }
+
+    #[test]
+    fn connection_complete() {
+        let event = Event::LeConnectionComplete {
+            status: Status::Success,
+            handle: 0x0001,
+            master: false,
+            peer: DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Random),
+            interval: Duration::from_millis(30),
+            slave_latency: 0,
+            supervision_timeout: Duration::from_millis(1000),
+        };
+        assert_eq!(
+            encode(event),
+            [
+                0x3E, 0x13, 0x01, 0x00, 0x01, 0x00, 0x01, 0x01, 6, 5, 4, 3, 2, 1, 0x18, 0x00, 0x00,
+                0x00, 0x64, 0x00, 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn advertising_report() {
+        let event = Event::LeAdvertisingReport {
+            pdu_type: PduType::ScanRsp,
+            address: DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Public),
+            data: &[0x02, 0x01, 0x06],
+            rssi: -40,
+        };
+        assert_eq!(
+            encode(event),
+            [0x3E, 0x0F, 0x02, 0x01, 0x04, 0x00, 1, 2, 3, 4, 5, 6, 0x03, 0x02, 0x01, 0x06, 0xD8]
+        );
+    }
+}
@@ -0,0 +1,895 @@
+//! Host Controller Interface (HCI) over an H4 UART transport.
+//!
+//! This module turns a Rubble [`LinkLayer`] into a BLE controller that can be driven by a host
+//! stack running on another processor (eg. BlueZ on Linux via `btattach`). The host sends HCI
+//! command and ACL data packets over a serial line, and the [`Controller`] answers with HCI event
+//! and ACL data packets.
+//!
+//! The H4 transport prefixes every packet with a 1-Byte packet indicator:
+//!
+//! | Indicator | Packet      | Direction          |
+//! |-----------|-------------|--------------------|
+//! | `0x01`    | Command     | Host to Controller |
+//! | `0x02`    | ACL Data    | Both               |
+//! | `0x04`    | Event       | Controller to Host |
+//!
+//! The [`H4Decoder`] reassembles packets from the bytes received over the serial line, which are
+//! then passed to [`Controller::process_packet`]. All packets sent to the host are written to an
+//! [`H4Sink`].
+//!
+//! Only a small subset of HCI is supported: Enough to advertise, scan, and establish a single
+//! connection in either role. See [`command::Opcode`] for the supported commands.
+//!
+//! [`LinkLayer`]: ../link/struct.LinkLayer.html
+//! [`Controller`]: struct.Controller.html
+//! [`Controller::process_packet`]: struct.Controller.html#method.process_packet
+//! [`H4Decoder`]: struct.H4Decoder.html
+//! [`H4Sink`]: trait.H4Sink.html
+//! [`command::Opcode`]: command/enum.Opcode.html
+
+pub mod command;
+pub mod event;
+
+use self::command::{Command, Opcode, MAX_ADV_DATA_LEN};
+use self::event::{Event, Status};
+use crate::bytes::{ByteReader, ByteWriter, BytesOr, FromBytes, ToBytes};
+use crate::link::ad_structure::AdStructure;
+use crate::link::advertising::{self, AdvertisingParameters, AdvertisingType};
+use crate::link::advertising::{ConnectRequestData, PduType};
+use crate::link::data::{self, Llid, Pdu};
+use crate::link::filter::AllowAll;
+use crate::link::llcp::ControlPdu;
+use crate::link::queue::{Consume, Consumer, PacketQueue, Producer};
+use crate::link::{ChannelMap, Cmd, DeviceAddress, LinkLayer, NextUpdate, RadioCmd};
+use crate::link::{ReportCallback, ScanReport, Scanner, MAX_DATA_PAYLOAD_BUF};
+use crate::time::{Duration, Instant, Timer};
+use crate::{config::*, utils::XorShift32, Error};
+use core::mem;
+use heapless::{consts::U4, Vec};
+use rand_core::RngCore;
+
+enum_with_unknown! {
+    /// H4 packet indicators.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum PacketIndicator(u8) {
+        Command = 0x01,
+        AclData = 0x02,
+        SyncData = 0x03,
+        Event = 0x04,
+    }
+}
+
+/// Handle of the (only) connection managed by the `Controller`.
+pub const CONNECTION_HANDLE: u16 = 0x0001;
+
+/// Max. size of an ACL data packet accepted from the host.
+///
+/// Every packet is sent in a single data channel PDU, so this is the minimum payload size all
+/// devices support.
+pub const ACL_BUFFER_SIZE: u16 = 27;
+
+/// RSSI reported in advertising reports, meaning "not available".
+const RSSI_UNAVAILABLE: i8 = 127;
+
+/// Size of the buffer an `H4Decoder` reassembles packets in.
+///
+/// This fits a command packet with the max. parameter length of 255 Bytes.
+const H4_BUF: usize = 1 + 3 + 255;
+
+/// Size of the buffer outgoing packets are encoded in.
+const OUT_BUF: usize = 1 + 4 + MAX_DATA_PAYLOAD_BUF;
+
+/// Receives the H4 packets sent to the host.
+pub trait H4Sink {
+    /// Sends a complete packet, starting with its packet indicator, to the host.
+    ///
+    /// This is typically implemented by writing `packet` to a UART. The `Controller` does not
+    /// handle errors, so implementations have to deal with them (eg. by dropping the packet).
+    fn write_packet(&mut self, packet: &[u8]);
+}
+
+/// A packet received from the host.
+#[derive(Debug, Copy, Clone)]
+pub enum H4Packet<'a> {
+    /// A command packet (opcode, parameter length and parameters).
+    Command(&'a [u8]),
+
+    /// An ACL data packet (handle and flags, data length and data).
+    AclData(&'a [u8]),
+}
+
+/// Reassembles H4 packets from a byte stream sent by the host.
+pub struct H4Decoder {
+    buf: [u8; H4_BUF],
+    len: usize,
+
+    /// Number of Bytes of a rejected packet that still have to be skipped.
+    skip: u16,
+}
+
+impl H4Decoder {
+    /// Creates a decoder expecting the start of a packet.
+    pub fn new() -> Self {
+        Self {
+            buf: [0; H4_BUF],
+            len: 0,
+            skip: 0,
+        }
+    }
+
+    /// Feeds the next received `byte` to the decoder.
+    ///
+    /// Returns the decoded packet once it is complete.
+    ///
+    /// Returns `Error::InvalidValue` if `byte` should start a packet, but isn't the indicator of a
+    /// packet the host may send to the controller, and `Error::InvalidLength` if an ACL data
+    /// packet is larger than `ACL_BUFFER_SIZE`. The offending byte or packet is skipped, and
+    /// decoding continues with the next packet.
+    pub fn push(&mut self, byte: u8) -> Result<Option<H4Packet<'_>>, Error> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return Ok(None);
+        }
+
+        if self.len == 0 {
+            match PacketIndicator::from(byte) {
+                PacketIndicator::Command | PacketIndicator::AclData => {}
+                _ => return Err(Error::InvalidValue),
+            }
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        let (header_len, payload_len) = match PacketIndicator::from(self.buf[0]) {
+            PacketIndicator::Command if self.len >= 4 => (4, usize::from(self.buf[3])),
+            PacketIndicator::AclData if self.len >= 5 => {
+                let len = u16::from_le_bytes([self.buf[3], self.buf[4]]);
+                if len > ACL_BUFFER_SIZE {
+                    self.len = 0;
+                    self.skip = len;
+                    return Err(Error::InvalidLength);
+                }
+                (5, usize::from(len))
+            }
+            _ => return Ok(None),
+        };
+
+        if self.len < header_len + payload_len {
+            return Ok(None);
+        }
+
+        let len = self.len;
+        self.len = 0;
+        let packet = &self.buf[1..len];
+        Ok(Some(match PacketIndicator::from(self.buf[0]) {
+            PacketIndicator::Command => H4Packet::Command(packet),
+            _ => H4Packet::AclData(packet),
+        }))
+    }
+}
+
+impl Default for H4Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An advertising or scan response PDU to report to the host.
+struct Report {
+    pdu_type: PduType,
+    address: DeviceAddress,
+    data: [u8; MAX_ADV_DATA_LEN],
+    data_len: u8,
+}
+
+impl Report {
+    fn new(
+        pdu_type: PduType,
+        address: DeviceAddress,
+        data: BytesOr<'_, [AdStructure<'_>]>,
+    ) -> Self {
+        let mut buf = [0; MAX_ADV_DATA_LEN];
+        let mut writer = ByteWriter::new(&mut buf);
+        // AD structures received over the air always fit
+        data.to_bytes(&mut writer).ok();
+        let data_len = (MAX_ADV_DATA_LEN - writer.space_left()) as u8;
+        Self {
+            pdu_type,
+            address,
+            data: buf,
+            data_len,
+        }
+    }
+}
+
+/// Stores scan results until they are reported to the host.
+///
+/// Reports are dropped when the host doesn't pick them up fast enough.
+struct ReportBuffer(Vec<Report, U4>);
+
+impl ReportCallback for ReportBuffer {
+    fn report(&mut self, report: &ScanReport<'_>) {
+        let address = report.advertiser();
+        let adv = Report::new(report.pdu_type(), address, report.advertising_data());
+        if self.0.push(adv).is_err() {
+            return;
+        }
+        if let Some(data) = report.scan_response_data() {
+            self.0
+                .push(Report::new(PduType::ScanRsp, address, data))
+                .ok();
+        }
+    }
+}
+
+/// An ACL data packet from the host, waiting for space in the TX queue.
+struct PendingAcl {
+    llid: Llid,
+    data: [u8; ACL_BUFFER_SIZE as usize],
+    len: u8,
+}
+
+/// A BLE controller driven by a host via HCI.
+///
+/// The `Controller` owns the `LinkLayer` and both of its packet queues. Like the `LinkLayer`, it
+/// has a real-time part and a non-real-time part:
+///
+/// * `process_adv_packet`, `process_data_packet` and `update_timer` have to be called from the
+///   radio and timer interrupt handlers, just like the `LinkLayer` methods of the same name.
+/// * `process_packet` executes commands and forwards data sent by the host, and `poll` reports
+///   events and received data to the host. These can be run in the idle loop, but need exclusive
+///   access to the `Controller`, so interrupts have to be blocked while they run. If
+///   `process_packet` returns a `Cmd`, it has to be applied to the radio and timer.
+///
+/// `poll` should be called regularly, or at least whenever a returned `Cmd` has its
+/// `queued_work` flag set.
+pub struct Controller<C: Config> {
+    ll: LinkLayer<C>,
+    scanner: Scanner<ReportBuffer, AllowAll>,
+    rng: XorShift32,
+
+    adv_params: AdvertisingParameters,
+    adv_data: [u8; MAX_ADV_DATA_LEN],
+    adv_data_len: u8,
+    scan_active: bool,
+    scan_interval: Duration,
+
+    /// Whether we're scanning. The `LinkLayer` is in standby while scanning.
+    scanning: bool,
+
+    /// Whether the host was told about the current connection.
+    connected: bool,
+
+    /// Queue halves used by the `LinkLayer`, while it doesn't need them.
+    ll_queues: Option<(ConfConsumer<C>, ConfProducer<C>)>,
+
+    tx: ConfProducer<C>,
+    rx: ConfConsumer<C>,
+    pending_acl: Option<PendingAcl>,
+}
+
+impl<C: Config> Controller<C> {
+    /// Creates a controller using `ll`, which must be in standby. `ll` will not resume advertising
+    /// when a connection is lost, since HCI leaves that decision to the host.
+    ///
+    /// `tx_queue` carries data from the host to the `LinkLayer`, and `rx_queue` carries data
+    /// received by the `LinkLayer` to the host. `rng` seeds the PRNG used for scanning and
+    /// connection parameters.
+    pub fn new<R: RngCore>(
+        mut ll: LinkLayer<C>,
+        tx_queue: C::PacketQueue,
+        rx_queue: C::PacketQueue,
+        rng: &mut R,
+    ) -> Self {
+        ll.set_resume_advertising(false);
+
+        let (tx, tx_cons) = tx_queue.split();
+        let (rx_prod, rx) = rx_queue.split();
+        Self {
+            ll,
+            scanner: Scanner::new(ReportBuffer(Vec::new())),
+            rng: XorShift32::new(rng.next_u32()),
+            adv_params: default_adv_params(),
+            adv_data: [0; MAX_ADV_DATA_LEN],
+            adv_data_len: 0,
+            scan_active: false,
+            scan_interval: default_scan_interval(),
+            scanning: false,
+            connected: false,
+            ll_queues: Some((tx_cons, rx_prod)),
+            tx,
+            rx,
+            pending_acl: None,
+        }
+    }
+
+    /// Returns a reference to the `LinkLayer`.
+    pub fn link_layer(&mut self) -> &mut LinkLayer<C> {
+        &mut self.ll
+    }
+
+    /// Processes an incoming advertising channel packet.
+    ///
+    /// See `LinkLayer::process_adv_packet`.
+    pub fn process_adv_packet(
+        &mut self,
+        rx_end: Instant,
+        tx: &mut C::Transmitter,
+        header: advertising::Header,
+        payload: &[u8],
+        crc_ok: bool,
+    ) -> Cmd {
+        if self.scanning {
+            let mut cmd =
+                self.scanner
+                    .process_adv_packet(tx, header, payload, crc_ok, RSSI_UNAVAILABLE);
+            cmd.queued_work = !self.scanner.callback().0.is_empty();
+            cmd
+        } else {
+            self.ll
+                .process_adv_packet(rx_end, tx, header, payload, crc_ok)
+        }
+    }
+
+    /// Processes an incoming data channel packet.
+    ///
+    /// See `LinkLayer::process_data_packet`.
+    pub fn process_data_packet(
+        &mut self,
+        rx_end: Instant,
+        tx: &mut C::Transmitter,
+        header: data::Header,
+        payload: &[u8],
+        crc_ok: bool,
+    ) -> Cmd {
+        self.ll
+            .process_data_packet(rx_end, tx, header, payload, crc_ok)
+    }
+
+    /// Updates the controller after the timer expires.
+    ///
+    /// See `LinkLayer::update_timer`.
+    pub fn update_timer(&mut self, tx: &mut C::Transmitter) -> Cmd {
+        if self.scanning {
+            let now = self.ll.timer().now();
+            let mut cmd = self.scanner.timer_update(now);
+            cmd.queued_work = !self.scanner.callback().0.is_empty();
+            cmd
+        } else {
+            self.ll.update_timer(tx)
+        }
+    }
+
+    /// Processes a packet sent by the host.
+    ///
+    /// Commands are answered with a *Command Complete* or *Command Status* event written to
+    /// `out`. Before the packet is processed, `poll` is called, so that the host learns about
+    /// state changes before the response to its command.
+    ///
+    /// Returns a `Cmd` to apply to the radio and timer if the command changed the state of the
+    /// controller.
+    pub fn process_packet<S: H4Sink>(
+        &mut self,
+        packet: H4Packet<'_>,
+        tx: &mut C::Transmitter,
+        out: &mut S,
+    ) -> Option<Cmd> {
+        self.poll(out);
+        match packet {
+            H4Packet::Command(bytes) => self.process_command(bytes, tx, out),
+            H4Packet::AclData(bytes) => {
+                self.process_acl_data(bytes);
+                self.poll(out);
+                None
+            }
+        }
+    }
+
+    /// Reports events and received data to the host, and passes ACL data from the host on to the
+    /// `LinkLayer` when there is space in the packet queue.
+    pub fn poll<S: H4Sink>(&mut self, out: &mut S) {
+        if !self.connected {
+            if let Some(conn) = self.ll.connection() {
+                self.connected = true;
+                send_event(
+                    out,
+                    Event::LeConnectionComplete {
+                        status: Status::Success,
+                        handle: CONNECTION_HANDLE,
+                        master: conn.is_master(),
+                        peer: conn.peer_address(),
+                        interval: conn.connection_interval(),
+                        slave_latency: conn.slave_latency(),
+                        supervision_timeout: conn.supervision_timeout(),
+                    },
+                );
+            }
+        }
+
+        self.forward_rx(out);
+
+        if self.connected && !self.ll.is_connected() {
+            self.connected = false;
+            let reason = self
+                .ll
+                .disconnect_reason()
+                .map_or(Status::ConnectionTimeout, |r| Status::from(r.error_code()));
+            send_event(
+                out,
+                Event::DisconnectionComplete {
+                    handle: CONNECTION_HANDLE,
+                    reason,
+                },
+            );
+        }
+        self.reclaim_queues();
+
+        if let Some(acl) = &self.pending_acl {
+            let data = &acl.data[..usize::from(acl.len)];
+            let llid = acl.llid;
+            let result = self.tx.produce_with(acl.len, |writer| -> Result<_, Error> {
+                writer.write_slice(data)?;
+                Ok(llid)
+            });
+            if result.is_ok() {
+                self.pending_acl = None;
+                send_event(
+                    out,
+                    Event::NumberOfCompletedPackets {
+                        handle: CONNECTION_HANDLE,
+                        count: 1,
+                    },
+                );
+            }
+        }
+
+        let reports = mem::replace(&mut self.scanner.callback().0, Vec::new());
+        for report in &reports {
+            send_event(
+                out,
+                Event::LeAdvertisingReport {
+                    pdu_type: report.pdu_type,
+                    address: report.address,
+                    data: &report.data[..usize::from(report.data_len)],
+                    rssi: RSSI_UNAVAILABLE,
+                },
+            );
+        }
+    }
+
+    /// Sends data received from the connected device to the host.
+    fn forward_rx<S: H4Sink>(&mut self, out: &mut S) {
+        let tx = &mut self.tx;
+        while self.rx.has_data() {
+            let result = self.rx.consume_pdu_with(|_, pdu| match pdu {
+                Pdu::Control { data } => {
+                    // The `LinkLayer` handles all procedures we support
+                    let response = ControlPdu::UnknownRsp {
+                        unknown_type: data.read().opcode(),
+                    };
+                    Consume::on_success(tx.produce_with(response.encoded_size(), |writer| {
+                        response.to_bytes(writer)?;
+                        Ok(Llid::Control)
+                    }))
+                }
+                Pdu::DataStart { message } => {
+                    send_acl(out, 0b10, message);
+                    Consume::always(Ok(()))
+                }
+                Pdu::DataCont { message } => {
+                    send_acl(out, 0b01, message);
+                    Consume::always(Ok(()))
+                }
+            });
+            if result.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Takes back the packet queues after the `LinkLayer` has entered standby.
+    fn reclaim_queues(&mut self) {
+        if let Some((mut tx, rx)) = self.ll.take_data_queues() {
+            // Drop data that was meant for the old connection
+            while tx.has_data() {
+                tx.consume_raw_with(|_, _| Consume::always(Ok(()))).ok();
+            }
+            while self.rx.has_data() {
+                self.rx
+                    .consume_raw_with(|_, _| Consume::always(Ok(())))
+                    .ok();
+            }
+            self.pending_acl = None;
+            self.ll_queues = Some((tx, rx));
+        }
+    }
+
+    fn process_command<S: H4Sink>(
+        &mut self,
+        bytes: &[u8],
+        tx: &mut C::Transmitter,
+        out: &mut S,
+    ) -> Option<Cmd> {
+        let cmd = match Command::from_bytes(&mut ByteReader::new(bytes)) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                let opcode = Opcode::from(u16::from_le_bytes([bytes[0], bytes[1]]));
+                debug!("HCI command {:?} rejected: {:?}", opcode, e);
+                let status = Status::InvalidParameters;
+                match opcode {
+                    Opcode::Disconnect | Opcode::LeCreateConnection => {
+                        send_event(out, Event::CommandStatus { opcode, status });
+                    }
+                    _ => complete(out, opcode, status, &[]),
+                }
+                return None;
+            }
+        };
+        debug!("HCI command: {:?}", cmd);
+
+        let opcode = cmd.opcode();
+        let idle = self.ll_queues.is_some() && !self.scanning;
+        match cmd {
+            Command::Reset => {
+                let cmd = self.reset();
+                complete(out, opcode, Status::Success, &[]);
+                Some(cmd)
+            }
+            Command::ReadBdAddr => {
+                let addr = self.ll.device_address();
+                complete(out, opcode, Status::Success, addr.raw());
+                None
+            }
+            Command::LeReadBufferSize => {
+                let size = ACL_BUFFER_SIZE.to_le_bytes();
+                complete(out, opcode, Status::Success, &[size[0], size[1], 1]);
+                None
+            }
+            Command::LeSetAdvertisingParameters(params) => {
+                let status = if self.ll.is_advertising() {
+                    Status::CommandDisallowed
+                } else {
+                    self.adv_params = params;
+                    Status::Success
+                };
+                complete(out, opcode, status, &[]);
+                None
+            }
+            Command::LeSetAdvertisingData { data } => {
+                let status = match parse_ad_structures(data, |_| Ok(())) {
+                    Ok(()) => {
+                        self.adv_data[..data.len()].copy_from_slice(data);
+                        self.adv_data_len = data.len() as u8;
+                        Status::Success
+                    }
+                    Err(_) => Status::InvalidParameters,
+                };
+                complete(out, opcode, status, &[]);
+                None
+            }
+            Command::LeSetScanResponseData { data } => {
+                let ll = &mut self.ll;
+                let status = match parse_ad_structures(data, |ads| ll.set_scan_response_data(ads)) {
+                    Ok(()) => Status::Success,
+                    Err(_) => Status::InvalidParameters,
+                };
+                complete(out, opcode, status, &[]);
+                None
+            }
+            Command::LeSetAdvertisingEnable { enable: true } => {
+                if self.ll.is_advertising() {
+                    complete(out, opcode, Status::Success, &[]);
+                    None
+                } else if !idle {
+                    complete(out, opcode, Status::CommandDisallowed, &[]);
+                    None
+                } else {
+                    let cmd = self.start_advertising(tx);
+                    let status = if cmd.is_some() {
+                        Status::Success
+                    } else {
+                        Status::InvalidParameters
+                    };
+                    complete(out, opcode, status, &[]);
+                    cmd
+                }
+            }
+            Command::LeSetAdvertisingEnable { enable: false } => {
+                let cmd = if self.ll.is_advertising() {
+                    let cmd = self.ll.enter_standby();
+                    self.reclaim_queues();
+                    Some(cmd)
+                } else {
+                    None
+                };
+                complete(out, opcode, Status::Success, &[]);
+                cmd
+            }
+            Command::LeSetScanParameters { active, interval } => {
+                let status = if self.scanning {
+                    Status::CommandDisallowed
+                } else {
+                    self.scan_active = active;
+                    self.scan_interval = interval;
+                    Status::Success
+                };
+                complete(out, opcode, status, &[]);
+                None
+            }
+            Command::LeSetScanEnable { enable: true } => {
+                if self.scanning {
+                    complete(out, opcode, Status::Success, &[]);
+                    None
+                } else if !idle {
+                    complete(out, opcode, Status::CommandDisallowed, &[]);
+                    None
+                } else {
+                    self.scanning = true;
+                    self.scanner = Scanner::new(ReportBuffer(Vec::new()));
+                    if self.scan_active {
+                        let addr = self.ll.device_address();
+                        self.scanner.set_active(addr, &mut self.rng);
+                    }
+                    let now = self.ll.timer().now();
+                    let cmd = self.scanner.configure(now, self.scan_interval);
+                    complete(out, opcode, Status::Success, &[]);
+                    Some(cmd)
+                }
+            }
+            Command::LeSetScanEnable { enable: false } => {
+                let cmd = if self.scanning {
+                    self.scanning = false;
+                    Some(standby_cmd())
+                } else {
+                    None
+                };
+                complete(out, opcode, Status::Success, &[]);
+                cmd
+            }
+            Command::LeCreateConnection(params) => {
+                if !idle {
+                    let status = Status::CommandDisallowed;
+                    send_event(out, Event::CommandStatus { opcode, status });
+                    return None;
+                }
+
+                let lldata = ConnectRequestData::new(
+                    params.interval(),
+                    params.slave_latency(),
+                    params.supervision_timeout(),
+                    ChannelMap::with_all_channels(),
+                    &mut self.rng,
+                );
+                match lldata {
+                    Ok(lldata) => {
+                        let status = Status::Success;
+                        send_event(out, Event::CommandStatus { opcode, status });
+                        let (tx, rx) = self.ll_queues.take().unwrap();
+                        Some(self.ll.start_connecting(params.peer(), lldata, tx, rx))
+                    }
+                    Err(_) => {
+                        let status = Status::InvalidParameters;
+                        send_event(out, Event::CommandStatus { opcode, status });
+                        None
+                    }
+                }
+            }
+            Command::Disconnect { handle, reason } => {
+                let status = if handle != CONNECTION_HANDLE || !self.connected {
+                    Status::UnknownConnectionId
+                } else if self.ll.disconnect(reason).is_ok() {
+                    Status::Success
+                } else {
+                    Status::CommandDisallowed
+                };
+                send_event(out, Event::CommandStatus { opcode, status });
+                None
+            }
+            Command::Unknown(_) => {
+                complete(out, opcode, Status::UnknownCommand, &[]);
+                None
+            }
+        }
+    }
+
+    /// Starts advertising with the configured parameters and data.
+    ///
+    /// Returns `None` if the advertising data can't be sent with the advertising type.
+    fn start_advertising(&mut self, tx: &mut C::Transmitter) -> Option<Cmd> {
+        let params = self.adv_params;
+        let data = &self.adv_data[..usize::from(self.adv_data_len)];
+        let dev_addr = self.ll.device_address();
+        // Check the data first, since `start_advertise` drops the queues on error
+        parse_ad_structures(data, |ads| params.build_pdu(dev_addr, ads).map(|_| ())).ok()?;
+
+        let (tx_cons, rx_prod) = self.ll_queues.take().unwrap();
+        let ll = &mut self.ll;
+        let next_update = parse_ad_structures(data, |ads| {
+            ll.start_advertise(params, ads, tx, tx_cons, rx_prod)
+        })
+        .expect("advertising data was checked");
+        Some(Cmd {
+            radio: RadioCmd::ListenAdvertising {
+                channel: params.channels().first(),
+            },
+            next_update,
+            queued_work: false,
+        })
+    }
+
+    /// Stops all activity and restores the default parameters.
+    fn reset(&mut self) -> Cmd {
+        self.scanning = false;
+        let cmd = self.ll.enter_standby();
+        self.reclaim_queues();
+        self.connected = false;
+        self.adv_params = default_adv_params();
+        self.adv_data_len = 0;
+        self.ll.set_scan_response_data(&[]).unwrap();
+        self.scan_active = false;
+        self.scan_interval = default_scan_interval();
+        self.scanner = Scanner::new(ReportBuffer(Vec::new()));
+        cmd
+    }
+
+    /// Stores an ACL data packet from the host until it fits in the TX queue.
+    fn process_acl_data(&mut self, bytes: &[u8]) {
+        let mut reader = ByteReader::new(bytes);
+        let (handle_flags, len) = match (reader.read_u16_le(), reader.read_u16_le()) {
+            (Ok(handle_flags), Ok(len)) => (handle_flags, len),
+            _ => return,
+        };
+        let data = reader.read_rest();
+        let llid = match (handle_flags >> 12) & 0b11 {
+            0b00 | 0b10 => Llid::DataStart,
+            0b01 => Llid::DataCont,
+            _ => return,
+        };
+
+        if handle_flags & 0x0FFF != CONNECTION_HANDLE
+            || !self.connected
+            || self.pending_acl.is_some()
+            || usize::from(len) != data.len()
+        {
+            warn!("dropping ACL data from host");
+            return;
+        }
+
+        let mut acl = PendingAcl {
+            llid,
+            data: [0; ACL_BUFFER_SIZE as usize],
+            len: data.len() as u8,
+        };
+        acl.data[..data.len()].copy_from_slice(data);
+        self.pending_acl = Some(acl);
+    }
+}
+
+fn default_adv_params() -> AdvertisingParameters {
+    // 1.28 s, as specified for the HCI command
+    let interval = Duration::from_millis(1280);
+    AdvertisingParameters::new(AdvertisingType::ConnectableUndirected, interval, interval).unwrap()
+}
+
+fn default_scan_interval() -> Duration {
+    Duration::from_millis(10)
+}
+
+fn standby_cmd() -> Cmd {
+    Cmd {
+        radio: RadioCmd::Off,
+        next_update: NextUpdate::Disable,
+        queued_work: false,
+    }
+}
+
+/// Parses raw advertising data and passes the AD structures to `f`.
+fn parse_ad_structures<R>(
+    data: &[u8],
+    f: impl FnOnce(&[AdStructure<'_>]) -> Result<R, Error>,
+) -> Result<R, Error> {
+    // Every AD structure takes up at least 2 Bytes
+    let mut ads = Vec::<_, heapless::consts::U16>::new();
+    let mut reader = ByteReader::new(data);
+    while !reader.is_empty() {
+        ads.push(AdStructure::from_bytes(&mut reader)?)
+            .map_err(|_| Error::Eof)?;
+    }
+    f(&ads)
+}
+
+fn complete<S: H4Sink>(out: &mut S, opcode: Opcode, status: Status, params: &[u8]) {
+    send_event(
+        out,
+        Event::CommandComplete {
+            opcode,
+            status,
+            params,
+        },
+    );
+}
+
+fn send_event<S: H4Sink>(out: &mut S, event: Event<'_>) {
+    debug!("HCI event: {:?}", event);
+    let mut buf = [0; OUT_BUF];
+    buf[0] = PacketIndicator::Event.into();
+    let mut writer = ByteWriter::new(&mut buf[1..]);
+    let free = writer.space_left();
+    event.to_bytes(&mut writer).unwrap();
+    let len = 1 + free - writer.space_left();
+    out.write_packet(&buf[..len]);
+}
+
+/// Sends an ACL data packet to the host.
+///
+/// `boundary` is the value of the Packet Boundary flag, which is `0b10` for the start of an L2CAP
+/// message and `0b01` for continuation fragments.
+fn send_acl<S: H4Sink>(out: &mut S, boundary: u16, message: &[u8]) {
+    let mut buf = [0; OUT_BUF];
+    buf[0] = PacketIndicator::AclData.into();
+    buf[1..3].copy_from_slice(&(CONNECTION_HANDLE | (boundary << 12)).to_le_bytes());
+    buf[3..5].copy_from_slice(&(message.len() as u16).to_le_bytes());
+    buf[5..5 + message.len()].copy_from_slice(message);
+    out.write_packet(&buf[..5 + message.len()]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes all packets in `stream`.
+    fn decode(stream: &[u8]) -> std::vec::Vec<Result<std::vec::Vec<u8>, Error>> {
+        let mut decoder = H4Decoder::new();
+        let mut packets = std::vec::Vec::new();
+        for &byte in stream {
+            match decoder.push(byte) {
+                Ok(Some(H4Packet::Command(bytes))) => {
+                    let mut packet = vec![0x01];
+                    packet.extend_from_slice(bytes);
+                    packets.push(Ok(packet));
+                }
+                Ok(Some(H4Packet::AclData(bytes))) => {
+                    let mut packet = vec![0x02];
+                    packet.extend_from_slice(bytes);
+                    packets.push(Ok(packet));
+                }
+                Ok(None) => {}
+                Err(e) => packets.push(Err(e)),
+            }
+        }
+        packets
+    }
+
+    #[test]
+    fn h4_decoder() {
+        let reset = [0x01, 0x03, 0x0C, 0x00];
+        let enable = [0x01, 0x0A, 0x20, 0x01, 0x01];
+        let acl = [0x02, 0x01, 0x20, 0x03, 0x00, 0xAA, 0xBB, 0xCC];
+        let stream = [&reset[..], &enable, &acl].concat();
+        assert_eq!(
+            decode(&stream),
+            [Ok(reset.to_vec()), Ok(enable.to_vec()), Ok(acl.to_vec())]
+        );
+    }
+
+    #[test]
+    fn h4_decoder_errors() {
+        // An event can't be sent to the controller
+        let event = [0x04, 0x0E];
+        // ACL data larger than the buffer is skipped
+        let mut acl = vec![0x02, 0x01, 0x20, 0x1C, 0x00];
+        acl.extend_from_slice(&[0x01; 28]);
+        let reset = [0x01, 0x03, 0x0C, 0x00];
+        let stream = [&event[..], &acl, &reset].concat();
+        assert_eq!(
+            decode(&stream),
+            [
+                Err(Error::InvalidValue),
+                Err(Error::InvalidValue),
+                Err(Error::InvalidLength),
+                Ok(reset.to_vec())
+            ]
+        );
+    }
+}
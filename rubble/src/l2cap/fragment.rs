@@ -0,0 +1,216 @@
+//! Fragmentation and reassembly of L2CAP messages.
+//!
+//! L2CAP messages that do not fit in a single Link-Layer data PDU are split into several PDUs: The
+//! first one is sent with an LLID of `DataStart` and contains the L2CAP header, all following ones
+//! use `DataCont`. Fragments belonging to different L2CAP messages must not be interleaved.
+
+use super::{Channel, Header, MAX_MESSAGE_SIZE};
+use crate::link::{data::Llid, queue::Producer};
+use crate::{bytes::*, utils::HexSlice, Error};
+use core::{cmp, fmt};
+
+/// Size of the buffers used for fragmentation and reassembly.
+///
+/// Fits the largest supported L2CAP message payload and its header.
+const BUF_SIZE: usize = MAX_MESSAGE_SIZE + Header::SIZE as usize;
+
+/// Reassembles incoming L2CAP messages that were split across multiple data PDUs.
+pub(super) struct Reassembler {
+    /// Destination channel of the message being reassembled, or `None` if no message is in
+    /// progress.
+    channel: Option<Channel>,
+
+    /// Payload length announced in the L2CAP header.
+    length: u16,
+
+    /// Number of payload Bytes received so far.
+    received: u16,
+
+    buf: [u8; MAX_MESSAGE_SIZE],
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self {
+            channel: None,
+            length: 0,
+            received: 0,
+            buf: [0; MAX_MESSAGE_SIZE],
+        }
+    }
+
+    /// Returns whether a partially received message is currently buffered.
+    pub fn in_progress(&self) -> bool {
+        self.channel.is_some()
+    }
+
+    /// Discards any partially received message.
+    pub fn reset(&mut self) {
+        self.channel = None;
+        self.length = 0;
+        self.received = 0;
+    }
+
+    /// Starts reassembling a message sent to `channel` whose payload has `length` Bytes in total.
+    ///
+    /// `first` is the part of the payload contained in the `DataStart` PDU.
+    ///
+    /// Returns `Error::InvalidLength` if the message does not fit in the reassembly buffer.
+    pub fn start(&mut self, channel: Channel, length: u16, first: &[u8]) -> Result<(), Error> {
+        self.reset();
+
+        if usize::from(length) > MAX_MESSAGE_SIZE || first.len() > usize::from(length) {
+            return Err(Error::InvalidLength);
+        }
+
+        self.buf[..first.len()].copy_from_slice(first);
+        self.channel = Some(channel);
+        self.length = length;
+        self.received = first.len() as u16;
+        Ok(())
+    }
+
+    /// Appends a continuation fragment to the message in progress.
+    ///
+    /// If the message is now complete, returns its destination channel and payload. Note that the
+    /// fragment is *not* committed in that case: The caller has to call `reset` once the message
+    /// has been processed, otherwise the same fragment can be passed to `append` again.
+    ///
+    /// Returns `Error::InvalidLength` if the fragment exceeds the announced message length, and
+    /// `Error::InvalidValue` if no message is in progress.
+    pub fn append(&mut self, data: &[u8]) -> Result<Option<(Channel, &[u8])>, Error> {
+        let channel = self.channel.ok_or(Error::InvalidValue)?;
+        let start = usize::from(self.received);
+        let end = start + data.len();
+        if end > usize::from(self.length) {
+            return Err(Error::InvalidLength);
+        }
+
+        self.buf[start..end].copy_from_slice(data);
+        if end == usize::from(self.length) {
+            Ok(Some((channel, &self.buf[..end])))
+        } else {
+            self.received = end as u16;
+            Ok(None)
+        }
+    }
+}
+
+impl fmt::Debug for Reassembler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reassembler")
+            .field("channel", &self.channel)
+            .field("length", &self.length)
+            .field("data", &HexSlice(&self.buf[..usize::from(self.received)]))
+            .finish()
+    }
+}
+
+/// Holds an outgoing L2CAP message and splits it into data PDUs as space becomes available in the
+/// TX queue.
+pub(super) struct Fragmenter {
+    /// L2CAP header and payload of the staged message.
+    buf: [u8; BUF_SIZE],
+
+    /// Number of valid Bytes in `buf`. 0 when no message is staged.
+    len: usize,
+
+    /// Number of Bytes that have already been enqueued for transmission.
+    sent: usize,
+}
+
+impl Fragmenter {
+    pub fn new() -> Self {
+        Self {
+            buf: [0; BUF_SIZE],
+            len: 0,
+            sent: 0,
+        }
+    }
+
+    /// Returns whether no message is waiting to be sent.
+    pub fn is_idle(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Discards the staged message, including any fragments that weren't sent yet.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.sent = 0;
+    }
+
+    /// Encodes an L2CAP message into the staging buffer.
+    ///
+    /// The closure `f` is passed a `ByteWriter` with exactly `pdu` Bytes of space. If it returns an
+    /// error, nothing is staged.
+    pub fn stage<T, E>(
+        &mut self,
+        channel: Channel,
+        pdu: u8,
+        f: impl FnOnce(&mut ByteWriter<'_>) -> Result<T, E>,
+    ) -> Result<T, E>
+    where
+        E: From<Error>,
+    {
+        if !self.is_idle() {
+            return Err(Error::Eof.into());
+        }
+
+        let (header_buf, payload_buf) = self.buf.split_at_mut(usize::from(Header::SIZE));
+        let mut payload_writer = ByteWriter::new(&mut payload_buf[..usize::from(pdu)]);
+        let left = payload_writer.space_left();
+        let result = f(&mut payload_writer)?;
+        let used = left - payload_writer.space_left();
+
+        Header {
+            length: used as u16,
+            channel,
+        }
+        .to_bytes(&mut ByteWriter::new(header_buf))?;
+
+        self.len = usize::from(Header::SIZE) + used;
+        self.sent = 0;
+        Ok(result)
+    }
+
+    /// Enqueues as many fragments of the staged message as `tx` has space for.
+    ///
+    /// Once the whole message has been enqueued, the fragmenter becomes idle again.
+    pub fn flush(&mut self, tx: &mut dyn Producer) -> Result<(), Error> {
+        while self.sent < self.len {
+            let free = usize::from(tx.free_space());
+            if free == 0 {
+                break;
+            }
+
+            let chunk = &self.buf[self.sent..][..cmp::min(free, self.len - self.sent)];
+            let llid = if self.sent == 0 {
+                Llid::DataStart
+            } else {
+                Llid::DataCont
+            };
+
+            tx.produce_dyn(chunk.len() as u8, &mut |writer| {
+                writer.write_slice(chunk)?;
+                Ok(llid)
+            })?;
+            self.sent += chunk.len();
+        }
+
+        if self.sent == self.len {
+            self.len = 0;
+            self.sent = 0;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Fragmenter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Fragmenter")
+            .field("data", &HexSlice(&self.buf[..self.len]))
+            .field("sent", &self.sent)
+            .finish()
+    }
+}
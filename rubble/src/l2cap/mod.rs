@@ -21,17 +21,27 @@
 //! [`Channel`]: struct.Channel.html
 //! [l2c]: https://www.bluetooth.com/specifications/assigned-numbers/logical-link-control
 
+mod fragment;
 mod signaling;
 
+use self::fragment::{Fragmenter, Reassembler};
 use self::signaling::SignalingState;
-use crate::att::{self, AttributeProvider, AttributeServer, NoAttributes};
+use crate::att::{self, AttributeProvider, AttributeServer, ClientHandler, NoAttributes, NoClient};
 use crate::link::queue::{Consume, Producer};
-use crate::link::{data::Llid, MIN_DATA_PAYLOAD_BUF};
-use crate::security::{NoSecurity, SecurityLevel, SecurityManager};
+use crate::link::{data::Llid, DeviceAddress};
+use crate::security::{self, NoSecurity, SecurityLevel, SecurityManager};
 use crate::{bytes::*, utils::HexSlice, Error};
 use core::fmt;
 use core::ops::{Deref, DerefMut};
 
+/// Max. size of an L2CAP message payload that can be reassembled or fragmented.
+///
+/// Messages that don't fit in a single data channel PDU are split into several PDUs, and buffered
+/// until they are complete on the receiving side. Incoming messages larger than this are discarded.
+///
+/// This matches the largest `Protocol::RSP_PDU_SIZE` that can be expressed.
+pub const MAX_MESSAGE_SIZE: usize = 255;
+
 /// An L2CAP channel identifier (CID).
 ///
 /// Channels are basically like TCP ports. A `Protocol` can listen on a channel and is connected to
@@ -118,11 +128,22 @@ pub trait ChannelMapper {
     /// The attribute provider used by the ATT server.
     type AttributeProvider: AttributeProvider;
 
+    /// The handler of ATT PDUs addressed to the local ATT client.
+    type ClientHandler: ClientHandler;
+
+    /// The security level supported by the Security Manager.
+    type SecurityLevel: SecurityLevel;
+
     /// Look up what's connected to `channel` (eg. the `Protocol` to which to forward).
     fn lookup(&mut self, channel: Channel) -> Option<ChannelData<'_, dyn ProtocolObj + '_>>;
 
     /// Returns information about the Attribute Protocol on channel `0x0004`.
-    fn att(&mut self) -> ChannelData<'_, AttributeServer<Self::AttributeProvider>>;
+    fn att(
+        &mut self,
+    ) -> ChannelData<'_, AttributeServer<Self::AttributeProvider, Self::ClientHandler>>;
+
+    /// Returns information about the Security Manager on channel `0x0006`.
+    fn security(&mut self) -> ChannelData<'_, SecurityManager<Self::SecurityLevel>>;
 }
 
 /// Data associated with a connected L2CAP channel.
@@ -138,8 +159,8 @@ pub struct ChannelData<'a, P: ?Sized> {
 
     /// Outgoing PDU size of the protocol.
     ///
-    /// This is the number of bytes that must be available to the protocol in the TX buffer to
-    /// guarantee that all of the protocol's PDUs will fit.
+    /// This is the number of bytes that must be available to the protocol when sending a message
+    /// to guarantee that all of the protocol's PDUs will fit.
     pdu: u8,
 }
 
@@ -147,14 +168,9 @@ impl<'a> ChannelData<'a, dyn ProtocolObj + 'a> {
     /// Creates a `ChannelData` carrying a dynamically-dispatched `dyn ProtocolObj` from a concrete
     /// `Protocol` implementor `T`.
     fn new_dyn<T: Protocol + 'a>(response_channel: Channel, protocol: &'a mut T) -> Self {
-        assert!(
-            usize::from(T::RSP_PDU_SIZE + Header::SIZE) <= MIN_DATA_PAYLOAD_BUF,
-            "protocol min PDU is smaller than data channel PDU (L2CAP reassembly NYI)"
-        );
-
         ChannelData {
             response_channel,
-            pdu: T::RSP_PDU_SIZE,
+            pdu: protocol.rsp_pdu_size(),
             protocol,
         }
     }
@@ -162,14 +178,9 @@ impl<'a> ChannelData<'a, dyn ProtocolObj + 'a> {
 
 impl<'a, P: Protocol> ChannelData<'a, P> {
     fn new(response_channel: Channel, protocol: &'a mut P) -> Self {
-        assert!(
-            usize::from(P::RSP_PDU_SIZE + Header::SIZE) <= MIN_DATA_PAYLOAD_BUF,
-            "protocol min PDU is smaller than data channel PDU (L2CAP reassembly NYI)"
-        );
-
         ChannelData {
             response_channel,
-            pdu: P::RSP_PDU_SIZE,
+            pdu: protocol.rsp_pdu_size(),
             protocol,
         }
     }
@@ -187,8 +198,9 @@ impl<'a, P: ?Sized> ChannelData<'a, P> {
     /// outgoing PDUs. `Protocol` implementations may make use of additional space as well, but this
     /// is the very minimum.
     ///
-    /// The L2CAP implementation will not forward incoming PDUs to the protocol unless this amount
-    /// of space is available in the TX buffer. This guarantees that the response will always fit.
+    /// The L2CAP implementation will always provide this amount of space to the protocol's
+    /// `Sender`. Responses that do not fit into the TX buffer as a single data channel PDU are
+    /// fragmented automatically.
     pub fn pdu_size(&self) -> u8 {
         self.pdu
     }
@@ -215,8 +227,8 @@ impl<'a, P: ?Sized> ChannelData<'a, P> {
 /// * `0x0004`: Attribute protocol (ATT).
 /// * `0x0005`: LE L2CAP signaling channel.
 /// * `0x0006`: LE Security Manager protocol.
-pub struct BleChannelMap<A: AttributeProvider, S: SecurityLevel> {
-    att: AttributeServer<A>,
+pub struct BleChannelMap<A: AttributeProvider, S: SecurityLevel, H: ClientHandler = NoClient> {
+    att: AttributeServer<A, H>,
     signaling: SignalingState,
     sm: SecurityManager<S>,
 }
@@ -246,8 +258,35 @@ impl<A: AttributeProvider> BleChannelMap<A, NoSecurity> {
     }
 }
 
-impl<A: AttributeProvider, S: SecurityLevel> ChannelMapper for BleChannelMap<A, S> {
+impl<A: AttributeProvider, S: SecurityLevel> BleChannelMap<A, S> {
+    /// Creates a channel map hosting `att` and supporting pairing via `sm`.
+    pub fn with_security(att: A, sm: SecurityManager<S>) -> Self {
+        Self {
+            att: AttributeServer::new(att),
+            signaling: SignalingState::new(),
+            sm,
+        }
+    }
+}
+
+impl<A: AttributeProvider, S: SecurityLevel, H: ClientHandler> BleChannelMap<A, S, H> {
+    /// Creates a channel map hosting `att`, supporting pairing via `sm`, and passing ATT PDUs
+    /// addressed to the local ATT client to `client`.
+    pub fn with_client(att: A, sm: SecurityManager<S>, client: H) -> Self {
+        Self {
+            att: AttributeServer::with_client(att, client),
+            signaling: SignalingState::new(),
+            sm,
+        }
+    }
+}
+
+impl<A: AttributeProvider, S: SecurityLevel, H: ClientHandler> ChannelMapper
+    for BleChannelMap<A, S, H>
+{
     type AttributeProvider = A;
+    type ClientHandler = H;
+    type SecurityLevel = S;
 
     fn lookup(&mut self, channel: Channel) -> Option<ChannelData<'_, dyn ProtocolObj + '_>> {
         match channel {
@@ -258,9 +297,15 @@ impl<A: AttributeProvider, S: SecurityLevel> ChannelMapper for BleChannelMap<A,
         }
     }
 
-    fn att(&mut self) -> ChannelData<'_, AttributeServer<Self::AttributeProvider>> {
+    fn att(
+        &mut self,
+    ) -> ChannelData<'_, AttributeServer<Self::AttributeProvider, Self::ClientHandler>> {
         ChannelData::new(Channel::ATT, &mut self.att)
     }
+
+    fn security(&mut self) -> ChannelData<'_, SecurityManager<Self::SecurityLevel>> {
+        ChannelData::new(Channel::LE_SECURITY_MANAGER, &mut self.sm)
+    }
 }
 
 /// Trait for protocols that sit on top of L2CAP (object-safe part).
@@ -270,7 +315,8 @@ pub trait ProtocolObj {
     /// Process a message sent to the protocol.
     ///
     /// The message is reassembled by L2CAP already, and the `responder` is guaranteed to fit a
-    /// protocol payload of at least `Protocol::RSP_PDU_SIZE` Bytes, as defined by the protocol.
+    /// protocol payload of at least `Protocol::RSP_PDU_SIZE` Bytes, as defined by the protocol
+    /// (fragmenting it if necessary).
     ///
     /// # Errors
     ///
@@ -287,9 +333,17 @@ pub trait ProtocolObj {
 pub trait Protocol: ProtocolObj {
     /// Minimum size needed by PDUs sent by this protocol.
     ///
-    /// Incoming PDUs will only be forwarded to the protocol if there is at least this much space in
-    /// the TX buffer.
+    /// Incoming PDUs will only be forwarded to the protocol if a response of this size can be sent,
+    /// either in a single data channel PDU or by fragmenting it.
     const RSP_PDU_SIZE: u8;
+
+    /// Returns the size needed by PDUs sent by this protocol instance.
+    ///
+    /// This defaults to `RSP_PDU_SIZE`. Protocols that negotiate a larger PDU size at runtime (such
+    /// as ATT with its `ATT_MTU`) can return a larger value, but never a smaller one.
+    fn rsp_pdu_size(&self) -> u8 {
+        Self::RSP_PDU_SIZE
+    }
 }
 
 /// Header used by *all* L2CAP PDUs.
@@ -322,45 +376,56 @@ impl ToBytes for Header {
     }
 }
 
-struct Message<P> {
-    header: Header,
-    payload: P,
-}
-
-impl<'a, P: FromBytes<'a>> FromBytes<'a> for Message<P> {
-    fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
-        let header = Header::from_bytes(bytes)?;
-        assert_eq!(
-            header.length as usize,
-            bytes.bytes_left(),
-            "L2CAP reassembly not yet implemented"
-        );
-
-        Ok(Self {
-            header,
-            payload: P::from_bytes(bytes)?,
-        })
-    }
-}
-
-impl<P: ToBytes> ToBytes for Message<P> {
-    fn to_bytes(&self, writer: &mut ByteWriter<'_>) -> Result<(), Error> {
-        self.header.to_bytes(writer)?;
-        self.payload.to_bytes(writer)?;
-        Ok(())
-    }
-}
-
 /// L2CAP channel manager and responder.
 #[derive(Debug)]
 pub struct L2CAPState<M: ChannelMapper> {
     mapper: M,
+
+    /// Incoming message that is currently being reassembled.
+    reassembler: Reassembler,
+
+    /// Outgoing message that is currently being fragmented.
+    fragmenter: Fragmenter,
 }
 
 impl<M: ChannelMapper> L2CAPState<M> {
     /// Creates a new L2CAP state using the given channel configuration.
     pub fn new(mapper: M) -> Self {
-        Self { mapper }
+        Self {
+            mapper,
+            reassembler: Reassembler::new(),
+            fragmenter: Fragmenter::new(),
+        }
+    }
+
+    /// Returns the attribute server listening on the ATT channel.
+    pub fn attribute_server(
+        &mut self,
+    ) -> &mut AttributeServer<M::AttributeProvider, M::ClientHandler> {
+        self.mapper.att().into_protocol()
+    }
+
+    /// Returns the Security Manager responsible for pairing.
+    pub fn security_manager(&mut self) -> &mut SecurityManager<M::SecurityLevel> {
+        self.mapper.security().into_protocol()
+    }
+
+    /// Resets all per-connection state.
+    ///
+    /// This must be called whenever a new connection is established. It discards partially
+    /// received and partially sent messages, and resets the attribute server and the Security
+    /// Manager (see `AttributeServer::connection_established` and
+    /// `SecurityManager::connection_established`).
+    ///
+    /// # Parameters
+    ///
+    /// * **`local`**: The address of this device.
+    /// * **`peer`**: The address of the connected device.
+    pub fn connection_established(&mut self, local: DeviceAddress, peer: DeviceAddress) {
+        self.reassembler.reset();
+        self.fragmenter.reset();
+        self.attribute_server().connection_established();
+        self.security_manager().connection_established(local, peer);
     }
 
     /// Gives this instance the ability to transmit packets.
@@ -373,6 +438,10 @@ impl<M: ChannelMapper> L2CAPState<M> {
 ///
 /// This can be done either in response to an incoming packet (via `ProtocolObj::process_msg`), or
 /// as a device-initiated packet (eg. an attribute notification).
+///
+/// Messages that do not fit into the TX queue as a single data channel PDU are copied into a
+/// per-connection buffer and split into several PDUs, which are enqueued as space becomes
+/// available (see `L2CAPStateTx::flush`).
 pub struct Sender<'a> {
     /// The protocol's max. outgoing PDU size.
     pdu: u8,
@@ -380,20 +449,25 @@ pub struct Sender<'a> {
     /// Data PDU channel.
     tx: &'a mut dyn Producer,
 
+    /// Buffer for messages that have to be fragmented.
+    fragmenter: &'a mut Fragmenter,
+
     /// Channel to which the response will be addressed.
     channel: Channel,
 }
 
 impl<'a> Sender<'a> {
-    /// Creates a `Sender` from a `Producer`, ensuring that sufficient free space is available to
-    /// fit a PDU described by `chdata`.
+    /// Creates a `Sender` for the protocol described by `chdata`.
     ///
-    /// If there is not enough space in `tx`, returns `None`.
-    fn new<T: ?Sized>(chdata: &ChannelData<'_, T>, tx: &'a mut dyn Producer) -> Option<Self> {
-        let free = tx.free_space();
-        let needed = chdata.pdu_size() + Header::SIZE;
-        if free < needed {
-            debug!("{} free bytes, need {}", free, needed);
+    /// If a previously sent message is still being fragmented, returns `None`, since fragments of
+    /// different L2CAP messages must not be interleaved.
+    fn new<T: ?Sized>(
+        chdata: &ChannelData<'_, T>,
+        tx: &'a mut dyn Producer,
+        fragmenter: &'a mut Fragmenter,
+    ) -> Option<Self> {
+        if !fragmenter.is_idle() {
+            debug!("L2CAP message still being fragmented, deferring");
             return None;
         }
 
@@ -402,16 +476,27 @@ impl<'a> Sender<'a> {
         Some(Sender {
             pdu,
             tx,
+            fragmenter,
             channel: resp_channel,
         })
     }
 
+    /// Returns a `Sender` borrowing from `self`, for use with a shorter lifetime.
+    pub(crate) fn reborrow(&mut self) -> Sender<'_> {
+        Sender {
+            pdu: self.pdu,
+            tx: self.tx,
+            fragmenter: self.fragmenter,
+            channel: self.channel,
+        }
+    }
+
     /// Enqueues an L2CAP message to be sent over the data connection.
     ///
     /// L2CAP header (including the destination endpoint's channel) and the data channel PDU header
     /// will be added automatically.
     ///
-    /// This will fail if there's not enough space left in the TX queue.
+    /// This will fail if another message is still being sent.
     pub fn send<P: ToBytes>(&mut self, payload: P) -> Result<(), Error> {
         self.send_with(|writer| payload.to_bytes(writer))
     }
@@ -421,7 +506,7 @@ impl<'a> Sender<'a> {
     /// L2CAP header and data channel PDU header will be added automatically. The closure `f` only
     /// has to write the protocol PDU to transmit over L2CAP.
     ///
-    /// The L2CAP implementation will ensure that there are exactly `Protocol::RSP_PDU_SIZE` Bytes
+    /// The L2CAP implementation will ensure that there are exactly `Protocol::rsp_pdu_size` Bytes
     /// available in the `ByteWriter` passed to the closure.
     pub fn send_with<T, E>(
         &mut self,
@@ -430,7 +515,13 @@ impl<'a> Sender<'a> {
     where
         E: From<Error>,
     {
-        // FIXME automatic fragmentation is not implemented
+        let needed = usize::from(self.pdu) + usize::from(Header::SIZE);
+        if usize::from(self.tx.free_space()) < needed {
+            // Might not fit in a single data PDU, go through the fragmentation buffer.
+            let result = self.fragmenter.stage(self.channel, self.pdu, f)?;
+            self.fragmenter.flush(self.tx)?;
+            return Ok(result);
+        }
 
         // The payload length goes into the header, so we have to skip that part and write it later
         let mut f = Some(f);
@@ -438,7 +529,7 @@ impl<'a> Sender<'a> {
         let pdu = self.pdu;
         let mut r = None;
         let r2 = self.tx.produce_dyn(
-            pdu + Header::SIZE,
+            needed as u8,
             &mut |writer: &mut ByteWriter<'_>| -> Result<_, Error> {
                 let mut header_writer = writer.split_off(usize::from(Header::SIZE))?;
 
@@ -495,46 +586,100 @@ impl<'a, M: ChannelMapper, P: Producer> L2CAPStateTx<'a, M, P> {
     /// Process the start of a new L2CAP message (or a complete, unfragmented message).
     ///
     /// If the incoming message is unfragmented, it will be forwarded to the protocol listening on
-    /// the addressed channel, and a response may be sent.
+    /// the addressed channel, and a response may be sent. Otherwise, the message is buffered until
+    /// all continuation fragments have been received via `process_cont`.
     pub fn process_start(&mut self, message: &[u8]) -> Consume<()> {
-        let msg = match Message::<&[u8]>::from_bytes(&mut ByteReader::new(message)) {
-            Ok(msg) => msg,
+        let mut bytes = ByteReader::new(message);
+        let header = match Header::from_bytes(&mut bytes) {
+            Ok(header) => header,
             Err(e) => return Consume::always(Err(e)),
         };
+        let payload = bytes.into_rest();
+
+        if self.l2cap.reassembler.in_progress() {
+            warn!(
+                "discarding incomplete L2CAP message: {:?}",
+                self.l2cap.reassembler
+            );
+            self.l2cap.reassembler.reset();
+        }
 
-        if usize::from(msg.header.length) != msg.payload.len() {
-            // Lengths mismatch => Reassembly needed
-            unimplemented!("L2CAP reassembly");
+        if usize::from(header.length) == payload.len() {
+            let L2CAPState {
+                mapper, fragmenter, ..
+            } = &mut *self.l2cap;
+            return dispatch(mapper, fragmenter, self.tx, header.channel, payload);
         }
 
-        self.dispatch(msg.header.channel, msg.payload)
+        // Lengths mismatch => Reassembly needed
+        if let Err(e) = self
+            .l2cap
+            .reassembler
+            .start(header.channel, header.length, payload)
+        {
+            warn!(
+                "dropping L2CAP message ({:?}, {} of {} Bytes): {}",
+                header.channel,
+                payload.len(),
+                header.length,
+                e
+            );
+        }
+
+        Consume::always(Ok(()))
     }
 
     /// Process continuation of an L2CAP message.
     ///
-    /// This is not yet implemented and will always panic.
-    pub fn process_cont(&mut self, _data: &[u8]) -> Consume<()> {
-        unimplemented!("reassembly")
-    }
-
-    /// Dispatches a fully reassembled L2CAP message to the protocol listening on the addressed
-    /// channel.
-    fn dispatch(&mut self, channel: Channel, payload: &[u8]) -> Consume<()> {
-        if let Some(mut chdata) = self.l2cap.mapper.lookup(channel) {
-            let sender = if let Some(sender) = Sender::new(&chdata, self.tx) {
-                sender
-            } else {
-                return Consume::never(Ok(()));
-            };
-
-            Consume::always(chdata.protocol().process_message(payload, sender))
-        } else {
-            warn!(
-                "ignoring message sent to unconnected channel {:?}: {:?}",
-                channel,
-                HexSlice(payload)
-            );
-            Consume::always(Ok(()))
+    /// Once the last fragment of a message has been received, the reassembled message is forwarded
+    /// to the protocol listening on the addressed channel.
+    pub fn process_cont(&mut self, data: &[u8]) -> Consume<()> {
+        let L2CAPState {
+            mapper,
+            reassembler,
+            fragmenter,
+        } = &mut *self.l2cap;
+
+        let consume = match reassembler.append(data) {
+            Ok(None) => return Consume::always(Ok(())),
+            Ok(Some((channel, message))) => dispatch(mapper, fragmenter, self.tx, channel, message),
+            Err(e) => {
+                warn!("dropping L2CAP continuation {:?}: {}", HexSlice(data), e);
+                Consume::always(Ok(()))
+            }
+        };
+
+        if consume.should_consume() {
+            reassembler.reset();
+        }
+        consume
+    }
+
+    /// Returns whether a fragmented outgoing message, or a message queued by the Security Manager,
+    /// can make progress.
+    ///
+    /// If this returns `true`, `flush` will enqueue at least one more PDU.
+    pub fn can_flush(&mut self) -> bool {
+        if self.tx.free_space() == 0 {
+            return false;
+        }
+
+        !self.l2cap.fragmenter.is_idle() || self.security_manager().has_pending()
+    }
+
+    /// Enqueues as many remaining fragments of an outgoing L2CAP message as the TX queue has space
+    /// for.
+    ///
+    /// Until the message is fully enqueued, no other L2CAP message can be sent. Once it is, a
+    /// message the Security Manager had to queue in the meantime is sent.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if !self.l2cap.fragmenter.is_idle() {
+            return self.l2cap.fragmenter.flush(self.tx);
+        }
+
+        match self.security() {
+            Some(sm) => sm.send_pending(),
+            None => Ok(()),
         }
     }
 
@@ -544,12 +689,60 @@ impl<'a, M: ChannelMapper, P: Producer> L2CAPStateTx<'a, M, P> {
     /// return an `AttributeServerTx` instance that can be used to initiate an ATT-specific
     /// procedure.
     ///
-    /// Returns `None` if there's not enough space in the TX packet queue to send an ATT PDU. If
-    /// that happens, calling this method again at a later time (after the Link-Layer had time to
-    /// transmit more packets) might succeed.
-    pub fn att(&mut self) -> Option<att::AttributeServerTx<'_, M::AttributeProvider>> {
-        let att = self.l2cap.mapper.att();
-        Sender::new(&att, self.tx).map(move |sender| att.into_protocol().with_sender(sender))
+    /// Returns `None` if another L2CAP message is still being sent. If that happens, calling this
+    /// method again at a later time (after the Link-Layer had time to transmit more packets) might
+    /// succeed.
+    pub fn att(
+        &mut self,
+    ) -> Option<att::AttributeServerTx<'_, M::AttributeProvider, M::ClientHandler>> {
+        let L2CAPState {
+            mapper, fragmenter, ..
+        } = &mut *self.l2cap;
+        let att = mapper.att();
+        Sender::new(&att, self.tx, fragmenter)
+            .map(move |sender| att.into_protocol().with_sender(sender))
+    }
+
+    /// Prepares for sending Security Manager commands.
+    ///
+    /// The returned `SecurityManagerTx` can be used to pass user input to an ongoing pairing
+    /// procedure.
+    ///
+    /// Returns `None` if another L2CAP message is still being sent.
+    pub fn security(&mut self) -> Option<security::SecurityManagerTx<'_, M::SecurityLevel>> {
+        let L2CAPState {
+            mapper, fragmenter, ..
+        } = &mut *self.l2cap;
+        let sm = mapper.security();
+        Sender::new(&sm, self.tx, fragmenter)
+            .map(move |sender| sm.into_protocol().with_sender(sender))
+    }
+}
+
+/// Dispatches a fully reassembled L2CAP message to the protocol listening on the addressed
+/// channel.
+fn dispatch<M: ChannelMapper>(
+    mapper: &mut M,
+    fragmenter: &mut Fragmenter,
+    tx: &mut dyn Producer,
+    channel: Channel,
+    payload: &[u8],
+) -> Consume<()> {
+    if let Some(mut chdata) = mapper.lookup(channel) {
+        let sender = if let Some(sender) = Sender::new(&chdata, tx, fragmenter) {
+            sender
+        } else {
+            return Consume::never(Ok(()));
+        };
+
+        Consume::always(chdata.protocol().process_message(payload, sender))
+    } else {
+        warn!(
+            "ignoring message sent to unconnected channel {:?}: {:?}",
+            channel,
+            HexSlice(payload)
+        );
+        Consume::always(Ok(()))
     }
 }
 
@@ -566,3 +759,142 @@ impl<'a, M: ChannelMapper, P: Producer> DerefMut for L2CAPStateTx<'a, M, P> {
         &mut self.l2cap
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::queue::{Consumer, PacketQueue, SimpleQueue};
+
+    /// Protocol that echoes every message back, padded to `RSP_PDU_SIZE`.
+    struct Echo;
+
+    impl ProtocolObj for Echo {
+        fn process_message(&mut self, message: &[u8], mut sender: Sender<'_>) -> Result<(), Error> {
+            sender.send_with(|writer| {
+                writer.write_slice(message)?;
+                writer.write_slice(&[0xEE; MAX_MESSAGE_SIZE][..writer.space_left()])
+            })
+        }
+    }
+
+    impl Protocol for Echo {
+        const RSP_PDU_SIZE: u8 = 255;
+    }
+
+    struct EchoMapper {
+        echo: Echo,
+        att: AttributeServer<NoAttributes>,
+        sm: SecurityManager<NoSecurity>,
+    }
+
+    const ECHO: Channel = Channel(0x0040);
+
+    impl ChannelMapper for EchoMapper {
+        type AttributeProvider = NoAttributes;
+        type ClientHandler = NoClient;
+        type SecurityLevel = NoSecurity;
+
+        fn lookup(&mut self, channel: Channel) -> Option<ChannelData<'_, dyn ProtocolObj + '_>> {
+            match channel {
+                ECHO => Some(ChannelData::new_dyn(channel, &mut self.echo)),
+                _ => None,
+            }
+        }
+
+        fn att(
+            &mut self,
+        ) -> ChannelData<'_, AttributeServer<Self::AttributeProvider, Self::ClientHandler>>
+        {
+            ChannelData::new(Channel::ATT, &mut self.att)
+        }
+
+        fn security(&mut self) -> ChannelData<'_, SecurityManager<Self::SecurityLevel>> {
+            ChannelData::new(Channel::LE_SECURITY_MANAGER, &mut self.sm)
+        }
+    }
+
+    fn l2cap() -> L2CAPState<EchoMapper> {
+        L2CAPState::new(EchoMapper {
+            echo: Echo,
+            att: AttributeServer::new(NoAttributes),
+            sm: SecurityManager::no_security(),
+        })
+    }
+
+    #[test]
+    fn reassemble_and_fragment() {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = queue.split();
+        let mut l2cap = l2cap();
+
+        // 30 Byte message, split into 3 fragments.
+        let msg: Vec<u8> = (0..30).collect();
+        let mut start = vec![30, 0, 0x40, 0];
+        start.extend_from_slice(&msg[..10]);
+
+        let mut l2 = l2cap.tx(&mut tx);
+        assert!(l2.process_start(&start).should_consume());
+        assert!(l2.process_cont(&msg[10..20]).should_consume());
+        assert!(!l2.can_flush());
+        assert!(l2.process_cont(&msg[20..]).should_consume());
+
+        // The 259 Byte response doesn't fit in a single PDU.
+        let mut received = Vec::new();
+        let mut llids = Vec::new();
+        loop {
+            rx.consume_raw_with(|header, payload| {
+                llids.push(header.llid());
+                received.extend_from_slice(payload);
+                Consume::always(Ok(()))
+            })
+            .unwrap();
+
+            let mut l2 = l2cap.tx(&mut tx);
+            if !l2.can_flush() {
+                break;
+            }
+            l2.flush().unwrap();
+        }
+
+        assert_eq!(llids, [Llid::DataStart, Llid::DataCont]);
+        assert_eq!(&received[..4], &[255, 0, 0x40, 0]);
+        assert_eq!(&received[4..34], &msg[..]);
+        assert_eq!(&received[34..], &[0xEE; 225][..]);
+    }
+
+    #[test]
+    fn oversized_fragment_dropped() {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, _rx) = queue.split();
+        let mut l2cap = l2cap();
+        let mut l2 = l2cap.tx(&mut tx);
+
+        assert!(l2.process_start(&[4, 0, 0x40, 0, 1, 2]).should_consume());
+        assert!(l2.process_cont(&[3, 4, 5]).result().is_ok());
+        assert!(!l2.reassembler.in_progress());
+        assert!(!l2.can_flush());
+    }
+
+    #[test]
+    fn att_mtu_exchange() {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = queue.split();
+        let mut l2cap = L2CAPState::new(BleChannelMap::empty());
+        l2cap.attribute_server().set_max_mtu(100);
+
+        // Client requests an `ATT_MTU` of 200, the server supports 100.
+        let mut l2 = l2cap.tx(&mut tx);
+        assert!(l2
+            .process_start(&[3, 0, 0x04, 0, 0x02, 200, 0])
+            .should_consume());
+        rx.consume_raw_with(|_, payload| {
+            assert_eq!(payload, &[3, 0, 0x04, 0, 0x03, 100, 0]);
+            Consume::always(Ok(()))
+        })
+        .unwrap();
+        assert_eq!(l2cap.attribute_server().att_mtu(), 100);
+
+        l2cap.attribute_server().connection_established();
+        assert_eq!(l2cap.attribute_server().att_mtu(), 23);
+    }
+}
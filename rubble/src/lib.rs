@@ -35,9 +35,11 @@ pub mod att;
 pub mod beacon;
 pub mod bytes;
 pub mod config;
+pub mod crypto;
 pub mod ecdh;
 mod error;
 pub mod gatt;
+pub mod hci;
 pub mod l2cap;
 pub mod link;
 pub mod phy;
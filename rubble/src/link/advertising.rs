@@ -9,9 +9,11 @@
 
 use crate::link::ad_structure::{AdStructure, Flags};
 use crate::link::{channel_map::ChannelMap, AddressKind, DeviceAddress};
+use crate::phy::AdvertisingChannelMap;
 use crate::utils::{Hex, HexSlice};
 use crate::{bytes::*, time::Duration, Error};
 use core::{convert::TryInto, fmt, iter};
+use rand_core::RngCore;
 
 /// CRC initialization value for advertising channel packets.
 ///
@@ -349,6 +351,58 @@ pub struct ConnectRequestData {
 }
 
 impl ConnectRequestData {
+    /// Creates connection parameters for a new connection, to be sent to an advertiser in a
+    /// `CONNECT_REQ` PDU.
+    ///
+    /// The Access Address, CRC initialization value and hop increment are chosen randomly using
+    /// `rng`. The transmit window starts right after the mandatory 1.25 ms delay and is 1.25 ms
+    /// long.
+    ///
+    /// # Parameters
+    ///
+    /// * **`interval`**: Connection event interval. Must be in range 7.5 ms to 4 s, and is rounded
+    ///   down to units of 1.25 ms.
+    /// * **`slave_latency`**: Number of connection events the slave may skip. Must be at most 499.
+    /// * **`timeout`**: Supervision timeout. Must be in range 100 ms to 32 s, is rounded down to
+    ///   units of 10 ms, and must be larger than `(1 + slave_latency) * interval * 2`.
+    /// * **`channel_map`**: Data channels to use. At least 2 channels must be marked as used.
+    /// * **`rng`**: Random number generator.
+    ///
+    /// Returns `Error::InvalidValue` if any parameter is out of range.
+    pub fn new<R: RngCore>(
+        interval: Duration,
+        slave_latency: u16,
+        timeout: Duration,
+        channel_map: ChannelMap,
+        rng: &mut R,
+    ) -> Result<Self, Error> {
+        check_conn_params(interval, slave_latency, timeout)?;
+        if channel_map.num_used_channels() < 2 {
+            return Err(Error::InvalidValue);
+        }
+
+        let access_address = loop {
+            let aa = rng.next_u32();
+            if is_valid_access_address(aa) {
+                break aa;
+            }
+        };
+
+        Ok(Self {
+            access_address: Hex(access_address),
+            crc_init: Hex(rng.next_u32() & 0x00FF_FFFF),
+            win_size: Duration::from_micros(1250),
+            win_offset: Duration::from_micros(0),
+            interval: Duration::from_micros(interval.as_micros() / 1250 * 1250),
+            latency: slave_latency,
+            timeout: Duration::from_micros(timeout.as_micros() / 10_000 * 10_000),
+            chm: channel_map,
+            hop: 5 + (rng.next_u32() % 12) as u8,
+            // We don't know how accurate our sleep clock is, so assume the worst
+            sca: SleepClockAccuracy::Ppm251To500,
+        })
+    }
+
     /// Returns the Access Address to use for data channel communication.
     ///
     /// The address is randomly generated by the initiator (the device sending the connection
@@ -376,6 +430,14 @@ impl ConnectRequestData {
         self.hop
     }
 
+    /// Returns the start of the transmit window from the end of the `CONNECT_REQ` containing
+    /// `self`.
+    ///
+    /// The master sends its first data channel packet inside the transmit window.
+    pub fn start_of_tx_window(&self) -> Duration {
+        self.win_offset + Duration::from_micros(1250)
+    }
+
     /// Returns the end of the transmit window from reception of the `CONNECT_REQ` containing
     /// `self`.
     pub fn end_of_tx_window(&self) -> Duration {
@@ -445,6 +507,64 @@ impl FromBytes<'_> for ConnectRequestData {
     }
 }
 
+impl ToBytes for ConnectRequestData {
+    fn to_bytes(&self, writer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        writer.write_u32_le(self.access_address.0)?;
+        writer.write_slice(&self.crc_init.0.to_le_bytes()[..3])?;
+        writer.write_u8((self.win_size.as_micros() / 1250) as u8)?;
+        writer.write_u16_le((self.win_offset.as_micros() / 1250) as u16)?;
+        writer.write_u16_le((self.interval.as_micros() / 1250) as u16)?;
+        writer.write_u16_le(self.latency)?;
+        writer.write_u16_le((self.timeout.as_micros() / 10_000) as u16)?;
+        writer.write_slice(&self.chm.to_raw())?;
+        writer.write_u8(self.hop | (self.sca as u8) << 5)?;
+        Ok(())
+    }
+}
+
+/// Checks connection parameters set by the master against the ranges allowed by the spec.
+///
+/// Returns `Error::InvalidValue` if the interval, slave latency or supervision timeout are out of
+/// range, or if the timeout is too short to allow for the given latency.
+pub(crate) fn check_conn_params(
+    interval: Duration,
+    slave_latency: u16,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let interval = interval.as_micros();
+    let timeout = timeout.as_micros();
+    let min_timeout = (1 + u64::from(slave_latency)) * u64::from(interval) * 2;
+    if !(7_500..=4_000_000).contains(&interval)
+        || slave_latency > 499
+        || !(100_000..=32_000_000).contains(&timeout)
+        || u64::from(timeout) <= min_timeout
+    {
+        Err(Error::InvalidValue)
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns whether `aa` satisfies the spec's requirements for data channel Access Addresses.
+fn is_valid_access_address(aa: u32) -> bool {
+    // Bit `i` is set if bits `i` and `i + 1` of `aa` differ
+    let transitions = (aa ^ (aa >> 1)) & 0x7FFF_FFFF;
+    let bytes = aa.to_le_bytes();
+    // No more than 6 consecutive zeros or ones
+    let has_long_run = (0..=25).any(|i| {
+        let bits = (aa >> i) & 0x7F;
+        bits == 0 || bits == 0x7F
+    });
+
+    // Must differ from the advertising Access Address by more than 1 bit
+    (aa ^ ACCESS_ADDRESS).count_ones() > 1
+        && !bytes.iter().all(|b| *b == bytes[0])
+        && !has_long_run
+        && transitions.count_ones() <= 24
+        // At least 2 transitions in the 6 most significant bits
+        && (transitions >> 26).count_ones() >= 2
+}
+
 /// Indicates the master's sleep clock accuracy (SCA) in ppm (parts per
 /// million).
 ///
@@ -461,6 +581,162 @@ pub enum SleepClockAccuracy {
     Ppm0To20,
 }
 
+/// The kind of advertising events to send.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AdvertisingType {
+    /// Connectable and scannable undirected advertising (`ADV_IND`).
+    ///
+    /// A `Flags` structure marking the device as discoverable is added to the advertising data
+    /// (like `PduBuf::discoverable` does).
+    ConnectableUndirected,
+
+    /// Connectable directed advertising (`ADV_DIRECT_IND`), only accepting a connection from
+    /// `initiator`.
+    ///
+    /// This uses low duty cycle directed advertising, so the advertising interval limits apply
+    /// as for the other types. Directed advertisements can not carry advertising data.
+    ConnectableDirected {
+        /// Address of the device allowed to connect.
+        initiator: DeviceAddress,
+    },
+
+    /// Scannable undirected advertising (`ADV_SCAN_IND`).
+    ScannableUndirected,
+
+    /// Non-connectable and non-scannable undirected advertising (`ADV_NONCONN_IND`).
+    NonconnectableUndirected,
+}
+
+impl AdvertisingType {
+    /// Returns the type of advertising PDU sent.
+    pub fn pdu_type(&self) -> PduType {
+        match self {
+            AdvertisingType::ConnectableUndirected => PduType::AdvInd,
+            AdvertisingType::ConnectableDirected { .. } => PduType::AdvDirectInd,
+            AdvertisingType::ScannableUndirected => PduType::AdvScanInd,
+            AdvertisingType::NonconnectableUndirected => PduType::AdvNonconnInd,
+        }
+    }
+
+    /// Returns whether scan requests are answered.
+    pub fn is_scannable(&self) -> bool {
+        match self {
+            AdvertisingType::ConnectableUndirected | AdvertisingType::ScannableUndirected => true,
+            AdvertisingType::ConnectableDirected { .. }
+            | AdvertisingType::NonconnectableUndirected => false,
+        }
+    }
+
+    /// Returns whether a `CONNECT_REQ` from `initiator` should be accepted.
+    pub fn accepts_connect_request(&self, initiator: &DeviceAddress) -> bool {
+        match self {
+            AdvertisingType::ConnectableUndirected => true,
+            AdvertisingType::ConnectableDirected { initiator: allowed } => allowed == initiator,
+            AdvertisingType::ScannableUndirected | AdvertisingType::NonconnectableUndirected => {
+                false
+            }
+        }
+    }
+}
+
+/// Parameters controlling how a device advertises.
+#[derive(Copy, Clone, Debug)]
+pub struct AdvertisingParameters {
+    ty: AdvertisingType,
+    interval_min: Duration,
+    interval_max: Duration,
+    channels: AdvertisingChannelMap,
+}
+
+impl AdvertisingParameters {
+    /// Creates advertising parameters using all advertising channels.
+    ///
+    /// # Parameters
+    ///
+    /// * `ty`: The kind of advertising PDU to send.
+    /// * `interval_min`, `interval_max`: Range of the advertising interval (rounded down to units
+    ///   of 0.625 ms). The spec allows intervals from 20 ms to 10.24 s.
+    ///
+    /// The Link-Layer advertises with an interval of `interval_min`. The spec additionally adds a
+    /// random delay of 0-10 ms to every advertising event, so the time between events is
+    /// slightly longer.
+    ///
+    /// Returns `Error::InvalidValue` if an interval is out of range or `interval_min` is larger
+    /// than `interval_max`.
+    pub fn new(
+        ty: AdvertisingType,
+        interval_min: Duration,
+        interval_max: Duration,
+    ) -> Result<Self, Error> {
+        let round = |interval: Duration| Duration::from_micros(interval.as_micros() / 625 * 625);
+        let (interval_min, interval_max) = (round(interval_min), round(interval_max));
+        let range = Duration::from_millis(20)..=Duration::from_micros(10_240_000);
+        if !range.contains(&interval_min)
+            || !range.contains(&interval_max)
+            || interval_min > interval_max
+        {
+            return Err(Error::InvalidValue);
+        }
+
+        Ok(Self {
+            ty,
+            interval_min,
+            interval_max,
+            channels: AdvertisingChannelMap::all(),
+        })
+    }
+
+    /// Restricts advertising to the channels in `channels`.
+    pub fn set_channels(&mut self, channels: AdvertisingChannelMap) {
+        self.channels = channels;
+    }
+
+    /// Returns the kind of advertising PDU sent.
+    pub fn advertising_type(&self) -> AdvertisingType {
+        self.ty
+    }
+
+    /// Returns the lower bound of the advertising interval.
+    pub fn interval_min(&self) -> Duration {
+        self.interval_min
+    }
+
+    /// Returns the upper bound of the advertising interval.
+    pub fn interval_max(&self) -> Duration {
+        self.interval_max
+    }
+
+    /// Returns the advertising channels used.
+    pub fn channels(&self) -> AdvertisingChannelMap {
+        self.channels
+    }
+
+    /// Builds the advertising PDU sent by `advertiser`, carrying `data`.
+    ///
+    /// Returns `Error::InvalidValue` if `data` is not empty but the advertising type does not
+    /// allow advertising data, and `Error::Eof` if `data` does not fit into the PDU.
+    pub fn build_pdu(
+        &self,
+        advertiser: DeviceAddress,
+        data: &[AdStructure<'_>],
+    ) -> Result<PduBuf, Error> {
+        match self.ty {
+            AdvertisingType::ConnectableUndirected => PduBuf::discoverable(advertiser, data),
+            AdvertisingType::ConnectableDirected { initiator } => {
+                if data.is_empty() {
+                    Ok(PduBuf::connectable_directed(advertiser, initiator))
+                } else {
+                    Err(Error::InvalidValue)
+                }
+            }
+            AdvertisingType::ScannableUndirected => PduBuf::scannable_undirected(advertiser, data),
+            AdvertisingType::NonconnectableUndirected => {
+                PduBuf::nonconnectable_undirected(advertiser, data)
+            }
+        }
+    }
+}
+
 /// Stores an advertising channel PDU.
 ///
 /// This is an owned version of `Pdu` and should be used when *creating* a PDU
@@ -554,8 +830,6 @@ impl PduBuf {
     }
 
     /// Creates a scannable undirected advertising PDU (`ADV_SCAN_IND`).
-    ///
-    /// Note that scanning is not supported at the moment.
     pub fn scannable_undirected(
         advertiser_addr: DeviceAddress,
         advertiser_data: &[AdStructure<'_>],
@@ -603,21 +877,58 @@ impl PduBuf {
 
     /// Creates a scan request PDU.
     ///
-    /// Note that scanning is not yet implemented.
-    ///
     /// # Parameters
     ///
     /// * `scanner`: Device address of the device in scanning state (sender of
     ///   the request).
     /// * `adv`: Device address of the advertising device that this scan request
     ///   is directed towards.
-    pub fn scan_request(_scanner: DeviceAddress, _adv: DeviceAddress) -> Result<Self, Error> {
-        unimplemented!()
+    pub fn scan_request(scanner: DeviceAddress, adv: DeviceAddress) -> Result<Self, Error> {
+        let mut payload = [0; MAX_PAYLOAD_SIZE];
+        payload[0..6].copy_from_slice(scanner.raw());
+        payload[6..12].copy_from_slice(adv.raw());
+
+        let mut header = Header::new(PduType::ScanReq);
+        header.set_payload_length(6 + 6);
+        header.set_tx_add(scanner.is_random());
+        header.set_rx_add(adv.is_random());
+        Ok(Self {
+            header,
+            payload_buf: payload,
+        })
     }
 
-    /// Creates a scan response PDU.
+    /// Creates a connection request PDU (`CONNECT_REQ`).
+    ///
+    /// # Parameters
     ///
-    /// Note that scanning is not yet implemented.
+    /// * `initiator_addr`: Device address of the initiating device (sender of the request).
+    /// * `advertiser_addr`: Device address of the advertising device to connect to.
+    /// * `lldata`: Parameters of the new connection.
+    pub fn connect_request(
+        initiator_addr: DeviceAddress,
+        advertiser_addr: DeviceAddress,
+        lldata: &ConnectRequestData,
+    ) -> Self {
+        let mut payload = [0; MAX_PAYLOAD_SIZE];
+        let mut buf = ByteWriter::new(&mut payload[..]);
+        buf.write_slice(initiator_addr.raw()).unwrap();
+        buf.write_slice(advertiser_addr.raw()).unwrap();
+        lldata.to_bytes(&mut buf).unwrap();
+
+        let left = buf.space_left();
+        let used = payload.len() - left;
+        let mut header = Header::new(PduType::ConnectReq);
+        header.set_payload_length(used as u8);
+        header.set_tx_add(initiator_addr.is_random());
+        header.set_rx_add(advertiser_addr.is_random());
+        Self {
+            header,
+            payload_buf: payload,
+        }
+    }
+
+    /// Creates a scan response PDU.
     pub fn scan_response(
         advertiser_addr: DeviceAddress,
         scan_data: &[AdStructure<'_>],
@@ -791,7 +1102,7 @@ enum_with_unknown! {
     ///
     /// [`Header`]: struct.Header.html
     /// [`PduBuf`]: struct.PduBuf.html
-    #[derive(Debug, PartialEq, Eq)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     pub enum PduType(u8) {
         /// Connectable undirected advertising event (`ADV_IND`).
         AdvInd = 0b0000,
@@ -839,3 +1150,117 @@ impl PduType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::XorShift32;
+
+    #[test]
+    fn access_address() {
+        assert!(!is_valid_access_address(ACCESS_ADDRESS));
+        assert!(!is_valid_access_address(ACCESS_ADDRESS ^ 0x0001_0000));
+        assert!(!is_valid_access_address(0x5555_5555));
+        assert!(!is_valid_access_address(0x8E89_BE80));
+        assert!(is_valid_access_address(0x8E89_BEC6 ^ 0x0100_0000));
+        assert!(is_valid_access_address(0x5065_A64A));
+    }
+
+    #[test]
+    fn connect_request() {
+        let initiator = DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Random);
+        let advertiser = DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Public);
+        let interval = Duration::from_millis(50);
+        let timeout = Duration::from_secs(1);
+        let map = ChannelMap::with_all_channels();
+
+        assert_eq!(
+            ConnectRequestData::new(interval, 10, timeout, map, &mut XorShift32::new(1)).err(),
+            Some(Error::InvalidValue)
+        );
+        assert_eq!(
+            ConnectRequestData::new(
+                Duration::from_secs(5),
+                0,
+                timeout,
+                map,
+                &mut XorShift32::new(1)
+            )
+            .err(),
+            Some(Error::InvalidValue)
+        );
+
+        let lldata =
+            ConnectRequestData::new(interval, 2, timeout, map, &mut XorShift32::new(1)).unwrap();
+        assert!(is_valid_access_address(lldata.access_address()));
+        assert_eq!(lldata.crc_init() & !0x00FF_FFFF, 0);
+        assert!((5..=16).contains(&lldata.hop()));
+
+        let buf = PduBuf::connect_request(initiator, advertiser, &lldata);
+        assert_eq!(buf.payload().len(), 34);
+        let pdu = Pdu::from_header_and_payload(buf.header(), &mut ByteReader::new(buf.payload()))
+            .unwrap();
+        match pdu {
+            Pdu::ConnectRequest {
+                initiator_addr,
+                advertiser_addr,
+                lldata: parsed,
+            } => {
+                assert_eq!(initiator_addr, initiator);
+                assert_eq!(advertiser_addr, advertiser);
+                assert_eq!(parsed.access_address(), lldata.access_address());
+                assert_eq!(parsed.crc_init(), lldata.crc_init());
+                assert_eq!(parsed.hop(), lldata.hop());
+                assert_eq!(parsed.interval(), interval);
+                assert_eq!(parsed.slave_latency(), 2);
+                assert_eq!(parsed.supervision_timeout(), timeout);
+                assert_eq!(parsed.channel_map(), &map);
+                assert_eq!(parsed.start_of_tx_window(), Duration::from_micros(1250));
+            }
+            _ => panic!("unexpected PDU {:?}", pdu),
+        }
+    }
+
+    #[test]
+    fn advertising_parameters() {
+        let ms = Duration::from_millis;
+        let new =
+            |min, max| AdvertisingParameters::new(AdvertisingType::ConnectableUndirected, min, max);
+        assert!(new(ms(19), ms(100)).is_err());
+        assert!(new(ms(100), ms(10_241)).is_err());
+        assert!(new(ms(200), ms(100)).is_err());
+
+        let params = new(Duration::from_micros(20_624), ms(10_240)).unwrap();
+        assert_eq!(params.interval_min(), ms(20));
+        assert_eq!(params.interval_max(), ms(10_240));
+        assert_eq!(params.channels(), AdvertisingChannelMap::all());
+
+        let addr = DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Random);
+        let peer = DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Public);
+        let data = [AdStructure::CompleteLocalName("x")];
+        let directed = AdvertisingType::ConnectableDirected { initiator: peer };
+        let params = AdvertisingParameters::new(directed, ms(100), ms(100)).unwrap();
+        assert_eq!(
+            params.build_pdu(addr, &data).err(),
+            Some(Error::InvalidValue)
+        );
+        let pdu = params.build_pdu(addr, &[]).unwrap();
+        assert_eq!(pdu.header().type_(), PduType::AdvDirectInd);
+        assert!(directed.accepts_connect_request(&peer));
+        assert!(!directed.accepts_connect_request(&addr));
+        assert!(!directed.is_scannable());
+        assert!(!AdvertisingType::ScannableUndirected.accepts_connect_request(&peer));
+
+        let channels = AdvertisingChannelMap::from_raw(0b101).unwrap();
+        let first = channels.first();
+        assert_eq!(first.channel(), 37);
+        assert_eq!(channels.next_after(first).map(|ch| ch.channel()), Some(39));
+        assert_eq!(
+            channels.next_after(first.cycle()).map(|ch| ch.channel()),
+            Some(39)
+        );
+        assert!(channels.next_after(first.cycle().cycle()).is_none());
+        assert!(AdvertisingChannelMap::from_raw(0).is_err());
+        assert!(AdvertisingChannelMap::from_raw(0b1000).is_err());
+    }
+}
@@ -1,19 +1,23 @@
 //! Link-Layer connection management and LLCP implementation.
 
 use crate::link::data::{self, Header, Llid, Pdu};
-use crate::link::llcp::{ConnectionUpdateData, ControlPdu};
+use crate::link::encryption::{Encryption, LtkRequest};
+use crate::link::llcp::{ConnectionUpdateData, ControlOpcode, ControlPdu, DataLength};
 use crate::link::queue::{Consume, Consumer, Producer};
 use crate::link::{
-    advertising::ConnectRequestData, channel_map::ChannelMap, Cmd, CompanyId, FeatureSet,
-    NextUpdate, RadioCmd, SeqNum, Transmitter,
+    advertising::ConnectRequestData, channel_map::ChannelMap, Cmd, CompanyId, DeviceAddress,
+    FeatureSet, NextUpdate, RadioCmd, SeqNum, Transmitter, MAX_DATA_PAYLOAD_BUF,
 };
 use crate::time::{Duration, Instant, Timer};
 use crate::utils::{Hex, HexSlice};
-use crate::{bytes::*, config::*, phy::DataChannel, Error, BLUETOOTH_VERSION};
-use core::{marker::PhantomData, num::Wrapping};
+use crate::{bytes::*, config::*, crypto::Key, phy::DataChannel, Error, BLUETOOTH_VERSION};
+use core::{cmp, marker::PhantomData, num::Wrapping};
 
 /// Connection state and parameters.
 pub struct Connection<C: Config> {
+    /// Address of the connected device.
+    peer: DeviceAddress,
+
     access_address: u32,
     crc_init: u32,
     channel_map: ChannelMap,
@@ -24,6 +28,9 @@ pub struct Connection<C: Config> {
     /// Connection event interval (duration between the start of 2 subsequent connection events).
     conn_interval: Duration,
 
+    slave_latency: u16,
+    supervision_timeout: Duration,
+
     /// Connection event counter (`connEventCount(er)` in the spec).
     conn_event_count: Wrapping<u16>,
 
@@ -35,6 +42,12 @@ pub struct Connection<C: Config> {
     /// Actual data channel on which the next data packets will be exchanged.
     channel: DataChannel,
 
+    /// Data channel of the connection event in progress.
+    ///
+    /// Only used in the master role, where `channel` already refers to the next connection event
+    /// while waiting for the slave's response.
+    event_channel: DataChannel,
+
     // Acknowledgement / Flow Control state
     /// `SN` bit to be used
     transmit_seq_num: SeqNum,
@@ -46,6 +59,35 @@ pub struct Connection<C: Config> {
     /// Whether we have ever received a data packet in this connection.
     received_packet: bool,
 
+    /// Whether a packet with a valid CRC was received, which means the connection is established.
+    established: bool,
+
+    /// Reception time of the last packet with a valid CRC, or the creation time of the connection.
+    ///
+    /// This is where the connection supervision timer starts.
+    last_rx: Instant,
+
+    /// Packet sizes supported by us (`connMaxTxOctets`, `connMaxRxOctets` and the corresponding
+    /// times).
+    local_length: DataLength,
+
+    /// Packet sizes supported by the connected device, as announced in `LL_LENGTH_REQ` or
+    /// `LL_LENGTH_RSP`.
+    remote_length: DataLength,
+
+    /// Whether we still have to send an `LL_LENGTH_REQ` to initiate the *Data Length Update
+    /// Procedure*.
+    length_req_pending: bool,
+
+    /// Number of payload Bytes of the first PDU in the `tx` queue that have already been sent.
+    ///
+    /// Queued PDUs that are larger than the negotiated max. payload size are sent in several
+    /// chunks.
+    tx_offset: u8,
+
+    /// State of the *Encryption Start* and *Encryption Pause Procedures*, and the session keys.
+    encryption: Encryption,
+
     tx: ConfConsumer<C>,
     rx: ConfProducer<C>,
 
@@ -54,6 +96,27 @@ pub struct Connection<C: Config> {
     /// Contains the *instant* at which it should be applied to the Link Layer state.
     update_data: Option<LlcpUpdate>,
 
+    /// Whether we are the master of this connection (ie. we have sent the `CONNECT_REQ`).
+    master: bool,
+
+    /// Anchor point of the next connection event.
+    ///
+    /// Only used in the master role, where we have to transmit the first packet of every
+    /// connection event.
+    anchor: Instant,
+
+    /// Update requested by the host, which still has to be sent to the slave.
+    ///
+    /// The *instant* is chosen when the LL Control PDU is sent.
+    pending_update: Option<LlcpUpdate>,
+
+    /// Error code of an `LL_TERMINATE_IND` requested by the host, which still has to be sent.
+    pending_terminate: Option<u8>,
+
+    /// Error code of the `LL_TERMINATE_IND` we have sent. The connection ends once it is
+    /// acknowledged.
+    terminate_sent: Option<u8>,
+
     _p: PhantomData<C>,
 }
 
@@ -65,69 +128,155 @@ impl<C: Config> Connection<C> {
     ///
     /// # Parameters
     ///
+    /// * **`peer`**: Address of the initiator that sent the `CONNECT_REQ`.
     /// * **`lldata`**: Data contained in the `CONNECT_REQ` advertising PDU.
     /// * **`rx_end`**: Instant at which the `CONNECT_REQ` PDU was fully received.
+    /// * **`max_payload`**: Largest data channel PDU payload supported by the radio.
     /// * **`tx`**: Channel for packets to transmit.
     /// * **`rx`**: Channel for received packets.
     pub(crate) fn create(
+        peer: DeviceAddress,
         lldata: &ConnectRequestData,
         rx_end: Instant,
+        max_payload: u8,
+        tx: ConfConsumer<C>,
+        rx: ConfProducer<C>,
+    ) -> (Self, Cmd) {
+        let mut this = Self::new(peer, lldata, false, rx_end, max_payload, tx, rx);
+        this.encryption = Encryption::slave();
+
+        let cmd = Cmd {
+            next_update: NextUpdate::At(
+                rx_end + lldata.end_of_tx_window() + Duration::from_micros(500),
+            ),
+            radio: RadioCmd::ListenData {
+                channel: this.channel,
+                access_address: this.access_address,
+                crc_init: this.crc_init,
+                timeout: false,
+            },
+            queued_work: false,
+        };
+
+        (this, cmd)
+    }
+
+    /// Initializes a connection state in the master role, after sending a `CONNECT_REQ`
+    /// advertising PDU containing `lldata`.
+    ///
+    /// The first packet is sent at the start of the transmit window, which becomes the anchor point
+    /// of the first connection event. Encryption is not supported in the master role.
+    ///
+    /// Returns the connection state and a `Cmd` to apply to the radio/timer.
+    ///
+    /// # Parameters
+    ///
+    /// * **`peer`**: Address of the advertiser the `CONNECT_REQ` was sent to.
+    /// * **`lldata`**: Data contained in the `CONNECT_REQ` advertising PDU.
+    /// * **`tx_end`**: Instant at which the `CONNECT_REQ` PDU was fully transmitted.
+    /// * **`max_payload`**: Largest data channel PDU payload supported by the radio.
+    /// * **`tx`**: Channel for packets to transmit.
+    /// * **`rx`**: Channel for received packets.
+    pub(crate) fn create_master(
+        peer: DeviceAddress,
+        lldata: &ConnectRequestData,
+        tx_end: Instant,
+        max_payload: u8,
         tx: ConfConsumer<C>,
         rx: ConfProducer<C>,
     ) -> (Self, Cmd) {
+        let anchor = tx_end + lldata.start_of_tx_window();
+        let this = Self::new(peer, lldata, true, anchor, max_payload, tx, rx);
+
+        let cmd = Cmd {
+            next_update: NextUpdate::At(anchor),
+            radio: RadioCmd::Off,
+            queued_work: false,
+        };
+
+        (this, cmd)
+    }
+
+    fn new(
+        peer: DeviceAddress,
+        lldata: &ConnectRequestData,
+        master: bool,
+        anchor: Instant,
+        max_payload: u8,
+        tx: ConfConsumer<C>,
+        rx: ConfProducer<C>,
+    ) -> Self {
+        // We can only receive packets that fit in the RX queue.
+        let max_rx = cmp::min(max_payload, rx.free_space());
+        let local_length = DataLength::new(max_rx.into(), max_payload.into());
+
         let mut this = Self {
+            peer,
             access_address: lldata.access_address(),
             crc_init: lldata.crc_init(),
             channel_map: *lldata.channel_map(),
             hop: lldata.hop(),
             conn_interval: lldata.interval(),
+            slave_latency: lldata.slave_latency(),
+            supervision_timeout: lldata.supervision_timeout(),
             conn_event_count: Wrapping(0),
 
             unmapped_channel: DataChannel::new(0),
             channel: DataChannel::new(0),
+            event_channel: DataChannel::new(0),
 
             transmit_seq_num: SeqNum::ZERO,
             next_expected_seq_num: SeqNum::ZERO,
             last_header: Header::new(Llid::DataCont),
             received_packet: false,
+            established: false,
+            last_rx: anchor,
+
+            local_length,
+            remote_length: DataLength::default(),
+            length_req_pending: local_length != DataLength::default(),
+            tx_offset: 0,
+
+            encryption: Encryption::new(),
 
             tx,
             rx,
             update_data: None,
 
+            master,
+            anchor,
+            pending_update: None,
+            pending_terminate: None,
+            terminate_sent: None,
+
             _p: PhantomData,
         };
 
         // Calculate the first channel to use
         this.hop_channel();
-
-        let cmd = Cmd {
-            next_update: NextUpdate::At(
-                rx_end + lldata.end_of_tx_window() + Duration::from_micros(500),
-            ),
-            radio: RadioCmd::ListenData {
-                channel: this.channel,
-                access_address: this.access_address,
-                crc_init: this.crc_init,
-                timeout: false,
-            },
-            queued_work: false,
-        };
-
-        (this, cmd)
+        this
     }
 
     /// Called by the `LinkLayer` when a data channel packet is received.
     ///
-    /// Returns `Err(())` when the connection is ended (not necessarily due to an error condition).
-    pub(crate) fn process_data_packet(
+    /// Returns the reason when the connection is ended (not necessarily due to an error condition).
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn process_data_packet<T: Transmitter>(
         &mut self,
         rx_end: Instant,
-        tx: &mut C::Transmitter,
+        tx: &mut T,
+        aes: &mut C::AesProvider,
+        rng: &mut C::Rng,
         header: data::Header,
         payload: &[u8],
         crc_ok: bool,
-    ) -> Result<Cmd, ()> {
+    ) -> Result<Cmd, DisconnectReason> {
+        if crc_ok {
+            // Any valid packet resets the supervision timer.
+            self.established = true;
+            self.last_rx = rx_end;
+        }
+
         // If the sequence number of the packet is the same as our next expected sequence number,
         // the packet contains new data that we should try to process. However, if the CRC is bad,
         // we'll never try to process the data and instead request a retransmission.
@@ -139,18 +288,44 @@ impl<C: Config> Connection<C> {
         // was acknowledged and thus always retransmit.
         let acknowledged = header.nesn() == self.transmit_seq_num + SeqNum::ONE && crc_ok;
 
+        if acknowledged {
+            // Might switch decryption on or off.
+            self.encryption.acknowledged();
+        }
+
+        // Decrypt the payload if encryption is enabled. A wrong MIC means that the connection is
+        // lost.
+        let mut buf = [0; MAX_DATA_PAYLOAD_BUF];
+        let (header, payload) = if is_new {
+            match self.encryption.decrypt(aes, header, payload, &mut buf) {
+                Ok(pdu) => pdu,
+                Err(e) => {
+                    error!("failed to decrypt {:?}: {:?}", header, e);
+                    return Err(DisconnectReason::MicFailure);
+                }
+            }
+        } else {
+            (header, payload)
+        };
+
         let is_empty = header.llid() == Llid::DataCont && payload.is_empty();
 
         if acknowledged {
             self.received_packet = true;
             self.transmit_seq_num += SeqNum::ONE;
+
+            if let Some(error_code) = self.terminate_sent {
+                info!("LL_TERMINATE_IND acknowledged, closing connection");
+                return Err(DisconnectReason::LocalTerminated(error_code));
+            }
         }
 
         // Whether we've already sent a response packet.
         let mut responded = false;
-        // Whether we've pushed more work into the RX queue.
+        // Whether we've pushed more work into the RX queue, or freed up space in the TX queue.
         let mut queued_work = false;
 
+        let next_expected_seq_num = self.next_expected_seq_num;
         if is_new {
             if is_empty {
                 // Always acknowledge empty packets, no need to process them
@@ -165,21 +340,19 @@ impl<C: Config> Connection<C> {
                     // packet we sent, because we'll directly use the radio's TX buffer to send
                     // back the LLCP response.
 
-                    match self.process_control_pdu(pdu, acknowledged) {
+                    match self.process_control_pdu(pdu, acknowledged, rng) {
                         Ok(Some(response)) => {
                             self.next_expected_seq_num += SeqNum::ONE;
 
-                            let rsp = Pdu::from(&response);
-                            let mut payload_writer = ByteWriter::new(tx.tx_payload_buf());
-                            let left = payload_writer.space_left();
-                            rsp.to_bytes(&mut payload_writer).unwrap();
-
-                            let mut header = Header::new(Llid::Control);
-                            let pl_len = (left - payload_writer.space_left()) as u8;
-                            header.set_payload_length(pl_len);
-                            self.send(header, tx);
+                            self.send_control(&response, tx, aes);
                             responded = true;
 
+                            if matches!(pdu, ControlPdu::EncReq { .. } | ControlPdu::StartEncRsp) {
+                                // The host needs to provide the LTK, or may distribute keys now
+                                // that encryption has started.
+                                queued_work = true;
+                            }
+
                             info!("LLCP<- {:?}", pdu);
                             info!("LLCP-> {:?}", response);
                         }
@@ -189,8 +362,22 @@ impl<C: Config> Connection<C> {
                             info!("LLCP<- {:?}", pdu);
                             info!("LLCP-> (no response)");
                         }
-                        Err(LlcpError::ConnectionLost) => {
-                            return Err(());
+                        Err(LlcpError::ConnectionLost(reason)) => {
+                            if let ControlPdu::TerminateInd { .. } = pdu {
+                                // The initiator leaves the connection once it sees the ACK, so
+                                // send it right away (in both roles). Empty PDUs have no MIC.
+                                self.next_expected_seq_num += SeqNum::ONE;
+                                let mut header = Header::new(Llid::DataCont);
+                                header.set_nesn(self.next_expected_seq_num);
+                                header.set_sn(self.transmit_seq_num);
+                                tx.transmit_data(
+                                    self.access_address,
+                                    self.crc_init,
+                                    header,
+                                    self.channel,
+                                );
+                            }
+                            return Err(reason);
                         }
                         Err(LlcpError::NoSpace) => {
                             // Do not acknowledge the PDU
@@ -220,22 +407,83 @@ impl<C: Config> Connection<C> {
             }
         }
 
+        if self.next_expected_seq_num != next_expected_seq_num && !payload.is_empty() {
+            // Acknowledged a new PDU, the next one will use the next packet counter.
+            self.encryption.commit_rx();
+        }
+
         if acknowledged {
-            if !responded {
+            if responded {
+                // Already sent an LLCP response.
+            } else if let Some(error_code) = self.pending_terminate.take() {
+                // Terminate the connection as requested by the host.
+                self.terminate_sent = Some(error_code);
+                let pdu = ControlPdu::TerminateInd {
+                    error_code: Hex(error_code),
+                };
+                self.send_control(&pdu, tx, aes);
+            } else if let Some(pdu) = self.encryption.next_control_pdu() {
+                self.send_control(&pdu, tx, aes);
+            } else if self.encryption.data_paused() {
+                // No data or unrelated LLCP PDUs may be sent while encryption is started or paused.
+                self.send(Header::new(Llid::DataCont), tx, aes);
+            } else if let Some(update) = self.pending_update.take() {
+                // Start a procedure requested by the host. The slave gets 6 connection events to
+                // receive the request before the update takes effect.
+                let update = update.with_instant((self.conn_event_count + Wrapping(6)).0);
+                self.update_data = Some(update);
+                self.send_control(&update.to_control_pdu(), tx, aes);
+            } else if self.length_req_pending {
+                // Initiate the Data Length Update Procedure before sending any queued data.
+                self.length_req_pending = false;
+                let req = ControlPdu::LengthReq(self.local_length);
+                self.send_control(&req, tx, aes);
+            } else {
                 // Send a new data packet.
 
-                // Try to acquire PDU from the tx queue, fall back to an empty PDU.
+                // Try to acquire PDU from the tx queue, fall back to an empty PDU. PDUs that are
+                // too large for the connected device are sent in multiple chunks.
+                let offset = usize::from(self.tx_offset);
+                let max_tx = usize::from(self.max_tx_octets());
                 let mut payload_writer = ByteWriter::new(tx.tx_payload_buf());
-                let header = match self.tx.consume_raw_with(|header, pl| {
-                    payload_writer.write_slice(pl).expect("TX buf out of space");
-                    Consume::always(Ok(header))
-                }) {
-                    Ok(h) => h,
+                let result = self.tx.consume_raw_with(|header, pl| {
+                    let rest = &pl[offset..];
+                    let chunk = &rest[..cmp::min(rest.len(), max_tx)];
+                    payload_writer
+                        .write_slice(chunk)
+                        .expect("TX buf out of space");
+
+                    // Only the first chunk can start an L2CAP message.
+                    let llid = if offset == 0 {
+                        header.llid()
+                    } else {
+                        Llid::DataCont
+                    };
+                    let mut header = Header::new(llid);
+                    header.set_payload_length(chunk.len() as u8);
+
+                    if chunk.len() == rest.len() {
+                        Consume::always(Ok((header, 0)))
+                    } else {
+                        Consume::never(Ok((header, offset + chunk.len())))
+                    }
+                });
+                let header = match result {
+                    Ok((h, offset)) => {
+                        self.tx_offset = offset as u8;
+                        if offset == 0 {
+                            // The responder might be waiting for TX space to send more fragments.
+                            queued_work = true;
+                        }
+                        h
+                    }
                     Err(_) => Header::new(Llid::DataCont),
                 };
 
-                self.send(header, tx);
+                self.send(header, tx, aes);
             }
+        } else if self.master {
+            // Last packet not acknowledged, it will be resent in the next connection event.
         } else {
             // Last packet not acknowledged, resend.
             // If CRC is bad, this bit could be flipped, so we always retransmit in that case.
@@ -257,10 +505,28 @@ impl<C: Config> Connection<C> {
                 let pdu = Pdu::empty();
                 let mut payload_writer = ByteWriter::new(tx.tx_payload_buf());
                 pdu.to_bytes(&mut payload_writer).unwrap();
-                self.send(Header::new(pdu.llid()), tx);
+                self.send(Header::new(pdu.llid()), tx, aes);
             }
         }
 
+        if self.master {
+            // The connection event was already advanced when it started, and the next packet will
+            // be sent at the next anchor point.
+            trace!(
+                "#{} DATA<- {}{:?}, {:?}",
+                self.conn_event_count,
+                if crc_ok { "" } else { "BADCRC, " },
+                header,
+                HexSlice(payload)
+            );
+
+            return Ok(Cmd {
+                next_update: NextUpdate::Keep,
+                radio: RadioCmd::Off,
+                queued_work,
+            });
+        }
+
         let last_channel = self.channel;
 
         // FIXME: Don't hop if one of the MD bits is set to true (also don't log then)
@@ -313,10 +579,18 @@ impl<C: Config> Connection<C> {
     /// Called by the `LinkLayer` when the configured timer expires (according to a `Cmd` returned
     /// earlier).
     ///
-    /// Returns `Err(())` when the connection is closed or lost. In that case, the Link-Layer will
-    /// return to standby state.
-    pub(crate) fn timer_update(&mut self, timer: &mut C::Timer) -> Result<Cmd, ()> {
-        if self.received_packet {
+    /// Returns the reason when the connection is closed or lost. In that case, the Link-Layer will
+    /// leave the connection state.
+    pub(crate) fn timer_update<T: Transmitter>(
+        &mut self,
+        timer: &mut C::Timer,
+        tx: &mut T,
+    ) -> Result<Cmd, DisconnectReason> {
+        self.check_supervision_timeout(timer.now())?;
+
+        if self.master {
+            Ok(self.start_master_event(tx))
+        } else if self.received_packet {
             // No packet from master, skip this connection event and listen on the next channel
 
             let last_channel = self.channel;
@@ -340,14 +614,107 @@ impl<C: Config> Connection<C> {
                 queued_work: false,
             })
         } else {
-            // Master did not transmit the first packet during this transmit window.
-
-            // TODO: Move the transmit window forward by the `connInterval`.
-            // (do we also need to hop channels here?)
+            // Master did not transmit the first packet during this transmit window. Listen in the
+            // next connection event, whose transmit window starts `connInterval` later.
 
+            let last_channel = self.channel;
+            self.hop_channel();
             self.conn_event_count += Wrapping(1);
-            trace!("missed transmit window");
-            Err(())
+            trace!(
+                "DATA({}->{}): missed transmit window #{}",
+                last_channel.index(),
+                self.channel.index(),
+                self.conn_event_count.0,
+            );
+
+            Ok(Cmd {
+                next_update: NextUpdate::At(timer.now() + self.conn_interval),
+                radio: RadioCmd::ListenData {
+                    channel: self.channel,
+                    access_address: self.access_address,
+                    crc_init: self.crc_init,
+                    timeout: true,
+                },
+                queued_work: false,
+            })
+        }
+    }
+
+    /// Checks whether the connection supervision timer has expired at `now`.
+    ///
+    /// Until the first packet is received, the connection is considered lost after 6 connection
+    /// intervals. After that, the supervision timeout negotiated with the peer applies.
+    fn check_supervision_timeout(&self, now: Instant) -> Result<(), DisconnectReason> {
+        let elapsed = now.duration_since(self.last_rx);
+        if self.established {
+            if elapsed > self.supervision_timeout {
+                info!("supervision timeout expired after {}", elapsed);
+                return Err(DisconnectReason::Timeout);
+            }
+        } else if elapsed >= Duration::from_micros(6 * self.conn_interval.as_micros()) {
+            info!("connection not established after {}", elapsed);
+            return Err(DisconnectReason::FailedToEstablish);
+        }
+        Ok(())
+    }
+
+    /// Starts a connection event in the master role by sending the packet prepared for it (or
+    /// retransmitting the last one, if it wasn't acknowledged).
+    ///
+    /// The slave's response is received on the same channel and passed to `process_data_packet`,
+    /// which prepares the packet for the next connection event.
+    fn start_master_event<T: Transmitter>(&mut self, tx: &mut T) -> Cmd {
+        let channel = self.channel;
+        self.event_channel = channel;
+        self.last_header.set_nesn(self.next_expected_seq_num);
+        tx.transmit_data(
+            self.access_address,
+            self.crc_init,
+            self.last_header,
+            channel,
+        );
+
+        // Advance to the next connection event right away, its packet will be prepared when the
+        // response arrives.
+        self.conn_event_count += Wrapping(1);
+        let mut next_anchor = self.anchor + self.conn_interval;
+
+        if let Some(update) = self.update_data.take() {
+            if update.instant() == self.conn_event_count.0 {
+                match update {
+                    LlcpUpdate::ConnUpdate(data) => {
+                        // The transmit window is relative to the old connection interval
+                        next_anchor += data.win_offset();
+                        self.conn_interval = data.interval();
+                        self.slave_latency = data.latency();
+                        self.supervision_timeout = data.timeout();
+                    }
+                    LlcpUpdate::ChannelMap { map, .. } => {
+                        self.channel_map = map;
+                    }
+                }
+                info!("LLCP patch applied: {:?}", update);
+            } else {
+                // Put it back
+                self.update_data = Some(update);
+            }
+        }
+
+        // Hop channels after applying LLCP update because it might change the channel map used
+        // by the next event
+        self.hop_channel();
+        self.anchor = next_anchor;
+
+        Cmd {
+            next_update: NextUpdate::At(self.anchor),
+            // Listen for the response on the channel of the current connection event
+            radio: RadioCmd::ListenData {
+                channel,
+                access_address: self.access_address,
+                crc_init: self.crc_init,
+                timeout: false,
+            },
+            queued_work: false,
         }
     }
 
@@ -381,14 +748,42 @@ impl<C: Config> Connection<C> {
         };
     }
 
+    /// Encodes an LL Control PDU into the radio's TX buffer and sends it to the connected device.
+    fn send_control<T: Transmitter>(
+        &mut self,
+        pdu: &ControlPdu<'_>,
+        tx: &mut T,
+        aes: &mut C::AesProvider,
+    ) {
+        let pdu = Pdu::from(pdu);
+        let mut payload_writer = ByteWriter::new(tx.tx_payload_buf());
+        let left = payload_writer.space_left();
+        pdu.to_bytes(&mut payload_writer).unwrap();
+
+        let mut header = Header::new(Llid::Control);
+        let pl_len = (left - payload_writer.space_left()) as u8;
+        header.set_payload_length(pl_len);
+        self.send(header, tx, aes);
+    }
+
     /// Sends a new PDU to the connected device (ie. a non-retransmitted PDU).
-    fn send(&mut self, mut header: Header, tx: &mut C::Transmitter) {
+    ///
+    /// If encryption is enabled, the payload in the radio's TX buffer is encrypted and the MIC is
+    /// appended to it.
+    ///
+    /// In the master role, the PDU is only prepared, and sent at the start of the next connection
+    /// event.
+    fn send<T: Transmitter>(&mut self, mut header: Header, tx: &mut T, aes: &mut C::AesProvider) {
         header.set_md(self.has_more_data());
         header.set_nesn(self.next_expected_seq_num);
         header.set_sn(self.transmit_seq_num);
+        self.encryption
+            .encrypt(aes, &mut header, tx.tx_payload_buf());
         self.last_header = header;
 
-        tx.transmit_data(self.access_address, self.crc_init, header, self.channel);
+        if !self.master {
+            tx.transmit_data(self.access_address, self.crc_init, header, self.channel);
+        }
 
         let pl = &tx.tx_payload_buf()[..usize::from(header.payload_length())];
         trace!("DATA->{:?}, {:?}", header, HexSlice(pl));
@@ -396,7 +791,7 @@ impl<C: Config> Connection<C> {
 
     /// Tries to process and acknowledge an LL Control PDU.
     ///
-    /// Returns `Err(())` when the connection is closed or lost.
+    /// Returns `LlcpError::ConnectionLost` when the connection is closed or lost.
     ///
     /// Note this this function is on a time-critical path and thus can not use logging since that's
     /// currently way too slow. Critical errors can still be logged, since they abort the connection
@@ -408,17 +803,19 @@ impl<C: Config> Connection<C> {
     /// * **`can_respond`**: Whether the radio's TX buffer may be overwritten to send a response. If
     ///   this is `false`, this method may choose not to acknowledge the PDU and wait for a
     ///   retransmission instead.
+    /// * **`rng`**: Generates our contributions to the session key when encryption is started.
     fn process_control_pdu(
         &mut self,
         pdu: ControlPdu<'_>,
         can_respond: bool,
+        rng: &mut C::Rng,
     ) -> Result<Option<ControlPdu<'static>>, LlcpError> {
         let response = match pdu {
-            ControlPdu::ConnectionUpdateReq(data) => {
+            ControlPdu::ConnectionUpdateReq(data) if !self.master => {
                 self.prepare_llcp_update(LlcpUpdate::ConnUpdate(data))?;
                 return Ok(None);
             }
-            ControlPdu::ChannelMapReq { map, instant } => {
+            ControlPdu::ChannelMapReq { map, instant } if !self.master => {
                 self.prepare_llcp_update(LlcpUpdate::ChannelMap { map, instant })?;
                 return Ok(None);
             }
@@ -427,11 +824,47 @@ impl<C: Config> Connection<C> {
                     "closing connection due to termination request: code {:?}",
                     error_code
                 );
-                return Err(LlcpError::ConnectionLost);
+                return Err(LlcpError::ConnectionLost(
+                    DisconnectReason::RemoteTerminated(error_code.0),
+                ));
             }
             ControlPdu::FeatureReq { features_master } => ControlPdu::FeatureRsp {
                 features_used: features_master & FeatureSet::supported(),
             },
+            ControlPdu::LengthReq(remote) => {
+                // The master initiated the procedure, no need to do it ourselves.
+                self.remote_length = remote;
+                self.length_req_pending = false;
+                ControlPdu::LengthRsp(self.local_length)
+            }
+            ControlPdu::LengthRsp(remote) => {
+                self.remote_length = remote;
+                return Ok(None);
+            }
+            ControlPdu::EncReq { .. }
+            | ControlPdu::StartEncRsp
+            | ControlPdu::PauseEncReq
+            | ControlPdu::PauseEncRsp => {
+                if !can_respond {
+                    // Don't advance the procedure if we might not be able to send the response.
+                    return Err(LlcpError::NoSpace);
+                }
+
+                match self.encryption.process_control_pdu(&pdu, rng) {
+                    Ok(Some(response)) => response,
+                    Ok(None) => return Ok(None),
+                    Err(()) => {
+                        return Err(LlcpError::ConnectionLost(DisconnectReason::ProcedureFailed))
+                    }
+                }
+            }
+            ControlPdu::UnknownRsp {
+                unknown_type: ControlOpcode::LengthReq,
+            } => {
+                // The connected device doesn't support the Data Length Update Procedure, keep
+                // using the default packet sizes.
+                return Ok(None);
+            }
             ControlPdu::VersionInd { .. } => {
                 // FIXME this should be something real, and defined somewhere else
                 let comp_id = 0xFFFF;
@@ -466,7 +899,7 @@ impl<C: Config> Connection<C> {
                 "got update data {:?} while update {:?} is already queued",
                 update, data
             );
-            Err(LlcpError::ConnectionLost)
+            Err(LlcpError::ConnectionLost(DisconnectReason::ProcedureFailed))
         } else {
             self.update_data = Some(update);
             Ok(())
@@ -482,6 +915,8 @@ impl<C: Config> Connection<C> {
             LlcpUpdate::ConnUpdate(data) => {
                 let old_conn_interval = self.conn_interval;
                 self.conn_interval = data.interval();
+                self.slave_latency = data.latency();
+                self.supervision_timeout = data.timeout();
 
                 self.hop_channel();
 
@@ -507,10 +942,51 @@ impl<C: Config> Connection<C> {
             }
         }
     }
+
+    /// Returns whether we are the master of this connection.
+    pub(crate) fn is_master(&self) -> bool {
+        self.master
+    }
+
+    /// Returns the data channel on which the next packet will be received.
+    pub(crate) fn rx_channel(&self) -> DataChannel {
+        if self.master {
+            self.event_channel
+        } else {
+            self.channel
+        }
+    }
+
+    pub(crate) fn access_address(&self) -> u32 {
+        self.access_address
+    }
+
+    pub(crate) fn crc_init(&self) -> u32 {
+        self.crc_init
+    }
+
+    /// Terminates the connection by sending an `LL_TERMINATE_IND` with `error_code`.
+    ///
+    /// The connection ends once the PDU is acknowledged.
+    pub(crate) fn terminate(&mut self, error_code: u8) {
+        if self.terminate_sent.is_none() {
+            self.pending_terminate = Some(error_code);
+        }
+    }
+
+    /// Ends the connection, returning the packet queues it used.
+    pub(crate) fn into_queues(self) -> (ConfConsumer<C>, ConfProducer<C>) {
+        (self.tx, self.rx)
+    }
 }
 
 // Public API
 impl<C: Config> Connection<C> {
+    /// Returns the address of the connected device.
+    pub fn peer_address(&self) -> DeviceAddress {
+        self.peer
+    }
+
     /// Returns the configured interval between connection events.
     ///
     /// The connection event interval is arbitrated by the device in the Central role and heavily
@@ -523,6 +999,84 @@ impl<C: Config> Connection<C> {
     pub fn connection_interval(&self) -> Duration {
         self.conn_interval
     }
+
+    /// Returns the number of connection events the slave may skip when it has no data to send.
+    pub fn slave_latency(&self) -> u16 {
+        self.slave_latency
+    }
+
+    /// Returns the time after which the connection is considered lost when no packets are
+    /// received.
+    pub fn supervision_timeout(&self) -> Duration {
+        self.supervision_timeout
+    }
+
+    /// Returns the max. payload size of data channel PDUs sent to the connected device.
+    ///
+    /// This is 27 Bytes initially, and may increase after the *Data Length Update Procedure* has
+    /// been performed.
+    pub fn max_tx_octets(&self) -> u16 {
+        self.local_length.effective_tx_octets(&self.remote_length)
+    }
+
+    /// Returns the max. payload size of data channel PDUs received from the connected device.
+    ///
+    /// This is 27 Bytes initially, and may increase after the *Data Length Update Procedure* has
+    /// been performed.
+    pub fn max_rx_octets(&self) -> u16 {
+        self.remote_length.effective_tx_octets(&self.local_length)
+    }
+
+    /// Returns whether the connection is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_encrypted()
+    }
+
+    /// Returns the pending request for a Long Term Key, if the connected device has started the
+    /// *Encryption Start Procedure* and is waiting for the host to provide the key.
+    pub fn ltk_request(&self) -> Option<LtkRequest> {
+        self.encryption.ltk_request()
+    }
+}
+
+// Host interface
+impl<C: Config> Connection<C> {
+    pub(crate) fn ltk_reply(&mut self, aes: &mut C::AesProvider, ltk: &Key) -> Result<(), Error> {
+        self.encryption.ltk_reply(aes, ltk)
+    }
+
+    pub(crate) fn ltk_negative_reply(&mut self) -> Result<(), Error> {
+        self.encryption.ltk_negative_reply()
+    }
+
+    pub(crate) fn update_connection_params(
+        &mut self,
+        interval: Duration,
+        slave_latency: u16,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        // The instant is filled in when the request is sent
+        let data = ConnectionUpdateData::new(interval, slave_latency, timeout, 0)?;
+        self.request_update(LlcpUpdate::ConnUpdate(data))
+    }
+
+    pub(crate) fn update_channel_map(&mut self, map: ChannelMap) -> Result<(), Error> {
+        if map.num_used_channels() < 2 {
+            return Err(Error::InvalidValue);
+        }
+        self.request_update(LlcpUpdate::ChannelMap { map, instant: 0 })
+    }
+
+    /// Queues an update to be sent to the slave.
+    ///
+    /// Only one update can be in progress at a time.
+    fn request_update(&mut self, update: LlcpUpdate) -> Result<(), Error> {
+        if !self.master || self.update_data.is_some() || self.pending_update.is_some() {
+            return Err(Error::InvalidValue);
+        }
+        self.pending_update = Some(update);
+        Ok(())
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -531,7 +1085,59 @@ enum LlcpError {
     NoSpace,
 
     /// Consider the connection lost due to a critical error or timeout.
-    ConnectionLost,
+    ConnectionLost(DisconnectReason),
+}
+
+/// The reason a connection has ended.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// No packet was received within the supervision timeout.
+    Timeout,
+
+    /// No packet was received within the first 6 connection events.
+    FailedToEstablish,
+
+    /// A received packet failed authentication.
+    MicFailure,
+
+    /// The connected device violated an LL Control procedure.
+    ProcedureFailed,
+
+    /// The connected device sent an `LL_TERMINATE_IND` with the contained error code.
+    RemoteTerminated(u8),
+
+    /// We have sent an `LL_TERMINATE_IND` with the contained error code, as requested via
+    /// `LinkLayer::disconnect`.
+    LocalTerminated(u8),
+}
+
+impl DisconnectReason {
+    /// Returns the HCI error code describing this reason.
+    ///
+    /// This is the reason reported to the host in the *Disconnection Complete* event.
+    pub fn error_code(&self) -> u8 {
+        match *self {
+            DisconnectReason::Timeout => 0x08,
+            DisconnectReason::FailedToEstablish => 0x3E,
+            DisconnectReason::MicFailure => 0x3D,
+            DisconnectReason::ProcedureFailed => 0x24,
+            DisconnectReason::RemoteTerminated(code) => code,
+            DisconnectReason::LocalTerminated(_) => 0x16,
+        }
+    }
+}
+
+/// A change of the connection state, reported by `LinkLayer::take_connection_event`.
+///
+/// The per-connection state of the upper layers (eg. via `Responder::connection_established`
+/// and `Responder::connection_ended`) has to be reset whenever one of these is reported.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// A connection with the device at `peer` was established.
+    Established { peer: DeviceAddress },
+
+    /// The connection has ended for the contained reason.
+    Ended(DisconnectReason),
 }
 
 /// A Link-Layer state update that may be applied with a delay.
@@ -561,4 +1167,20 @@ impl LlcpUpdate {
             LlcpUpdate::ChannelMap { instant, .. } => *instant,
         }
     }
+
+    /// Returns a copy of `self` that is applied at connection event `instant`.
+    fn with_instant(self, instant: u16) -> Self {
+        match self {
+            LlcpUpdate::ConnUpdate(data) => LlcpUpdate::ConnUpdate(data.with_instant(instant)),
+            LlcpUpdate::ChannelMap { map, .. } => LlcpUpdate::ChannelMap { map, instant },
+        }
+    }
+
+    /// Returns the LL Control PDU the master sends to request this update.
+    fn to_control_pdu(self) -> ControlPdu<'static> {
+        match self {
+            LlcpUpdate::ConnUpdate(data) => ControlPdu::ConnectionUpdateReq(data),
+            LlcpUpdate::ChannelMap { map, instant } => ControlPdu::ChannelMapReq { map, instant },
+        }
+    }
 }
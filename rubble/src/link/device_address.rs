@@ -34,6 +34,14 @@ impl DeviceAddress {
         self.kind == AddressKind::Random
     }
 
+    /// Returns whether this is a resolvable private address.
+    ///
+    /// These addresses are generated from an Identity Resolving Key (IRK) and change over time,
+    /// but can be resolved by devices that know the IRK.
+    pub fn is_resolvable_private(&self) -> bool {
+        self.is_random() && self.bytes[5] >> 6 == 0b01
+    }
+
     /// Returns the raw bytes making up this address (LSB first).
     pub fn raw(&self) -> &[u8; 6] {
         &self.bytes
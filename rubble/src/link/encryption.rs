@@ -0,0 +1,410 @@
+//! Link-Layer encryption (*Encryption Start* and *Encryption Pause Procedures*).
+//!
+//! Once encryption is started, all non-empty data channel PDUs are encrypted with AES-CCM using a
+//! session key (SK) derived from the Long Term Key (LTK) provided by the host, and are
+//! authenticated by a 4-octet MIC appended to the payload.
+//!
+//! Only the slave role is supported, so the procedures are always initiated by the master.
+
+use crate::crypto::{self, AesProvider, Key, Nonce, MIC_SIZE};
+use crate::link::{data::Header, llcp::ControlPdu};
+use crate::utils::Hex;
+use crate::Error;
+use rand_core::{CryptoRng, RngCore};
+
+/// Error code sent in `LL_REJECT_IND` when the host has no LTK for the connected device.
+const PIN_OR_KEY_MISSING: u8 = 0x06;
+
+/// A request for the Long Term Key to use for encrypting a connection.
+///
+/// The master identifies the LTK using the `EDIV` and `Rand` values that were distributed along
+/// with it during pairing. For LE Secure Connections, both are 0.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LtkRequest {
+    rand: [u8; 8],
+    ediv: u16,
+}
+
+impl LtkRequest {
+    /// Creates a request for the LTK identified by `rand` and `ediv`.
+    pub fn new(rand: [u8; 8], ediv: u16) -> Self {
+        Self { rand, ediv }
+    }
+
+    /// Returns the random number (`Rand`) identifying the requested LTK.
+    pub fn rand(&self) -> [u8; 8] {
+        self.rand
+    }
+
+    /// Returns the encrypted diversifier (`EDIV`) identifying the requested LTK.
+    pub fn ediv(&self) -> u16 {
+        self.ediv
+    }
+}
+
+/// State of the encryption procedures of a connection.
+#[derive(Debug, Copy, Clone)]
+enum Procedure {
+    /// No procedure in progress.
+    Idle,
+
+    /// Answered `LL_ENC_REQ` with `LL_ENC_RSP`, waiting for the host to provide the LTK.
+    ///
+    /// Stores the combined session key diversifier (`SKDm || SKDs`) and IV (`IVm || IVs`).
+    LtkRequested {
+        request: LtkRequest,
+        skd: [u8; 16],
+        iv: [u8; 8],
+    },
+
+    /// Host provided the LTK, `LL_START_ENC_REQ` needs to be sent.
+    SendStartEncReq,
+
+    /// Sent `LL_START_ENC_REQ`, waiting for the master's encrypted `LL_START_ENC_RSP`.
+    WaitStartEncRsp,
+
+    /// Host has no LTK, `LL_REJECT_IND` needs to be sent.
+    SendRejectInd,
+
+    /// Responded to `LL_PAUSE_ENC_REQ`, waiting for the master's unencrypted `LL_PAUSE_ENC_RSP`.
+    WaitPauseEncRsp,
+
+    /// Encryption was paused. Waiting for the master to restart it with `LL_ENC_REQ`.
+    Paused,
+}
+
+/// Keys and counters of an encrypted connection.
+struct Session {
+    /// The session key.
+    sk: Key,
+
+    /// The initialization vector, `IVm || IVs`.
+    iv: [u8; 8],
+
+    /// Counter of encrypted PDUs sent to the master.
+    tx_counter: u64,
+
+    /// Counter of encrypted PDUs received from the master.
+    rx_counter: u64,
+}
+
+/// Encryption state of a connection.
+pub(crate) struct Encryption {
+    procedure: Procedure,
+    session: Option<Session>,
+
+    /// Whether the master may start encryption (only supported in the slave role).
+    supported: bool,
+
+    /// Whether transmitted PDUs are encrypted.
+    tx_enabled: bool,
+
+    /// Whether received PDUs are encrypted.
+    rx_enabled: bool,
+
+    /// Whether `rx_enabled` should be toggled once the master acknowledges the last PDU we sent.
+    ///
+    /// The master switches encryption on or off as soon as it has received our `LL_START_ENC_REQ`
+    /// or `LL_PAUSE_ENC_RSP`, so the first PDU acknowledging them already uses the new setting.
+    toggle_rx_on_ack: bool,
+}
+
+impl Encryption {
+    /// Creates the encryption state of a connection that doesn't support encryption.
+    ///
+    /// `LL_ENC_REQ` is treated as a procedure violation.
+    pub fn new() -> Self {
+        Self {
+            procedure: Procedure::Idle,
+            session: None,
+            supported: false,
+            tx_enabled: false,
+            rx_enabled: false,
+            toggle_rx_on_ack: false,
+        }
+    }
+
+    /// Creates the encryption state of a connection in the slave role.
+    pub fn slave() -> Self {
+        Self {
+            supported: true,
+            ..Self::new()
+        }
+    }
+
+    /// Returns whether PDUs are currently encrypted in both directions.
+    pub fn is_encrypted(&self) -> bool {
+        self.tx_enabled && self.rx_enabled
+    }
+
+    /// Returns whether sending queued data PDUs is paused because an encryption procedure is in
+    /// progress.
+    pub fn data_paused(&self) -> bool {
+        !matches!(self.procedure, Procedure::Idle)
+    }
+
+    /// Returns the pending request for an LTK, if the host still has to provide one.
+    pub fn ltk_request(&self) -> Option<LtkRequest> {
+        match self.procedure {
+            Procedure::LtkRequested { request, .. } => Some(request),
+            _ => None,
+        }
+    }
+
+    /// Provides the LTK requested by the master and derives the session key from it.
+    ///
+    /// Returns `Error::InvalidValue` if no LTK was requested.
+    pub fn ltk_reply<A: AesProvider>(&mut self, aes: &mut A, ltk: &Key) -> Result<(), Error> {
+        let (skd, iv) = match self.procedure {
+            Procedure::LtkRequested { skd, iv, .. } => (skd, iv),
+            _ => return Err(Error::InvalidValue),
+        };
+
+        self.session = Some(Session {
+            sk: Key(crypto::e(aes, ltk, skd)),
+            iv,
+            tx_counter: 0,
+            rx_counter: 0,
+        });
+        self.procedure = Procedure::SendStartEncReq;
+        Ok(())
+    }
+
+    /// Tells the master that the host has no LTK, which aborts the procedure.
+    ///
+    /// Returns `Error::InvalidValue` if no LTK was requested.
+    pub fn ltk_negative_reply(&mut self) -> Result<(), Error> {
+        match self.procedure {
+            Procedure::LtkRequested { .. } => {
+                self.procedure = Procedure::SendRejectInd;
+                Ok(())
+            }
+            _ => Err(Error::InvalidValue),
+        }
+    }
+
+    /// Processes an encryption-related LL Control PDU.
+    ///
+    /// `rng` generates our parts of the session key diversifier and IV, which are sent in
+    /// `LL_ENC_RSP`.
+    ///
+    /// Returns the response to send, if any. Returns `Err(())` if `pdu` violates the procedure and
+    /// the connection should be considered lost.
+    pub fn process_control_pdu<R: RngCore + CryptoRng>(
+        &mut self,
+        pdu: &ControlPdu<'_>,
+        rng: &mut R,
+    ) -> Result<Option<ControlPdu<'static>>, ()> {
+        match *pdu {
+            ControlPdu::EncReq {
+                rand,
+                ediv,
+                skd_m,
+                iv_m,
+            } => match self.procedure {
+                Procedure::Idle | Procedure::Paused if self.supported && !self.tx_enabled => {
+                    // Respond right away, the host's answer to the LTK request decides whether
+                    // encryption is started.
+                    let mut skd_s = [0; 8];
+                    let mut iv_s = [0; 4];
+                    rng.fill_bytes(&mut skd_s);
+                    rng.fill_bytes(&mut iv_s);
+
+                    // SKD = SKDm || SKDs and IV = IVm || IVs, with the master's part in the least
+                    // significant octets.
+                    let mut skd = [0; 16];
+                    skd[..8].copy_from_slice(&skd_m);
+                    skd[8..].copy_from_slice(&skd_s);
+                    let mut iv = [0; 8];
+                    iv[..4].copy_from_slice(&iv_m);
+                    iv[4..].copy_from_slice(&iv_s);
+
+                    self.procedure = Procedure::LtkRequested {
+                        request: LtkRequest { rand, ediv: ediv.0 },
+                        skd,
+                        iv,
+                    };
+                    return Ok(Some(ControlPdu::EncRsp { skd_s, iv_s }));
+                }
+                _ => {}
+            },
+            ControlPdu::StartEncRsp => {
+                if let Procedure::WaitStartEncRsp = self.procedure {
+                    // The master's response was encrypted, so encrypt ours as well.
+                    self.tx_enabled = true;
+                    self.procedure = Procedure::Idle;
+                    return Ok(Some(ControlPdu::StartEncRsp));
+                }
+            }
+            ControlPdu::PauseEncReq => {
+                if let Procedure::Idle = self.procedure {
+                    if self.is_encrypted() {
+                        // Our response is still encrypted, but the master will send the next PDU
+                        // unencrypted.
+                        self.procedure = Procedure::WaitPauseEncRsp;
+                        self.toggle_rx_on_ack = true;
+                        return Ok(Some(ControlPdu::PauseEncRsp));
+                    }
+                }
+            }
+            ControlPdu::PauseEncRsp => {
+                if let Procedure::WaitPauseEncRsp = self.procedure {
+                    self.tx_enabled = false;
+                    self.session = None;
+                    self.procedure = Procedure::Paused;
+                    return Ok(None);
+                }
+            }
+            _ => {}
+        }
+
+        error!("unexpected {:?} in state {:?}", pdu, self.procedure);
+        Err(())
+    }
+
+    /// Returns the next LL Control PDU to send as part of an ongoing procedure.
+    ///
+    /// This assumes that the returned PDU will be sent, and advances the procedure accordingly.
+    pub fn next_control_pdu(&mut self) -> Option<ControlPdu<'static>> {
+        match self.procedure {
+            Procedure::SendStartEncReq => {
+                // Sent unencrypted, but the master's reply will be encrypted.
+                self.procedure = Procedure::WaitStartEncRsp;
+                self.toggle_rx_on_ack = true;
+                Some(ControlPdu::StartEncReq)
+            }
+            Procedure::SendRejectInd => {
+                self.procedure = Procedure::Idle;
+                Some(ControlPdu::RejectInd {
+                    error_code: Hex(PIN_OR_KEY_MISSING),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Must be called when a received PDU acknowledges the last PDU we sent.
+    pub fn acknowledged(&mut self) {
+        if self.toggle_rx_on_ack {
+            self.toggle_rx_on_ack = false;
+            self.rx_enabled = !self.rx_enabled;
+        }
+    }
+
+    /// Encrypts the payload of an outgoing PDU in place if encryption is enabled.
+    ///
+    /// `buf` must contain the payload followed by at least `MIC_SIZE` Bytes of space for the MIC.
+    /// The payload length in `header` is updated to include the MIC.
+    pub fn encrypt<A: AesProvider>(&mut self, aes: &mut A, header: &mut Header, buf: &mut [u8]) {
+        let len = usize::from(header.payload_length());
+        if !self.tx_enabled || len == 0 {
+            return;
+        }
+
+        let session = self.session.as_mut().unwrap();
+        let nonce = Nonce::new(session.tx_counter, false, session.iv);
+        let (payload, rest) = buf.split_at_mut(len);
+        let mic = aes.ccm_encrypt(&session.sk, &nonce, header.to_u16() as u8, payload);
+        rest[..MIC_SIZE].copy_from_slice(&mic);
+        header.set_payload_length((len + MIC_SIZE) as u8);
+        session.tx_counter += 1;
+    }
+
+    /// Decrypts the payload of a received PDU into `buf` if encryption is enabled.
+    ///
+    /// Returns the header and payload of the plaintext PDU. `commit_rx` must be called once the
+    /// PDU was acknowledged, so that the next PDU is decrypted with the right packet counter.
+    ///
+    /// Returns `Error::InvalidLength` if the PDU is too short to contain a MIC, and
+    /// `Error::InvalidValue` if the MIC is wrong. Both are fatal to the connection.
+    pub fn decrypt<'a, A: AesProvider>(
+        &mut self,
+        aes: &mut A,
+        mut header: Header,
+        payload: &'a [u8],
+        buf: &'a mut [u8],
+    ) -> Result<(Header, &'a [u8]), Error> {
+        if !self.rx_enabled || payload.is_empty() {
+            return Ok((header, payload));
+        }
+        if payload.len() <= MIC_SIZE || payload.len() - MIC_SIZE > buf.len() {
+            return Err(Error::InvalidLength);
+        }
+
+        let session = self.session.as_ref().unwrap();
+        let nonce = Nonce::new(session.rx_counter, true, session.iv);
+        let len = payload.len() - MIC_SIZE;
+        let mut mic = [0; MIC_SIZE];
+        mic.copy_from_slice(&payload[len..]);
+        let buf = &mut buf[..len];
+        buf.copy_from_slice(&payload[..len]);
+        aes.ccm_decrypt(&session.sk, &nonce, header.to_u16() as u8, buf, &mic)?;
+
+        header.set_payload_length(len as u8);
+        Ok((header, buf))
+    }
+
+    /// Advances the RX packet counter after a new, non-empty PDU was acknowledged.
+    pub fn commit_rx(&mut self) {
+        if let Some(session) = &mut self.session {
+            if self.rx_enabled {
+                session.rx_counter += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SoftAesProvider;
+    use crate::utils::CountingRng;
+
+    const ENC_REQ: ControlPdu<'static> = ControlPdu::EncReq {
+        rand: [1; 8],
+        ediv: Hex(0x1234),
+        skd_m: [2; 8],
+        iv_m: [3; 4],
+    };
+
+    #[test]
+    fn enc_rsp_before_ltk() {
+        let mut enc = Encryption::slave();
+
+        // LL_ENC_RSP is sent right away, LL_START_ENC_REQ once the host provided the LTK.
+        // SKDs and IVs come from the RNG.
+        match enc.process_control_pdu(&ENC_REQ, &mut CountingRng(0)) {
+            Ok(Some(ControlPdu::EncRsp { skd_s, iv_s })) => {
+                assert_eq!(skd_s, [1, 2, 3, 4, 5, 6, 7, 8]);
+                assert_eq!(iv_s, [9, 10, 11, 12]);
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+        assert_eq!(enc.ltk_request(), Some(LtkRequest::new([1; 8], 0x1234)));
+        assert!(enc.next_control_pdu().is_none());
+
+        enc.ltk_reply(&mut SoftAesProvider::new(), &Key([4; 16]))
+            .unwrap();
+        assert_eq!(enc.ltk_request(), None);
+        match enc.next_control_pdu() {
+            Some(ControlPdu::StartEncReq) => {}
+            other => panic!("unexpected PDU {:?}", other),
+        }
+
+        // Without a key, the procedure is rejected after LL_ENC_RSP.
+        let mut enc = Encryption::slave();
+        enc.process_control_pdu(&ENC_REQ, &mut CountingRng(0))
+            .unwrap();
+        enc.ltk_negative_reply().unwrap();
+        match enc.next_control_pdu() {
+            Some(ControlPdu::RejectInd { error_code }) => assert_eq!(error_code.0, 0x06),
+            other => panic!("unexpected PDU {:?}", other),
+        }
+        assert!(!enc.data_paused());
+
+        // Encryption isn't supported in the master role.
+        assert!(Encryption::new()
+            .process_control_pdu(&ENC_REQ, &mut CountingRng(0))
+            .is_err());
+    }
+}
@@ -67,7 +67,7 @@ bitflags! {
 impl FeatureSet {
     /// Returns the feature set supported by Rubble.
     pub fn supported() -> Self {
-        FeatureSet::empty()
+        FeatureSet::LE_ENCRYPTION | FeatureSet::LE_PACKET_LENGTH_EXTENSION
     }
 }
 
@@ -1,5 +1,6 @@
 //! Defines packet structures used by the Link Layer Control Protocol.
 
+use crate::link::advertising::check_conn_params;
 use crate::link::{channel_map::ChannelMap, comp_id::CompanyId, features::FeatureSet};
 use crate::{bytes::*, time::Duration, utils::Hex, Error};
 use core::{cmp, convert::TryInto};
@@ -136,6 +137,31 @@ pub struct ConnectionUpdateData {
 }
 
 impl ConnectionUpdateData {
+    /// Creates update data for switching to new connection parameters at connection event
+    /// `instant`.
+    ///
+    /// The transmit window starts right at the anchor point of the `instant` and is 1.25 ms long.
+    /// `interval` is rounded down to units of 1.25 ms, `timeout` to units of 10 ms.
+    ///
+    /// Returns `Error::InvalidValue` if a parameter is outside the range allowed for new
+    /// connections (see `ConnectRequestData::new`).
+    pub fn new(
+        interval: Duration,
+        latency: u16,
+        timeout: Duration,
+        instant: u16,
+    ) -> Result<Self, Error> {
+        check_conn_params(interval, latency, timeout)?;
+        Ok(Self {
+            win_size: 1,
+            win_offset: 0,
+            interval: (interval.as_micros() / 1_250) as u16,
+            latency,
+            timeout: (timeout.as_micros() / 10_000) as u16,
+            instant,
+        })
+    }
+
     /// Returns the size of the transmit window for the first PDU of the connection.
     pub fn win_size(&self) -> Duration {
         Duration::from_micros(u32::from(self.win_size) * 1_250)
@@ -165,6 +191,142 @@ impl ConnectionUpdateData {
     pub fn instant(&self) -> u16 {
         self.instant
     }
+
+    pub(crate) fn with_instant(self, instant: u16) -> Self {
+        Self { instant, ..self }
+    }
+}
+
+/// Data length parameters exchanged via `LL_LENGTH_REQ` and `LL_LENGTH_RSP`.
+///
+/// These describe the largest data channel PDU payloads a device is able to send and receive, and
+/// the maximum time it may take to transmit or receive them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DataLength {
+    max_rx_octets: u16,
+    max_rx_time: u16,
+    max_tx_octets: u16,
+    max_tx_time: u16,
+}
+
+impl DataLength {
+    /// Min. allowed payload size in octets. This is also the value used before any data length
+    /// update has taken place.
+    pub const MIN_OCTETS: u16 = 27;
+
+    /// Max. allowed payload size in octets.
+    pub const MAX_OCTETS: u16 = 251;
+
+    /// Min. allowed packet time in µs (the time needed to transmit `MIN_OCTETS` on the LE 1M PHY).
+    pub const MIN_TIME: u16 = 328;
+
+    /// Max. allowed packet time in µs (the time needed to transmit `MAX_OCTETS` on the LE 1M PHY).
+    pub const MAX_TIME: u16 = 2120;
+
+    /// Creates data length parameters from the max. payload sizes in each direction.
+    ///
+    /// The packet times are derived from the payload sizes, assuming the LE 1M PHY is used. Both
+    /// payload sizes will be constrained to lie in the range `MIN_OCTETS` to `MAX_OCTETS`.
+    pub fn new(max_rx_octets: u16, max_tx_octets: u16) -> Self {
+        let max_rx_octets = clamp(max_rx_octets, Self::MIN_OCTETS, Self::MAX_OCTETS);
+        let max_tx_octets = clamp(max_tx_octets, Self::MIN_OCTETS, Self::MAX_OCTETS);
+        Self {
+            max_rx_octets,
+            max_rx_time: octets_to_time(max_rx_octets),
+            max_tx_octets,
+            max_tx_time: octets_to_time(max_tx_octets),
+        }
+    }
+
+    /// Returns the max. payload size of received data channel PDUs.
+    pub fn max_rx_octets(&self) -> u16 {
+        self.max_rx_octets
+    }
+
+    /// Returns the max. time it may take to receive a data channel PDU.
+    pub fn max_rx_time(&self) -> Duration {
+        Duration::from_micros(self.max_rx_time.into())
+    }
+
+    /// Returns the max. payload size of transmitted data channel PDUs.
+    pub fn max_tx_octets(&self) -> u16 {
+        self.max_tx_octets
+    }
+
+    /// Returns the max. time it may take to transmit a data channel PDU.
+    pub fn max_tx_time(&self) -> Duration {
+        Duration::from_micros(self.max_tx_time.into())
+    }
+
+    /// Computes the max. payload size of PDUs sent from a device with limits `self` to a device
+    /// with limits `receiver`.
+    ///
+    /// This is `connEffectiveMaxTxOctets`, further limited by `connEffectiveMaxTxTime`, assuming
+    /// the LE 1M PHY is used.
+    pub fn effective_tx_octets(&self, receiver: &DataLength) -> u16 {
+        let octets = cmp::min(self.max_tx_octets, receiver.max_rx_octets);
+        let time = cmp::min(self.max_tx_time, receiver.max_rx_time);
+        cmp::max(cmp::min(octets, time_to_octets(time)), Self::MIN_OCTETS)
+    }
+
+    /// Replaces out-of-range values with the closest valid value.
+    ///
+    /// The spec requires values outside the allowed ranges to be treated this way.
+    fn sanitize(self) -> Self {
+        Self {
+            max_rx_octets: clamp(self.max_rx_octets, Self::MIN_OCTETS, Self::MAX_OCTETS),
+            max_rx_time: clamp(self.max_rx_time, Self::MIN_TIME, Self::MAX_TIME),
+            max_tx_octets: clamp(self.max_tx_octets, Self::MIN_OCTETS, Self::MAX_OCTETS),
+            max_tx_time: clamp(self.max_tx_time, Self::MIN_TIME, Self::MAX_TIME),
+        }
+    }
+}
+
+impl Default for DataLength {
+    /// Returns the data length used by connections before the *Data Length Update Procedure* has
+    /// been performed.
+    fn default() -> Self {
+        Self::new(Self::MIN_OCTETS, Self::MIN_OCTETS)
+    }
+}
+
+impl<'a> FromBytes<'a> for DataLength {
+    fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
+        Ok(Self {
+            max_rx_octets: bytes.read_u16_le()?,
+            max_rx_time: bytes.read_u16_le()?,
+            max_tx_octets: bytes.read_u16_le()?,
+            max_tx_time: bytes.read_u16_le()?,
+        }
+        .sanitize())
+    }
+}
+
+impl ToBytes for DataLength {
+    fn to_bytes(&self, writer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        writer.write_u16_le(self.max_rx_octets)?;
+        writer.write_u16_le(self.max_rx_time)?;
+        writer.write_u16_le(self.max_tx_octets)?;
+        writer.write_u16_le(self.max_tx_time)?;
+        Ok(())
+    }
+}
+
+fn clamp(value: u16, min: u16, max: u16) -> u16 {
+    cmp::min(cmp::max(value, min), max)
+}
+
+/// Time in µs needed to transmit a packet with `octets` Bytes of payload on the LE 1M PHY.
+///
+/// This includes preamble, access address, header, MIC and CRC (14 Bytes in total) at 8 µs per
+/// Byte.
+fn octets_to_time(octets: u16) -> u16 {
+    (octets + 14) * 8
+}
+
+/// Inverse of `octets_to_time`.
+fn time_to_octets(time: u16) -> u16 {
+    (time / 8).saturating_sub(14)
 }
 
 /// A structured representation of an LL Control PDU used by the Link Layer Control Protocol (LLCP).
@@ -190,6 +352,38 @@ pub enum ControlPdu<'a> {
         error_code: Hex<u8>,
     },
 
+    /// `0x03`/`LL_ENC_REQ` - Master requests encryption of the connection.
+    ///
+    /// Starts the *Encryption Start Procedure*. Contains the master's parts of the session key
+    /// diversifier and initialization vector, and identifies the Long Term Key to use.
+    EncReq {
+        /// Random number identifying the Long Term Key (LTK).
+        rand: [u8; 8],
+        /// Encrypted diversifier identifying the Long Term Key (LTK).
+        ediv: Hex<u16>,
+        /// Master's portion of the session key diversifier.
+        skd_m: [u8; 8],
+        /// Master's portion of the initialization vector.
+        iv_m: [u8; 4],
+    },
+
+    /// `0x04`/`LL_ENC_RSP` - Slave responds to `LL_ENC_REQ` with its parts of the session key
+    /// diversifier and initialization vector.
+    EncRsp {
+        /// Slave's portion of the session key diversifier.
+        skd_s: [u8; 8],
+        /// Slave's portion of the initialization vector.
+        iv_s: [u8; 4],
+    },
+
+    /// `0x05`/`LL_START_ENC_REQ` - Sent unencrypted by the slave once it is ready to receive
+    /// encrypted PDUs.
+    StartEncReq,
+
+    /// `0x06`/`LL_START_ENC_RSP` - Sent encrypted by both master and slave to finish the
+    /// *Encryption Start Procedure*.
+    StartEncRsp,
+
     /// `0x07`/`LL_UNKNOWN_RSP` - Response to unknown/unsupported LL Control PDUs.
     ///
     /// This is returned as a response to an incoming LL Control PDU when the opcode is
@@ -212,6 +406,17 @@ pub enum ControlPdu<'a> {
         features_used: FeatureSet,
     },
 
+    /// `0x0A`/`LL_PAUSE_ENC_REQ` - Master requests to pause encryption, for example to change the
+    /// key in use.
+    ///
+    /// Sent encrypted.
+    PauseEncReq,
+
+    /// `0x0B`/`LL_PAUSE_ENC_RSP` - Response to `LL_PAUSE_ENC_REQ`.
+    ///
+    /// The slave sends this encrypted, the master's response to it is unencrypted.
+    PauseEncRsp,
+
     /// `0x0C`/`LL_VERSION_IND` - Bluetooth version indication (sent by both master and slave).
     ///
     /// When either master or slave receive this PDU, they should respond with their version if they
@@ -222,9 +427,26 @@ pub enum ControlPdu<'a> {
         sub_vers_nr: Hex<u16>,
     },
 
+    /// `0x0D`/`LL_REJECT_IND` - Rejects a request.
+    ///
+    /// Sent by the slave in response to `LL_ENC_REQ` if the host has no Long Term Key.
+    RejectInd {
+        error_code: Hex<u8>,
+    },
+
     ConnectionParamReq(ConnectionParamRequest),
     ConnectionParamRsp(ConnectionParamRequest),
 
+    /// `0x14`/`LL_LENGTH_REQ` - Initiates the *Data Length Update Procedure*.
+    ///
+    /// Can be sent by master or slave. Contains the sender's supported packet sizes.
+    LengthReq(DataLength),
+
+    /// `0x15`/`LL_LENGTH_RSP` - Response to `LL_LENGTH_REQ`.
+    ///
+    /// Contains the responder's supported packet sizes.
+    LengthRsp(DataLength),
+
     /// Catch-all variant for unsupported opcodes.
     Unknown {
         /// The opcode we don't support. This can also be the `Unknown` variant.
@@ -242,12 +464,21 @@ impl ControlPdu<'_> {
             ControlPdu::ConnectionUpdateReq { .. } => ControlOpcode::ConnectionUpdateReq,
             ControlPdu::ChannelMapReq { .. } => ControlOpcode::ChannelMapReq,
             ControlPdu::TerminateInd { .. } => ControlOpcode::TerminateInd,
+            ControlPdu::EncReq { .. } => ControlOpcode::EncReq,
+            ControlPdu::EncRsp { .. } => ControlOpcode::EncRsp,
+            ControlPdu::StartEncReq => ControlOpcode::StartEncReq,
+            ControlPdu::StartEncRsp => ControlOpcode::StartEncRsp,
             ControlPdu::UnknownRsp { .. } => ControlOpcode::UnknownRsp,
             ControlPdu::FeatureReq { .. } => ControlOpcode::FeatureReq,
             ControlPdu::FeatureRsp { .. } => ControlOpcode::FeatureRsp,
+            ControlPdu::PauseEncReq => ControlOpcode::PauseEncReq,
+            ControlPdu::PauseEncRsp => ControlOpcode::PauseEncRsp,
             ControlPdu::VersionInd { .. } => ControlOpcode::VersionInd,
+            ControlPdu::RejectInd { .. } => ControlOpcode::RejectInd,
             ControlPdu::ConnectionParamReq(_) => ControlOpcode::ConnectionParamReq,
             ControlPdu::ConnectionParamRsp(_) => ControlOpcode::ConnectionParamRsp,
+            ControlPdu::LengthReq(_) => ControlOpcode::LengthReq,
+            ControlPdu::LengthRsp(_) => ControlOpcode::LengthRsp,
             ControlPdu::Unknown { opcode, .. } => *opcode,
         }
     }
@@ -315,6 +546,18 @@ impl<'a> FromBytes<'a> for ControlPdu<'a> {
             ControlOpcode::TerminateInd => ControlPdu::TerminateInd {
                 error_code: Hex(bytes.read_u8()?),
             },
+            ControlOpcode::EncReq => ControlPdu::EncReq {
+                rand: bytes.read_array()?,
+                ediv: Hex(bytes.read_u16_le()?),
+                skd_m: bytes.read_array()?,
+                iv_m: bytes.read_array()?,
+            },
+            ControlOpcode::EncRsp => ControlPdu::EncRsp {
+                skd_s: bytes.read_array()?,
+                iv_s: bytes.read_array()?,
+            },
+            ControlOpcode::StartEncReq => ControlPdu::StartEncReq,
+            ControlOpcode::StartEncRsp => ControlPdu::StartEncRsp,
             ControlOpcode::UnknownRsp => ControlPdu::UnknownRsp {
                 unknown_type: ControlOpcode::from(bytes.read_u8()?),
             },
@@ -324,11 +567,18 @@ impl<'a> FromBytes<'a> for ControlPdu<'a> {
             ControlOpcode::FeatureRsp => ControlPdu::FeatureRsp {
                 features_used: FeatureSet::from_bytes(bytes)?,
             },
+            ControlOpcode::PauseEncReq => ControlPdu::PauseEncReq,
+            ControlOpcode::PauseEncRsp => ControlPdu::PauseEncRsp,
             ControlOpcode::VersionInd => ControlPdu::VersionInd {
                 vers_nr: VersionNumber::from(bytes.read_u8()?),
                 comp_id: CompanyId::from_raw(bytes.read_u16_le()?),
                 sub_vers_nr: Hex(bytes.read_u16_le()?),
             },
+            ControlOpcode::RejectInd => ControlPdu::RejectInd {
+                error_code: Hex(bytes.read_u8()?),
+            },
+            ControlOpcode::LengthReq => ControlPdu::LengthReq(DataLength::from_bytes(bytes)?),
+            ControlOpcode::LengthRsp => ControlPdu::LengthRsp(DataLength::from_bytes(bytes)?),
             _ => ControlPdu::Unknown {
                 opcode,
                 ctr_data: bytes.read_rest(),
@@ -355,10 +605,31 @@ impl<'a> ToBytes for ControlPdu<'a> {
                 buffer.write_u16_le(*instant)?;
                 Ok(())
             }
-            ControlPdu::TerminateInd { error_code } => {
+            ControlPdu::TerminateInd { error_code } | ControlPdu::RejectInd { error_code } => {
                 buffer.write_u8(error_code.0)?;
                 Ok(())
             }
+            ControlPdu::EncReq {
+                rand,
+                ediv,
+                skd_m,
+                iv_m,
+            } => {
+                buffer.write_slice(rand)?;
+                buffer.write_u16_le(ediv.0)?;
+                buffer.write_slice(skd_m)?;
+                buffer.write_slice(iv_m)?;
+                Ok(())
+            }
+            ControlPdu::EncRsp { skd_s, iv_s } => {
+                buffer.write_slice(skd_s)?;
+                buffer.write_slice(iv_s)?;
+                Ok(())
+            }
+            ControlPdu::StartEncReq
+            | ControlPdu::StartEncRsp
+            | ControlPdu::PauseEncReq
+            | ControlPdu::PauseEncRsp => Ok(()),
             ControlPdu::UnknownRsp { unknown_type } => {
                 buffer.write_u8(u8::from(*unknown_type))?;
                 Ok(())
@@ -378,6 +649,7 @@ impl<'a> ToBytes for ControlPdu<'a> {
             ControlPdu::ConnectionParamReq(data) | ControlPdu::ConnectionParamRsp(data) => {
                 data.to_bytes(buffer)
             }
+            ControlPdu::LengthReq(data) | ControlPdu::LengthRsp(data) => data.to_bytes(buffer),
             ControlPdu::Unknown { ctr_data, .. } => {
                 buffer.write_slice(ctr_data)?;
                 Ok(())
@@ -472,4 +744,30 @@ mod tests {
         let mut req = ConnectionParamRequest::new();
         req.set_conn_interval(Duration::from_secs(8), Duration::from_secs(7));
     }
+
+    #[test]
+    fn data_length() {
+        let default = DataLength::default();
+        assert_eq!(default.max_tx_octets(), 27);
+        assert_eq!(default.max_tx_time(), Duration::from_micros(328));
+
+        let max = DataLength::new(251, 251);
+        assert_eq!(max.max_rx_time(), Duration::from_micros(2120));
+        assert_eq!(max.effective_tx_octets(&default), 27);
+        assert_eq!(max.effective_tx_octets(&max), 251);
+
+        // Out-of-range values are clamped, and the packet time also limits the payload size.
+        let raw = [0xFF, 0x00, 0x00, 0x10, 0x10, 0x00, 0x48, 0x03];
+        let remote = DataLength::from_bytes(&mut ByteReader::new(&raw)).unwrap();
+        assert_eq!(remote.max_rx_octets(), 251);
+        assert_eq!(remote.max_rx_time(), Duration::from_micros(2120));
+        assert_eq!(remote.max_tx_octets(), 27);
+        assert_eq!(remote.max_tx_time(), Duration::from_micros(840));
+        assert_eq!(remote.effective_tx_octets(&max), 27);
+        assert_eq!(max.effective_tx_octets(&remote), 251);
+
+        let remote = DataLength::new(251, 100);
+        assert_eq!(remote.effective_tx_octets(&max), 100);
+        assert_eq!(DataLength::new(251, 5).max_tx_octets(), 27);
+    }
 }
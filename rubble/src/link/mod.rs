@@ -124,24 +124,36 @@ mod comp_id;
 mod connection;
 pub mod data;
 mod device_address;
+mod encryption;
 mod features;
 pub mod filter;
 pub mod llcp;
+pub mod pcap;
 pub mod queue;
 mod responder;
+mod scanner;
 mod seq_num;
 
+pub use self::channel_map::ChannelMap;
 pub use self::comp_id::*;
-pub use self::connection::Connection;
+pub use self::connection::{Connection, ConnectionEvent, DisconnectReason};
 pub use self::device_address::*;
+pub use self::encryption::LtkRequest;
 pub use self::features::*;
 pub use self::responder::*;
+pub use self::scanner::*;
+pub use self::seq_num::SeqNum;
 
-use self::advertising::{Pdu, PduBuf};
-use self::{ad_structure::AdStructure, seq_num::SeqNum};
-use crate::phy::{AdvertisingChannel, DataChannel};
+use self::ad_structure::AdStructure;
+use self::advertising::{AdvertisingParameters, AdvertisingType, ConnectRequestData, Pdu, PduBuf};
+use self::pcap::{CaptureTx, Direction, PcapWriter};
+use self::queue::{Consume, Consumer};
+use crate::phy::{AdvertisingChannel, AdvertisingChannelMap, DataChannel};
 use crate::time::{Duration, Instant, Timer};
-use crate::{bytes::ByteReader, config::*, utils::HexSlice, Error};
+use crate::utils::{HexSlice, XorShift32};
+use crate::{bytes::ByteReader, config::*, crypto::Key, Error};
+use core::mem;
+use rand_core::RngCore;
 
 /// The CRC polynomial to use for CRC24 generation.
 ///
@@ -169,21 +181,42 @@ pub const MIN_DATA_PAYLOAD_BUF: usize = 27;
 /// This is `MIN_DATA_PAYLOAD_BUF` plus the size of the data PDU header (2 Bytes).
 pub const MIN_DATA_PDU_BUF: usize = MIN_DATA_PAYLOAD_BUF + 2;
 
+/// Max. size of a data PDU payload when the Packet Length Extension is used.
+///
+/// Buffers of this size are needed to make full use of the *Data Length Update Procedure*, which
+/// allows exchanging data channel PDUs carrying up to 251 Bytes of payload.
+pub const MAX_DATA_PAYLOAD_BUF: usize = 251;
+
+/// Max. size of a data PDU buffer when the Packet Length Extension is used.
+///
+/// This is `MAX_DATA_PAYLOAD_BUF` plus the size of the data PDU header (2 Bytes).
+pub const MAX_DATA_PDU_BUF: usize = MAX_DATA_PAYLOAD_BUF + 2;
+
 /// Min. size a PDU payload buffer must have (to cover both advertising and data channels).
 ///
 /// The Advertising PDU header has a length field that is limited to 37 octets, while data channel
 /// PDUs in Bluetooth 4.0 and 4.1 only have a 5-bit length field, limiting the user payload to 27
 /// octets (after subtracting the optional 4-Byte MIC). Bluetooth 4.2 added the optional Packet
-/// Length Extension, which allows data channel PDUs containing up to 251 user payload bytes,
-/// however Rubble doesn't support that yet.
+/// Length Extension, which allows data channel PDUs containing up to 251 user payload bytes. Use
+/// `MAX_PAYLOAD_BUF` to support those.
 pub const MIN_PAYLOAD_BUF: usize = 37;
 
 /// Min. size a Link-Layer PDU buffer must have (to cover both advertising and data channels).
 ///
-/// Bluetooth 4.2 also allows exchanging larger PDUs using the Packet Length Extension, but Rubble
-/// does not currently support that.
+/// Bluetooth 4.2 also allows exchanging larger PDUs using the Packet Length Extension, which
+/// requires buffers of `MAX_PDU_BUF` Bytes.
 pub const MIN_PDU_BUF: usize = MIN_PAYLOAD_BUF + 2 /* 16-bit header */;
 
+/// Size a PDU payload buffer must have to cover both advertising channel PDUs and data channel PDUs
+/// using the Packet Length Extension.
+///
+/// This includes space for the 4-Byte MIC appended to the payload of encrypted data channel PDUs.
+pub const MAX_PAYLOAD_BUF: usize = MAX_DATA_PAYLOAD_BUF + crate::crypto::MIC_SIZE;
+
+/// Size a Link-Layer PDU buffer must have to cover all PDUs, including data channel PDUs using the
+/// Packet Length Extension.
+pub const MAX_PDU_BUF: usize = MAX_PAYLOAD_BUF + 2 /* 16-bit header */;
+
 /// Min. size a buffer for Link-Layer packets must have to comply with the spec.
 ///
 /// The packet contains everything that ends up being transmitted over the air: Preamble, Access
@@ -196,6 +229,26 @@ pub const MIN_PACKET_BUF: usize =
     MIN_PDU_BUF +
     3 /* crc */;
 
+/// Size a buffer for Link-Layer packets must have to fit packets using the Packet Length
+/// Extension.
+pub const MAX_PACKET_BUF: usize =
+    1 /* preamble */ +
+    4 /* access addr */ +
+    MAX_PDU_BUF +
+    3 /* crc */;
+
+/// How long the initiator listens on an advertising channel before switching to the next one.
+const INITIATOR_WINDOW: Duration = Duration::from_micros(100_000);
+
+/// Time between the advertising PDUs of an advertising event.
+///
+/// This leaves enough time to receive a `SCAN_REQ` and send the `SCAN_RSP`, or to receive a
+/// `CONNECT_REQ`, on each channel.
+const ADV_CHANNEL_DELAY: Duration = Duration::from_micros(1_500);
+
+/// Max. random delay (`advDelay`) added to the advertising interval.
+const MAX_ADV_DELAY: Duration = Duration::from_micros(10_000);
+
 /// Link-Layer state machine, according to the Bluetooth spec.
 enum State<C: Config> {
     /// Radio silence: Not listening, not transmitting anything.
@@ -203,16 +256,39 @@ enum State<C: Config> {
 
     /// Device is advertising and wants to establish a connection.
     Advertising {
-        /// Advertising interval.
-        // TODO: check spec for allowed/recommended values and check for them
+        /// Start of the next advertising event.
         next_adv: Instant,
+
+        /// Advertising interval, not including the random `advDelay`.
         interval: Duration,
 
+        /// Determines which requests are answered.
+        ty: AdvertisingType,
+
         /// Precomputed PDU payload to copy into the transmitter's buffer.
         pdu: advertising::PduBuf,
 
-        /// Next advertising channel to use for a message.
-        // FIXME: spec check; no idea what order or change delay
+        /// Advertising channels used by every advertising event.
+        channels: AdvertisingChannelMap,
+
+        /// Advertising channel the last PDU was sent on.
+        channel: AdvertisingChannel,
+
+        /// PRNG used to pick `advDelay`.
+        rng: XorShift32,
+
+        data_queues: Option<(ConfConsumer<C>, ConfProducer<C>)>,
+    },
+
+    /// Device is listening for advertisements of `peer` in order to connect to it.
+    Initiating {
+        /// Address of the device to connect to.
+        peer: DeviceAddress,
+
+        /// Connection parameters to send in the `CONNECT_REQ`.
+        lldata: ConnectRequestData,
+
+        /// Advertising channel we're listening on.
         channel: AdvertisingChannel,
 
         data_queues: Option<(ConfConsumer<C>, ConfProducer<C>)>,
@@ -228,8 +304,45 @@ enum State<C: Config> {
 /// `HardwareInterface`.
 pub struct LinkLayer<C: Config> {
     dev_addr: DeviceAddress,
+
+    /// Precomputed scan response, sent when a scanner requests more data while we're advertising.
+    scan_response: PduBuf,
+
     state: State<C>,
     timer: C::Timer,
+    aes: C::AesProvider,
+    rng: C::Rng,
+
+    /// Records every packet sent or received, if capturing.
+    capture: Option<PcapWriter<C::PcapSink>>,
+
+    /// Packet queues given back by the last advertising, initiating or connected state.
+    data_queues: Option<(ConfConsumer<C>, ConfProducer<C>)>,
+
+    /// Advertising state to return to when a connection established while advertising is lost.
+    resume_adv: Option<ResumeAdvertising>,
+
+    /// Whether to resume advertising when a connection is lost.
+    resume_enabled: bool,
+
+    /// Why the last connection has ended.
+    disconnect_reason: Option<DisconnectReason>,
+
+    /// Peer of a connection established since the last `take_connection_event` call.
+    pending_established: Option<DeviceAddress>,
+
+    /// Reason for the end of a connection that wasn't reported by `take_connection_event` yet.
+    pending_ended: Option<DisconnectReason>,
+}
+
+/// Advertising configuration kept while connected, so that advertising can resume when the
+/// connection is lost.
+struct ResumeAdvertising {
+    interval: Duration,
+    ty: AdvertisingType,
+    pdu: PduBuf,
+    channels: AdvertisingChannelMap,
+    rng: XorShift32,
 }
 
 impl<C: Config> LinkLayer<C> {
@@ -239,26 +352,91 @@ impl<C: Config> LinkLayer<C> {
     ///
     /// * **`dev_addr`**: The device address to broadcast as.
     /// * **`timer`**: A `Timer` implementation.
-    /// * **`tx`**: Input queue of packets to transmit when connected.
-    /// * **`rx`**: Output queue of received packets when connected.
-    pub fn new(dev_addr: DeviceAddress, timer: C::Timer) -> Self {
+    /// * **`aes`**: The AES implementation used for encrypting connections.
+    /// * **`rng`**: A cryptographically secure RNG. It generates our contribution to the session
+    ///   keys of encrypted connections, and seeds the PRNGs used for advertising.
+    pub fn new(dev_addr: DeviceAddress, timer: C::Timer, aes: C::AesProvider, rng: C::Rng) -> Self {
         trace!("new LinkLayer, dev={:?}", dev_addr);
         Self {
             dev_addr,
+            scan_response: PduBuf::scan_response(dev_addr, &[]).unwrap(),
             state: State::Standby,
             timer,
+            aes,
+            rng,
+            capture: None,
+            data_queues: None,
+            resume_adv: None,
+            resume_enabled: true,
+            disconnect_reason: None,
+            pending_established: None,
+            pending_ended: None,
         }
     }
 
+    /// Returns the device address the Link-Layer uses.
+    pub fn device_address(&self) -> DeviceAddress {
+        self.dev_addr
+    }
+
     /// Returns a reference to the timer instance used by the Link-Layer.
     pub fn timer(&mut self) -> &mut C::Timer {
         &mut self.timer
     }
 
+    /// Starts recording all packets sent and received by the Link-Layer to `writer`.
+    ///
+    /// If a capture is already running, it is replaced. Errors returned by the sink are ignored,
+    /// which drops the affected packets from the capture.
+    pub fn start_capture(&mut self, writer: PcapWriter<C::PcapSink>) {
+        self.capture = Some(writer);
+    }
+
+    /// Stops recording packets and returns the `PcapWriter` passed to `start_capture`.
+    ///
+    /// Returns `None` if no capture is running.
+    pub fn stop_capture(&mut self) -> Option<PcapWriter<C::PcapSink>> {
+        self.capture.take()
+    }
+
+    /// Sets the data sent in response to scan requests while advertising.
+    ///
+    /// This can be used to provide data that doesn't fit in the advertising PDU, such as the
+    /// device name or lists of service UUIDs. The scan response data is empty by default. It can
+    /// be updated at any time, including while advertising.
+    ///
+    /// Returns `Error::Eof` if `data` doesn't fit in a single PDU. The previous scan response data
+    /// is kept in that case.
+    pub fn set_scan_response_data(&mut self, data: &[AdStructure<'_>]) -> Result<(), Error> {
+        self.scan_response = PduBuf::scan_response(self.dev_addr, data)?;
+        debug!("scan response PDU = {:?}", self.scan_response);
+        Ok(())
+    }
+
+    /// Sets whether advertising is resumed when a connection is lost.
+    ///
+    /// If enabled (the default), a connection that was established while advertising and then
+    /// lost because the supervision timer expired makes the Link-Layer advertise again, using the
+    /// parameters and data passed to `start_advertise`. Otherwise, it enters standby like it does
+    /// when a connection is terminated.
+    pub fn set_resume_advertising(&mut self, enabled: bool) {
+        self.resume_enabled = enabled;
+    }
+
     /// Starts advertising this device, optionally sending data along with the advertising PDU.
+    ///
+    /// Every advertising event sends the advertising PDU on all channels enabled in `params`. The
+    /// events are spaced by the minimum advertising interval plus a random delay of 0-10 ms, which
+    /// is derived from the RNG passed to `new`. Scan requests and connection requests are only
+    /// accepted if the advertising type allows them.
+    ///
+    /// Scan response data can be configured via `set_scan_response_data`.
+    ///
+    /// Returns `Error::InvalidValue` if `data` isn't empty but the advertising type doesn't allow
+    /// advertising data, and `Error::Eof` if `data` doesn't fit in a single PDU.
     pub fn start_advertise(
         &mut self,
-        interval: Duration,
+        params: AdvertisingParameters,
         data: &[AdStructure<'_>],
         transmitter: &mut C::Transmitter,
         tx: ConfConsumer<C>,
@@ -266,19 +444,56 @@ impl<C: Config> LinkLayer<C> {
     ) -> Result<NextUpdate, Error> {
         // TODO tear down existing connection?
 
-        let pdu = PduBuf::discoverable(self.dev_addr, data)?;
+        let pdu = params.build_pdu(self.dev_addr, data)?;
+        debug!("start_advertise: params = {:?}", params);
         debug!("start_advertise: adv_data = {:?}", data);
         debug!("start_advertise: PDU = {:?}", pdu);
+        let channels = params.channels();
         self.state = State::Advertising {
             next_adv: self.timer().now(),
-            interval,
+            interval: params.interval_min(),
+            ty: params.advertising_type(),
             pdu,
-            channel: AdvertisingChannel::first(),
+            channels,
+            // Pretend the last event just ended, so the next one starts on the first channel
+            channel: channels.iter().last().unwrap(),
+            rng: XorShift32::new(self.rng.next_u32()),
             data_queues: Some((tx, rx)),
         };
         Ok(self.update_timer(transmitter).next_update)
     }
 
+    /// Starts connecting to the advertising device `peer`.
+    ///
+    /// The Link-Layer listens on the advertising channels until `peer` sends a connectable
+    /// advertisement, answers it with a `CONNECT_REQ` containing `lldata`, and then maintains the
+    /// connection in the master role. Encryption is not supported in the master role.
+    ///
+    /// Returns a `Cmd` to apply to the radio and timer.
+    pub fn start_connecting(
+        &mut self,
+        peer: DeviceAddress,
+        lldata: ConnectRequestData,
+        tx: ConfConsumer<C>,
+        rx: ConfProducer<C>,
+    ) -> Cmd {
+        // TODO tear down existing connection?
+
+        debug!("start_connecting: peer = {:?}, lldata = {:?}", peer, lldata);
+        let channel = AdvertisingChannel::first();
+        self.state = State::Initiating {
+            peer,
+            lldata,
+            channel,
+            data_queues: Some((tx, rx)),
+        };
+        Cmd {
+            radio: RadioCmd::ListenAdvertising { channel },
+            next_update: NextUpdate::At(self.timer.now() + INITIATOR_WINDOW),
+            queued_work: false,
+        }
+    }
+
     /// Process an incoming packet from an advertising channel.
     ///
     /// The access address of the packet must be `ADVERTISING_ADDRESS`.
@@ -298,10 +513,33 @@ impl<C: Config> LinkLayer<C> {
         payload: &[u8],
         crc_ok: bool,
     ) -> Cmd {
+        if let Some(capture) = &mut self.capture {
+            let channel = match self.state {
+                State::Advertising { channel, .. } | State::Initiating { channel, .. } => {
+                    Some(channel)
+                }
+                _ => None,
+            };
+            if let Some(channel) = channel {
+                let rx_start = rx_end - pcap::air_time(header.payload_length());
+                capture
+                    .write_advertising(rx_start, channel, header, payload, crc_ok)
+                    .ok();
+            }
+        }
+        let direction = self.tx_direction();
+        let tx = &mut CaptureTx::new(
+            tx,
+            self.capture.as_mut(),
+            rx_end + Duration::T_IFS,
+            direction,
+        );
+
         let pdu = advertising::Pdu::from_header_and_payload(header, &mut ByteReader::new(payload));
 
         if let Ok(pdu) = pdu {
             if let State::Advertising {
+                ty,
                 channel,
                 data_queues,
                 ..
@@ -310,25 +548,94 @@ impl<C: Config> LinkLayer<C> {
                 if crc_ok && pdu.receiver() == Some(&self.dev_addr) {
                     // Got a packet addressed at us, can be a scan or connect request
                     match pdu {
-                        Pdu::ScanRequest { .. } => {
-                            let scan_data = &[]; // TODO make this configurable
-                            let response = PduBuf::scan_response(self.dev_addr, scan_data).unwrap();
+                        Pdu::ScanRequest { .. } if ty.is_scannable() => {
+                            let response = &self.scan_response;
+                            let payload = response.payload();
+                            tx.tx_payload_buf()[..payload.len()].copy_from_slice(payload);
                             tx.transmit_advertising(response.header(), *channel);
 
                             // Log after responding to meet timing
                             debug!("-> SCAN RESP: {:?}", response);
                         }
-                        Pdu::ConnectRequest { lldata, .. } => {
+                        Pdu::ConnectRequest {
+                            initiator_addr,
+                            lldata,
+                            ..
+                        } if ty.accepts_connect_request(&initiator_addr) => {
                             trace!("ADV<- CONN! {:?}", pdu);
 
+                            let max_payload = tx.max_data_payload();
                             let (tx, rx) = data_queues.take().unwrap();
-                            let (conn, cmd) = Connection::create(&lldata, rx_end, tx, rx);
-                            self.state = State::Connection(conn);
+                            let (conn, mut cmd) = Connection::create(
+                                initiator_addr,
+                                &lldata,
+                                rx_end,
+                                max_payload,
+                                tx,
+                                rx,
+                            );
+                            self.pending_established = Some(initiator_addr);
+                            cmd.queued_work = true;
+
+                            // Keep the advertising state around in case the connection is lost
+                            let state = mem::replace(&mut self.state, State::Connection(conn));
+                            if let State::Advertising {
+                                interval,
+                                ty,
+                                pdu,
+                                channels,
+                                rng,
+                                ..
+                            } = state
+                            {
+                                self.resume_adv = Some(ResumeAdvertising {
+                                    interval,
+                                    ty,
+                                    pdu,
+                                    channels,
+                                    rng,
+                                });
+                            }
                             return cmd;
                         }
                         _ => {}
                     }
                 }
+            } else if let State::Initiating {
+                peer,
+                lldata,
+                channel,
+                data_queues,
+            } = &mut self.state
+            {
+                let connectable = match pdu {
+                    Pdu::ConnectableUndirected { .. } => true,
+                    Pdu::ConnectableDirected { initiator_addr, .. } => {
+                        initiator_addr == self.dev_addr
+                    }
+                    _ => false,
+                };
+
+                if crc_ok && connectable && pdu.sender() == peer {
+                    let request = PduBuf::connect_request(self.dev_addr, *peer, lldata);
+                    let payload = request.payload();
+                    tx.tx_payload_buf()[..payload.len()].copy_from_slice(payload);
+                    tx.transmit_advertising(request.header(), *channel);
+
+                    // The `CONNECT_REQ` is sent `T_IFS` after the advertisement, and takes 352 µs
+                    let tx_end = rx_end + Duration::T_IFS + Duration::from_micros(352);
+                    let max_payload = tx.max_data_payload();
+                    let (tx, rx) = data_queues.take().unwrap();
+                    let (conn, mut cmd) =
+                        Connection::create_master(*peer, lldata, tx_end, max_payload, tx, rx);
+                    self.pending_established = Some(*peer);
+                    cmd.queued_work = true;
+                    self.state = State::Connection(conn);
+
+                    // Log after sending the request to meet timing
+                    trace!("ADV-> CONN! {:?}", request);
+                    return cmd;
+                }
             }
         }
 
@@ -343,7 +650,7 @@ impl<C: Config> LinkLayer<C> {
         match self.state {
             State::Standby => unreachable!("standby, can't receive packets"),
             State::Connection { .. } => unreachable!("process_adv_packet called while connected"),
-            State::Advertising { channel, .. } => {
+            State::Advertising { channel, .. } | State::Initiating { channel, .. } => {
                 Cmd {
                     radio: RadioCmd::ListenAdvertising { channel },
                     // no change
@@ -363,19 +670,41 @@ impl<C: Config> LinkLayer<C> {
         payload: &[u8],
         crc_ok: bool,
     ) -> Cmd {
+        let direction = self.tx_direction();
         if let State::Connection(conn) = &mut self.state {
-            match conn.process_data_packet(rx_end, tx, header, payload, crc_ok) {
+            if let Some(capture) = &mut self.capture {
+                let rx_start = rx_end - pcap::air_time(header.payload_length());
+                capture
+                    .write_data(
+                        rx_start,
+                        direction.reverse(),
+                        conn.rx_channel(),
+                        conn.access_address(),
+                        conn.crc_init(),
+                        header,
+                        payload,
+                        crc_ok,
+                    )
+                    .ok();
+            }
+            let tx = &mut CaptureTx::new(
+                tx,
+                self.capture.as_mut(),
+                rx_end + Duration::T_IFS,
+                direction,
+            );
+
+            match conn.process_data_packet(
+                rx_end,
+                tx,
+                &mut self.aes,
+                &mut self.rng,
+                header,
+                payload,
+                crc_ok,
+            ) {
                 Ok(cmd) => cmd,
-                Err(()) => {
-                    debug!("connection ended, standby");
-                    self.state = State::Standby;
-                    Cmd {
-                        next_update: NextUpdate::Disable,
-                        radio: RadioCmd::Off,
-                        // FIXME(#70) this might need to be changed to `true`
-                        queued_work: false,
-                    }
-                }
+                Err(reason) => self.connection_ended(reason),
             }
         } else {
             unreachable!("received data channel PDU while not in connected state");
@@ -390,48 +719,213 @@ impl<C: Config> LinkLayer<C> {
     ///
     /// * `tx`: A `Transmitter` for sending packets.
     pub fn update_timer(&mut self, tx: &mut C::Transmitter) -> Cmd {
+        let direction = self.tx_direction();
+        let tx = &mut CaptureTx::new(tx, self.capture.as_mut(), self.timer.now(), direction);
+
         match &mut self.state {
             State::Advertising {
                 next_adv,
                 interval,
                 pdu,
+                channels,
                 channel,
+                rng,
                 ..
             } => {
-                *channel = channel.cycle();
+                // Continue the current advertising event, or start the next one
+                *channel = match channels.next_after(*channel) {
+                    Some(next) => next,
+                    None => channels.first(),
+                };
                 let payload = pdu.payload();
                 let buf = tx.tx_payload_buf();
                 buf[..payload.len()].copy_from_slice(payload);
-
-                // FIXME According to the spec, this has to broadcast on all advertising channels
-
                 tx.transmit_advertising(pdu.header(), *channel);
 
-                *next_adv += *interval;
+                let next_update = if channels.next_after(*channel).is_some() {
+                    self.timer.now() + ADV_CHANNEL_DELAY
+                } else {
+                    // Last channel of this event. The spec adds a pseudo-random `advDelay` to
+                    // every interval to avoid repeated collisions with other advertisers.
+                    let max_delay = MAX_ADV_DELAY.as_micros() + 1;
+                    let adv_delay = Duration::from_micros(rng.next_u32() % max_delay);
+                    *next_adv += *interval + adv_delay;
+                    *next_adv
+                };
 
                 Cmd {
                     radio: RadioCmd::ListenAdvertising { channel: *channel },
-                    next_update: NextUpdate::At(*next_adv),
+                    next_update: NextUpdate::At(next_update),
                     queued_work: false,
                 }
             }
-            State::Connection(conn) => match conn.timer_update(&mut self.timer) {
-                Ok(cmd) => cmd,
-                Err(()) => {
-                    debug!("connection ended (timer), standby");
-                    self.state = State::Standby;
-                    Cmd {
-                        next_update: NextUpdate::Disable,
-                        radio: RadioCmd::Off,
-                        // FIXME(#70) this might need to be changed to `true`
-                        queued_work: false,
-                    }
+            State::Initiating { channel, .. } => {
+                *channel = channel.cycle();
+
+                Cmd {
+                    radio: RadioCmd::ListenAdvertising { channel: *channel },
+                    next_update: NextUpdate::At(self.timer.now() + INITIATOR_WINDOW),
+                    queued_work: false,
                 }
+            }
+            State::Connection(conn) => match conn.timer_update(&mut self.timer, tx) {
+                Ok(cmd) => cmd,
+                Err(reason) => self.connection_ended(reason),
             },
             State::Standby => unreachable!("LL in standby received timer event"),
         }
     }
 
+    /// Stops advertising, initiating, or the current connection.
+    ///
+    /// This enters standby immediately, without notifying the connected device. The packet queues
+    /// passed to `start_advertise` or `start_connecting` can be retrieved via `take_data_queues`.
+    ///
+    /// Returns a `Cmd` to apply to the radio and timer.
+    pub fn enter_standby(&mut self) -> Cmd {
+        debug!("entering standby");
+        self.release_state();
+        Cmd {
+            next_update: NextUpdate::Disable,
+            radio: RadioCmd::Off,
+            queued_work: false,
+        }
+    }
+
+    /// Returns the packet queues of the last advertising, initiating or connected state, once the
+    /// Link-Layer has entered standby.
+    ///
+    /// This allows reusing the queues for the next call to `start_advertise` or
+    /// `start_connecting`. Packets that weren't sent before a connection ended are discarded, but
+    /// the RX queue may still contain packets received from the old connection (they're discarded
+    /// by `Responder::connection_ended`).
+    ///
+    /// Returns `None` if the queues are in use, or have already been taken.
+    pub fn take_data_queues(&mut self) -> Option<(ConfConsumer<C>, ConfProducer<C>)> {
+        self.data_queues.take()
+    }
+
+    /// Terminates the current connection.
+    ///
+    /// An `LL_TERMINATE_IND` carrying `error_code` is sent to the connected device, and the
+    /// Link-Layer enters standby once it was acknowledged.
+    ///
+    /// Returns `Error::InvalidValue` if we're not connected.
+    pub fn disconnect(&mut self, error_code: u8) -> Result<(), Error> {
+        if let State::Connection(conn) = &mut self.state {
+            conn.terminate(error_code);
+            Ok(())
+        } else {
+            Err(Error::InvalidValue)
+        }
+    }
+
+    /// Returns why the last connection has ended.
+    ///
+    /// Returns `None` if no connection has ended yet.
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.disconnect_reason
+    }
+
+    /// Returns the next change of the connection state the app wasn't told about yet.
+    ///
+    /// The app has to call this when the Link-Layer has queued work, and reset the per-connection
+    /// state of the upper layers when a connection is established or has ended, so that nothing
+    /// leaks from one connection into the next. The Link-Layer sets `Cmd::queued_work` when a
+    /// connection is established or ends.
+    ///
+    /// If several events happened since the last call, they're returned in order. A connection
+    /// that ended before it was reported is only reported as ended. Connections ended via
+    /// `enter_standby` are not reported.
+    pub fn take_connection_event(&mut self) -> Option<ConnectionEvent> {
+        if let Some(reason) = self.pending_ended.take() {
+            Some(ConnectionEvent::Ended(reason))
+        } else {
+            self.pending_established
+                .take()
+                .map(|peer| ConnectionEvent::Established { peer })
+        }
+    }
+
+    /// Leaves the connection state after the connection has ended for `reason`.
+    ///
+    /// If the connection was established while advertising and was lost because the supervision
+    /// timer expired, advertising is resumed one advertising interval later. Otherwise, the
+    /// Link-Layer enters standby.
+    fn connection_ended(&mut self, reason: DisconnectReason) -> Cmd {
+        let resume = self.resume_adv.take();
+        self.release_state();
+        self.disconnect_reason = Some(reason);
+        self.pending_established = None;
+        self.pending_ended = Some(reason);
+
+        let lost = matches!(
+            reason,
+            DisconnectReason::Timeout | DisconnectReason::FailedToEstablish
+        );
+        match resume {
+            Some(adv) if lost && self.resume_enabled => {
+                debug!("connection lost ({:?}), resuming advertising", reason);
+                let next_adv = self.timer.now() + adv.interval;
+                self.state = State::Advertising {
+                    next_adv,
+                    interval: adv.interval,
+                    ty: adv.ty,
+                    pdu: adv.pdu,
+                    channels: adv.channels,
+                    // The next event starts on the first channel
+                    channel: adv.channels.iter().last().unwrap(),
+                    rng: adv.rng,
+                    data_queues: self.data_queues.take(),
+                };
+                Cmd {
+                    next_update: NextUpdate::At(next_adv),
+                    radio: RadioCmd::Off,
+                    queued_work: true,
+                }
+            }
+            _ => {
+                debug!("connection ended ({:?}), standby", reason);
+                Cmd {
+                    next_update: NextUpdate::Disable,
+                    radio: RadioCmd::Off,
+                    queued_work: true,
+                }
+            }
+        }
+    }
+
+    /// Enters standby, keeping the packet queues of the previous state.
+    fn release_state(&mut self) {
+        let queues = match mem::replace(&mut self.state, State::Standby) {
+            State::Standby => None,
+            State::Advertising { data_queues, .. } | State::Initiating { data_queues, .. } => {
+                data_queues
+            }
+            State::Connection(conn) => {
+                // Data that wasn't sent must not leak into the next connection
+                let (mut tx, rx) = conn.into_queues();
+                while tx.has_data() {
+                    tx.consume_raw_with(|_, _| Consume::always(Ok(()))).ok();
+                }
+                Some((tx, rx))
+            }
+        };
+        if queues.is_some() {
+            self.data_queues = queues;
+        }
+        self.resume_adv = None;
+    }
+
+    /// Returns the direction of data channel packets sent by us.
+    fn tx_direction(&self) -> Direction {
+        match &self.state {
+            State::Initiating { .. } => Direction::MasterToSlave,
+            State::Connection(conn) if conn.is_master() => Direction::MasterToSlave,
+            _ => Direction::SlaveToMaster,
+        }
+    }
+
     /// Returns a reference to the connection state.
     ///
     /// If the Link Layer is not currently in a connection, returns `None`.
@@ -443,6 +937,78 @@ impl<C: Config> LinkLayer<C> {
         }
     }
 
+    /// Returns the pending request for a Long Term Key (LTK).
+    ///
+    /// When the connected master starts encrypting the connection, the host has to look up the LTK
+    /// identified by the request and pass it to `ltk_reply`, or call `ltk_negative_reply` if it has
+    /// no such key. The Link-Layer sets `Cmd::queued_work` when a new request arrives.
+    ///
+    /// Returns `None` if no request is pending.
+    pub fn ltk_request(&self) -> Option<LtkRequest> {
+        self.connection().and_then(|conn| conn.ltk_request())
+    }
+
+    /// Provides the Long Term Key requested by the connected master, continuing the *Encryption
+    /// Start Procedure*.
+    ///
+    /// Returns `Error::InvalidValue` if no LTK was requested.
+    pub fn ltk_reply(&mut self, ltk: &Key) -> Result<(), Error> {
+        if let State::Connection(conn) = &mut self.state {
+            conn.ltk_reply(&mut self.aes, ltk)
+        } else {
+            Err(Error::InvalidValue)
+        }
+    }
+
+    /// Rejects the request for a Long Term Key because the host doesn't have the requested key.
+    ///
+    /// The connection stays unencrypted.
+    ///
+    /// Returns `Error::InvalidValue` if no LTK was requested.
+    pub fn ltk_negative_reply(&mut self) -> Result<(), Error> {
+        if let State::Connection(conn) = &mut self.state {
+            conn.ltk_negative_reply()
+        } else {
+            Err(Error::InvalidValue)
+        }
+    }
+
+    /// Requests new connection parameters from the connected slave.
+    ///
+    /// The parameters are sent in an `LL_CONNECTION_UPDATE_REQ` and take effect a few connection
+    /// events later. Refer to `ConnectRequestData::new` for the allowed parameter ranges.
+    ///
+    /// Returns `Error::InvalidValue` if a parameter is out of range, if we're not connected in the
+    /// master role, or if another connection or channel map update is still in progress.
+    pub fn update_connection_params(
+        &mut self,
+        interval: Duration,
+        slave_latency: u16,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        if let State::Connection(conn) = &mut self.state {
+            conn.update_connection_params(interval, slave_latency, timeout)
+        } else {
+            Err(Error::InvalidValue)
+        }
+    }
+
+    /// Switches the connection to a different set of data channels.
+    ///
+    /// The new map is sent to the connected slave in an `LL_CHANNEL_MAP_REQ` and takes effect a
+    /// few connection events later.
+    ///
+    /// Returns `Error::InvalidValue` if `map` marks fewer than 2 channels as used, if we're not
+    /// connected in the master role, or if another connection or channel map update is still in
+    /// progress.
+    pub fn update_channel_map(&mut self, map: ChannelMap) -> Result<(), Error> {
+        if let State::Connection(conn) = &mut self.state {
+            conn.update_channel_map(map)
+        } else {
+            Err(Error::InvalidValue)
+        }
+    }
+
     /// Returns whether the Link-Layer is currently broadcasting advertisement packets.
     pub fn is_advertising(&self) -> bool {
         if let State::Advertising { .. } = self.state {
@@ -479,6 +1045,9 @@ pub struct Cmd {
 
     /// Whether the Link-Layer code has enqueued more work into the packet queue.
     ///
+    /// This is also set when a packet was taken out of the TX queue, since the `Responder` might be
+    /// waiting for space to become available in order to send the rest of a fragmented message.
+    ///
     /// If this is `true`, the caller needs to ensure that the queue is drained and processed by
     /// calling the `Responder`. The apps idle loop might unconditionally do that, in which case
     /// checking this flag is not necessary.
@@ -550,7 +1119,8 @@ pub trait Transmitter {
     /// The buffer must hold at least 37 Bytes, as that is the maximum length of advertising channel
     /// payloads. While data channel payloads can be up to 251 Bytes in length (resulting in a
     /// "length" field of 255 with the MIC), devices are allowed to use smaller buffers and report
-    /// the supported payload length.
+    /// the supported payload length. In any case, the buffer must have room for the payload and
+    /// the 4-Byte MIC of encrypted data channel PDUs.
     ///
     /// Both advertising and data channel packets also use an additional 2-Byte header preceding
     /// this payload.
@@ -559,6 +1129,18 @@ pub trait Transmitter {
     /// contents after transmitting a packet. A separate buffer must be used for received packets.
     fn tx_payload_buf(&mut self) -> &mut [u8];
 
+    /// Returns the largest data channel PDU payload this radio can transmit and receive.
+    ///
+    /// This is announced to the connected device using the *Data Length Update Procedure*. Both
+    /// the payload buffer returned by `tx_payload_buf` and the buffer used for received packets
+    /// must be able to hold payloads of this size, plus the 4-Byte MIC.
+    ///
+    /// The default implementation returns `MIN_DATA_PAYLOAD_BUF`, which means that only packets
+    /// supported by Bluetooth 4.0 and 4.1 will be exchanged.
+    fn max_data_payload(&self) -> u8 {
+        MIN_DATA_PAYLOAD_BUF as u8
+    }
+
     /// Transmit an Advertising Channel PDU.
     ///
     /// For Advertising Channel PDUs, the CRC initialization value is always `CRC_PRESET`, and the
@@ -0,0 +1,505 @@
+//! Packet capture in the pcap format.
+//!
+//! A `PcapWriter` records Link-Layer packets as a pcap stream using the
+//! `LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR` link type, which can be opened in Wireshark. Every record
+//! carries the RF channel, Access Address, CRC status and direction of the packet in a pseudo
+//! header.
+//!
+//! The `LinkLayer` records all packets it sends and receives once `LinkLayer::start_capture` has
+//! been called. The stream is written to the `PcapSink` configured in the `Config`. On a device,
+//! `ByteWriter` can be used to capture into a RAM buffer, and `HexSink` to print the capture to
+//! any `fmt::Write` implementation (such as a debug console), from which it can be restored using
+//! `xxd -r -p`.
+//!
+//! Since the radio does not provide the CRC of packets, it is recomputed when writing the record.
+//! Packets received with a CRC error are recorded with the inverted CRC, so that the CRC field
+//! agrees with the CRC status in the pseudo header.
+
+use crate::bytes::ByteWriter;
+use crate::link::{advertising, data, Transmitter, MAX_PAYLOAD_BUF};
+use crate::phy::{AdvertisingChannel, DataChannel};
+use crate::time::{Duration, Instant};
+use crate::Error;
+use core::fmt;
+
+/// The pcap link type for BLE Link-Layer packets with a pseudo header.
+pub const LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR: u32 = 256;
+
+/// Size of the pseudo header preceding every packet.
+const PHDR_SIZE: usize = 10;
+
+/// Size of the Access Address, PDU header and CRC surrounding the PDU payload.
+const PACKET_OVERHEAD: usize = 4 + 2 + 3;
+
+/// Max. size of a recorded packet, including the pseudo header.
+const SNAPLEN: usize = PHDR_SIZE + PACKET_OVERHEAD + MAX_PAYLOAD_BUF;
+
+/// Size of the header preceding every record in the file.
+const RECORD_HEADER_SIZE: usize = 16;
+
+// Flags of the pseudo header.
+const FLAG_DEWHITENED: u16 = 0x0001;
+const FLAG_REF_ACCESS_ADDRESS_VALID: u16 = 0x0010;
+const FLAG_CRC_CHECKED: u16 = 0x0400;
+const FLAG_CRC_VALID: u16 = 0x0800;
+
+// PDU types of the pseudo header (bits 7-9 of the flags).
+const PDU_TYPE_ADVERTISING: u16 = 1 << 7;
+const PDU_TYPE_MASTER_TO_SLAVE: u16 = 2 << 7;
+const PDU_TYPE_SLAVE_TO_MASTER: u16 = 3 << 7;
+
+/// A byte stream a pcap capture can be written to.
+pub trait PcapSink {
+    /// Appends `bytes` to the stream.
+    ///
+    /// The `PcapWriter` passes every record to a single call of this method. If the sink is full,
+    /// this should return an error and not write anything, which drops the record but keeps the
+    /// stream intact.
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error>;
+}
+
+impl PcapSink for ByteWriter<'_> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.write_slice(bytes)
+    }
+}
+
+/// A `PcapSink` for configurations that never capture packets.
+///
+/// This type is uninhabited, so no `PcapWriter` can be created for it.
+#[derive(Debug)]
+pub enum NoCapture {}
+
+impl PcapSink for NoCapture {
+    fn write_bytes(&mut self, _: &[u8]) -> Result<(), Error> {
+        match *self {}
+    }
+}
+
+/// Writes a pcap stream as hexadecimal text to a `fmt::Write` implementation.
+///
+/// Every record is written on its own line.
+#[derive(Debug)]
+pub struct HexSink<W: fmt::Write>(pub W);
+
+impl<W: fmt::Write> PcapSink for HexSink<W> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let mut write = || -> fmt::Result {
+            for byte in bytes {
+                write!(self.0, "{:02x}", byte)?;
+            }
+            self.0.write_char('\n')
+        };
+        write().map_err(|_| Error::Eof)
+    }
+}
+
+/// Direction of a data channel packet.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    /// The packet was sent by the master of the connection.
+    MasterToSlave,
+
+    /// The packet was sent by the slave of the connection.
+    SlaveToMaster,
+}
+
+impl Direction {
+    /// Returns the direction of packets sent by the other device.
+    pub fn reverse(self) -> Self {
+        match self {
+            Direction::MasterToSlave => Direction::SlaveToMaster,
+            Direction::SlaveToMaster => Direction::MasterToSlave,
+        }
+    }
+}
+
+/// Writes Link-Layer packets to a `PcapSink` in the pcap format.
+pub struct PcapWriter<S: PcapSink> {
+    sink: S,
+
+    /// Timestamp of the last record, and its time in µs since the raw `Instant` 0.
+    ///
+    /// `Instant`s wrap around after ~71 minutes, so the time is tracked separately.
+    last: Option<(Instant, u64)>,
+}
+
+impl<S: PcapSink> PcapWriter<S> {
+    /// Creates a `PcapWriter` and writes the pcap file header to `sink`.
+    pub fn new(mut sink: S) -> Result<Self, Error> {
+        let mut buf = [0; 24];
+        let mut writer = ByteWriter::new(&mut buf);
+        writer.write_u32_le(0xA1B2_C3D4)?; // magic number (µs timestamps)
+        writer.write_u16_le(2)?; // version
+        writer.write_u16_le(4)?;
+        writer.write_u32_le(0)?; // timezone offset
+        writer.write_u32_le(0)?; // timestamp accuracy
+        writer.write_u32_le(SNAPLEN as u32)?;
+        writer.write_u32_le(LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR)?;
+        sink.write_bytes(&buf)?;
+
+        Ok(Self { sink, last: None })
+    }
+
+    /// Returns a reference to the underlying sink.
+    pub fn sink(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    /// Consumes the writer and returns the underlying sink.
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+
+    /// Records an advertising channel packet.
+    ///
+    /// # Parameters
+    ///
+    /// * **`timestamp`**: The time at which the transmission of the packet started.
+    /// * **`channel`**: The advertising channel the packet was sent on.
+    /// * **`header`**: The PDU header.
+    /// * **`payload`**: The PDU payload following the header.
+    /// * **`crc_ok`**: Whether the packet's CRC is correct.
+    pub fn write_advertising(
+        &mut self,
+        timestamp: Instant,
+        channel: AdvertisingChannel,
+        header: advertising::Header,
+        payload: &[u8],
+        crc_ok: bool,
+    ) -> Result<(), Error> {
+        self.write_packet(
+            timestamp,
+            channel.rf_channel(),
+            flags(PDU_TYPE_ADVERTISING, crc_ok),
+            advertising::ACCESS_ADDRESS,
+            advertising::CRC_PRESET,
+            header.to_u16(),
+            payload,
+        )
+    }
+
+    /// Records a data channel packet.
+    ///
+    /// # Parameters
+    ///
+    /// * **`timestamp`**: The time at which the transmission of the packet started.
+    /// * **`direction`**: Whether the packet was sent by the master or the slave.
+    /// * **`channel`**: The data channel the packet was sent on.
+    /// * **`access_address`**: The Access Address of the connection.
+    /// * **`crc_init`**: The CRC initialization value of the connection.
+    /// * **`header`**: The PDU header.
+    /// * **`payload`**: The PDU payload following the header (encrypted if the connection is).
+    /// * **`crc_ok`**: Whether the packet's CRC is correct.
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_data(
+        &mut self,
+        timestamp: Instant,
+        direction: Direction,
+        channel: DataChannel,
+        access_address: u32,
+        crc_init: u32,
+        header: data::Header,
+        payload: &[u8],
+        crc_ok: bool,
+    ) -> Result<(), Error> {
+        let pdu_type = match direction {
+            Direction::MasterToSlave => PDU_TYPE_MASTER_TO_SLAVE,
+            Direction::SlaveToMaster => PDU_TYPE_SLAVE_TO_MASTER,
+        };
+        self.write_packet(
+            timestamp,
+            channel.rf_channel(),
+            flags(pdu_type, crc_ok),
+            access_address,
+            crc_init,
+            header.to_u16(),
+            payload,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write_packet(
+        &mut self,
+        timestamp: Instant,
+        rf_channel: u8,
+        flags: u16,
+        access_address: u32,
+        crc_init: u32,
+        header: u16,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        if payload.len() > MAX_PAYLOAD_BUF {
+            return Err(Error::InvalidLength);
+        }
+
+        let micros = self.micros(timestamp);
+        let len = PHDR_SIZE + PACKET_OVERHEAD + payload.len();
+        let mut buf = [0; RECORD_HEADER_SIZE + SNAPLEN];
+        {
+            let mut writer = ByteWriter::new(&mut buf);
+
+            // Record header
+            writer.write_u32_le((micros / 1_000_000) as u32)?;
+            writer.write_u32_le((micros % 1_000_000) as u32)?;
+            writer.write_u32_le(len as u32)?;
+            writer.write_u32_le(len as u32)?;
+
+            // Pseudo header
+            writer.write_u8(rf_channel)?;
+            writer.write_u8(0)?; // signal power (invalid)
+            writer.write_u8(0)?; // noise power (invalid)
+            writer.write_u8(0)?; // Access Address offenses (invalid)
+            writer.write_u32_le(access_address)?;
+            writer.write_u16_le(flags)?;
+
+            // Packet
+            writer.write_u32_le(access_address)?;
+            writer.write_u16_le(header)?;
+            writer.write_slice(payload)?;
+        }
+
+        let pdu_start = RECORD_HEADER_SIZE + PHDR_SIZE + 4;
+        let pdu_end = pdu_start + 2 + payload.len();
+        let mut crc = crc24(crc_init, &buf[pdu_start..pdu_end]);
+        if flags & FLAG_CRC_VALID == 0 {
+            crc = !crc;
+        }
+        buf[pdu_end..pdu_end + 3].copy_from_slice(&crc_to_bytes(crc));
+
+        self.sink.write_bytes(&buf[..RECORD_HEADER_SIZE + len])
+    }
+
+    /// Converts `timestamp` to µs since the first recorded `Instant` raw value of 0.
+    fn micros(&mut self, timestamp: Instant) -> u64 {
+        let micros = match self.last {
+            None => u64::from(timestamp.raw_micros()),
+            Some((last, last_micros)) => {
+                // Allow small steps backwards, since received packets are recorded with the time
+                // their transmission started
+                let delta = timestamp.raw_micros().wrapping_sub(last.raw_micros()) as i32;
+                (last_micros as i64 + i64::from(delta)) as u64
+            }
+        };
+        self.last = Some((timestamp, micros));
+        micros
+    }
+}
+
+impl<S: PcapSink> fmt::Debug for PcapWriter<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PcapWriter")
+            .field("last", &self.last)
+            .finish()
+    }
+}
+
+/// Returns the pseudo header flags for a packet of the given PDU type.
+fn flags(pdu_type: u16, crc_ok: bool) -> u16 {
+    let flags = FLAG_DEWHITENED | FLAG_REF_ACCESS_ADDRESS_VALID | FLAG_CRC_CHECKED | pdu_type;
+    if crc_ok {
+        flags | FLAG_CRC_VALID
+    } else {
+        flags
+    }
+}
+
+/// Returns the time it takes to transmit a packet with a `payload_len`-Byte PDU payload on the LE
+/// 1M PHY.
+pub(crate) fn air_time(payload_len: u8) -> Duration {
+    // 1 Byte preamble, 4 Bytes Access Address, 2 Bytes header, 3 Bytes CRC
+    Duration::from_micros((1 + 4 + 2 + u32::from(payload_len) + 3) * 8)
+}
+
+/// Computes the 24-bit CRC over `pdu` (header and payload).
+///
+/// Position 0 of the LFSR is the LSb of the returned value. Bits are shifted in LSb first.
+fn crc24(crc_init: u32, pdu: &[u8]) -> u32 {
+    // x^24 + x^10 + x^9 + x^6 + x^4 + x^3 + x + 1
+    const POLY: u32 = 0x00_065B;
+
+    let mut state = crc_init & 0x00FF_FFFF;
+    for byte in pdu {
+        for bit in 0..8 {
+            let feedback = ((byte >> bit) & 1) as u32 ^ (state >> 23);
+            state = (state << 1) & 0x00FF_FFFF;
+            if feedback != 0 {
+                state ^= POLY;
+            }
+        }
+    }
+    state
+}
+
+/// Converts a CRC to the 3 Bytes sent over the air.
+///
+/// The CRC is transmitted starting with position 23 of the LFSR, while Bytes are transmitted LSb
+/// first.
+fn crc_to_bytes(crc: u32) -> [u8; 3] {
+    [
+        ((crc >> 16) as u8).reverse_bits(),
+        ((crc >> 8) as u8).reverse_bits(),
+        (crc as u8).reverse_bits(),
+    ]
+}
+
+/// A `Transmitter` recording all packets sent through it.
+///
+/// The `LinkLayer` wraps the user's `Transmitter` in this for the duration of each call, so that
+/// packets sent anywhere in the Link-Layer are captured.
+pub(crate) struct CaptureTx<'a, T: Transmitter, S: PcapSink> {
+    inner: &'a mut T,
+    writer: Option<&'a mut PcapWriter<S>>,
+
+    /// Timestamp to record sent packets with.
+    timestamp: Instant,
+
+    /// Direction of sent data channel packets.
+    direction: Direction,
+}
+
+impl<'a, T: Transmitter, S: PcapSink> CaptureTx<'a, T, S> {
+    pub(crate) fn new(
+        inner: &'a mut T,
+        writer: Option<&'a mut PcapWriter<S>>,
+        timestamp: Instant,
+        direction: Direction,
+    ) -> Self {
+        Self {
+            inner,
+            writer,
+            timestamp,
+            direction,
+        }
+    }
+}
+
+impl<T: Transmitter, S: PcapSink> Transmitter for CaptureTx<'_, T, S> {
+    fn tx_payload_buf(&mut self) -> &mut [u8] {
+        self.inner.tx_payload_buf()
+    }
+
+    fn max_data_payload(&self) -> u8 {
+        self.inner.max_data_payload()
+    }
+
+    fn transmit_advertising(&mut self, header: advertising::Header, channel: AdvertisingChannel) {
+        self.inner.transmit_advertising(header, channel);
+
+        // Record after transmitting to meet timing
+        if let Some(writer) = &mut self.writer {
+            let payload = &self.inner.tx_payload_buf()[..usize::from(header.payload_length())];
+            writer
+                .write_advertising(self.timestamp, channel, header, payload, true)
+                .ok();
+        }
+    }
+
+    fn transmit_data(
+        &mut self,
+        access_address: u32,
+        crc_iv: u32,
+        header: data::Header,
+        channel: DataChannel,
+    ) {
+        self.inner
+            .transmit_data(access_address, crc_iv, header, channel);
+
+        if let Some(writer) = &mut self.writer {
+            let payload = &self.inner.tx_payload_buf()[..usize::from(header.payload_length())];
+            writer
+                .write_data(
+                    self.timestamp,
+                    self.direction,
+                    channel,
+                    access_address,
+                    crc_iv,
+                    header,
+                    payload,
+                    true,
+                )
+                .ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::advertising::PduType;
+    use crate::link::data::Llid;
+
+    #[test]
+    fn crc() {
+        let pdu = [0x40, 0x06, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+        let crc = crc24(advertising::CRC_PRESET, &pdu);
+
+        // Running the LFSR over the PDU and its CRC (as sent over the air) results in 0
+        let mut packet = pdu.to_vec();
+        packet.extend_from_slice(&crc_to_bytes(crc));
+        assert_eq!(crc24(advertising::CRC_PRESET, &packet), 0);
+    }
+
+    #[test]
+    fn records() {
+        let mut buf = [0; 256];
+        let mut writer = PcapWriter::new(ByteWriter::new(&mut buf)).unwrap();
+
+        // Time continues past the wraparound of the raw `Instant`
+        let start = Instant::from_raw_micros(u32::MAX - 499_999);
+        let mut header = advertising::Header::new(PduType::AdvInd);
+        header.set_payload_length(6);
+        writer
+            .write_advertising(
+                start,
+                AdvertisingChannel::first(),
+                header,
+                &[1, 2, 3, 4, 5, 6],
+                true,
+            )
+            .unwrap();
+
+        let mut header = data::Header::new(Llid::DataCont);
+        header.set_payload_length(0);
+        writer
+            .write_data(
+                start + Duration::from_millis(1500),
+                Direction::SlaveToMaster,
+                DataChannel::new(11),
+                0x1234_5678,
+                0x00AB_CDEF,
+                header,
+                &[],
+                false,
+            )
+            .unwrap();
+        let left = writer.into_inner().space_left();
+        let bytes = &buf[..256 - left];
+
+        // File header
+        assert_eq!(bytes.len(), 24 + (16 + 25) + (16 + 19));
+        assert_eq!(bytes[..4], [0xD4, 0xC3, 0xB2, 0xA1]);
+        assert_eq!(bytes[20..24], [0, 1, 0, 0]);
+
+        // Advertising packet on RF channel 0
+        let record = &bytes[24..24 + 16 + 25];
+        assert_eq!(record[..4], 4294u32.to_le_bytes());
+        assert_eq!(record[4..8], 467_296u32.to_le_bytes());
+        assert_eq!(record[8..16], [25, 0, 0, 0, 25, 0, 0, 0]);
+        assert_eq!(record[16..20], [0, 0, 0, 0]);
+        assert_eq!(record[20..24], advertising::ACCESS_ADDRESS.to_le_bytes());
+        assert_eq!(record[24..26], [0x91, 0x0C]);
+        assert_eq!(record[26..30], advertising::ACCESS_ADDRESS.to_le_bytes());
+        assert_eq!(record[30..38], [0x00, 0x06, 1, 2, 3, 4, 5, 6]);
+
+        // Data packet on RF channel 13, with a bad CRC
+        let record = &bytes[24 + 16 + 25..];
+        assert_eq!(record[..4], 4295u32.to_le_bytes());
+        assert_eq!(record[4..8], 967_296u32.to_le_bytes());
+        assert_eq!(record[16], 13);
+        assert_eq!(record[24..26], [0x91, 0x05]);
+        assert_eq!(record[26..30], 0x1234_5678u32.to_le_bytes());
+        let crc = crc24(0x00AB_CDEF, &record[30..32]);
+        assert_eq!(record[32..35], crc_to_bytes(!crc));
+    }
+}
@@ -20,8 +20,9 @@
 //! [`SimpleConsumer`]: struct.SimpleConsumer.html
 
 use crate::link::data::{self, Llid};
-use crate::link::{MIN_DATA_PAYLOAD_BUF, MIN_DATA_PDU_BUF};
+use crate::link::{MAX_DATA_PAYLOAD_BUF, MAX_DATA_PDU_BUF, MIN_DATA_PAYLOAD_BUF};
 use crate::{bytes::*, Error};
+use core::cmp;
 use heapless::consts::U1;
 use heapless::spsc::{self, MultiCore};
 
@@ -29,6 +30,10 @@ use heapless::spsc::{self, MultiCore};
 ///
 /// Implementations of this trait must fit at least one data channel packet with a total size of
 /// `MIN_DATA_PDU_BUF` bytes (header and payload).
+///
+/// To make use of the Packet Length Extension, a queue must also be able to fit packets of up to
+/// `MAX_DATA_PDU_BUF` bytes. The Link-Layer will not announce support for received packets that
+/// are larger than the free space in an empty RX queue.
 pub trait PacketQueue {
     /// Producing (writing) half of the queue.
     type Producer: Producer;
@@ -218,7 +223,7 @@ impl<T> Consume<T> {
 /// This queue also minimizes RAM usage: In addition to the raw buffer space, only minimal space is
 /// needed for housekeeping.
 pub struct SimpleQueue {
-    inner: spsc::Queue<[u8; MIN_DATA_PDU_BUF], U1, u8, MultiCore>,
+    inner: spsc::Queue<[u8; MAX_DATA_PDU_BUF], U1, u8, MultiCore>,
 }
 
 impl SimpleQueue {
@@ -243,14 +248,14 @@ impl<'a> PacketQueue for &'a mut SimpleQueue {
 
 /// Producer (writer) half returned by `SimpleQueue::split`.
 pub struct SimpleProducer<'a> {
-    inner: spsc::Producer<'a, [u8; MIN_DATA_PDU_BUF], U1, u8, MultiCore>,
+    inner: spsc::Producer<'a, [u8; MAX_DATA_PDU_BUF], U1, u8, MultiCore>,
 }
 
 impl<'a> Producer for SimpleProducer<'a> {
     fn free_space(&self) -> u8 {
-        // We can only have space for either 0 or 1 packets with min. payload size
+        // We can only have space for either 0 or 1 packets with max. payload size
         if self.inner.ready() {
-            MIN_DATA_PAYLOAD_BUF as u8
+            MAX_DATA_PAYLOAD_BUF as u8
         } else {
             0
         }
@@ -261,13 +266,13 @@ impl<'a> Producer for SimpleProducer<'a> {
         payload_bytes: u8,
         f: &mut dyn FnMut(&mut ByteWriter<'_>) -> Result<Llid, Error>,
     ) -> Result<(), Error> {
-        assert!(usize::from(payload_bytes) <= MIN_DATA_PAYLOAD_BUF);
+        assert!(usize::from(payload_bytes) <= MAX_DATA_PAYLOAD_BUF);
 
         if !self.inner.ready() {
             return Err(Error::Eof);
         }
 
-        let mut buf = [0; MIN_DATA_PDU_BUF];
+        let mut buf = [0; MAX_DATA_PDU_BUF];
         let mut writer = ByteWriter::new(&mut buf[2..]);
         let free = writer.space_left();
         let llid = f(&mut writer)?;
@@ -284,7 +289,7 @@ impl<'a> Producer for SimpleProducer<'a> {
 
 /// Consumer (reader) half returned by `SimpleQueue::split`.
 pub struct SimpleConsumer<'a> {
-    inner: spsc::Consumer<'a, [u8; MIN_DATA_PDU_BUF], U1, u8, MultiCore>,
+    inner: spsc::Consumer<'a, [u8; MAX_DATA_PDU_BUF], U1, u8, MultiCore>,
 }
 
 impl<'a> Consumer for SimpleConsumer<'a> {
@@ -366,13 +371,16 @@ pub fn run_tests(queue: impl PacketQueue) {
     );
 
     // Enqueue the largest packet
-    p.produce_with(MIN_DATA_PAYLOAD_BUF as u8, |writer| -> Result<_, Error> {
+    let largest = cmp::min(usize::from(free_space), MAX_DATA_PAYLOAD_BUF);
+    p.produce_with(largest as u8, |writer| -> Result<_, Error> {
         assert_eq!(
             writer.space_left(),
-            MIN_DATA_PAYLOAD_BUF,
+            usize::from(free_space),
             "produce_with didn't pass ByteWriter with correct buffer"
         );
-        writer.write_slice(&[0; MIN_DATA_PAYLOAD_BUF]).unwrap();
+        writer
+            .write_slice(&[0; MAX_DATA_PAYLOAD_BUF][..largest])
+            .unwrap();
         Ok(Llid::DataStart)
     })
     .expect("enqueuing packet failed");
@@ -384,10 +392,10 @@ pub fn run_tests(queue: impl PacketQueue) {
 
     // Peek at the packet
     c.consume_raw_with(|header, data| -> Consume<()> {
-        assert_eq!(usize::from(header.payload_length()), MIN_DATA_PAYLOAD_BUF);
+        assert_eq!(usize::from(header.payload_length()), largest);
         assert_eq!(
             data,
-            &[0; MIN_DATA_PAYLOAD_BUF][..],
+            &[0; MAX_DATA_PAYLOAD_BUF][..largest],
             "consume_raw_with didn't yield correct payload"
         );
         Consume::never(Ok(()))
@@ -401,7 +409,7 @@ pub fn run_tests(queue: impl PacketQueue) {
 
     // Now consume it
     c.consume_pdu_with(|header, _| -> Consume<()> {
-        assert_eq!(usize::from(header.payload_length()), MIN_DATA_PAYLOAD_BUF);
+        assert_eq!(usize::from(header.payload_length()), largest);
         Consume::always(Ok(()))
     })
     .expect("consume_pdu_with failed when data is available");
@@ -413,7 +421,7 @@ pub fn run_tests(queue: impl PacketQueue) {
     p.produce_with(0, |writer| -> Result<_, Error> {
         assert_eq!(
             writer.space_left(),
-            MIN_DATA_PAYLOAD_BUF,
+            usize::from(free_space),
             "produce_with didn't pass ByteWriter with correct buffer"
         );
         Ok(Llid::DataStart)
@@ -2,6 +2,7 @@ use crate::l2cap::{L2CAPState, L2CAPStateTx};
 use crate::link::data::{Llid, Pdu};
 use crate::link::llcp::ControlPdu;
 use crate::link::queue::{Consume, Consumer, Producer};
+use crate::link::DeviceAddress;
 use crate::{bytes::ToBytes, config::*, utils::HexSlice, Error};
 
 /// Data channel packet processor.
@@ -38,13 +39,20 @@ impl<C: Config> Responder<C> {
     /// If this returns `true`, `process` may be called to process incoming packets and send
     /// outgoing ones.
     pub fn has_work(&mut self) -> bool {
-        self.with_rx(|rx, _| rx.has_data())
+        self.with_rx(|rx, this| rx.has_data() || this.l2cap().can_flush())
     }
 
     /// Processes a single incoming packet in the packet queue.
     ///
+    /// If an outgoing L2CAP message is still being fragmented and there is space in the TX queue,
+    /// this will enqueue its remaining fragments instead.
+    ///
     /// Returns `Error::Eof` if there are no incoming packets in the RX queue.
     pub fn process_one(&mut self) -> Result<(), Error> {
+        if self.l2cap().can_flush() {
+            return self.l2cap().flush();
+        }
+
         self.with_rx(|rx, this| {
             rx.consume_pdu_with(|_, pdu| match pdu {
                 Pdu::Control { data } => {
@@ -85,6 +93,27 @@ impl<C: Config> Responder<C> {
         })
     }
 
+    /// Prepares for a new connection with the device at `peer`.
+    ///
+    /// This resets all per-connection state of the L2CAP layer (see
+    /// `L2CAPState::connection_established`). `local` is the address of this device. It should be
+    /// called when `LinkLayer::take_connection_event` reports an established connection.
+    pub fn connection_established(&mut self, local: DeviceAddress, peer: DeviceAddress) {
+        self.l2cap.connection_established(local, peer);
+    }
+
+    /// Discards the packets received from a connection that has ended.
+    ///
+    /// This should be called when `LinkLayer::take_connection_event` reports that the connection
+    /// has ended, so that stale packets aren't processed as part of the next connection.
+    pub fn connection_ended(&mut self) {
+        self.with_rx(|rx, _| {
+            while rx.has_data() {
+                rx.consume_raw_with(|_, _| Consume::always(Ok(()))).ok();
+            }
+        });
+    }
+
     /// Obtains access to the L2CAP instance.
     pub fn l2cap(&mut self) -> L2CAPStateTx<'_, C::ChannelMapper, ConfProducer<C>> {
         self.l2cap.tx(&mut self.tx)
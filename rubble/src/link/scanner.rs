@@ -0,0 +1,488 @@
+//! Scanning for advertising devices.
+
+use crate::link::ad_structure::AdStructure;
+use crate::link::advertising::{Header, Pdu, PduBuf, PduType};
+use crate::link::filter::{self, AddressFilter, ScanFilter};
+use crate::link::{Cmd, DeviceAddress, NextUpdate, RadioCmd, Transmitter};
+use crate::phy::AdvertisingChannel;
+use crate::time::{Duration, Instant};
+use crate::{bytes::*, utils::XorShift32, Error};
+use core::cmp;
+use rand_core::RngCore;
+
+/// Max. size of the advertising data in an advertising channel PDU (37 Bytes minus the
+/// advertiser's address).
+const MAX_ADV_DATA: usize = 31;
+
+/// Callback for the `Scanner`.
+pub trait ReportCallback {
+    /// Called when an advertisement is received and has passed the configured device address
+    /// filter.
+    ///
+    /// When scanning actively, this is called after the advertiser's scan response was received
+    /// (or not), and `report` includes the scan response data.
+    fn report(&mut self, report: &ScanReport<'_>);
+}
+
+/// Information about a received advertisement.
+#[derive(Debug, Copy, Clone)]
+pub struct ScanReport<'a> {
+    advertiser: DeviceAddress,
+    pdu_type: PduType,
+    rssi: i8,
+    adv_data: BytesOr<'a, [AdStructure<'a>]>,
+    scan_data: Option<BytesOr<'a, [AdStructure<'a>]>>,
+}
+
+impl<'a> ScanReport<'a> {
+    /// Returns the address of the advertising device.
+    ///
+    /// The address type can be obtained via `DeviceAddress::kind`.
+    pub fn advertiser(&self) -> DeviceAddress {
+        self.advertiser
+    }
+
+    /// Returns the type of the advertising PDU.
+    ///
+    /// This determines whether the advertiser is connectable and scannable.
+    pub fn pdu_type(&self) -> PduType {
+        self.pdu_type
+    }
+
+    /// Returns the signal strength of the advertising PDU in dBm.
+    pub fn rssi(&self) -> i8 {
+        self.rssi
+    }
+
+    /// Returns whether the advertiser answered our scan request.
+    pub fn has_scan_response(&self) -> bool {
+        self.scan_data.is_some()
+    }
+
+    /// Returns the advertising data.
+    pub fn advertising_data(&self) -> BytesOr<'a, [AdStructure<'a>]> {
+        self.adv_data
+    }
+
+    /// Returns the data from the advertiser's scan response, if one was received.
+    pub fn scan_response_data(&self) -> Option<BytesOr<'a, [AdStructure<'a>]>> {
+        self.scan_data
+    }
+
+    /// Returns an iterator over the AD structures in the advertisement, followed by the ones in
+    /// the scan response (if any).
+    pub fn ad_structures(&self) -> impl Iterator<Item = AdStructure<'a>> + 'a {
+        self.adv_data
+            .iter()
+            .chain(self.scan_data.into_iter().flat_map(|data| data.iter()))
+    }
+}
+
+/// A scanner reporting advertisements of all types.
+///
+/// By default, the scanner is passive and only listens for advertisements. After calling
+/// `set_active`, it will also send scan requests to scannable advertisers and report their scan
+/// response data together with the advertising data. To avoid collisions with other scanners,
+/// scan requests are limited by the backoff procedure defined in the spec.
+pub struct Scanner<C: ReportCallback, F: AddressFilter> {
+    cb: C,
+    filter: ScanFilter<F>,
+    interval: Duration,
+    channel: AdvertisingChannel,
+
+    /// Our device address and backoff state, if scanning actively.
+    active: Option<(DeviceAddress, Backoff)>,
+
+    /// An advertisement whose report is delayed until the scan response is received.
+    pending: Option<PendingReport>,
+}
+
+/// An advertisement we have sent a scan request for.
+struct PendingReport {
+    advertiser: DeviceAddress,
+    pdu_type: PduType,
+    rssi: i8,
+    adv_data: [u8; MAX_ADV_DATA],
+    adv_data_len: u8,
+}
+
+impl<C: ReportCallback> Scanner<C, filter::AllowAll> {
+    /// Creates a passive `Scanner` that will report advertisements from any device.
+    pub fn new(callback: C) -> Self {
+        Self::with_filter(callback, filter::AllowAll)
+    }
+}
+
+impl<C: ReportCallback, F: AddressFilter> Scanner<C, F> {
+    /// Creates a passive `Scanner` with a custom device filter.
+    pub fn with_filter(callback: C, scan_filter: F) -> Self {
+        Self {
+            cb: callback,
+            filter: ScanFilter::new(scan_filter),
+            interval: Duration::from_micros(0),
+            channel: AdvertisingChannel::first(),
+            active: None,
+            pending: None,
+        }
+    }
+
+    /// Enables active scanning.
+    ///
+    /// Scan requests are sent from `dev_addr`. `rng` is used to seed the pseudo-random backoff
+    /// between scan requests.
+    pub fn set_active<R: RngCore>(&mut self, dev_addr: DeviceAddress, rng: &mut R) {
+        self.active = Some((dev_addr, Backoff::new(rng.next_u32())));
+    }
+
+    /// Returns a reference to the callback.
+    pub fn callback(&mut self) -> &mut C {
+        &mut self.cb
+    }
+
+    /// Configures the `Scanner` and returns a `Cmd` to apply to the radio.
+    ///
+    /// The `next_update` field of the returned `Cmd` specifies when to call `timer_update` the next
+    /// time. The timer used for this does not have to be very accurate, it is only used to switch
+    /// to the next advertising channel after `interval` elapses.
+    pub fn configure(&mut self, now: Instant, interval: Duration) -> Cmd {
+        self.interval = interval;
+        self.channel = AdvertisingChannel::first();
+        self.listen(NextUpdate::At(now + self.interval))
+    }
+
+    /// Updates the `Scanner` after the configured timer has fired.
+    ///
+    /// This switches to the next advertising channel and will listen there.
+    pub fn timer_update(&mut self, now: Instant) -> Cmd {
+        self.flush_pending();
+        self.channel = self.channel.cycle();
+        self.listen(NextUpdate::At(now + self.interval))
+    }
+
+    /// Processes a received advertising channel packet.
+    ///
+    /// This should be called whenever the radio receives a packet on the configured advertising
+    /// channel.
+    ///
+    /// # Parameters
+    ///
+    /// * **`tx`**: A packet transmitter, used to send scan requests.
+    /// * **`header`**: The header of the received packet.
+    /// * **`payload`**: The packet payload following the header.
+    /// * **`crc_ok`**: Whether the packet's CRC is correct.
+    /// * **`rssi`**: Received signal strength of the packet in dBm.
+    pub fn process_adv_packet<T: Transmitter>(
+        &mut self,
+        tx: &mut T,
+        header: Header,
+        payload: &[u8],
+        crc_ok: bool,
+        rssi: i8,
+    ) -> Cmd {
+        let pdu = if crc_ok {
+            Pdu::from_header_and_payload(header, &mut ByteReader::new(payload)).ok()
+        } else {
+            None
+        };
+
+        if let Some(pending) = self.pending.take() {
+            // This packet is the reply to our scan request, if there is one
+            match pdu {
+                Some(Pdu::ScanResponse {
+                    advertiser_addr,
+                    scan_data,
+                }) if advertiser_addr == pending.advertiser => {
+                    if let Some((_, backoff)) = &mut self.active {
+                        backoff.success();
+                    }
+                    pending.report(&mut self.cb, Some(scan_data));
+                    return self.listen(NextUpdate::Keep);
+                }
+                _ => {
+                    self.pending = Some(pending);
+                    self.flush_pending();
+                }
+            }
+        }
+
+        let pdu = match pdu {
+            Some(pdu) => pdu,
+            None => return self.listen(NextUpdate::Keep),
+        };
+        let advertiser = *pdu.sender();
+        let adv_data = match pdu {
+            Pdu::ConnectableUndirected {
+                advertising_data, ..
+            }
+            | Pdu::NonconnectableUndirected {
+                advertising_data, ..
+            }
+            | Pdu::ScannableUndirected {
+                advertising_data, ..
+            } => advertising_data,
+            Pdu::ConnectableDirected { .. } => BytesOr::from_ref(&[][..]),
+            // Not sent by advertisers (or not solicited by us)
+            Pdu::ScanRequest { .. } | Pdu::ScanResponse { .. } | Pdu::ConnectRequest { .. } => {
+                return self.listen(NextUpdate::Keep)
+            }
+        };
+        if !self.filter.should_scan(advertiser) {
+            return self.listen(NextUpdate::Keep);
+        }
+
+        let scannable = pdu.ty() == PduType::AdvInd || pdu.ty() == PduType::AdvScanInd;
+        if let Some((dev_addr, backoff)) = &mut self.active {
+            if scannable && backoff.should_request() {
+                let request = PduBuf::scan_request(*dev_addr, advertiser).unwrap();
+                let request_payload = request.payload();
+                tx.tx_payload_buf()[..request_payload.len()].copy_from_slice(request_payload);
+                tx.transmit_advertising(request.header(), self.channel);
+
+                // Report the advertisement once the scan response arrives. The raw AD structures
+                // follow the address in the payload.
+                let raw = &payload[6..];
+                let mut adv_data = [0; MAX_ADV_DATA];
+                let len = cmp::min(raw.len(), MAX_ADV_DATA);
+                adv_data[..len].copy_from_slice(&raw[..len]);
+                self.pending = Some(PendingReport {
+                    advertiser,
+                    pdu_type: pdu.ty(),
+                    rssi,
+                    adv_data,
+                    adv_data_len: len as u8,
+                });
+                return self.listen(NextUpdate::Keep);
+            }
+        }
+
+        self.cb.report(&ScanReport {
+            advertiser,
+            pdu_type: pdu.ty(),
+            rssi,
+            adv_data,
+            scan_data: None,
+        });
+        self.listen(NextUpdate::Keep)
+    }
+
+    /// Reports a pending advertisement whose scan request wasn't answered.
+    fn flush_pending(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            if let Some((_, backoff)) = &mut self.active {
+                backoff.failure();
+            }
+            pending.report(&mut self.cb, None);
+        }
+    }
+
+    fn listen(&self, next_update: NextUpdate) -> Cmd {
+        Cmd {
+            next_update,
+            radio: RadioCmd::ListenAdvertising {
+                channel: self.channel,
+            },
+            queued_work: false,
+        }
+    }
+}
+
+impl PendingReport {
+    fn report<C: ReportCallback>(
+        &self,
+        cb: &mut C,
+        scan_data: Option<BytesOr<'_, [AdStructure<'_>]>>,
+    ) {
+        let raw = &self.adv_data[..usize::from(self.adv_data_len)];
+        let adv_data: Result<_, Error> = BytesOr::from_bytes(&mut ByteReader::new(raw));
+        cb.report(&ScanReport {
+            advertiser: self.advertiser,
+            pdu_type: self.pdu_type,
+            rssi: self.rssi,
+            // The data was already parsed successfully when the advertisement was received
+            adv_data: adv_data.unwrap(),
+            scan_data,
+        });
+    }
+}
+
+/// State of the backoff procedure limiting the number of scan requests.
+///
+/// A scan request is only sent for every `count`th scannable advertisement. After every request,
+/// `count` is set to a random number between 1 and `upper_limit`, which is halved after 2
+/// successful requests in a row and doubled after 2 failed ones.
+struct Backoff {
+    count: u16,
+    upper_limit: u16,
+
+    /// Number of consecutive successful (positive) or failed (negative) scan requests.
+    streak: i8,
+
+    /// PRNG used to pick `count`.
+    rng: XorShift32,
+}
+
+impl Backoff {
+    fn new(seed: u32) -> Self {
+        Self {
+            count: 1,
+            upper_limit: 1,
+            streak: 0,
+            rng: XorShift32::new(seed),
+        }
+    }
+
+    /// Called for every scannable advertisement. Returns whether a scan request should be sent.
+    fn should_request(&mut self) -> bool {
+        self.count = self.count.saturating_sub(1);
+        self.count == 0
+    }
+
+    /// Called when the scan response to our request was received.
+    fn success(&mut self) {
+        self.streak = cmp::max(self.streak, 0) + 1;
+        if self.streak == 2 {
+            self.upper_limit = cmp::max(self.upper_limit / 2, 1);
+            self.streak = 0;
+        }
+        self.reset_count();
+    }
+
+    /// Called when no scan response to our request was received.
+    fn failure(&mut self) {
+        self.streak = cmp::min(self.streak, 0) - 1;
+        if self.streak == -2 {
+            self.upper_limit = cmp::min(self.upper_limit * 2, 256);
+            self.streak = 0;
+        }
+        self.reset_count();
+    }
+
+    fn reset_count(&mut self) {
+        self.count = 1 + (self.rng.next_u32() % u32::from(self.upper_limit)) as u16;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::{data, AddressKind, MIN_PAYLOAD_BUF};
+    use crate::phy::DataChannel;
+    use std::vec::Vec;
+
+    struct Radio {
+        buf: [u8; MIN_PAYLOAD_BUF],
+        sent: Vec<(PduType, Vec<u8>)>,
+    }
+
+    impl Transmitter for Radio {
+        fn tx_payload_buf(&mut self) -> &mut [u8] {
+            &mut self.buf
+        }
+
+        fn transmit_advertising(&mut self, header: Header, _channel: AdvertisingChannel) {
+            let payload = self.buf[..usize::from(header.payload_length())].to_vec();
+            self.sent.push((header.type_(), payload));
+        }
+
+        fn transmit_data(&mut self, _: u32, _: u32, _: data::Header, _: DataChannel) {
+            unreachable!()
+        }
+    }
+
+    #[derive(Default)]
+    struct Reports(Vec<(PduType, i8, Vec<u8>, bool)>);
+
+    impl ReportCallback for Reports {
+        fn report(&mut self, report: &ScanReport<'_>) {
+            let names = report
+                .ad_structures()
+                .filter_map(|ad| match ad {
+                    // Names are not decoded
+                    AdStructure::Unknown { ty: 0x09, data } => Some(data[0]),
+                    _ => None,
+                })
+                .collect();
+            self.0.push((
+                report.pdu_type(),
+                report.rssi(),
+                names,
+                report.has_scan_response(),
+            ));
+        }
+    }
+
+    fn receive(scanner: &mut Scanner<Reports, filter::AllowAll>, radio: &mut Radio, pdu: PduBuf) {
+        let _ = scanner.process_adv_packet(radio, pdu.header(), pdu.payload(), true, -40);
+    }
+
+    #[test]
+    fn active() {
+        let adv = DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Random);
+        let own = DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Random);
+        let mut radio = Radio {
+            buf: [0; MIN_PAYLOAD_BUF],
+            sent: Vec::new(),
+        };
+        let mut scanner = Scanner::new(Reports::default());
+        scanner.set_active(own, &mut XorShift32::new(1));
+
+        let ad = [AdStructure::CompleteLocalName("a")];
+        let scan_ad = [AdStructure::CompleteLocalName("b")];
+        let adv_ind = || PduBuf::connectable_undirected(adv, &ad).unwrap();
+        let scan_rsp = || PduBuf::scan_response(adv, &scan_ad).unwrap();
+
+        // Beacons are reported right away
+        receive(&mut scanner, &mut radio, PduBuf::beacon(adv, &ad).unwrap());
+        assert!(radio.sent.is_empty());
+
+        // Scannable advertisements get a scan request, and are reported with the response
+        receive(&mut scanner, &mut radio, adv_ind());
+        assert_eq!(radio.sent.len(), 1);
+        assert_eq!(radio.sent[0].0, PduType::ScanReq);
+        assert_eq!(radio.sent[0].1[..6], own.raw()[..]);
+        assert_eq!(radio.sent[0].1[6..], adv.raw()[..]);
+        receive(&mut scanner, &mut radio, scan_rsp());
+
+        // An unanswered request is reported without scan data
+        receive(&mut scanner, &mut radio, adv_ind());
+        let _ = scanner.timer_update(Instant::from_raw_micros(0));
+
+        assert_eq!(
+            scanner.callback().0,
+            [
+                (PduType::AdvNonconnInd, -40, vec![b'a'], false),
+                (PduType::AdvInd, -40, vec![b'a', b'b'], true),
+                (PduType::AdvInd, -40, vec![b'a'], false),
+            ]
+        );
+    }
+
+    #[test]
+    fn backoff() {
+        let mut backoff = Backoff::new(1234);
+        assert!(backoff.should_request());
+        backoff.failure();
+        assert_eq!(backoff.upper_limit, 1);
+        backoff.failure();
+        assert_eq!(backoff.upper_limit, 2);
+        backoff.failure();
+        backoff.failure();
+        assert_eq!(backoff.upper_limit, 4);
+        assert!((1..=4).contains(&backoff.count));
+
+        backoff.success();
+        assert_eq!(backoff.upper_limit, 4);
+        backoff.success();
+        assert_eq!(backoff.upper_limit, 2);
+
+        for _ in 0..20 {
+            backoff.failure();
+        }
+        assert_eq!(backoff.upper_limit, 256);
+        for _ in 0..20 {
+            backoff.success();
+        }
+        assert_eq!(backoff.upper_limit, 1);
+        assert!(backoff.should_request());
+    }
+}
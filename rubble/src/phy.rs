@@ -9,6 +9,8 @@
 //! (presumably to simplify channel hopping). The Link-Layer is only interested in these channel
 //! indices, so only those are implemented here.
 
+use crate::Error;
+
 /// Returns the center frequency in MHz corresponding to an RF channel.
 fn rf_channel_freq(rf_channel: u8) -> u16 {
     2402 + u16::from(rf_channel) * 2
@@ -21,7 +23,7 @@ fn whitening_iv(channel_idx: u8) -> u8 {
 }
 
 /// One of the three advertising channels (channel indices 37, 38 or 39).
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct AdvertisingChannel(u8);
 
 impl AdvertisingChannel {
@@ -87,6 +89,58 @@ impl AdvertisingChannel {
     }
 }
 
+/// A non-empty set of advertising channels to advertise on.
+///
+/// Advertising events send the advertising PDU on every channel in the set, in ascending order.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AdvertisingChannelMap(u8);
+
+impl AdvertisingChannelMap {
+    /// Returns a map containing all 3 advertising channels.
+    pub fn all() -> Self {
+        AdvertisingChannelMap(0b111)
+    }
+
+    /// Creates a map from its raw representation.
+    ///
+    /// Bit 0 stands for channel 37, bit 1 for channel 38 and bit 2 for channel 39 (this is the
+    /// format used by HCI). Returns `Error::InvalidValue` if no channel is enabled or any other
+    /// bit is set.
+    pub fn from_raw(raw: u8) -> Result<Self, Error> {
+        if raw == 0 || raw & !0b111 != 0 {
+            Err(Error::InvalidValue)
+        } else {
+            Ok(AdvertisingChannelMap(raw))
+        }
+    }
+
+    /// Returns the raw representation of this map.
+    pub fn to_raw(&self) -> u8 {
+        self.0
+    }
+
+    /// Returns whether `channel` is part of this map.
+    pub fn contains(&self, channel: AdvertisingChannel) -> bool {
+        self.0 & (1 << (channel.0 - 37)) != 0
+    }
+
+    /// Returns an iterator over the channels in this map, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = AdvertisingChannel> {
+        let map = *self;
+        AdvertisingChannel::iter_all().filter(move |ch| map.contains(*ch))
+    }
+
+    /// Returns the lowest-numbered channel in this map.
+    pub fn first(&self) -> AdvertisingChannel {
+        self.iter().next().unwrap()
+    }
+
+    /// Returns the channel following `channel` in this map, or `None` if there is none.
+    pub fn next_after(&self, channel: AdvertisingChannel) -> Option<AdvertisingChannel> {
+        self.iter().find(|ch| ch.0 > channel.0)
+    }
+}
+
 /// One of 37 data channels on which data channel PDUs are sent between connected devices.
 ///
 /// (channel indices 0..=36)
@@ -0,0 +1,147 @@
+//! Storage of keys exchanged during bonding.
+
+use super::toolbox::ah;
+use crate::crypto::{AesProvider, Key};
+use crate::link::DeviceAddress;
+use crate::Error;
+use heapless::{ArrayLength, Vec};
+
+/// A Long Term Key and the values identifying it.
+///
+/// When encrypting a connection, the master identifies the LTK to use by `EDIV` and `Rand`. Keys
+/// generated by *LE Secure Connections* pairing are identified by both being 0.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LongTermKey {
+    /// The key itself.
+    pub key: Key,
+
+    /// The encrypted diversifier identifying the key.
+    pub ediv: u16,
+
+    /// The random number identifying the key.
+    pub rand: [u8; 8],
+}
+
+/// The keys and identity of a bonded device.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Bond {
+    /// The identity address of the bonded device.
+    ///
+    /// If the device did not distribute its identity address, this is the address it used during
+    /// pairing.
+    pub identity: DeviceAddress,
+
+    /// The LTK used to encrypt connections with the bonded device.
+    pub ltk: Option<LongTermKey>,
+
+    /// The Identity Resolving Key (IRK) of the bonded device, used to resolve its private
+    /// addresses.
+    pub irk: Option<Key>,
+
+    /// The Connection Signature Resolving Key (CSRK) of the bonded device, used to verify data it
+    /// signs.
+    pub csrk: Option<Key>,
+}
+
+impl Bond {
+    /// Returns whether `addr` belongs to the bonded device.
+    ///
+    /// This is the case if `addr` is the device's identity address, or a resolvable private address
+    /// generated from its IRK.
+    pub(crate) fn identifies(&self, aes: &mut dyn AesProvider, addr: &DeviceAddress) -> bool {
+        if *addr == self.identity {
+            return true;
+        }
+
+        match self.irk {
+            Some(irk) if addr.is_resolvable_private() => {
+                // The lower 24 bits are the hash of the upper 24 bits (`prand`).
+                let raw = addr.raw();
+                let mut hash = [0; 3];
+                let mut prand = [0; 3];
+                hash.copy_from_slice(&raw[..3]);
+                prand.copy_from_slice(&raw[3..]);
+                ah(aes, &irk, &prand) == hash
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Trait for persistent storage of bonds.
+///
+/// Where and how bonds are stored is up to the implementation. Rubble comes with
+/// `MemoryBondStore`, which keeps them in RAM and is mostly useful for testing.
+pub trait BondStore {
+    /// Stores `bond`, replacing any stored bond with the same identity address.
+    ///
+    /// Returns an error if the bond can not be stored (eg. because the store is full).
+    fn store(&mut self, bond: &Bond) -> Result<(), Error>;
+
+    /// Returns the bond at `index`, or `None` if less than `index + 1` bonds are stored.
+    fn get(&self, index: usize) -> Option<Bond>;
+
+    /// Removes the bond with identity address `identity`, if it exists.
+    fn remove(&mut self, identity: &DeviceAddress);
+}
+
+/// A `BondStore` that does not store anything.
+///
+/// This is used as a placeholder when bonding is not supported.
+#[derive(Debug)]
+pub struct NoBonds;
+
+impl BondStore for NoBonds {
+    fn store(&mut self, _: &Bond) -> Result<(), Error> {
+        Err(Error::Eof)
+    }
+
+    fn get(&self, _: usize) -> Option<Bond> {
+        None
+    }
+
+    fn remove(&mut self, _: &DeviceAddress) {}
+}
+
+/// A `BondStore` keeping up to `N` bonds in RAM.
+///
+/// Bonds are lost on reset, so this is mostly useful for tests.
+#[derive(Debug)]
+pub struct MemoryBondStore<N: ArrayLength<Bond>> {
+    bonds: Vec<Bond, N>,
+}
+
+impl<N: ArrayLength<Bond>> MemoryBondStore<N> {
+    /// Creates an empty bond store.
+    pub fn new() -> Self {
+        Self { bonds: Vec::new() }
+    }
+}
+
+impl<N: ArrayLength<Bond>> Default for MemoryBondStore<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<N: ArrayLength<Bond>> BondStore for MemoryBondStore<N> {
+    fn store(&mut self, bond: &Bond) -> Result<(), Error> {
+        match self.bonds.iter_mut().find(|b| b.identity == bond.identity) {
+            Some(existing) => {
+                *existing = *bond;
+                Ok(())
+            }
+            None => self.bonds.push(*bond).map_err(|_| Error::Eof),
+        }
+    }
+
+    fn get(&self, index: usize) -> Option<Bond> {
+        self.bonds.get(index).copied()
+    }
+
+    fn remove(&mut self, identity: &DeviceAddress) {
+        if let Some(i) = self.bonds.iter().position(|b| b.identity == *identity) {
+            self.bonds.swap_remove(i);
+        }
+    }
+}
@@ -0,0 +1,2145 @@
+//! The LE Security Manager protocol.
+//!
+//! The Security Manager is a mandatory part of BLE and is connected to L2CAP channel `0x0006` when
+//! the Link-Layer connection is established.
+//!
+//! # BLE Security
+//!
+//! As is tradition, BLE security is a complexity nightmare. This section hopes to clear up a few
+//! things and tries to define terms used throughout the code and specficiation.
+//!
+//! ## Pairing and Bonding
+//!
+//! * **Pairing** is the process of generating and exchanging connection-specific keys in order to
+//!   accomplish an encrypted Link-Layer connection.
+//!
+//!   This is done by having the *Security Managers* of the devices talk to each other to perform
+//!   the key exchange, and then using *LL Control PDUs* to enable the negotiated encryption
+//!   parameters.
+//!
+//! * **Bonding** means permanently storing the shared keys derived by *Pairing* in order to reuse
+//!   them for later connections.
+//!
+//!   The way keys are stored is inherently platform- and application-dependent, we just have to
+//!   provide interfaces to export and import key sets.
+//!
+//! Most times, when talking about *pairing*, the *bonding* part is implied. If it were not, you
+//! would constantly have to re-pair devices when reconnecting them.
+//!
+//! ## LE Legacy Pairing vs. LE Secure Connections
+//!
+//! Bluetooth's security track record is an actual record in that it is so atrociously bad that this
+//! protocol should have never seen the light of the day. Alas, here we are.
+//!
+//! LE security is generally able to utilize *AES-128-CCM* for encryption, which isn't broken by
+//! itself (unlike the "export-grade" encryption used by earlier Bluetooth versions). However, the
+//! way the AES key is exchanged differs between *LE Legacy Pairing* and *LE Secure Connections*
+//! pairing, which hugely impacts actual security.
+//!
+//! ### LE Legacy Pairing
+//!
+//! For BLE 4.0 and 4.1, only the *LE Legacy Pairing* (as it is now known as) was available. Like
+//! every awfully designed protocol, they've rolled their own crypto and use their own key exchange
+//! procedure (with the usual catastrophic consequences). First, a shared 128-bit **T**emporary
+//! **K**ey (TK) is obtained, which is then used to generate the 128-bit **S**hort-**T**erm **K**ey
+//! (STK) that is used to initially encrypt the connection while other keys are exchanged.
+//!
+//! The STK is generated from the TK by mixing in random values from master (`Mrand`) and slave
+//! (`Srand`), which are exchanged in plain text. If a passive eavesdropper manages to obtain TK,
+//! they only need to listen for the `Mrand` and `Srand` value and can then compute the STK and
+//! decrypt the connection.
+//!
+//! There are 3 methods of determining the TK:
+//! * *"Just Works"*: TK=0
+//! * *Passkey Entry*: A 6-digit number is displayed on one device and input on the other device.
+//!   The number is directly used as the TK (after zero-padding it to 128 bits).
+//! * *Out-of-Band* (OOB): The 128-bit TK is provided by an external mechanism (eg. NFC).
+//!
+//! "Just Works" obviously is broken without any effort other than listening for the exchanged
+//! `Mrand` and `Srand` values.
+//!
+//! The Passkey Entry method only allows 1000000 different TKs (equivalent to using 20-bit keys)
+//! and does not do any key derivation. This makes it trivial to brute-force the TK by running the
+//! STK derivation up to a million times.
+//!
+//! **The only way to perform *LE Legacy Pairing* with meaningful protection against passive
+//! eavesdropping is by using a secure Out-of-Band channel for agreeing on the TK.**
+//!
+//! ### LE Secure Connections pairing
+//!
+//! Added with BLE 4.2, this finally uses established cryptography to do everything. It uses ECDH on
+//! the P-256 curve (aka "secp256r1" or "prime256v1").
+//!
+//! Using ECDH immediately protects against passive eavesdropping. MITM-protection works similarly
+//! to what *LE Legacy Pairing* attempted to do, but is actually relevant here since the base key
+//! exchange isn't broken to begin with. There are several user confirmation processes that can
+//! offer MITM-protection:
+//!
+//! * *"Just Works"*: No MITM-protection. Uses the *Numeric Comparison* protocol internally, with
+//!   automatic confirmation.
+//! * *Numeric Comparison*: Both devices display a 6-digit confirmation value and the user is
+//!   required to compare them and confirm on each device if they're equal.
+//! * *Passkey Entry*: Either a generated passkey is displayed on one device and input on the other,
+//!   or the user inputs the same passkey into both devices.
+//! * *Out-of-Band* (OOB): An Out-of-Band mechanism is used to exchange random nonces and confirm
+//!   values. The mechanism has to be secure against MITM.
+//!
+//! ## LE Privacy
+//!
+//! BLE devices are normally extremely easy to track. Since many people use BLE devices, and device
+//! addresses are device-unique, they can be very easily used to identify and track people just by
+//! recording BLE advertisements.
+//!
+//! The LE privacy feature can prevent this by changing the device address over time. Bonded devices
+//! can still *resolve* this address by using a shared **I**dentity **R**esolving **K**ey (IRK).
+//!
+//! This feature is not related to encryption or authentication of connections.
+//!
+//! # Usage
+//!
+//! Rubble supports pairing in the slave role. *LE Legacy Pairing* is enabled by creating the
+//! `SecurityManager` with the `LegacyPairing` security level, while the `SecureConnections`
+//! security level additionally enables *LE Secure Connections* pairing (using an `EcdhProvider`
+//! from the [`ecdh`] module). The application has to:
+//!
+//! * Call `SecurityManager::connection_established` when a connection is established.
+//! * Call `SecurityManager::check_timeout` regularly to enforce the SMP timeout.
+//! * Display the passkey returned by `SecurityManager::passkey_display`, or pass the passkey
+//!   entered by the user to `SecurityManagerTx::passkey_reply` when
+//!   `SecurityManager::passkey_requested` returns `true`.
+//! * Display the value returned by `SecurityManager::numeric_comparison` and pass the user's
+//!   decision to `SecurityManagerTx::numeric_comparison_reply`.
+//! * Answer the Link-Layer's LTK requests (`LinkLayer::ltk_request`) by looking up the key via
+//!   `SecurityManager::long_term_key` and passing it to `LinkLayer::ltk_reply`.
+//! * Call `SecurityManager::set_encrypted` with the result of `Connection::is_encrypted` when the
+//!   Link-Layer has queued work, so that keys can be distributed once encryption is enabled.
+//!
+//! Bonding is supported when the security level is created with a `BondStore` (via
+//! `LegacyPairing::with_bond_store` or `SecureConnections::with_bond_store`). The keys exchanged
+//! with masters that request bonding are stored there, and reconnecting masters can re-encrypt the
+//! connection with the stored Long Term Key instead of pairing again.
+//!
+//! [`ecdh`]: ../ecdh/index.html
+
+mod bond;
+mod toolbox;
+
+pub use self::bond::{Bond, BondStore, LongTermKey, MemoryBondStore, NoBonds};
+
+use self::toolbox::{c1, f4, f5, f6, g2, reversed, s1};
+use crate::crypto::{AesProvider, Key};
+use crate::ecdh::{EcdhProvider, InvalidPublicKey, PublicKey, SecretKey, SharedSecret};
+use crate::l2cap::{Protocol, ProtocolObj, Sender};
+use crate::link::{AddressKind, DeviceAddress, LtkRequest};
+use crate::time::{Duration, Instant};
+use crate::{bytes::*, utils::HexSlice, Error};
+use bitflags::bitflags;
+use core::fmt;
+use rand_core::{CryptoRng, RngCore};
+
+/// Supported security levels.
+pub trait SecurityLevel {
+    /// The L2CAP MTU required by this security level.
+    const MTU: u8;
+
+    /// Returns the AES implementation and random number generator to use for pairing.
+    ///
+    /// If this returns `None`, pairing is not supported and all pairing requests will be rejected.
+    fn pairing_crypto(&mut self) -> Option<(&mut dyn AesProvider, &mut dyn RngCore)>;
+
+    /// Whether *LE Secure Connections* pairing is supported.
+    ///
+    /// If this is `false`, *LE Legacy Pairing* is used for all pairing requests.
+    const SECURE_CONNECTIONS: bool = false;
+
+    /// Performs the ECDH key agreement for *LE Secure Connections* pairing.
+    ///
+    /// Generates a fresh key pair and agrees on a shared secret (the DHKey) with the master's public
+    /// key `foreign`. Returns the generated public key and the DHKey.
+    ///
+    /// This is only called if `SECURE_CONNECTIONS` is `true`.
+    fn ecdh(&mut self, foreign: &PublicKey) -> Result<(PublicKey, SharedSecret), InvalidPublicKey> {
+        let _ = foreign;
+        Err(InvalidPublicKey::new())
+    }
+
+    /// Returns the store for bonded devices.
+    ///
+    /// If this returns `None`, bonding is not supported and keys will not be distributed.
+    fn bond_store(&mut self) -> Option<&mut dyn BondStore> {
+        None
+    }
+}
+
+/// Pairing is not supported, so connections will not be encrypted.
+#[derive(Debug)]
+pub struct NoSecurity;
+impl SecurityLevel for NoSecurity {
+    /// 23 Bytes when *LE Secure Connections* are unsupported
+    const MTU: u8 = 23;
+
+    fn pairing_crypto(&mut self) -> Option<(&mut dyn AesProvider, &mut dyn RngCore)> {
+        None
+    }
+}
+
+/// Supports *LE Legacy Pairing*, but not *LE Secure Connections*.
+///
+/// Note that *LE Legacy Pairing* offers no protection against passive eavesdropping unless the
+/// Temporary Key is exchanged Out-of-Band. Refer to the module docs for details.
+pub struct LegacyPairing<A: AesProvider, R: RngCore + CryptoRng, B: BondStore = NoBonds> {
+    aes: A,
+    rng: R,
+    bonds: Option<B>,
+}
+
+impl<A: AesProvider, R: RngCore + CryptoRng> LegacyPairing<A, R> {
+    /// Creates a new *LE Legacy Pairing* security level without support for bonding.
+    ///
+    /// `aes` is used to compute confirm values and to derive keys, and `rng` is used to generate
+    /// random values and passkeys. `rng` must be cryptographically secure.
+    pub fn new(aes: A, rng: R) -> Self {
+        Self {
+            aes,
+            rng,
+            bonds: None,
+        }
+    }
+}
+
+impl<A: AesProvider, R: RngCore + CryptoRng, B: BondStore> LegacyPairing<A, R, B> {
+    /// Creates a new *LE Legacy Pairing* security level that supports bonding.
+    ///
+    /// Keys of masters requesting bonding are stored in `bonds`. `rng` is additionally used to
+    /// generate the Long Term Keys distributed to them.
+    pub fn with_bond_store(aes: A, rng: R, bonds: B) -> Self {
+        Self {
+            aes,
+            rng,
+            bonds: Some(bonds),
+        }
+    }
+}
+
+impl<A: AesProvider, R: RngCore + CryptoRng, B: BondStore> SecurityLevel
+    for LegacyPairing<A, R, B>
+{
+    /// 23 Bytes when *LE Secure Connections* are unsupported
+    const MTU: u8 = 23;
+
+    fn pairing_crypto(&mut self) -> Option<(&mut dyn AesProvider, &mut dyn RngCore)> {
+        Some((&mut self.aes, &mut self.rng))
+    }
+
+    fn bond_store(&mut self) -> Option<&mut dyn BondStore> {
+        self.bonds.as_mut().map(|bonds| bonds as &mut dyn BondStore)
+    }
+}
+
+impl<A: AesProvider, R: RngCore + CryptoRng, B: BondStore> fmt::Debug for LegacyPairing<A, R, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LegacyPairing")
+    }
+}
+
+/// Supports *LE Secure Connections* pairing.
+///
+/// *LE Legacy Pairing* is still used if the master does not support *LE Secure Connections*.
+/// *Out-of-Band* data is not supported for *LE Secure Connections*.
+pub struct SecureConnections<
+    A: AesProvider,
+    E: EcdhProvider,
+    R: RngCore + CryptoRng,
+    B: BondStore = NoBonds,
+> {
+    aes: A,
+    ecdh: E,
+    rng: R,
+    bonds: Option<B>,
+}
+
+impl<A: AesProvider, E: EcdhProvider, R: RngCore + CryptoRng> SecureConnections<A, E, R> {
+    /// Creates a new *LE Secure Connections* security level without support for bonding.
+    ///
+    /// `ecdh` is used to generate a key pair for every pairing procedure, `aes` is used by the
+    /// AES-CMAC-based key derivation, and `rng` is used to generate nonces and passkeys. `rng` must
+    /// be cryptographically secure.
+    pub fn new(aes: A, ecdh: E, rng: R) -> Self {
+        Self {
+            aes,
+            ecdh,
+            rng,
+            bonds: None,
+        }
+    }
+}
+
+impl<A: AesProvider, E: EcdhProvider, R: RngCore + CryptoRng, B: BondStore>
+    SecureConnections<A, E, R, B>
+{
+    /// Creates a new *LE Secure Connections* security level that supports bonding.
+    ///
+    /// Keys of masters requesting bonding are stored in `bonds`.
+    pub fn with_bond_store(aes: A, ecdh: E, rng: R, bonds: B) -> Self {
+        Self {
+            aes,
+            ecdh,
+            rng,
+            bonds: Some(bonds),
+        }
+    }
+}
+
+impl<A: AesProvider, E: EcdhProvider, R: RngCore + CryptoRng, B: BondStore> SecurityLevel
+    for SecureConnections<A, E, R, B>
+{
+    /// 65 Bytes when *LE Secure Connections* are supported
+    const MTU: u8 = 65;
+
+    fn pairing_crypto(&mut self) -> Option<(&mut dyn AesProvider, &mut dyn RngCore)> {
+        Some((&mut self.aes, &mut self.rng))
+    }
+
+    const SECURE_CONNECTIONS: bool = true;
+
+    fn ecdh(&mut self, foreign: &PublicKey) -> Result<(PublicKey, SharedSecret), InvalidPublicKey> {
+        let (secret, public) = self.ecdh.generate_keypair(&mut self.rng);
+        let shared = secret.agree(foreign)?;
+        Ok((public, shared))
+    }
+
+    fn bond_store(&mut self) -> Option<&mut dyn BondStore> {
+        self.bonds.as_mut().map(|bonds| bonds as &mut dyn BondStore)
+    }
+}
+
+impl<A: AesProvider, E: EcdhProvider, R: RngCore + CryptoRng, B: BondStore> fmt::Debug
+    for SecureConnections<A, E, R, B>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecureConnections")
+    }
+}
+
+/// The LE Security Manager.
+///
+/// Manages pairing and key generation and exchange.
+///
+/// Only the responder role is supported: Pairing is always initiated by the connected master. Once
+/// pairing has finished, the master will encrypt the connection using the generated key, which
+/// causes the Link-Layer to request a Long Term Key (see `LinkLayer::ltk_request`). The host has
+/// to answer that request with the key returned by `SecurityManager::long_term_key`.
+///
+/// If both devices want to bond, keys are distributed over the encrypted connection and the bond
+/// is saved in the security level's `BondStore`.
+#[derive(Debug)]
+pub struct SecurityManager<S: SecurityLevel> {
+    security: S,
+
+    /// Our I/O capabilities, used to select the pairing method.
+    io: IoCapabilities,
+
+    /// Our authentication requirements.
+    auth_req: AuthReq,
+
+    /// Temporary Key agreed on via an Out-of-Band mechanism.
+    oob_tk: Option<Key>,
+
+    /// Addresses of the local and the connected device, needed to compute confirm values.
+    addresses: Option<(DeviceAddress, DeviceAddress)>,
+
+    state: State,
+
+    /// Command to send once the previous response has been sent.
+    ///
+    /// This is needed because *LE Secure Connections* requires us to send 2 commands in response
+    /// to the master's public key.
+    pending: Option<Command<'static>>,
+
+    /// Time at which the last SMP command of the current pairing procedure was exchanged.
+    timer_start: Option<Instant>,
+
+    /// Whether an SMP command was exchanged since the last call to `check_timeout`.
+    timer_restart: bool,
+
+    /// Whether the connection is encrypted, as reported via `set_encrypted`.
+    encrypted: bool,
+}
+
+impl SecurityManager<NoSecurity> {
+    pub fn no_security() -> Self {
+        Self::new(NoSecurity)
+    }
+}
+
+impl<S: SecurityLevel> SecurityManager<S> {
+    /// Creates a Security Manager providing the given security level.
+    ///
+    /// The device is assumed to have no input or output capabilities, so *Just Works* pairing will
+    /// be used unless `set_io_capabilities` or `set_oob_tk` is called.
+    pub fn new(security: S) -> Self {
+        Self {
+            security,
+            io: IoCapabilities::NoInputNoOutput,
+            auth_req: AuthReq(0),
+            oob_tk: None,
+            addresses: None,
+            state: State::Idle,
+            pending: None,
+            timer_start: None,
+            timer_restart: false,
+            encrypted: false,
+        }
+    }
+
+    /// Sets the I/O capabilities of this device, which determine the pairing method.
+    pub fn set_io_capabilities(&mut self, io: IoCapabilities) {
+        self.io = io;
+    }
+
+    /// Sets whether pairing has to protect against man-in-the-middle attacks.
+    ///
+    /// If this is set, pairing requests that would have to use *Just Works* will be rejected.
+    pub fn set_mitm(&mut self, mitm: bool) {
+        self.auth_req.set_mitm(mitm);
+    }
+
+    /// Sets the Temporary Key (TK) obtained via an Out-of-Band mechanism (eg. NFC).
+    ///
+    /// If the master also has OOB data, the TK will be used for pairing instead of a passkey.
+    pub fn set_oob_tk(&mut self, tk: Option<Key>) {
+        self.oob_tk = tk;
+    }
+
+    /// Prepares the Security Manager for a new connection.
+    ///
+    /// This must be called whenever a connection is established, otherwise pairing requests will be
+    /// rejected. It discards all state belonging to the previous connection.
+    ///
+    /// # Parameters
+    ///
+    /// * **`local`**: The address of this device.
+    /// * **`peer`**: The address of the connected master.
+    pub fn connection_established(&mut self, local: DeviceAddress, peer: DeviceAddress) {
+        self.addresses = Some((local, peer));
+        self.state = State::Idle;
+        self.pending = None;
+        self.timer_start = None;
+        self.timer_restart = false;
+        self.encrypted = false;
+    }
+
+    /// Informs the Security Manager whether the connection is currently encrypted.
+    ///
+    /// Keys are only distributed once the master has encrypted the connection with the key
+    /// generated by pairing. This should be called with the result of `Connection::is_encrypted`
+    /// whenever the Link-Layer has queued work.
+    pub fn set_encrypted(&mut self, encrypted: bool) {
+        self.encrypted = encrypted;
+    }
+
+    /// Checks whether the ongoing pairing procedure has timed out.
+    ///
+    /// Pairing fails if no SMP command is exchanged for 30 seconds. After that, no further pairing
+    /// can take place on the connection. This method should be called regularly (eg. from the
+    /// app's idle loop) with the current time.
+    pub fn check_timeout(&mut self, now: Instant) {
+        if self.pairing_in_progress() {
+            if self.timer_restart {
+                self.timer_restart = false;
+                self.timer_start = Some(now);
+            }
+
+            if let Some(start) = self.timer_start {
+                if now.duration_since(start) >= Duration::from_secs(30) {
+                    warn!("SMP timeout, pairing failed");
+                    self.state = State::TimedOut;
+                }
+            }
+        } else {
+            self.timer_start = None;
+            self.timer_restart = false;
+        }
+    }
+
+    /// Returns the passkey that has to be displayed to the user, if any.
+    ///
+    /// The user has to enter this passkey on the master to finish pairing.
+    pub fn passkey_display(&self) -> Option<u32> {
+        match &self.state {
+            State::Legacy(legacy) => legacy.passkey,
+            State::Sc(sc) if sc.method == Method::PasskeyDisplay => sc.passkey,
+            _ => None,
+        }
+    }
+
+    /// Returns whether the user has to enter the passkey displayed by the master.
+    ///
+    /// The passkey can be passed to `SecurityManagerTx::passkey_reply`.
+    pub fn passkey_requested(&self) -> bool {
+        match &self.state {
+            State::Legacy(legacy) => legacy.tk.is_none(),
+            State::Sc(sc) => sc.method == Method::PasskeyInput && sc.passkey.is_none(),
+            _ => false,
+        }
+    }
+
+    /// Returns the value to display for *Numeric Comparison*, if the user has to confirm it.
+    ///
+    /// The user has to check that the master displays the same value, and the result has to be
+    /// passed to `SecurityManagerTx::numeric_comparison_reply`.
+    pub fn numeric_comparison(&self) -> Option<u32> {
+        match &self.state {
+            State::Sc(sc) if !sc.confirmed => sc.compare,
+            _ => None,
+        }
+    }
+
+    /// Returns whether pairing has finished successfully on this connection.
+    pub fn is_paired(&self) -> bool {
+        matches!(self.state, State::Paired(_))
+    }
+
+    /// Looks up the key requested by the Link-Layer to encrypt the connection.
+    ///
+    /// After *LE Legacy Pairing*, the master encrypts the connection with the Short Term Key
+    /// (STK), identified by `EDIV` and `Rand` being 0. After *LE Secure Connections* pairing, the
+    /// generated Long Term Key is identified the same way.
+    ///
+    /// Bonded masters are identified by the `EDIV` and `Rand` values we distributed to them, or, if
+    /// both are 0, by their device address. In that case, the stored Long Term Key is returned.
+    ///
+    /// Returns `None` if no matching key is known. The request should then be rejected using
+    /// `LinkLayer::ltk_negative_reply`.
+    pub fn long_term_key(&mut self, request: &LtkRequest) -> Option<Key> {
+        let (ediv, rand) = (request.ediv(), request.rand());
+        if let State::Paired(paired) = &self.state {
+            if ediv == 0 && rand == [0; 8] {
+                return Some(paired.key);
+            }
+        }
+
+        let peer = self.addresses?.1;
+        let bond = self.find_bond(|bond, aes| match bond.ltk {
+            // Only keys generated by *LE Secure Connections* pairing have `EDIV` and `Rand` set
+            // to 0, so the master has to be identified by its address instead.
+            Some(ltk) if ltk.ediv == ediv && ltk.rand == rand => {
+                ediv != 0 || rand != [0; 8] || bond.identifies(aes, &peer)
+            }
+            _ => false,
+        })?;
+        bond.ltk.map(|ltk| ltk.key)
+    }
+
+    /// Returns the bond of the connected master, if it is bonded.
+    ///
+    /// Resolvable private addresses are resolved using the stored IRKs.
+    pub fn peer_bond(&mut self) -> Option<Bond> {
+        let peer = self.addresses?.1;
+        self.find_bond(|bond, aes| bond.identifies(aes, &peer))
+    }
+
+    /// Returns the first stored bond for which `pred` returns `true`.
+    fn find_bond(
+        &mut self,
+        mut pred: impl FnMut(&Bond, &mut dyn AesProvider) -> bool,
+    ) -> Option<Bond> {
+        let mut index = 0;
+        loop {
+            let bond = self.security.bond_store()?.get(index)?;
+            let aes = self.security.pairing_crypto()?.0;
+            if pred(&bond, aes) {
+                return Some(bond);
+            }
+            index += 1;
+        }
+    }
+
+    /// Returns whether a command is waiting to be sent by `SecurityManagerTx::send_pending`.
+    pub(crate) fn has_pending(&self) -> bool {
+        if self.pending.is_some() {
+            return true;
+        }
+
+        match &self.state {
+            State::Paired(paired) => self.encrypted && paired.sending(),
+            _ => false,
+        }
+    }
+
+    /// Returns the next command to send on our own, if any.
+    ///
+    /// These are commands queued while sending a response, and the keys we distribute after
+    /// pairing (once the connection is encrypted).
+    fn next_pending(&mut self) -> Option<Command<'static>> {
+        if let Some(cmd) = self.pending.take() {
+            return Some(cmd);
+        }
+
+        let paired = match &mut self.state {
+            State::Paired(paired) if self.encrypted => paired,
+            _ => return None,
+        };
+        let ltk = paired.bond?.ltk?;
+        if paired.send_enc_info {
+            paired.send_enc_info = false;
+            Some(Command::EncryptionInformation(ltk.key))
+        } else if paired.send_master_ident {
+            paired.send_master_ident = false;
+            Some(Command::MasterIdentification {
+                ediv: ltk.ediv,
+                rand: ltk.rand,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether a pairing procedure (including key distribution) is ongoing.
+    fn pairing_in_progress(&self) -> bool {
+        match &self.state {
+            State::Legacy(_) | State::Sc(_) => true,
+            State::Paired(paired) => paired.bond.is_some(),
+            _ => false,
+        }
+    }
+
+    /// Finishes pairing with the generated `key`.
+    ///
+    /// If bonding was negotiated, this prepares the distribution of keys according to `dist`. `sc`
+    /// specifies whether *LE Secure Connections* pairing was performed.
+    fn paired(&mut self, key: Key, sc: bool, dist: Option<Distribution>) {
+        let mut paired = PairedState {
+            key,
+            bond: None,
+            send_enc_info: false,
+            send_master_ident: false,
+            expect: KeyDistribution::empty(),
+        };
+
+        if let Some(dist) = dist {
+            let ltk = if sc {
+                // The LTK was generated by pairing and is not distributed.
+                Some(LongTermKey {
+                    key,
+                    ediv: 0,
+                    rand: [0; 8],
+                })
+            } else if dist.local.contains(KeyDistribution::ENC_KEY) {
+                let rng = self.security.pairing_crypto().unwrap().1;
+                let mut ltk = LongTermKey {
+                    key: Key([0; 16]),
+                    ediv: 0,
+                    rand: [0; 8],
+                };
+                rng.fill_bytes(&mut ltk.key.0);
+                rng.fill_bytes(&mut ltk.rand);
+                ltk.ediv = rng.next_u32() as u16;
+                paired.send_enc_info = true;
+                paired.send_master_ident = true;
+                Some(ltk)
+            } else {
+                None
+            };
+
+            paired.expect = dist.peer;
+            paired.bond = Some(Bond {
+                identity: self.addresses.unwrap().1,
+                ltk,
+                irk: None,
+                csrk: None,
+            });
+        }
+
+        self.state = State::Paired(paired);
+
+        // Without any keys to distribute, bonding is already complete.
+        self.complete_bonding();
+    }
+
+    /// Stores the bond once all keys have been distributed.
+    fn complete_bonding(&mut self) {
+        let bond = match &mut self.state {
+            State::Paired(paired) if !paired.sending() && paired.expect.is_empty() => {
+                paired.bond.take()
+            }
+            _ => None,
+        };
+
+        if let Some(bond) = bond {
+            match self.security.bond_store().map(|store| store.store(&bond)) {
+                Some(Ok(())) => info!("bonded with {:?}", bond.identity),
+                _ => error!("failed to store bond with {:?}", bond.identity),
+            }
+        }
+    }
+
+    /// Gives this Security Manager the ability to send SMP commands on its own.
+    pub fn with_sender<'a>(&'a mut self, sender: Sender<'a>) -> SecurityManagerTx<'a, S> {
+        SecurityManagerTx { sm: self, sender }
+    }
+
+    /// Processes an SMP command and returns the response to send.
+    ///
+    /// Returns an error if pairing failed. The caller has to send a *Pairing Failed* command with
+    /// the returned reason.
+    fn process_command(
+        &mut self,
+        cmd: Command<'_>,
+    ) -> Result<Option<Command<'static>>, PairingFailedReason> {
+        match cmd {
+            Command::PairingRequest(req) => {
+                if self.security.pairing_crypto().is_none() {
+                    return Err(PairingFailedReason::PairingNotSupported);
+                }
+                if self.addresses.is_none() {
+                    error!("pairing request before `connection_established` was called");
+                    return Err(PairingFailedReason::UnspecifiedReason);
+                }
+                if let IoCapabilities::Unknown(_) = req.io {
+                    return Err(PairingFailedReason::InvalidParameters);
+                }
+                if req.max_keysize < 16 {
+                    return Err(PairingFailedReason::EncryptionKeySize);
+                }
+
+                let sc = S::SECURE_CONNECTIONS && req.auth_req.secure_connection();
+                let bonding = match req.auth_req.bonding_type() {
+                    BondingType::Bonding => self.security.bond_store().is_some(),
+                    _ => false,
+                };
+                // We distribute our LTK (unless it is generated by pairing) and accept the
+                // master's identity and signing keys.
+                let dist = if bonding {
+                    Some(Distribution {
+                        local: req.responder_dist & KeyDistribution::ENC_KEY,
+                        peer: req.initiator_dist
+                            & (KeyDistribution::ID_KEY | KeyDistribution::SIGN_KEY),
+                    })
+                } else {
+                    None
+                };
+
+                let mut auth_req = self.auth_req;
+                auth_req.set_secure_connection(S::SECURE_CONNECTIONS);
+                auth_req.set_bonding_type(if bonding {
+                    BondingType::Bonding
+                } else {
+                    BondingType::NoBonding
+                });
+                let rsp = PairingParams {
+                    io: self.io,
+                    oob: !sc && self.oob_tk.is_some(),
+                    auth_req,
+                    max_keysize: 16,
+                    initiator_dist: dist.map_or(KeyDistribution::empty(), |d| d.peer),
+                    responder_dist: dist.map_or(KeyDistribution::empty(), |d| d.local),
+                };
+
+                let method = Method::select(&req, &rsp, sc);
+                if method == Method::JustWorks && self.auth_req.mitm() {
+                    return Err(PairingFailedReason::AuthenticationRequirements);
+                }
+
+                let rng = self.security.pairing_crypto().unwrap().1;
+                if sc {
+                    let passkey = match method {
+                        Method::Oob => return Err(PairingFailedReason::OobNotAvailable),
+                        Method::PasskeyDisplay => Some(rng.next_u32() % 1_000_000),
+                        _ => None,
+                    };
+                    self.state = State::Sc(ScState::new(method, &req, &rsp, passkey, dist));
+                    return Ok(Some(Command::PairingResponse(rsp)));
+                }
+
+                let (tk, passkey) = match method {
+                    Method::JustWorks | Method::NumericComparison => (Some(Key([0; 16])), None),
+                    Method::Oob => (self.oob_tk, None),
+                    Method::PasskeyDisplay => {
+                        let passkey = rng.next_u32() % 1_000_000;
+                        (Some(passkey_tk(passkey)), Some(passkey))
+                    }
+                    Method::PasskeyInput => (None, None),
+                };
+
+                self.state = State::Legacy(LegacyState {
+                    preq: req.encode(CommandCode::PairingRequest),
+                    pres: rsp.encode(CommandCode::PairingResponse),
+                    tk,
+                    passkey,
+                    mconfirm: None,
+                    srand: None,
+                    dist,
+                });
+                Ok(Some(Command::PairingResponse(rsp)))
+            }
+            Command::PairingConfirm(confirm) => {
+                // Respond with our confirm value, unless we're still waiting for the passkey.
+                match &mut self.state {
+                    State::Legacy(legacy) if legacy.mconfirm.is_none() => {
+                        legacy.mconfirm = Some(confirm);
+                        Ok(self.confirm())
+                    }
+                    State::Sc(sc) if sc.passkey_entry() && sc.ca.is_none() && sc.round < 20 => {
+                        if sc.dhkey.is_none() {
+                            return Err(PairingFailedReason::UnspecifiedReason);
+                        }
+                        sc.ca = Some(confirm);
+                        Ok(self.sc_confirm())
+                    }
+                    _ => Err(PairingFailedReason::UnspecifiedReason),
+                }
+            }
+            Command::PairingRandom(rand) => match self.state {
+                State::Legacy(_) => self.legacy_random(rand),
+                State::Sc(_) => self.sc_random(rand),
+                _ => Err(PairingFailedReason::UnspecifiedReason),
+            },
+            Command::PairingPublicKey { x, y } => self.sc_public_key(x, y),
+            Command::PairingDhKeyCheck(check) => {
+                match &mut self.state {
+                    State::Sc(sc) if sc.stage1_done() && sc.ea.is_none() => {
+                        sc.ea = Some(check);
+                    }
+                    _ => return Err(PairingFailedReason::UnspecifiedReason),
+                }
+
+                // Respond with our check value, unless the user still has to confirm pairing.
+                self.sc_check()
+            }
+            Command::PairingFailed(reason) => {
+                warn!("pairing failed: {:?}", reason);
+                self.state = State::Idle;
+                self.pending = None;
+                Ok(None)
+            }
+            Command::EncryptionInformation(_)
+            | Command::MasterIdentification { .. }
+            | Command::IdentityInformation(_)
+            | Command::IdentityAddressInformation(_)
+            | Command::SigningInformation(_) => {
+                // Keys may only be distributed over the encrypted connection.
+                match &mut self.state {
+                    State::Paired(paired) if self.encrypted => paired.receive_key(cmd)?,
+                    _ => return Err(PairingFailedReason::UnspecifiedReason),
+                }
+                self.complete_bonding();
+                Ok(None)
+            }
+            Command::PairingResponse(_) => Err(PairingFailedReason::CommandNotSupported),
+            Command::Unknown {
+                code: CommandCode::Unknown(code),
+                data,
+            } => {
+                // Commands with reserved codes must be ignored.
+                warn!(
+                    "unknown security manager cmd: 0x{:02X} {:?}",
+                    code,
+                    HexSlice(data)
+                );
+                Ok(None)
+            }
+            Command::Unknown { code, data } => {
+                warn!("[NYI] SMP cmd {:?}: {:?}", code, HexSlice(data));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Computes our confirm value (`Sconfirm`) once both the TK and `Mconfirm` are known.
+    fn confirm(&mut self) -> Option<Command<'static>> {
+        let legacy = match &mut self.state {
+            State::Legacy(legacy) => legacy,
+            _ => return None,
+        };
+        let tk = match (legacy.tk, legacy.mconfirm, legacy.srand) {
+            (Some(tk), Some(_), None) => tk,
+            _ => return None,
+        };
+
+        let (local, peer) = self.addresses.unwrap();
+        let (aes, rng) = self.security.pairing_crypto().unwrap();
+        let mut srand = [0; 16];
+        rng.fill_bytes(&mut srand);
+        legacy.srand = Some(srand);
+
+        let sconfirm = c1(aes, &tk, &srand, &legacy.preq, &legacy.pres, &peer, &local);
+        Some(Command::PairingConfirm(sconfirm))
+    }
+
+    /// Processes the master's random value (`Mrand`) in *LE Legacy Pairing*.
+    fn legacy_random(
+        &mut self,
+        mrand: [u8; 16],
+    ) -> Result<Option<Command<'static>>, PairingFailedReason> {
+        let legacy = match &self.state {
+            State::Legacy(legacy) if legacy.srand.is_some() => legacy,
+            _ => return Err(PairingFailedReason::UnspecifiedReason),
+        };
+        let (local, peer) = self.addresses.unwrap();
+        let aes = self.security.pairing_crypto().unwrap().0;
+        let tk = legacy.tk.unwrap();
+        let srand = legacy.srand.unwrap();
+
+        let mconfirm = c1(aes, &tk, &mrand, &legacy.preq, &legacy.pres, &peer, &local);
+        if Some(mconfirm) != legacy.mconfirm {
+            return Err(PairingFailedReason::ConfirmValueFailed);
+        }
+
+        let stk = s1(aes, &tk, &srand, &mrand);
+        let dist = legacy.dist;
+        self.paired(stk, false, dist);
+        Ok(Some(Command::PairingRandom(srand)))
+    }
+
+    /// Performs the key agreement with the master's public key in *LE Secure Connections*.
+    ///
+    /// Responds with our public key. For *Just Works* and *Numeric Comparison*, our confirm value
+    /// is sent afterwards.
+    fn sc_public_key(
+        &mut self,
+        x: [u8; 32],
+        y: [u8; 32],
+    ) -> Result<Option<Command<'static>>, PairingFailedReason> {
+        let sc = match &mut self.state {
+            State::Sc(sc) if sc.dhkey.is_none() => sc,
+            _ => return Err(PairingFailedReason::UnspecifiedReason),
+        };
+
+        // `PublicKey` is big-endian, while the coordinates are sent little-endian.
+        let mut foreign = PublicKey([0; 64]);
+        foreign.0[..32].copy_from_slice(&reversed(&x));
+        foreign.0[32..].copy_from_slice(&reversed(&y));
+        let (public, dhkey) = self.security.ecdh(&foreign).map_err(|e| {
+            warn!("{}", e);
+            PairingFailedReason::DhKeyCheckFailed
+        })?;
+
+        let mut pkb_x = [0; 32];
+        let mut pkb_y = [0; 32];
+        pkb_x.copy_from_slice(&public.0[..32]);
+        pkb_y.copy_from_slice(&public.0[32..]);
+        sc.pka = x;
+        sc.pkb = reversed(&pkb_x);
+        sc.dhkey = Some(reversed(&dhkey.0));
+
+        self.pending = self.sc_confirm();
+        Ok(Some(Command::PairingPublicKey {
+            x: reversed(&pkb_x),
+            y: reversed(&pkb_y),
+        }))
+    }
+
+    /// Computes our confirm value (`Cb`) in *LE Secure Connections*, once we can commit to a nonce.
+    ///
+    /// For *Just Works* and *Numeric Comparison*, this happens right after the public key
+    /// exchange. For *Passkey Entry*, this happens in every round, once the master's confirm value
+    /// and the passkey are known.
+    fn sc_confirm(&mut self) -> Option<Command<'static>> {
+        let sc = match &mut self.state {
+            State::Sc(sc) if sc.dhkey.is_some() && sc.nb.is_none() => sc,
+            _ => return None,
+        };
+        let z = if sc.passkey_entry() {
+            if sc.ca.is_none() || sc.passkey.is_none() {
+                return None;
+            }
+            sc.z()
+        } else {
+            0
+        };
+
+        let (aes, rng) = self.security.pairing_crypto().unwrap();
+        let mut nb = [0; 16];
+        rng.fill_bytes(&mut nb);
+        sc.nb = Some(nb);
+
+        Some(Command::PairingConfirm(f4(aes, &sc.pkb, &sc.pka, &nb, z)))
+    }
+
+    /// Processes the master's nonce (`Na`) in *LE Secure Connections* and responds with ours.
+    fn sc_random(&mut self, na: [u8; 16]) -> Result<Option<Command<'static>>, PairingFailedReason> {
+        let sc = match &mut self.state {
+            State::Sc(sc) if !sc.stage1_done() => sc,
+            _ => return Err(PairingFailedReason::UnspecifiedReason),
+        };
+        let nb = match sc.nb {
+            Some(nb) => nb,
+            None => return Err(PairingFailedReason::UnspecifiedReason),
+        };
+        let aes = self.security.pairing_crypto().unwrap().0;
+
+        match sc.method {
+            Method::PasskeyDisplay | Method::PasskeyInput => {
+                // Our nonce is only generated once the master's confirm value was received.
+                if f4(aes, &sc.pka, &sc.pkb, &na, sc.z()) != sc.ca.unwrap() {
+                    return Err(PairingFailedReason::ConfirmValueFailed);
+                }
+
+                // The passkey is confirmed bit by bit, in 20 rounds.
+                sc.round += 1;
+                if sc.round < 20 {
+                    sc.ca = None;
+                    sc.nb = None;
+                }
+            }
+            Method::NumericComparison => {
+                sc.compare = Some(g2(aes, &sc.pka, &sc.pkb, &na, &nb));
+            }
+            _ => {}
+        }
+
+        sc.na = Some(na);
+        Ok(Some(Command::PairingRandom(nb)))
+    }
+
+    /// Verifies the master's DHKey check value (`Ea`) in *LE Secure Connections*, and responds
+    /// with ours (`Eb`).
+    ///
+    /// Does nothing if `Ea` wasn't received yet, or the user still has to confirm pairing.
+    fn sc_check(&mut self) -> Result<Option<Command<'static>>, PairingFailedReason> {
+        let sc = match &self.state {
+            State::Sc(sc) => sc,
+            _ => return Ok(None),
+        };
+        let ea = match sc.ea {
+            Some(ea) if sc.confirmed => ea,
+            _ => return Ok(None),
+        };
+
+        let (local, peer) = self.addresses.unwrap();
+        let aes = self.security.pairing_crypto().unwrap().0;
+        let (na, nb) = (sc.na.unwrap(), sc.nb.unwrap());
+        let r = match sc.passkey {
+            Some(passkey) if sc.passkey_entry() => passkey_tk(passkey).0,
+            _ => [0; 16],
+        };
+
+        let (mac_key, ltk) = f5(aes, &sc.dhkey.unwrap(), &na, &nb, &peer, &local);
+        if f6(aes, &mac_key, &na, &nb, &r, &sc.io_cap_a, &peer, &local) != ea {
+            return Err(PairingFailedReason::DhKeyCheckFailed);
+        }
+
+        let eb = f6(aes, &mac_key, &nb, &na, &r, &sc.io_cap_b, &local, &peer);
+        let dist = sc.dist;
+        self.paired(ltk, true, dist);
+        Ok(Some(Command::PairingDhKeyCheck(eb)))
+    }
+
+    /// Sends the result of processing a command (or user input), and restarts the SMP timer.
+    fn respond(
+        &mut self,
+        result: Result<Option<Command<'static>>, PairingFailedReason>,
+        sender: &mut Sender<'_>,
+    ) -> Result<(), Error> {
+        let rsp = result.unwrap_or_else(|reason| {
+            warn!("pairing failed: {:?}", reason);
+            self.state = State::Idle;
+            self.pending = None;
+            Some(Command::PairingFailed(reason))
+        });
+
+        if self.pairing_in_progress() {
+            self.timer_restart = true;
+        }
+
+        if let Some(rsp) = rsp {
+            trace!("SMP rsp {:?}", rsp);
+            sender.send(rsp)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: SecurityLevel> ProtocolObj for SecurityManager<S> {
+    fn process_message(&mut self, message: &[u8], mut responder: Sender<'_>) -> Result<(), Error> {
+        let cmd = Command::from_bytes(&mut ByteReader::new(message))?;
+        trace!("SMP cmd {:?}, {:?}", cmd, HexSlice(message));
+
+        if let State::TimedOut = self.state {
+            // No more SMP commands may be sent after a timeout.
+            warn!("SMP timed out, ignoring {:?}", cmd);
+            return Ok(());
+        }
+
+        let result = self.process_command(cmd);
+        self.respond(result, &mut responder)
+    }
+}
+
+impl<S: SecurityLevel> Protocol for SecurityManager<S> {
+    const RSP_PDU_SIZE: u8 = S::MTU;
+}
+
+/// A `SecurityManager` with the ability to send SMP commands.
+///
+/// This is used to pass user input (eg. a passkey) to an ongoing pairing procedure. Obtained via
+/// `L2CAPStateTx::security`.
+pub struct SecurityManagerTx<'a, S: SecurityLevel> {
+    sm: &'a mut SecurityManager<S>,
+    sender: Sender<'a>,
+}
+
+impl<'a, S: SecurityLevel> SecurityManagerTx<'a, S> {
+    /// Provides the passkey entered by the user.
+    ///
+    /// Returns `Error::InvalidValue` if no passkey was requested or `passkey` has more than 6
+    /// digits.
+    pub fn passkey_reply(mut self, passkey: u32) -> Result<(), Error> {
+        if !self.sm.passkey_requested() || passkey >= 1_000_000 {
+            return Err(Error::InvalidValue);
+        }
+
+        let rsp = match &mut self.sm.state {
+            State::Legacy(legacy) => {
+                legacy.tk = Some(passkey_tk(passkey));
+                self.sm.confirm()
+            }
+            State::Sc(sc) => {
+                sc.passkey = Some(passkey);
+                self.sm.sc_confirm()
+            }
+            _ => None,
+        };
+        self.sm.respond(Ok(rsp), &mut self.sender)
+    }
+
+    /// Aborts pairing because the user canceled passkey entry.
+    ///
+    /// Returns `Error::InvalidValue` if no passkey was requested.
+    pub fn passkey_negative_reply(mut self) -> Result<(), Error> {
+        if !self.sm.passkey_requested() {
+            return Err(Error::InvalidValue);
+        }
+
+        self.sm.respond(
+            Err(PairingFailedReason::PasskeyEntryFailed),
+            &mut self.sender,
+        )
+    }
+
+    /// Provides the user's decision whether the *Numeric Comparison* values match.
+    ///
+    /// If `matches` is `false`, pairing is aborted.
+    ///
+    /// Returns `Error::InvalidValue` if no comparison is in progress.
+    pub fn numeric_comparison_reply(mut self, matches: bool) -> Result<(), Error> {
+        if self.sm.numeric_comparison().is_none() {
+            return Err(Error::InvalidValue);
+        }
+
+        let result = if matches {
+            if let State::Sc(sc) = &mut self.sm.state {
+                sc.confirmed = true;
+            }
+            self.sm.sc_check()
+        } else {
+            Err(PairingFailedReason::NumericComparisonFailed)
+        };
+        self.sm.respond(result, &mut self.sender)
+    }
+
+    /// Sends the next command that was queued while the previous response was being sent, or the
+    /// next key to distribute.
+    pub(crate) fn send_pending(mut self) -> Result<(), Error> {
+        match self.sm.next_pending() {
+            Some(cmd) => {
+                trace!("SMP rsp {:?}", cmd);
+                self.sender.send(cmd)?;
+                if self.sm.pairing_in_progress() {
+                    self.sm.timer_restart = true;
+                }
+                self.sm.complete_bonding();
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Pairing state of a connection.
+#[derive(Debug)]
+enum State {
+    /// No pairing procedure in progress.
+    Idle,
+
+    /// Performing *LE Legacy Pairing*.
+    Legacy(LegacyState),
+
+    /// Performing *LE Secure Connections* pairing.
+    Sc(ScState),
+
+    /// Pairing has finished. The connection can be encrypted with the resulting key, after which
+    /// keys are distributed if bonding.
+    Paired(PairedState),
+
+    /// Pairing failed because the SMP timer expired. No further SMP commands may be exchanged on
+    /// this connection.
+    TimedOut,
+}
+
+/// State of an ongoing *LE Legacy Pairing* procedure.
+#[derive(Debug)]
+struct LegacyState {
+    /// The encoded *Pairing Request* command.
+    preq: [u8; 7],
+
+    /// The encoded *Pairing Response* command.
+    pres: [u8; 7],
+
+    /// The Temporary Key, or `None` if the user still has to enter the passkey.
+    tk: Option<Key>,
+
+    /// Passkey to display to the user, if we generated it.
+    passkey: Option<u32>,
+
+    /// Confirm value received from the master.
+    mconfirm: Option<[u8; 16]>,
+
+    /// Our random value, generated when sending our confirm value.
+    srand: Option<[u8; 16]>,
+
+    /// Keys to distribute, or `None` if not bonding.
+    dist: Option<Distribution>,
+}
+
+/// State of an ongoing *LE Secure Connections* pairing procedure.
+#[derive(Debug)]
+struct ScState {
+    method: Method,
+
+    /// `IOcap` of the master, used for the DHKey checks.
+    io_cap_a: [u8; 3],
+
+    /// Our `IOcap`.
+    io_cap_b: [u8; 3],
+
+    /// The passkey for *Passkey Entry*, or `None` if the user still has to enter it.
+    passkey: Option<u32>,
+
+    /// X coordinate of the master's public key.
+    pka: [u8; 32],
+
+    /// X coordinate of our public key.
+    pkb: [u8; 32],
+
+    /// The shared secret, once public keys have been exchanged.
+    dhkey: Option<[u8; 32]>,
+
+    /// Number of completed *Passkey Entry* rounds.
+    round: u8,
+
+    /// Confirm value received from the master in the current round.
+    ca: Option<[u8; 16]>,
+
+    /// Our nonce of the current round, generated when sending our confirm value.
+    nb: Option<[u8; 16]>,
+
+    /// The master's nonce of the last completed round.
+    na: Option<[u8; 16]>,
+
+    /// The value to display for *Numeric Comparison*.
+    compare: Option<u32>,
+
+    /// Whether the user has confirmed pairing. Only needed for *Numeric Comparison*.
+    confirmed: bool,
+
+    /// DHKey check value received from the master.
+    ea: Option<[u8; 16]>,
+
+    /// Keys to distribute, or `None` if not bonding.
+    dist: Option<Distribution>,
+}
+
+impl ScState {
+    fn new(
+        method: Method,
+        req: &PairingParams,
+        rsp: &PairingParams,
+        passkey: Option<u32>,
+        dist: Option<Distribution>,
+    ) -> Self {
+        Self {
+            method,
+            io_cap_a: req.io_cap(),
+            io_cap_b: rsp.io_cap(),
+            passkey,
+            pka: [0; 32],
+            pkb: [0; 32],
+            dhkey: None,
+            round: 0,
+            ca: None,
+            nb: None,
+            na: None,
+            compare: None,
+            confirmed: method != Method::NumericComparison,
+            ea: None,
+            dist,
+        }
+    }
+
+    fn passkey_entry(&self) -> bool {
+        self.method == Method::PasskeyDisplay || self.method == Method::PasskeyInput
+    }
+
+    /// Returns whether the nonces have been exchanged (*Authentication stage 1*).
+    fn stage1_done(&self) -> bool {
+        if self.passkey_entry() {
+            self.round == 20
+        } else {
+            self.na.is_some()
+        }
+    }
+
+    /// Returns the `f4` parameter `Z` for the current *Passkey Entry* round.
+    fn z(&self) -> u8 {
+        0x80 | ((self.passkey.unwrap() >> self.round) & 1) as u8
+    }
+}
+
+/// State of a connection after pairing has finished.
+#[derive(Debug)]
+struct PairedState {
+    /// The key generated by pairing (the STK for *LE Legacy Pairing*, the LTK for *LE Secure
+    /// Connections*).
+    key: Key,
+
+    /// The bond being established, or `None` if not bonding or the bond has been stored.
+    bond: Option<Bond>,
+
+    /// Whether we still have to send the *Encryption Information* command.
+    send_enc_info: bool,
+
+    /// Whether we still have to send the *Master Identification* command.
+    send_master_ident: bool,
+
+    /// Keys still expected from the master.
+    expect: KeyDistribution,
+}
+
+impl PairedState {
+    /// Returns whether we still have keys to send.
+    fn sending(&self) -> bool {
+        self.bond.is_some() && (self.send_enc_info || self.send_master_ident)
+    }
+
+    /// Processes a key distributed by the master.
+    ///
+    /// The master distributes its keys after ours, and the *Identity Information* command has to
+    /// be followed by the *Identity Address Information* command.
+    fn receive_key(&mut self, cmd: Command<'_>) -> Result<(), PairingFailedReason> {
+        let bond = match &mut self.bond {
+            Some(bond) if !self.send_enc_info && !self.send_master_ident => bond,
+            _ => return Err(PairingFailedReason::UnspecifiedReason),
+        };
+
+        match cmd {
+            Command::IdentityInformation(irk)
+                if self.expect.contains(KeyDistribution::ID_KEY) && bond.irk.is_none() =>
+            {
+                bond.irk = Some(irk);
+            }
+            Command::IdentityAddressInformation(identity)
+                if self.expect.contains(KeyDistribution::ID_KEY) && bond.irk.is_some() =>
+            {
+                bond.identity = identity;
+                self.expect.remove(KeyDistribution::ID_KEY);
+            }
+            Command::SigningInformation(csrk)
+                if self.expect.contains(KeyDistribution::SIGN_KEY)
+                    && !self.expect.contains(KeyDistribution::ID_KEY) =>
+            {
+                bond.csrk = Some(csrk);
+                self.expect.remove(KeyDistribution::SIGN_KEY);
+            }
+            _ => return Err(PairingFailedReason::UnspecifiedReason),
+        }
+        Ok(())
+    }
+}
+
+/// Keys distributed after pairing, as negotiated in the *Pairing Response*.
+#[derive(Debug, Copy, Clone)]
+struct Distribution {
+    /// Keys we distribute to the master.
+    local: KeyDistribution,
+
+    /// Keys the master distributes to us.
+    peer: KeyDistribution,
+}
+
+/// Method used for authenticating the pairing procedure (association model).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Method {
+    /// No authentication (TK is 0 in *LE Legacy Pairing*).
+    JustWorks,
+
+    /// Both devices display a value and the user confirms that they match.
+    ///
+    /// Only available with *LE Secure Connections*.
+    NumericComparison,
+
+    /// We display a passkey and the user enters it on the master.
+    PasskeyDisplay,
+
+    /// The user enters the passkey on our side.
+    PasskeyInput,
+
+    /// Pairing data was exchanged Out-of-Band.
+    Oob,
+}
+
+impl Method {
+    /// Selects the pairing method according to the pairing request and response.
+    ///
+    /// `sc` specifies whether *LE Secure Connections* pairing is performed.
+    fn select(req: &PairingParams, rsp: &PairingParams, sc: bool) -> Self {
+        use self::IoCapabilities::*;
+
+        let oob = if sc {
+            req.oob || rsp.oob
+        } else {
+            req.oob && rsp.oob
+        };
+        if oob {
+            return Method::Oob;
+        }
+        if !req.auth_req.mitm() && !rsp.auth_req.mitm() {
+            return Method::JustWorks;
+        }
+
+        // Maps I/O capabilities of initiator and responder to a method.
+        match (req.io, rsp.io) {
+            (NoInputNoOutput, _) | (_, NoInputNoOutput) => Method::JustWorks,
+            (DisplayYesNo, DisplayYesNo)
+            | (DisplayYesNo, KeyboardDisplay)
+            | (KeyboardDisplay, DisplayYesNo)
+            | (KeyboardDisplay, KeyboardDisplay)
+                if sc =>
+            {
+                Method::NumericComparison
+            }
+            (DisplayOnly, DisplayOnly)
+            | (DisplayOnly, DisplayYesNo)
+            | (DisplayYesNo, DisplayOnly)
+            | (DisplayYesNo, DisplayYesNo) => Method::JustWorks,
+            // The user enters the same passkey on both devices.
+            (KeyboardOnly, KeyboardOnly) => Method::PasskeyInput,
+            (KeyboardOnly, _)
+            | (KeyboardDisplay, DisplayOnly)
+            | (KeyboardDisplay, DisplayYesNo)
+            | (KeyboardDisplay, KeyboardDisplay) => Method::PasskeyDisplay,
+            (DisplayOnly, _) | (DisplayYesNo, _) | (KeyboardDisplay, KeyboardOnly) => {
+                Method::PasskeyInput
+            }
+            _ => Method::JustWorks,
+        }
+    }
+}
+
+/// Converts a 6-digit passkey to a TK by zero-padding it to 128 bits.
+fn passkey_tk(passkey: u32) -> Key {
+    let mut tk = [0; 16];
+    tk[..4].copy_from_slice(&passkey.to_le_bytes());
+    Key(tk)
+}
+
+/// Parameters exchanged in the *Pairing Request* and *Pairing Response* commands.
+#[derive(Debug, Copy, Clone)]
+struct PairingParams {
+    /// The I/O capabilities of the device.
+    io: IoCapabilities,
+    /// Whether the device has OOB pairing data available.
+    oob: bool,
+    /// Authentication requirements of the device.
+    auth_req: AuthReq,
+    /// Maximum supported encryption key size in range 7..=16 Bytes.
+    ///
+    /// For BLE, this is always 16, since it always uses AES-128-CCM (even with the broken
+    /// *LE Legacy Pairing*). We consider anything smaller than 16 to be as insecure as a plain
+    /// text connection.
+    max_keysize: u8,
+    /// Set of keys the initiator (the device sending the request) distributes to the responder.
+    initiator_dist: KeyDistribution,
+    /// Set of keys the responder distributes to the initiator.
+    responder_dist: KeyDistribution,
+}
+
+impl PairingParams {
+    /// Encodes the parameters as a complete command with the given code, as needed by `c1`.
+    fn encode(&self, code: CommandCode) -> [u8; 7] {
+        let mut buf = [0; 7];
+        let mut writer = ByteWriter::new(&mut buf);
+        writer.write_u8(code.into()).unwrap();
+        self.to_bytes(&mut writer).unwrap();
+        buf
+    }
+
+    /// Returns the `IOcap` value used by `f6`, in on-air order.
+    fn io_cap(&self) -> [u8; 3] {
+        [self.io.into(), self.oob.into(), self.auth_req.0]
+    }
+}
+
+impl<'a> FromBytes<'a> for PairingParams {
+    fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
+        Ok(Self {
+            io: IoCapabilities::from(bytes.read_u8()?),
+            oob: bytes.read_u8()? == 0x01,
+            auth_req: AuthReq(bytes.read_u8()?),
+            max_keysize: bytes.read_u8()?,
+            initiator_dist: KeyDistribution::from_bits_truncate(bytes.read_u8()?),
+            responder_dist: KeyDistribution::from_bits_truncate(bytes.read_u8()?),
+        })
+    }
+}
+
+impl ToBytes for PairingParams {
+    fn to_bytes(&self, writer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        writer.write_u8(self.io.into())?;
+        writer.write_u8(self.oob.into())?;
+        writer.write_u8(self.auth_req.0)?;
+        writer.write_u8(self.max_keysize)?;
+        writer.write_u8(self.initiator_dist.bits())?;
+        writer.write_u8(self.responder_dist.bits())?;
+        Ok(())
+    }
+}
+
+/// An SMP command.
+#[derive(Debug, Copy, Clone)]
+enum Command<'a> {
+    /// `0x01` Pairing request
+    PairingRequest(PairingParams),
+    /// `0x02` Pairing response
+    PairingResponse(PairingParams),
+    /// `0x03` Pairing confirm, containing the sender's confirm value
+    PairingConfirm([u8; 16]),
+    /// `0x04` Pairing random, containing the random value used to compute the confirm value
+    PairingRandom([u8; 16]),
+    /// `0x05` Pairing failed
+    PairingFailed(PairingFailedReason),
+    /// `0x06` Encryption information, containing the distributed LTK
+    EncryptionInformation(Key),
+    /// `0x07` Master identification, containing the `EDIV` and `Rand` values identifying the LTK
+    MasterIdentification {
+        ediv: u16,
+        rand: [u8; 8],
+    },
+    /// `0x08` Identity information, containing the distributed IRK
+    IdentityInformation(Key),
+    /// `0x09` Identity address information, containing the sender's identity address
+    IdentityAddressInformation(DeviceAddress),
+    /// `0x0A` Signing information, containing the distributed CSRK
+    SigningInformation(Key),
+    /// `0x0C` Pairing public key, containing the X and Y coordinates of the sender's public key
+    PairingPublicKey {
+        x: [u8; 32],
+        y: [u8; 32],
+    },
+    /// `0x0D` Pairing DHKey check, containing the sender's check value
+    PairingDhKeyCheck([u8; 16]),
+    Unknown {
+        code: CommandCode,
+        data: &'a [u8],
+    },
+}
+
+impl Command<'_> {
+    fn code(&self) -> CommandCode {
+        match self {
+            Command::PairingRequest(_) => CommandCode::PairingRequest,
+            Command::PairingResponse(_) => CommandCode::PairingResponse,
+            Command::PairingConfirm(_) => CommandCode::PairingConfirm,
+            Command::PairingRandom(_) => CommandCode::PairingRandom,
+            Command::PairingFailed(_) => CommandCode::PairingFailed,
+            Command::EncryptionInformation(_) => CommandCode::EncryptionInformation,
+            Command::MasterIdentification { .. } => CommandCode::MasterIdentification,
+            Command::IdentityInformation(_) => CommandCode::IdentityInformation,
+            Command::IdentityAddressInformation(_) => CommandCode::IdentityAddressInformation,
+            Command::SigningInformation(_) => CommandCode::SigningInformation,
+            Command::PairingPublicKey { .. } => CommandCode::PairingPublicKey,
+            Command::PairingDhKeyCheck(_) => CommandCode::PairingDhKeyCheck,
+            Command::Unknown { code, .. } => *code,
+        }
+    }
+}
+
+impl<'a> FromBytes<'a> for Command<'a> {
+    fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
+        let code = CommandCode::from(bytes.read_u8()?);
+        Ok(match code {
+            CommandCode::PairingRequest => {
+                Command::PairingRequest(PairingParams::from_bytes(bytes)?)
+            }
+            CommandCode::PairingResponse => {
+                Command::PairingResponse(PairingParams::from_bytes(bytes)?)
+            }
+            CommandCode::PairingConfirm => Command::PairingConfirm(bytes.read_array()?),
+            CommandCode::PairingRandom => Command::PairingRandom(bytes.read_array()?),
+            CommandCode::PairingFailed => {
+                Command::PairingFailed(PairingFailedReason::from(bytes.read_u8()?))
+            }
+            CommandCode::EncryptionInformation => {
+                Command::EncryptionInformation(Key(bytes.read_array()?))
+            }
+            CommandCode::MasterIdentification => Command::MasterIdentification {
+                ediv: bytes.read_u16_le()?,
+                rand: bytes.read_array()?,
+            },
+            CommandCode::IdentityInformation => {
+                Command::IdentityInformation(Key(bytes.read_array()?))
+            }
+            CommandCode::IdentityAddressInformation => {
+                let kind = match bytes.read_u8()? {
+                    0x00 => AddressKind::Public,
+                    0x01 => AddressKind::Random,
+                    _ => return Err(Error::InvalidValue),
+                };
+                Command::IdentityAddressInformation(DeviceAddress::new(bytes.read_array()?, kind))
+            }
+            CommandCode::SigningInformation => {
+                Command::SigningInformation(Key(bytes.read_array()?))
+            }
+            CommandCode::PairingPublicKey => Command::PairingPublicKey {
+                x: bytes.read_array()?,
+                y: bytes.read_array()?,
+            },
+            CommandCode::PairingDhKeyCheck => Command::PairingDhKeyCheck(bytes.read_array()?),
+            _ => Command::Unknown {
+                code,
+                data: bytes.read_rest(),
+            },
+        })
+    }
+}
+
+impl ToBytes for Command<'_> {
+    fn to_bytes(&self, writer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        writer.write_u8(self.code().into())?;
+        match self {
+            Command::PairingRequest(params) | Command::PairingResponse(params) => {
+                params.to_bytes(writer)
+            }
+            Command::PairingConfirm(value)
+            | Command::PairingRandom(value)
+            | Command::PairingDhKeyCheck(value) => writer.write_slice(value),
+            Command::PairingPublicKey { x, y } => {
+                writer.write_slice(x)?;
+                writer.write_slice(y)
+            }
+            Command::PairingFailed(reason) => writer.write_u8((*reason).into()),
+            Command::EncryptionInformation(key)
+            | Command::IdentityInformation(key)
+            | Command::SigningInformation(key) => writer.write_slice(&key.0),
+            Command::MasterIdentification { ediv, rand } => {
+                writer.write_u16_le(*ediv)?;
+                writer.write_slice(rand)
+            }
+            Command::IdentityAddressInformation(addr) => {
+                writer.write_u8(if addr.is_random() { 0x01 } else { 0x00 })?;
+                writer.write_slice(addr.raw())
+            }
+            Command::Unknown { data, .. } => writer.write_slice(data),
+        }
+    }
+}
+
+enum_with_unknown! {
+    #[derive(Debug, Copy, Clone)]
+    enum CommandCode(u8) {
+        PairingRequest = 0x01,
+        PairingResponse = 0x02,
+        PairingConfirm = 0x03,
+        PairingRandom = 0x04,
+        PairingFailed = 0x05,
+        EncryptionInformation = 0x06,
+        MasterIdentification = 0x07,
+        IdentityInformation = 0x08,
+        IdentityAddressInformation = 0x09,
+        SigningInformation = 0x0A,
+        SecurityRequest = 0x0B,
+        PairingPublicKey = 0x0C,
+        PairingDhKeyCheck = 0x0D,
+        PairingKeypressNotification = 0x0E,
+    }
+}
+
+enum_with_unknown! {
+    /// Reason for a pairing failure, sent in the *Pairing Failed* command.
+    #[derive(Debug, Copy, Clone)]
+    enum PairingFailedReason(u8) {
+        /// The user input of the passkey failed or was canceled.
+        PasskeyEntryFailed = 0x01,
+        /// OOB data is not available.
+        OobNotAvailable = 0x02,
+        /// The available I/O capabilities don't allow the required authentication.
+        AuthenticationRequirements = 0x03,
+        /// The confirm value does not match the calculated value.
+        ConfirmValueFailed = 0x04,
+        /// Pairing is not supported by the device.
+        PairingNotSupported = 0x05,
+        /// The resultant encryption key size is insufficient.
+        EncryptionKeySize = 0x06,
+        /// The SMP command received is not supported.
+        CommandNotSupported = 0x07,
+        /// Pairing failed due to an unspecified reason.
+        UnspecifiedReason = 0x08,
+        /// Pairing was attempted too often.
+        RepeatedAttempts = 0x09,
+        /// The command length or a parameter is invalid.
+        InvalidParameters = 0x0A,
+        /// The DHKey Check value does not match the calculated value.
+        DhKeyCheckFailed = 0x0B,
+        /// The confirm values in the *Numeric Comparison* protocol do not match.
+        NumericComparisonFailed = 0x0C,
+    }
+}
+
+enum_with_unknown! {
+    /// Describes the I/O capabilities of a device that can be used for the pairing process.
+    #[derive(Debug, Copy, Clone)]
+    pub enum IoCapabilities(u8) {
+        /// Device can display a 6-digit number, but has no input capabilities.
+        DisplayOnly = 0x00,
+
+        /// Device can display a 6-digit number and the user can input "Yes" or "No".
+        DisplayYesNo = 0x01,
+
+        /// Device does not have output capability, but the user can input a passcode.
+        KeyboardOnly = 0x02,
+
+        /// Device has no meaningful input and output capabilities.
+        NoInputNoOutput = 0x03,
+
+        /// Device can display a 6-digit passcode and allows passcode entry via a keyboard.
+        KeyboardDisplay = 0x04,
+    }
+}
+
+/// Authentication requirements exchanged during pairing requests.
+#[derive(Copy, Clone)]
+pub struct AuthReq(u8);
+
+impl AuthReq {
+    const BITS_BONDING: u8 = 0b0000_0011;
+    const BITS_MITM: u8 = 0b0000_0100;
+    const BITS_SC: u8 = 0b0000_1000;
+    const BITS_KEYPRESS: u8 = 0b0001_0000;
+
+    /// Returns the requested bonding.
+    pub fn bonding_type(&self) -> BondingType {
+        BondingType::from(self.0 & Self::BITS_BONDING)
+    }
+
+    pub fn set_bonding_type(&mut self, ty: BondingType) {
+        self.0 = (self.0 & !Self::BITS_BONDING) | u8::from(ty);
+    }
+
+    /// Returns whether MITM protection is requested.
+    pub fn mitm(&self) -> bool {
+        self.0 & Self::BITS_MITM != 0
+    }
+
+    pub fn set_mitm(&mut self, mitm: bool) {
+        self.0 = (self.0 & !Self::BITS_MITM) | if mitm { Self::BITS_MITM } else { 0 };
+    }
+
+    /// Returns whether *LE Secure Connection* pairing is supported and requested.
+    ///
+    /// If this returns `false`, *LE Legacy Pairing* will be used. Note that *LE Legacy Pairing* has
+    /// serious security problems (refer to the module docs for more info).
+    pub fn secure_connection(&self) -> bool {
+        self.0 & Self::BITS_SC != 0
+    }
+
+    /// Sets whether *LE Secure Connection* pairing is supported and requested.
+    pub fn set_secure_connection(&mut self, sc: bool) {
+        self.0 = (self.0 & !Self::BITS_SC) | if sc { Self::BITS_SC } else { 0 };
+    }
+
+    pub fn keypress(&self) -> bool {
+        self.0 & Self::BITS_KEYPRESS != 0
+    }
+
+    pub fn set_keypress(&mut self, keypress: bool) {
+        self.0 = (self.0 & !Self::BITS_KEYPRESS) | if keypress { Self::BITS_KEYPRESS } else { 0 };
+    }
+}
+
+impl fmt::Debug for AuthReq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthReq")
+            .field("bonding_type", &self.bonding_type())
+            .field("mitm", &self.mitm())
+            .field("secure_connection", &self.secure_connection())
+            .field("keypress", &self.keypress())
+            .finish()
+    }
+}
+
+enum_with_unknown! {
+    /// Whether to perform bonding in addition to pairing.
+    ///
+    /// If `Bonding` is selected, the exchanged keys are permanently stored on both devices. This
+    /// is usually what you want.
+    #[derive(Debug, Copy, Clone)]
+    pub enum BondingType(u8) {
+        /// No bonding should be performed; the exchanged keys should not be permanently stored.
+        ///
+        /// This is usually not what you want since it requires the user to perform pairing every
+        /// time the devices connect again.
+        NoBonding = 0b00,
+
+        /// Permanently store the exchanged keys to allow resuming encryption on future connections.
+        Bonding = 0b01,
+    }
+}
+
+bitflags! {
+    /// Indicates which types of keys a device requests for distribution.
+    pub struct KeyDistribution: u8 {
+        const ENC_KEY = (1 << 0);
+        const ID_KEY = (1 << 1);
+        const SIGN_KEY = (1 << 2);
+        const LINK_KEY = (1 << 3);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SoftAesProvider;
+    use crate::ecdh::P256Provider;
+    use crate::utils::CountingRng;
+
+    #[test]
+    fn just_works() {
+        let local = DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Random);
+        let peer = DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Public);
+        let mut sm =
+            SecurityManager::new(LegacyPairing::new(SoftAesProvider::new(), CountingRng(0)));
+        sm.connection_established(local, peer);
+
+        let req = PairingParams {
+            io: IoCapabilities::NoInputNoOutput,
+            oob: false,
+            auth_req: AuthReq(0),
+            max_keysize: 16,
+            initiator_dist: KeyDistribution::empty(),
+            responder_dist: KeyDistribution::empty(),
+        };
+        let rsp = match sm.process_command(Command::PairingRequest(req)) {
+            Ok(Some(Command::PairingResponse(rsp))) => rsp,
+            other => panic!("unexpected response {:?}", other),
+        };
+        let preq = req.encode(CommandCode::PairingRequest);
+        let pres = rsp.encode(CommandCode::PairingResponse);
+
+        // Act as the master, using TK = 0.
+        let aes = &mut SoftAesProvider::new();
+        let tk = Key([0; 16]);
+        let mrand = [0xAB; 16];
+        let mconfirm = c1(aes, &tk, &mrand, &preq, &pres, &peer, &local);
+        let sconfirm = match sm.process_command(Command::PairingConfirm(mconfirm)) {
+            Ok(Some(Command::PairingConfirm(sconfirm))) => sconfirm,
+            other => panic!("unexpected response {:?}", other),
+        };
+        let srand = match sm.process_command(Command::PairingRandom(mrand)) {
+            Ok(Some(Command::PairingRandom(srand))) => srand,
+            other => panic!("unexpected response {:?}", other),
+        };
+        assert_eq!(sconfirm, c1(aes, &tk, &srand, &preq, &pres, &peer, &local));
+
+        assert!(sm.is_paired());
+        let stk = s1(aes, &tk, &srand, &mrand);
+        let ltk = sm.long_term_key(&LtkRequest::new([0; 8], 0));
+        assert_eq!(ltk, Some(stk));
+    }
+
+    #[test]
+    fn wrong_confirm_value() {
+        let local = DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Random);
+        let peer = DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Public);
+        let mut sm =
+            SecurityManager::new(LegacyPairing::new(SoftAesProvider::new(), CountingRng(0)));
+        sm.connection_established(local, peer);
+
+        let req = PairingParams {
+            io: IoCapabilities::NoInputNoOutput,
+            oob: false,
+            auth_req: AuthReq(0),
+            max_keysize: 16,
+            initiator_dist: KeyDistribution::empty(),
+            responder_dist: KeyDistribution::empty(),
+        };
+        sm.process_command(Command::PairingRequest(req)).unwrap();
+        sm.process_command(Command::PairingConfirm([0; 16]))
+            .unwrap();
+        match sm.process_command(Command::PairingRandom([0xAB; 16])) {
+            Err(PairingFailedReason::ConfirmValueFailed) => {}
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn secure_connections_numeric_comparison() {
+        let local = DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Random);
+        let peer = DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Public);
+        let mut sm = SecurityManager::new(SecureConnections::new(
+            SoftAesProvider::new(),
+            P256Provider::new(),
+            CountingRng(0),
+        ));
+        sm.set_io_capabilities(IoCapabilities::DisplayYesNo);
+        sm.connection_established(local, peer);
+
+        let mut auth_req = AuthReq(0);
+        auth_req.set_mitm(true);
+        auth_req.set_secure_connection(true);
+        let req = PairingParams {
+            io: IoCapabilities::DisplayYesNo,
+            oob: false,
+            auth_req,
+            max_keysize: 16,
+            initiator_dist: KeyDistribution::empty(),
+            responder_dist: KeyDistribution::empty(),
+        };
+        let rsp = match sm.process_command(Command::PairingRequest(req)) {
+            Ok(Some(Command::PairingResponse(rsp))) => rsp,
+            other => panic!("unexpected response {:?}", other),
+        };
+        assert!(rsp.auth_req.secure_connection());
+
+        // Act as the master and exchange public keys.
+        let (secret, public) = P256Provider::new().generate_keypair(&mut CountingRng(100));
+        let mut pka_x = [0; 32];
+        let mut pka_y = [0; 32];
+        pka_x.copy_from_slice(&public.0[..32]);
+        pka_y.copy_from_slice(&public.0[32..]);
+        let (pka_x, pka_y) = (reversed(&pka_x), reversed(&pka_y));
+        let (pkb_x, pkb_y) =
+            match sm.process_command(Command::PairingPublicKey { x: pka_x, y: pka_y }) {
+                Ok(Some(Command::PairingPublicKey { x, y })) => (x, y),
+                other => panic!("unexpected response {:?}", other),
+            };
+        let cb = match sm.pending.take() {
+            Some(Command::PairingConfirm(cb)) => cb,
+            other => panic!("unexpected pending command {:?}", other),
+        };
+        let mut foreign = PublicKey([0; 64]);
+        foreign.0[..32].copy_from_slice(&reversed(&pkb_x));
+        foreign.0[32..].copy_from_slice(&reversed(&pkb_y));
+        let dhkey = reversed(&secret.agree(&foreign).unwrap().0);
+
+        let aes = &mut SoftAesProvider::new();
+        let na = [0x55; 16];
+        let nb = match sm.process_command(Command::PairingRandom(na)) {
+            Ok(Some(Command::PairingRandom(nb))) => nb,
+            other => panic!("unexpected response {:?}", other),
+        };
+        assert_eq!(cb, f4(aes, &pkb_x, &pka_x, &nb, 0));
+        assert_eq!(
+            sm.numeric_comparison(),
+            Some(g2(aes, &pka_x, &pkb_x, &na, &nb))
+        );
+
+        // Our check value is held back until the user confirms the values match.
+        let (mac_key, ltk) = f5(aes, &dhkey, &na, &nb, &peer, &local);
+        let ea = f6(
+            aes,
+            &mac_key,
+            &na,
+            &nb,
+            &[0; 16],
+            &req.io_cap(),
+            &peer,
+            &local,
+        );
+        match sm.process_command(Command::PairingDhKeyCheck(ea)) {
+            Ok(None) => {}
+            other => panic!("unexpected response {:?}", other),
+        }
+        if let State::Sc(sc) = &mut sm.state {
+            sc.confirmed = true;
+        }
+        let eb = match sm.sc_check() {
+            Ok(Some(Command::PairingDhKeyCheck(eb))) => eb,
+            other => panic!("unexpected response {:?}", other),
+        };
+        assert_eq!(
+            eb,
+            f6(
+                aes,
+                &mac_key,
+                &nb,
+                &na,
+                &[0; 16],
+                &rsp.io_cap(),
+                &local,
+                &peer
+            )
+        );
+
+        assert!(sm.is_paired());
+        let ltk_req = LtkRequest::new([0; 8], 0);
+        assert_eq!(sm.long_term_key(&ltk_req), Some(ltk));
+    }
+
+    #[test]
+    fn secure_connections_bonding_without_keys() {
+        let local = DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Random);
+        let peer = DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Public);
+        let bonds = MemoryBondStore::<heapless::consts::U2>::new();
+        let mut sm = SecurityManager::new(SecureConnections::with_bond_store(
+            SoftAesProvider::new(),
+            P256Provider::new(),
+            CountingRng(0),
+            bonds,
+        ));
+        sm.connection_established(local, peer);
+
+        // Just Works pairing with bonding, but without any distributed keys.
+        let mut auth_req = AuthReq(0);
+        auth_req.set_bonding_type(BondingType::Bonding);
+        auth_req.set_secure_connection(true);
+        let req = PairingParams {
+            io: IoCapabilities::NoInputNoOutput,
+            oob: false,
+            auth_req,
+            max_keysize: 16,
+            initiator_dist: KeyDistribution::empty(),
+            responder_dist: KeyDistribution::empty(),
+        };
+        let rsp = match sm.process_command(Command::PairingRequest(req)) {
+            Ok(Some(Command::PairingResponse(rsp))) => rsp,
+            other => panic!("unexpected response {:?}", other),
+        };
+        assert!(rsp.auth_req.secure_connection());
+
+        let (secret, public) = P256Provider::new().generate_keypair(&mut CountingRng(100));
+        let mut pka_x = [0; 32];
+        let mut pka_y = [0; 32];
+        pka_x.copy_from_slice(&public.0[..32]);
+        pka_y.copy_from_slice(&public.0[32..]);
+        let (pka_x, pka_y) = (reversed(&pka_x), reversed(&pka_y));
+        let (pkb_x, pkb_y) =
+            match sm.process_command(Command::PairingPublicKey { x: pka_x, y: pka_y }) {
+                Ok(Some(Command::PairingPublicKey { x, y })) => (x, y),
+                other => panic!("unexpected response {:?}", other),
+            };
+        sm.pending.take();
+        let mut foreign = PublicKey([0; 64]);
+        foreign.0[..32].copy_from_slice(&reversed(&pkb_x));
+        foreign.0[32..].copy_from_slice(&reversed(&pkb_y));
+        let dhkey = reversed(&secret.agree(&foreign).unwrap().0);
+
+        let aes = &mut SoftAesProvider::new();
+        let na = [0x55; 16];
+        let nb = match sm.process_command(Command::PairingRandom(na)) {
+            Ok(Some(Command::PairingRandom(nb))) => nb,
+            other => panic!("unexpected response {:?}", other),
+        };
+        let (mac_key, ltk) = f5(aes, &dhkey, &na, &nb, &peer, &local);
+        let ea = f6(
+            aes,
+            &mac_key,
+            &na,
+            &nb,
+            &[0; 16],
+            &req.io_cap(),
+            &peer,
+            &local,
+        );
+        match sm.process_command(Command::PairingDhKeyCheck(ea)) {
+            Ok(Some(Command::PairingDhKeyCheck(_))) => {}
+            other => panic!("unexpected response {:?}", other),
+        }
+
+        assert!(sm.is_paired());
+        assert!(!sm.pairing_in_progress());
+        let bond = sm.security.bond_store().unwrap().get(0).unwrap();
+        assert_eq!(bond.identity, peer);
+        assert_eq!(bond.ltk.map(|ltk| ltk.key), Some(ltk));
+    }
+
+    #[test]
+    fn bonding() {
+        let local = DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Random);
+        let peer = DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Public);
+        let bonds = MemoryBondStore::<heapless::consts::U2>::new();
+        let mut sm = SecurityManager::new(LegacyPairing::with_bond_store(
+            SoftAesProvider::new(),
+            CountingRng(0),
+            bonds,
+        ));
+        sm.connection_established(local, peer);
+
+        let mut auth_req = AuthReq(0);
+        auth_req.set_bonding_type(BondingType::Bonding);
+        let req = PairingParams {
+            io: IoCapabilities::NoInputNoOutput,
+            oob: false,
+            auth_req,
+            max_keysize: 16,
+            initiator_dist: KeyDistribution::ID_KEY | KeyDistribution::LINK_KEY,
+            responder_dist: KeyDistribution::ENC_KEY | KeyDistribution::ID_KEY,
+        };
+        let rsp = match sm.process_command(Command::PairingRequest(req)) {
+            Ok(Some(Command::PairingResponse(rsp))) => rsp,
+            other => panic!("unexpected response {:?}", other),
+        };
+        assert_eq!(rsp.initiator_dist, KeyDistribution::ID_KEY);
+        assert_eq!(rsp.responder_dist, KeyDistribution::ENC_KEY);
+
+        let preq = req.encode(CommandCode::PairingRequest);
+        let pres = rsp.encode(CommandCode::PairingResponse);
+        let aes = &mut SoftAesProvider::new();
+        let mconfirm = c1(aes, &Key([0; 16]), &[0xAB; 16], &preq, &pres, &peer, &local);
+        sm.process_command(Command::PairingConfirm(mconfirm))
+            .unwrap();
+        sm.process_command(Command::PairingRandom([0xAB; 16]))
+            .unwrap();
+        assert!(sm.is_paired());
+
+        // Keys are only distributed once the connection is encrypted.
+        assert!(!sm.has_pending());
+        sm.set_encrypted(true);
+        let key = match sm.next_pending() {
+            Some(Command::EncryptionInformation(key)) => key,
+            other => panic!("unexpected command {:?}", other),
+        };
+        let (ediv, rand) = match sm.next_pending() {
+            Some(Command::MasterIdentification { ediv, rand }) => (ediv, rand),
+            other => panic!("unexpected command {:?}", other),
+        };
+        assert!(!sm.has_pending());
+
+        // The master distributes its IRK and identity address.
+        let irk = Key([0x42; 16]);
+        let identity = DeviceAddress::new([0x11; 6], AddressKind::Public);
+        sm.process_command(Command::IdentityInformation(irk))
+            .unwrap();
+        assert!(sm.security.bond_store().unwrap().get(0).is_none());
+        sm.process_command(Command::IdentityAddressInformation(identity))
+            .unwrap();
+        let bond = sm.security.bond_store().unwrap().get(0).unwrap();
+        assert_eq!(bond.identity, identity);
+        assert_eq!(bond.irk, Some(irk));
+        assert_eq!(bond.ltk, Some(LongTermKey { key, ediv, rand }));
+
+        // The master reconnects using a resolvable private address.
+        let prand = [0x12, 0x34, 0x56];
+        let hash = super::toolbox::ah(aes, &irk, &prand);
+        let mut raw = [0; 6];
+        raw[..3].copy_from_slice(&hash);
+        raw[3..].copy_from_slice(&prand);
+        let rpa = DeviceAddress::new(raw, AddressKind::Random);
+        assert!(rpa.is_resolvable_private());
+        sm.connection_established(local, rpa);
+
+        assert_eq!(sm.peer_bond(), Some(bond));
+        assert_eq!(sm.long_term_key(&LtkRequest::new(rand, ediv)), Some(key));
+        assert_eq!(sm.long_term_key(&LtkRequest::new([0; 8], 0)), None);
+    }
+}
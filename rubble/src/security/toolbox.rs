@@ -0,0 +1,449 @@
+//! The cryptographic toolbox used by the Security Manager.
+//!
+//! All values are in the byte order used on the air (least significant octet first), while the
+//! specification writes them most significant octet first.
+//!
+//! *LE Legacy Pairing* uses `c1` and `s1`, which are built on the security function `e`.
+//! *LE Secure Connections* uses `f4`, `f5`, `f6` and `g2`, which are built on AES-CMAC.
+
+use crate::crypto::{e, AesProvider, Key};
+use crate::link::{AddressKind, DeviceAddress};
+
+/// The confirm value generation function `c1` used by *LE Legacy Pairing*.
+///
+/// # Parameters
+///
+/// * **`k`**: The Temporary Key (TK).
+/// * **`r`**: The random value (`Mrand` or `Srand`) to confirm.
+/// * **`preq`**: The encoded *Pairing Request* command, including the command code.
+/// * **`pres`**: The encoded *Pairing Response* command, including the command code.
+/// * **`ia`**: The address of the initiating device (master).
+/// * **`ra`**: The address of the responding device (slave).
+pub fn c1<A: AesProvider + ?Sized>(
+    aes: &mut A,
+    k: &Key,
+    r: &[u8; 16],
+    preq: &[u8; 7],
+    pres: &[u8; 7],
+    ia: &DeviceAddress,
+    ra: &DeviceAddress,
+) -> [u8; 16] {
+    // p1 = pres || preq || rat' || iat'
+    let mut p1 = [0; 16];
+    p1[0] = address_type(ia);
+    p1[1] = address_type(ra);
+    p1[2..9].copy_from_slice(preq);
+    p1[9..].copy_from_slice(pres);
+
+    // p2 = padding || ia || ra
+    let mut p2 = [0; 16];
+    p2[..6].copy_from_slice(ra.raw());
+    p2[6..12].copy_from_slice(ia.raw());
+
+    let block = e(aes, k, xor(r, &p1));
+    e(aes, k, xor(&block, &p2))
+}
+
+/// The key generation function `s1` used to derive the Short Term Key (STK) in *LE Legacy Pairing*.
+///
+/// The STK is `s1(TK, Srand, Mrand)`.
+pub fn s1<A: AesProvider + ?Sized>(aes: &mut A, k: &Key, r1: &[u8; 16], r2: &[u8; 16]) -> Key {
+    // r' = r1' || r2', using the least significant 64 bits of each.
+    let mut r = [0; 16];
+    r[..8].copy_from_slice(&r2[..8]);
+    r[8..].copy_from_slice(&r1[..8]);
+    Key(e(aes, k, r))
+}
+
+/// The random address hash function `ah`, used to generate and resolve private addresses.
+///
+/// Returns the 24-bit hash of `r` using the Identity Resolving Key `k`.
+pub fn ah<A: AesProvider + ?Sized>(aes: &mut A, k: &Key, r: &[u8; 3]) -> [u8; 3] {
+    let mut r_ = [0; 16];
+    r_[..3].copy_from_slice(r);
+    let block = e(aes, k, r_);
+    let mut hash = [0; 3];
+    hash.copy_from_slice(&block[..3]);
+    hash
+}
+
+/// The confirm value generation function `f4` used by *LE Secure Connections*.
+///
+/// # Parameters
+///
+/// * **`u`**, **`v`**: X coordinates of public keys.
+/// * **`x`**: The nonce to confirm.
+/// * **`z`**: `0` for *Just Works* and *Numeric Comparison*, `0x80` or `0x81` for *Passkey Entry*.
+pub fn f4<A: AesProvider + ?Sized>(
+    aes: &mut A,
+    u: &[u8; 32],
+    v: &[u8; 32],
+    x: &[u8; 16],
+    z: u8,
+) -> [u8; 16] {
+    let mut buf = [0; 65];
+    let msg = concat(&mut buf, &[u, v, &[z]]);
+    aes_cmac(aes, x, msg)
+}
+
+/// The key generation function `f5` used by *LE Secure Connections*.
+///
+/// Returns the `MacKey` used to compute DHKey check values, and the Long Term Key.
+///
+/// # Parameters
+///
+/// * **`w`**: The shared secret (DHKey).
+/// * **`n1`**, **`n2`**: The nonces of initiator and responder.
+/// * **`a1`**, **`a2`**: The addresses of initiator and responder.
+pub fn f5<A: AesProvider + ?Sized>(
+    aes: &mut A,
+    w: &[u8; 32],
+    n1: &[u8; 16],
+    n2: &[u8; 16],
+    a1: &DeviceAddress,
+    a2: &DeviceAddress,
+) -> (Key, Key) {
+    // SALT and keyID are given most significant octet first already.
+    const SALT: [u8; 16] = [
+        0x6C, 0x88, 0x83, 0x91, 0xAA, 0xF5, 0xA5, 0x38, 0x60, 0x37, 0x0B, 0xDB, 0x5A, 0x60, 0x83,
+        0xBE,
+    ];
+    const KEY_ID: [u8; 4] = [0x62, 0x74, 0x6c, 0x65];
+
+    let mut buf = [0; 32];
+    let t = cmac_raw(aes, &SALT, concat(&mut buf, &[w]));
+
+    let (a1, a2) = (address(a1), address(a2));
+    let mut buf = [0; 53];
+    let mut derive = |counter: u8| {
+        let msg = concat(
+            &mut buf,
+            &[&[counter], &reversed(&KEY_ID), n1, n2, &a1, &a2, &[0, 1]],
+        );
+        reversed(&cmac_raw(aes, &t, msg))
+    };
+    let mac_key = derive(0);
+    let ltk = derive(1);
+    (Key(mac_key), Key(ltk))
+}
+
+/// The check value generation function `f6` used by *LE Secure Connections*.
+///
+/// # Parameters
+///
+/// * **`w`**: The `MacKey` returned by `f5`.
+/// * **`n1`**, **`n2`**: Nonces.
+/// * **`r`**: The passkey for *Passkey Entry*, 0 for *Just Works* and *Numeric Comparison*.
+/// * **`io_cap`**: I/O capabilities, OOB flag and authentication requirements of the device
+///   computing the check value, in the order they appear in the pairing command.
+/// * **`a1`**, **`a2`**: Addresses.
+#[allow(clippy::too_many_arguments)]
+pub fn f6<A: AesProvider + ?Sized>(
+    aes: &mut A,
+    w: &Key,
+    n1: &[u8; 16],
+    n2: &[u8; 16],
+    r: &[u8; 16],
+    io_cap: &[u8; 3],
+    a1: &DeviceAddress,
+    a2: &DeviceAddress,
+) -> [u8; 16] {
+    let (a1, a2) = (address(a1), address(a2));
+    let mut buf = [0; 65];
+    let msg = concat(&mut buf, &[n1, n2, r, io_cap, &a1, &a2]);
+    aes_cmac(aes, &w.0, msg)
+}
+
+/// The numeric comparison value generation function `g2` used by *LE Secure Connections*.
+///
+/// Returns the 6-digit value to display to the user.
+///
+/// # Parameters
+///
+/// * **`u`**, **`v`**: X coordinates of the initiator's and the responder's public key.
+/// * **`x`**, **`y`**: Nonces of initiator and responder.
+pub fn g2<A: AesProvider + ?Sized>(
+    aes: &mut A,
+    u: &[u8; 32],
+    v: &[u8; 32],
+    x: &[u8; 16],
+    y: &[u8; 16],
+) -> u32 {
+    let mut buf = [0; 80];
+    let msg = concat(&mut buf, &[u, v, y]);
+    let mac = aes_cmac(aes, x, msg);
+    let mut low = [0; 4];
+    low.copy_from_slice(&mac[..4]);
+    u32::from_le_bytes(low) % 1_000_000
+}
+
+/// Computes AES-CMAC (RFC 4493) with a key in on-air order, returning the MAC in on-air order.
+///
+/// `msg` must already be in the order used by AES-CMAC (see `concat`).
+fn aes_cmac<A: AesProvider + ?Sized>(aes: &mut A, key: &[u8; 16], msg: &[u8]) -> [u8; 16] {
+    reversed(&cmac_raw(aes, &reversed(key), msg))
+}
+
+/// Computes AES-CMAC as specified in RFC 4493 (most significant octet first).
+fn cmac_raw<A: AesProvider + ?Sized>(aes: &mut A, key: &[u8; 16], msg: &[u8]) -> [u8; 16] {
+    // Subkey generation
+    let mut l = [0; 16];
+    aes.aes128_encrypt(key, &mut l);
+    let k1 = double(&l);
+    let k2 = double(&k1);
+
+    let blocks = if msg.is_empty() {
+        1
+    } else {
+        msg.len().div_ceil(16)
+    };
+    let (head, last) = msg.split_at((blocks - 1) * 16);
+
+    let mut x = [0; 16];
+    for block in head.chunks(16) {
+        for (x, b) in x.iter_mut().zip(block) {
+            *x ^= b;
+        }
+        aes.aes128_encrypt(key, &mut x);
+    }
+
+    // The last block is padded if it is incomplete, and masked with a subkey.
+    let mut m = [0; 16];
+    m[..last.len()].copy_from_slice(last);
+    let subkey = if last.len() == 16 {
+        k1
+    } else {
+        m[last.len()] = 0x80;
+        k2
+    };
+    let mut x = xor(&x, &xor(&m, &subkey));
+    aes.aes128_encrypt(key, &mut x);
+    x
+}
+
+/// Multiplies `block` by `x` in GF(2^128), as needed for CMAC subkey generation.
+fn double(block: &[u8; 16]) -> [u8; 16] {
+    let mut out = [0; 16];
+    for i in 0..16 {
+        let carry = block.get(i + 1).map_or(0, |b| b >> 7);
+        out[i] = (block[i] << 1) | carry;
+    }
+    if block[0] & 0x80 != 0 {
+        out[15] ^= 0x87;
+    }
+    out
+}
+
+/// Concatenates `parts`, given in on-air order, into the order used by AES-CMAC.
+///
+/// The first part ends up in the most significant octets, and each part is reversed.
+fn concat<'a>(buf: &'a mut [u8], parts: &[&[u8]]) -> &'a [u8] {
+    let mut pos = 0;
+    for part in parts {
+        for (dest, src) in buf[pos..pos + part.len()].iter_mut().zip(part.iter().rev()) {
+            *dest = *src;
+        }
+        pos += part.len();
+    }
+    &buf[..pos]
+}
+
+/// Returns the 56-bit representation of `addr` used by `f5` and `f6` (address type in the most
+/// significant octet).
+fn address(addr: &DeviceAddress) -> [u8; 7] {
+    let mut out = [0; 7];
+    out[..6].copy_from_slice(addr.raw());
+    out[6] = address_type(addr);
+    out
+}
+
+/// Reverses the byte order of `bytes`.
+pub fn reversed<T: Default + AsMut<[u8]> + AsRef<[u8]>>(bytes: &T) -> T {
+    let mut out = T::default();
+    for (o, b) in out.as_mut().iter_mut().zip(bytes.as_ref().iter().rev()) {
+        *o = *b;
+    }
+    out
+}
+
+fn address_type(addr: &DeviceAddress) -> u8 {
+    match addr.kind() {
+        AddressKind::Public => 0,
+        AddressKind::Random => 1,
+    }
+}
+
+fn xor(a: &[u8; 16], b: &[u8; 16]) -> [u8; 16] {
+    let mut out = [0; 16];
+    for (o, (a, b)) in out.iter_mut().zip(a.iter().zip(b)) {
+        *o = a ^ b;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SoftAesProvider;
+
+    /// Converts a hex string (most significant octet first, as in the spec) to bytes in on-air
+    /// order.
+    fn bytes<T: Default + AsMut<[u8]>>(hex: &str) -> T {
+        let mut out = T::default();
+        let buf = out.as_mut();
+        assert_eq!(hex.len(), buf.len() * 2);
+        for (i, b) in buf.iter_mut().rev().enumerate() {
+            *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn c1_sample_data() {
+        let ia = DeviceAddress::new(bytes("a1a2a3a4a5a6"), AddressKind::Random);
+        let ra = DeviceAddress::new(bytes("b1b2b3b4b5b6"), AddressKind::Public);
+        let confirm = c1(
+            &mut SoftAesProvider::new(),
+            &Key([0; 16]),
+            &bytes("5783d52156ad6f0e6388274ec6702ee0"),
+            &bytes("07071000000101"),
+            &bytes("05000800000302"),
+            &ia,
+            &ra,
+        );
+        assert_eq!(
+            confirm,
+            bytes::<[u8; 16]>("1e1e3fef878988ead2a74dc5bef13b86")
+        );
+    }
+
+    #[test]
+    fn s1_sample_data() {
+        let stk = s1(
+            &mut SoftAesProvider::new(),
+            &Key([0; 16]),
+            &bytes("000f0e0d0c0b0a091122334455667788"),
+            &bytes("010203040506070899aabbccddeeff00"),
+        );
+        assert_eq!(stk.0, bytes::<[u8; 16]>("9a1fe1f0e8b0f49b5b4216ae796da062"));
+    }
+
+    #[test]
+    fn ah_sample_data() {
+        let hash = ah(
+            &mut SoftAesProvider::new(),
+            &Key(bytes("ec0234a357c8ad05341010a60a397d9b")),
+            &bytes("708194"),
+        );
+        assert_eq!(hash, bytes::<[u8; 3]>("0dfbaa"));
+    }
+
+    #[test]
+    fn aes_cmac_rfc4493() {
+        let aes = &mut SoftAesProvider::new();
+        let key = [
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+            0x4f, 0x3c,
+        ];
+        let msg = [
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93,
+            0x17, 0x2a, 0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac,
+            0x45, 0xaf, 0x8e, 0x51, 0x30, 0xc8, 0x1c, 0x46, 0xa3, 0x5c, 0xe4, 0x11,
+        ];
+
+        assert_eq!(
+            cmac_raw(aes, &key, &[]),
+            [
+                0xbb, 0x1d, 0x69, 0x29, 0xe9, 0x59, 0x37, 0x28, 0x7f, 0xa3, 0x7d, 0x12, 0x9b, 0x75,
+                0x67, 0x46
+            ]
+        );
+        assert_eq!(
+            cmac_raw(aes, &key, &msg[..16]),
+            [
+                0x07, 0x0a, 0x16, 0xb4, 0x6b, 0x4d, 0x41, 0x44, 0xf7, 0x9b, 0xdd, 0x9d, 0xd0, 0x4a,
+                0x28, 0x7c
+            ]
+        );
+        assert_eq!(
+            cmac_raw(aes, &key, &msg),
+            [
+                0xdf, 0xa6, 0x67, 0x47, 0xde, 0x9a, 0xe6, 0x30, 0x30, 0xca, 0x32, 0x61, 0x14, 0x97,
+                0xc8, 0x27
+            ]
+        );
+    }
+
+    const U: &str = "20b003d2f297be2c5e2c83a7e9f9a5b9eff49111acf4fddbcc0301480e359de6";
+    const V: &str = "55188b3d32f6bb9a900afcfbeed4e72a59cb9ac2f19d7cfb6b4fdd49f47fc5fd";
+    const N1: &str = "d5cb8454d177733effffb2ec712baeab";
+    const N2: &str = "a6e8e7cc25a75f6e216583f7ff3dc4cf";
+
+    fn addresses() -> (DeviceAddress, DeviceAddress) {
+        (
+            DeviceAddress::new(bytes("56123737bfce"), AddressKind::Public),
+            DeviceAddress::new(bytes("a713702dcfc1"), AddressKind::Public),
+        )
+    }
+
+    #[test]
+    fn f4_sample_data() {
+        let confirm = f4(
+            &mut SoftAesProvider::new(),
+            &bytes(U),
+            &bytes(V),
+            &bytes(N1),
+            0,
+        );
+        assert_eq!(
+            confirm,
+            bytes::<[u8; 16]>("f2c916f107a9bd1cf1eda1bea974872d")
+        );
+    }
+
+    #[test]
+    fn f5_sample_data() {
+        let (a1, a2) = addresses();
+        let (mac_key, ltk) = f5(
+            &mut SoftAesProvider::new(),
+            &bytes("ec0234a357c8ad05341010a60a397d9b99796b13b4f866f1868d34f373bfa698"),
+            &bytes(N1),
+            &bytes(N2),
+            &a1,
+            &a2,
+        );
+        assert_eq!(
+            mac_key.0,
+            bytes::<[u8; 16]>("2965f176a1084a02fd3f6a20ce636e20")
+        );
+        assert_eq!(ltk.0, bytes::<[u8; 16]>("6986791169d7cd23980522b594750a38"));
+    }
+
+    #[test]
+    fn f6_sample_data() {
+        let (a1, a2) = addresses();
+        let check = f6(
+            &mut SoftAesProvider::new(),
+            &Key(bytes("2965f176a1084a02fd3f6a20ce636e20")),
+            &bytes(N1),
+            &bytes(N2),
+            &bytes("12a3343bb453bb5408da42d20c2d0fc8"),
+            &bytes("010102"),
+            &a1,
+            &a2,
+        );
+        assert_eq!(check, bytes::<[u8; 16]>("e3c473989cd0e8c5d26c0b09da958f61"));
+    }
+
+    #[test]
+    fn g2_sample_data() {
+        let value = g2(
+            &mut SoftAesProvider::new(),
+            &bytes(U),
+            &bytes(V),
+            &bytes(N1),
+            &bytes(N2),
+        );
+        assert_eq!(value, 0x2f9e_d5ba % 1_000_000);
+    }
+}
@@ -18,7 +18,7 @@ impl Duration {
     pub const T_IFS: Self = Duration(150);
 
     /// Creates a `Duration` from a number of microseconds.
-    pub fn from_micros(micros: u32) -> Self {
+    pub const fn from_micros(micros: u32) -> Self {
         Duration(micros)
     }
 
@@ -1,4 +1,7 @@
 use core::fmt;
+#[cfg(test)]
+use rand_core::CryptoRng;
+use rand_core::{impls, RngCore};
 
 /// Creates an enum that can be converted from and to a primitive type, with invalid values becoming
 /// a catch-all `Unknown` variant.
@@ -81,3 +84,78 @@ impl<T: fmt::LowerHex> fmt::Debug for Hex<T> {
         write!(f, "{:#x}", self.0)
     }
 }
+
+/// A 32-bit xorshift PRNG.
+///
+/// Used where the Link-Layer needs cheap pseudo-random numbers, but shouldn't hold on to the
+/// user's RNG. This is not a cryptographically secure RNG.
+#[derive(Copy, Clone, Debug)]
+pub struct XorShift32(u32);
+
+impl XorShift32 {
+    /// Creates a PRNG seeded with `seed`.
+    pub fn new(seed: u32) -> Self {
+        // xorshift gets stuck at 0
+        XorShift32(if seed == 0 { 1 } else { seed })
+    }
+
+    /// Returns the next pseudo-random number.
+    pub fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
+
+impl RngCore for XorShift32 {
+    fn next_u32(&mut self) -> u32 {
+        XorShift32::next_u32(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_u32(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Deterministic "RNG" for tests, yielding consecutive bytes after the seed.
+///
+/// Unlike `XorShift32`, this implements `CryptoRng`, so it can be passed to the security
+/// procedures.
+#[cfg(test)]
+pub(crate) struct CountingRng(pub(crate) u8);
+
+#[cfg(test)]
+impl RngCore for CountingRng {
+    fn next_u32(&mut self) -> u32 {
+        impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for b in dest {
+            self.0 = self.0.wrapping_add(1);
+            *b = self.0;
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl CryptoRng for CountingRng {}